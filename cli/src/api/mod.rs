@@ -1,7 +1,14 @@
 
 use difference::{Changeset, Difference};
+use paradise_core::codec::CodecConfig;
+use paradise_core::stream::fec::FecConfig;
+use paradise_core::stream::header::SampleFormat;
+use paradise_core::stream::multicast::MulticastConfig;
+use paradise_core::stream::rtp::RtpConfig;
 use serde::{Deserialize, Serialize};
 
+pub use paradise_core::stream::quic::TLS;
+
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Outputs {
@@ -15,24 +22,50 @@ pub struct Inputs {
     pub listeners: Vec<Listener>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TLS {
-    pub cacert: Option<String>,
-    pub cert: String,
-    pub key: Option<String>,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Listener {
     pub addr: String,
+    /// Input channels the stream's channels are received on, in
+    /// order and numbered from zero. All of them if unset.
+    pub channels: Option<Vec<usize>>,
     pub tls: Option<TLS>,
+    /// File holding the pre-shared key UDP datagrams must be
+    /// encrypted with.
+    pub psk: Option<String>,
+    /// Interface to join the group on, if `addr` is a multicast group.
+    pub multicast: Option<MulticastConfig>,
+    /// Format of the stream, if `addr` is an RTP stream.
+    pub rtp: Option<RtpConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Destination {
     pub addr: String,
+    /// Output channels sent, in the order they're sent and numbered
+    /// from zero. Only these are encoded and take up bandwidth. All
+    /// of them if unset.
     pub channels: Option<Vec<usize>>,
     pub tls: Option<TLS>,
+    /// Skip verifying the listener's certificate. NOTE, this is
+    /// vulnerable to MITM attacks.
+    pub insecure: Option<bool>,
+    /// File holding the pre-shared key UDP datagrams are encrypted
+    /// with. Cleartext if unset.
+    pub psk: Option<String>,
+    /// TTL and interface, if `addr` is a multicast group.
+    pub multicast: Option<MulticastConfig>,
+    /// Payload type, encoding and packet time, if `addr` is an RTP
+    /// stream. Codec, FEC and sample format don't apply to RTP.
+    pub rtp: Option<RtpConfig>,
+    /// Compression applied to the audio. Uncompressed PCM if unset.
+    pub codec: Option<CodecConfig>,
+    /// Forward error correction for datagram transports.
+    pub fec: Option<FecConfig>,
+    /// Sample format of uncompressed audio. 32-bit float if unset.
+    #[serde(rename = "sampleFormat")]
+    pub sample_format: Option<SampleFormat>,
+    /// Apply TPDF dither when converting to an integer sample format.
+    pub dither: Option<bool>,
 }
 
 /// Defines a virtual audio device which can later be
@@ -55,9 +88,28 @@ impl Device {
     }
 }
 
+/// Receives a stream over one transport and sends it on over others,
+/// e.g. to bridge the studio LAN to remote listeners through a single
+/// hardened machine.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Relay {
+    pub name: String,
+    /// Channels of the stream received.
+    pub channels: usize,
+    /// Rate the stream is relayed at. 48 kHz if unset.
+    #[serde(rename = "sampleRate")]
+    pub sample_rate: Option<u32>,
+    pub listener: Listener,
+    /// Each destination picks its own channels, codec, FEC and
+    /// sample format from the relayed stream.
+    pub destinations: Vec<Destination>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Upstream {
     pub name: String,
+    /// `<IP>:<PORT>/<PROTOCOL>`, or `mdns://<name>` for an endpoint
+    /// advertised over mDNS (see `paradise_core::stream::mdns`).
     pub addr: String,
 }
 
@@ -65,6 +117,7 @@ pub struct Upstream {
 pub struct Config {
     pub upstream: Option<Vec<Upstream>>,
     pub devices: Vec<Device>,
+    pub relays: Option<Vec<Relay>>,
 }
 
 impl Config {
@@ -83,6 +136,9 @@ impl Config {
         self.devices
             .sort_by(|a, b| a.name.partial_cmp(&b.name).unwrap());
         self.devices.iter_mut().for_each(|d| d.sort());
+        if let Some(relays) = &mut self.relays {
+            relays.sort_by(|a, b| a.name.partial_cmp(&b.name).unwrap());
+        }
     }
 
     fn reconcile(a: &Config, b: &Config) -> Vec<Difference> {
@@ -136,6 +192,7 @@ impl Config {
         let Self {
             upstream,
             mut devices,
+            mut relays,
         } = self;
         let mut addrs: std::collections::BTreeMap<String, String> =
             std::collections::BTreeMap::new();
@@ -160,9 +217,20 @@ impl Config {
                     None => {}
                 });
         });
+        relays.iter_mut().flatten().for_each(|r| {
+            if let Some(addr) = addrs.get(&r.listener.addr) {
+                r.listener.addr = addr.clone();
+            }
+            r.destinations.iter_mut().for_each(|output| {
+                if let Some(addr) = addrs.get(&output.addr) {
+                    output.addr = addr.clone();
+                }
+            });
+        });
         Self {
             upstream: None,
             devices,
+            relays,
         }
     }
 }
@@ -286,7 +354,11 @@ mod test {
         let mut desired = current.clone();
         desired.devices[0].inputs.listeners.push(Listener {
             addr: String::from("127.0.0.1:2000/TCP"),
+            channels: None,
             tls: None,
+            psk: None,
+            multicast: None,
+            rtp: None,
         });
         let diffs = Config::diff(current, desired);
         assert_eq!(diffs.len(), 3);
@@ -340,12 +412,96 @@ mod test {
             addr: String::from("127.0.0.1:2000/TCP"),
             channels: None,
             tls: None,
+            insecure: None,
+            psk: None,
+            multicast: None,
+            rtp: None,
+            codec: None,
+            fec: None,
+            sample_format: None,
+            dither: None,
         });
         let diffs = Config::diff(current, desired);
         assert_eq!(diffs.len(), 2);
         assert!(is_add(&diffs[1]));
     }
 
+    #[test]
+    fn test_destination_fec() {
+        let config = Config::from_yaml(CONFIG).unwrap();
+        let destinations = &config.devices[0].outputs.destinations;
+        let fec = destinations
+            .iter()
+            .find_map(|d| d.fec)
+            .expect("example config should enable fec");
+        assert_eq!(fec.scheme, paradise_core::stream::fec::FecScheme::Xor);
+        assert_eq!(fec.group, 8);
+        assert_eq!(fec.parity, 1);
+        assert!(fec.validate().is_ok());
+    }
+
+    #[test]
+    fn test_destination_sample_format() {
+        let config = Config::from_yaml(CONFIG).unwrap();
+        let destination = config.devices[0]
+            .outputs
+            .destinations
+            .iter()
+            .find(|d| d.sample_format.is_some())
+            .expect("example config should set a sample format");
+        assert_eq!(destination.sample_format, Some(SampleFormat::I24));
+        assert_eq!(destination.dither, Some(true));
+    }
+
+    #[test]
+    fn test_channels() {
+        let config = Config::from_yaml(CONFIG).unwrap();
+        let device = &config.devices[0];
+        let destination = device
+            .outputs
+            .destinations
+            .iter()
+            .find_map(|d| d.channels.as_ref())
+            .expect("example config should select destination channels");
+        assert_eq!(destination, &vec![1]);
+        let listener = device
+            .inputs
+            .listeners
+            .iter()
+            .find_map(|l| l.channels.as_ref())
+            .expect("example config should map listener channels");
+        assert_eq!(listener, &vec![1]);
+    }
+
+    #[test]
+    fn test_destination_codec() {
+        let config = Config::from_yaml(CONFIG).unwrap();
+        let codec = config.devices[0]
+            .outputs
+            .destinations
+            .iter()
+            .find_map(|d| d.codec)
+            .expect("example config should enable a codec");
+        match codec {
+            CodecConfig::Opus(opus) => {
+                assert_eq!(opus.bitrate, Some(128_000));
+                assert_eq!(opus.frame_ms, 10.0);
+            }
+            _ => panic!("expected opus, got {:?}", codec),
+        }
+    }
+
+    #[test]
+    fn test_relay() {
+        let config = Config::from_yaml(CONFIG).unwrap().resolve();
+        let relay = &config.relays.as_ref().expect("example config should relay")[0];
+        assert_eq!(relay.channels, 2);
+        assert_eq!(relay.listener.addr, "127.0.0.1:20001/UDP");
+        let codecs: Vec<_> = relay.destinations.iter().map(|d| d.codec).collect();
+        assert!(matches!(codecs[0], Some(CodecConfig::Opus(_))));
+        assert_eq!(codecs[1], None);
+    }
+
     #[test]
     fn test_remove_destination() {
         let current = Config::from_yaml(CONFIG).unwrap();
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Context, Result};
+use paradise_core::stream::{
+    addr::{Protocol, StreamAddr},
+    catalog::{self, Catalog},
+    mdns::{self, MDNS_ADDR},
+    multicast::MulticastConfig,
+    sap::{self, SAP_ADDR},
+};
+use std::time::Duration;
+
+/// List the streams announced on the network, including
+/// those of AES67 devices, or the endpoints advertised
+/// over mDNS
+#[derive(clap::Clap)]
+pub struct CatalogArgs {
+    /// Seconds to listen for announcements. Default is
+    /// a little longer than senders take to repeat theirs.
+    #[clap(long = "wait")]
+    wait: Option<u64>,
+
+    /// Interface to listen for announcements on, by IPv4
+    /// address or IPv6 interface index
+    #[clap(long = "interface")]
+    interface: Option<String>,
+
+    /// Print each stream's SDP, e.g. to save for patch --sdp
+    #[clap(long = "sdp")]
+    sdp: bool,
+
+    /// List the endpoints advertised over mDNS instead, by
+    /// the names configs refer to them as, e.g.
+    /// mdns://studio-b-rack
+    #[clap(long = "mdns")]
+    mdns: bool,
+}
+
+pub async fn main(args: CatalogArgs) -> Result<()> {
+    let multicast = MulticastConfig {
+        interface: match &args.interface {
+            Some(interface) => Some(
+                interface
+                    .parse()
+                    .with_context(|| format!("invalid interface \"{}\"", interface))?,
+            ),
+            None => None,
+        },
+        ..Default::default()
+    };
+    if args.mdns {
+        if args.sdp {
+            return Err(anyhow!("--sdp is only for announced streams"));
+        }
+        return browse(&args, &multicast).await;
+    }
+    let wait = match args.wait {
+        Some(secs) => Duration::from_secs(secs),
+        None => sap::DEFAULT_INTERVAL + Duration::from_secs(5),
+    };
+    let catalog = Catalog::new(*SAP_ADDR, &multicast, catalog::DEFAULT_TIMEOUT)?;
+    info!("listening for announcements for {:?}", wait);
+    tokio::time::delay_for(wait).await;
+    catalog.shutdown().await;
+    let entries = catalog.entries();
+    if !args.sdp {
+        println!("{:<32} {:<24} {:<20} ORIGIN", "NAME", "ADDRESS", "FORMAT");
+    }
+    for entry in entries {
+        let sdp = &entry.sdp;
+        if args.sdp {
+            println!("{}", sdp);
+            continue;
+        }
+        println!(
+            "{:<32} {:<24} {:<20} {}",
+            sdp.name,
+            StreamAddr::new(sdp.dest, Protocol::Rtp).to_string(),
+            format!(
+                "{}/{}/{} {}ms",
+                sdp.rtp.encoding.as_str(),
+                sdp.rtp.sample_rate,
+                sdp.rtp.channels,
+                sdp.rtp.packet_time.as_millis()
+            ),
+            sdp.origin
+        );
+    }
+    Ok(())
+}
+
+/// Queries for the endpoints advertised over mDNS and lists those
+/// that answer.
+async fn browse(args: &CatalogArgs, multicast: &MulticastConfig) -> Result<()> {
+    let wait = Duration::from_secs(args.wait.unwrap_or(3));
+    info!("querying for endpoints for {:?}", wait);
+    let endpoints = mdns::browse(*MDNS_ADDR, multicast, wait).await?;
+    println!(
+        "{:<32} {:<24} {:<20} FINGERPRINT",
+        "NAME", "ADDRESS", "FORMAT"
+    );
+    for endpoint in endpoints {
+        let service = &endpoint.service;
+        let field = |value: Option<String>| value.unwrap_or_else(|| String::from("?"));
+        println!(
+            "{:<32} {:<24} {:<20} {}",
+            format!("{}{}", mdns::SCHEME, service.name),
+            endpoint.addr.to_string(),
+            format!(
+                "{}/{} {}",
+                field(service.sample_rate.map(|rate| rate.to_string())),
+                field(service.channels.map(|channels| channels.to_string())),
+                service.codec.map(|codec| codec.as_str()).unwrap_or("")
+            ),
+            field(
+                service
+                    .fingerprint
+                    .map(|fingerprint| fingerprint.to_string())
+            )
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use super::{x509::CertInfo, CA_CERT, CA_KEY};
+use anyhow::Result;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa,
+    KeyUsagePurpose,
+};
+
+/// Generate a certificate authority for issuing certificates
+#[derive(clap::Clap)]
+pub struct CaArgs {
+    /// Directory certificates are kept in. Default is the
+    /// user's config directory.
+    #[clap(long = "dir")]
+    dir: Option<String>,
+
+    /// Common name of the certificate authority
+    #[clap(long = "name", default_value = "Paradise CA")]
+    name: String,
+
+    /// Number of days the certificate authority is valid for
+    #[clap(long = "days", default_value = "3650")]
+    days: u32,
+
+    /// Replace an existing certificate authority. Certificates
+    /// it issued are no longer trusted by peers given the new one.
+    #[clap(long = "force")]
+    force: bool,
+}
+
+pub async fn main(args: CaArgs) -> Result<()> {
+    let dir = super::cert_dir(&args.dir)?;
+    let (cert_path, key_path) = (dir.join(CA_CERT), dir.join(CA_KEY));
+    super::ensure_absent(&[&cert_path, &key_path], args.force)?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, args.name.as_str());
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params.serial_number = Some(rand::random());
+    super::set_validity(&mut params, args.days);
+    let cert = Certificate::from_params(params)?;
+    let der = cert.serialize_der()?;
+    super::write_cert(&cert_path, &der, args.force)?;
+    super::write_key(&key_path, &cert.serialize_private_key_pem(), args.force)?;
+    let info = CertInfo::parse(&der)?;
+    info!(
+        "created certificate authority \"{}\" in {}",
+        &args.name,
+        dir.display()
+    );
+    println!("certificate: {}", cert_path.display());
+    println!("key:         {}", key_path.display());
+    println!("expires:     {}", info.not_after);
+    println!("sha256:      {}", info.fingerprint);
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use super::x509::{self, CertInfo};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// Show the details of certificates, including the SHA-256
+/// fingerprints peers can pin
+#[derive(clap::Clap)]
+pub struct InspectArgs {
+    /// Certificate files, PEM or DER encoded
+    files: Vec<String>,
+}
+
+pub async fn main(args: InspectArgs) -> Result<()> {
+    for (i, file) in args.files.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let path = Path::new(file);
+        for der in super::read_certs(path)? {
+            let info = CertInfo::parse(&der).with_context(|| file.clone())?;
+            println!("{}", file);
+            print(&info);
+        }
+    }
+    Ok(())
+}
+
+fn print(info: &CertInfo) {
+    let serial = info
+        .serial
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    println!("  subject:    {}", x509::display_name(&info.subject));
+    println!("  issuer:     {}", x509::display_name(&info.issuer));
+    println!("  serial:     {}", serial);
+    if !info.names.is_empty() {
+        println!("  names:      {}", info.names.join(", "));
+    }
+    println!("  ca:         {}", info.is_ca);
+    println!("  not before: {}", info.not_before);
+    println!(
+        "  not after:  {}{}",
+        info.not_after,
+        if info.is_expired() { " (EXPIRED)" } else { "" }
+    );
+    println!("  sha256:     {}", info.fingerprint);
+}
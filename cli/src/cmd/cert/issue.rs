@@ -0,0 +1,116 @@
+use super::{x509::CertInfo, CA_CERT, CA_KEY};
+use anyhow::{anyhow, bail, Context, Result};
+use rcgen::{
+    Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, SanType,
+};
+
+/// Issue a certificate for a device, signed by the certificate
+/// authority. The certificate is valid for both ends of a
+/// stream, so the same one can be used to listen and, for mutual
+/// TLS, to connect.
+#[derive(clap::Clap)]
+pub struct IssueArgs {
+    /// Name of the device. Used as the common name, the first DNS
+    /// name, and the file name of the certificate.
+    name: String,
+
+    /// Additional DNS name or IP address the certificate is
+    /// valid for. May be given more than once.
+    #[clap(long = "san")]
+    sans: Vec<String>,
+
+    /// Directory certificates are kept in. Default is the
+    /// user's config directory.
+    #[clap(long = "dir")]
+    dir: Option<String>,
+
+    /// Number of days the certificate is valid for
+    #[clap(long = "days", default_value = "825")]
+    days: u32,
+
+    /// Replace an existing certificate of the same name
+    #[clap(long = "force")]
+    force: bool,
+}
+
+pub async fn main(args: IssueArgs) -> Result<()> {
+    if args.name.is_empty() || args.name.starts_with('.') || args.name.contains(&['/', '\\'][..]) {
+        bail!("invalid certificate name \"{}\"", &args.name);
+    }
+    let dir = super::cert_dir(&args.dir)?;
+    let ca = load_ca(&dir)?;
+    let cert_path = dir.join(format!("{}.crt", &args.name));
+    let key_path = dir.join(format!("{}.key", &args.name));
+    super::ensure_absent(&[&cert_path, &key_path], args.force)?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, args.name.as_str());
+    params.subject_alt_names = std::iter::once(&args.name)
+        .chain(args.sans.iter())
+        .map(|name| match name.parse() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        })
+        .collect();
+    params.is_ca = IsCa::SelfSignedOnly;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ];
+    params.serial_number = Some(rand::random());
+    super::set_validity(&mut params, args.days);
+    let cert = Certificate::from_params(params)?;
+    let der = cert.serialize_der_with_signer(&ca)?;
+    super::write_cert(&cert_path, &der, args.force)?;
+    super::write_key(&key_path, &cert.serialize_private_key_pem(), args.force)?;
+    let info = CertInfo::parse(&der)?;
+    info!("issued certificate \"{}\"", &args.name);
+    println!("certificate: {}", cert_path.display());
+    println!("key:         {}", key_path.display());
+    println!("names:       {}", info.names.join(", "));
+    println!("expires:     {}", info.not_after);
+    println!("sha256:      {}", info.fingerprint);
+    Ok(())
+}
+
+/// Loads the certificate authority in `dir` for signing. Its
+/// subject is rebuilt from the certificate, which reproduces it
+/// exactly for authorities created by `paradise cert ca`.
+fn load_ca(dir: &std::path::Path) -> Result<Certificate> {
+    let (cert_path, key_path) = (dir.join(CA_CERT), dir.join(CA_KEY));
+    if !cert_path.exists() {
+        bail!(
+            "no certificate authority in {} (tip: run paradise cert ca)",
+            dir.display()
+        );
+    }
+    let der = super::read_certs(&cert_path)?.remove(0);
+    let info = CertInfo::parse(&der).with_context(|| format!("{}", cert_path.display()))?;
+    if !info.is_ca {
+        bail!("{} is not a certificate authority", cert_path.display());
+    }
+    if info.is_expired() {
+        bail!("certificate authority expired on {}", info.not_after);
+    }
+    let key =
+        std::fs::read_to_string(&key_path).with_context(|| format!("{}", key_path.display()))?;
+    let key = KeyPair::from_pem(&key).map_err(|e| anyhow!("{}: {}", key_path.display(), e))?;
+    let mut params = CertificateParams::default();
+    params.alg = key
+        .compatible_algs()
+        .next()
+        .ok_or_else(|| anyhow!("{}: unsupported key", key_path.display()))?;
+    params.distinguished_name = DistinguishedName::new();
+    for attr in &info.subject {
+        params
+            .distinguished_name
+            .push(DnType::from_oid(&attr.oid), attr.value.as_str());
+    }
+    params.is_ca = IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    params.key_pair = Some(key);
+    Ok(Certificate::from_params(params)?)
+}
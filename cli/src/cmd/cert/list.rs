@@ -0,0 +1,62 @@
+use super::x509::CertInfo;
+use anyhow::{Context, Result};
+
+/// List the certificates in the certificate directory
+#[derive(clap::Clap)]
+pub struct ListArgs {
+    /// Directory certificates are kept in. Default is the
+    /// user's config directory.
+    #[clap(long = "dir")]
+    dir: Option<String>,
+
+    /// Only list certificates that expire within this many days
+    #[clap(long = "expiring")]
+    expiring: Option<u32>,
+}
+
+pub async fn main(args: ListArgs) -> Result<()> {
+    let dir = super::cert_dir(&args.dir)?;
+    let mut paths = std::fs::read_dir(&dir)
+        .with_context(|| format!("{}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("crt") | Some("pem") | Some("der")
+            )
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    let horizon = args
+        .expiring
+        .map(|days| chrono::Utc::now() + chrono::Duration::days(days as i64));
+    println!("{:<24} {:<20} {:<12} SHA256", "NAME", "EXPIRES", "STATUS");
+    for path in paths {
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let info = match super::read_certs(&path).and_then(|certs| CertInfo::parse(&certs[0])) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if matches!(horizon, Some(horizon) if info.not_after > horizon) {
+            continue;
+        }
+        let status = if info.is_expired() {
+            "EXPIRED"
+        } else if info.is_ca {
+            "CA"
+        } else {
+            ""
+        };
+        println!(
+            "{:<24} {:<20} {:<12} {}",
+            name,
+            info.not_after.format("%Y-%m-%d %H:%M:%S"),
+            status,
+            info.fingerprint
+        );
+    }
+    Ok(())
+}
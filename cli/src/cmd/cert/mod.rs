@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Clap;
+use paradise_core::stream::{fingerprint::Fingerprint, quic::TLS};
+use std::path::{Path, PathBuf};
+
+pub mod ca;
+pub mod inspect;
+pub mod issue;
+pub mod list;
+pub mod psk;
+pub mod x509;
+
+/// File names of the certificate authority within a certificate
+/// directory. Issued certificates are named after their subject.
+pub const CA_CERT: &str = "ca.crt";
+pub const CA_KEY: &str = "ca.key";
+
+#[derive(Clap)]
+pub enum CertCommand {
+    /// Generate a certificate authority
+    #[clap(name = "ca")]
+    Ca(ca::CaArgs),
+
+    /// Issue a certificate signed by the certificate authority
+    #[clap(name = "issue")]
+    Issue(issue::IssueArgs),
+
+    /// Show the details of certificate files
+    #[clap(name = "inspect")]
+    Inspect(inspect::InspectArgs),
+
+    /// List the certificates in the certificate directory
+    #[clap(name = "list")]
+    List(list::ListArgs),
+
+    /// Generate a pre-shared key for encrypted UDP streams
+    #[clap(name = "psk")]
+    Psk(psk::PskArgs),
+}
+
+/// Manage the certificates securing QUIC streams and the keys
+/// encrypting UDP streams
+#[derive(Clap)]
+pub struct CertArgs {
+    #[clap(subcommand)]
+    cmd: CertCommand,
+}
+
+pub async fn main(args: CertArgs) -> Result<()> {
+    match args.cmd {
+        CertCommand::Ca(args) => ca::main(args).await,
+        CertCommand::Issue(args) => issue::main(args).await,
+        CertCommand::Inspect(args) => inspect::main(args).await,
+        CertCommand::List(args) => list::main(args).await,
+        CertCommand::Psk(args) => psk::main(args).await,
+    }
+}
+
+/// TLS options of commands that listen for or connect to QUIC
+/// streams. See `paradise_core::stream::quic::TLS`.
+#[derive(Clap)]
+pub struct TlsArgs {
+    /// Certificate chain to present, PEM or DER encoded
+    #[clap(long = "cert")]
+    cert: Option<String>,
+
+    /// Private key for --cert
+    #[clap(long = "key")]
+    key: Option<String>,
+
+    /// Certificate authority the peer's certificate must chain to
+    #[clap(long = "cacert")]
+    cacert: Option<String>,
+
+    /// SHA-256 fingerprint of a peer certificate to accept. May be
+    /// given more than once.
+    #[clap(long = "pin")]
+    pins: Vec<String>,
+}
+
+impl TlsArgs {
+    /// The configured settings, or `None` if no option was given.
+    pub fn tls(&self) -> Result<Option<TLS>> {
+        if self.cert.is_some() != self.key.is_some() {
+            return Err(anyhow!("--cert and --key must be given together"));
+        }
+        if self.cert.is_none() && self.cacert.is_none() && self.pins.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(TLS {
+            cacert: self.cacert.clone(),
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            pins: self.pins.clone(),
+            ..Default::default()
+        }))
+    }
+
+    /// Fingerprint of the certificate given with --cert, for peers
+    /// to pin.
+    pub fn fingerprint(&self) -> Result<Option<Fingerprint>> {
+        match &self.cert {
+            Some(path) => Ok(Some(Fingerprint::of(&read_certs(Path::new(path))?[0]))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Directory certificates are kept in. The user's config directory
+/// unless `dir` is given.
+pub fn cert_dir(dir: &Option<String>) -> Result<PathBuf> {
+    match dir {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => directories::ProjectDirs::from("org", "paradise", "paradise")
+            .map(|dirs| dirs.config_dir().join("certs"))
+            .ok_or_else(|| anyhow!("no home directory (tip: use --dir)")),
+    }
+}
+
+/// Reads every certificate in a PEM file, or the one in a DER file.
+pub fn read_certs(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let data = std::fs::read(path).with_context(|| format!("{}", path.display()))?;
+    if !data.starts_with(b"-----BEGIN ") {
+        return Ok(vec![data]);
+    }
+    let certs = pem::parse_many(&data)
+        .into_iter()
+        .filter(|pem| pem.tag == "CERTIFICATE")
+        .map(|pem| pem.contents)
+        .collect::<Vec<_>>();
+    if certs.is_empty() {
+        return Err(anyhow!("{}: no certificates found", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Fails if any of `paths` exists, unless `force` is set, so that a
+/// certificate and its key are written together or not at all.
+pub fn ensure_absent(paths: &[&Path], force: bool) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    match paths.iter().find(|path| path.exists()) {
+        Some(path) => Err(anyhow!(
+            "{} already exists (tip: use --force)",
+            path.display()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Writes a PEM encoded certificate, refusing to replace an
+/// existing one unless `force` is set.
+pub fn write_cert(path: &Path, der: &[u8], force: bool) -> Result<()> {
+    let pem = pem::encode(&pem::Pem {
+        tag: String::from("CERTIFICATE"),
+        contents: der.to_vec(),
+    });
+    write(path, pem.as_bytes(), force, 0o644)
+}
+
+/// Writes a private or pre-shared key readable only by its owner.
+pub fn write_key(path: &Path, key: &str, force: bool) -> Result<()> {
+    write(path, key.as_bytes(), force, 0o600)
+}
+
+fn write(path: &Path, data: &[u8], force: bool, mode: u32) -> Result<()> {
+    use std::io::Write;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => {
+            anyhow!("{} already exists (tip: use --force)", path.display())
+        }
+        _ => anyhow!("{}: {}", path.display(), e),
+    })?;
+    file.write_all(data)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Sets a certificate to be valid from now for `days`.
+pub fn set_validity(params: &mut rcgen::CertificateParams, days: u32) {
+    let now = chrono::Utc::now();
+    params.not_before = now;
+    params.not_after = now + chrono::Duration::days(days as i64);
+}
@@ -0,0 +1,32 @@
+use anyhow::{bail, Result};
+use paradise_core::stream::crypto::Key;
+
+/// Generate a pre-shared key for encrypting UDP streams. Both ends
+/// of a stream are given the same key file.
+#[derive(clap::Clap)]
+pub struct PskArgs {
+    /// Name of the key, used as its file name
+    name: String,
+
+    /// Directory certificates are kept in. Default is the
+    /// user's config directory.
+    #[clap(long = "dir")]
+    dir: Option<String>,
+
+    /// Replace an existing key of the same name
+    #[clap(long = "force")]
+    force: bool,
+}
+
+pub async fn main(args: PskArgs) -> Result<()> {
+    if args.name.is_empty() || args.name.starts_with('.') || args.name.contains(&['/', '\\'][..]) {
+        bail!("invalid key name \"{}\"", &args.name);
+    }
+    let path = super::cert_dir(&args.dir)?.join(format!("{}.psk", &args.name));
+    super::ensure_absent(&[&path], args.force)?;
+    let key = Key::generate();
+    super::write_key(&path, &format!("{}\n", key), args.force)?;
+    info!("generated pre-shared key \"{}\"", &args.name);
+    println!("key: {}", path.display());
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use paradise_core::stream::fingerprint::Fingerprint;
+use yasna::{models::ObjectIdentifier, BERReader, BERReaderSeq, Tag};
+
+const OID_COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+const OID_SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+const OID_BASIC_CONSTRAINTS: &[u64] = &[2, 5, 29, 19];
+
+/// An attribute of a distinguished name, e.g. the common name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub oid: Vec<u64>,
+    pub value: String,
+}
+
+/// The parts of a certificate worth showing to whoever provisions
+/// it. Only as much of X.509 is parsed as that takes.
+#[derive(Debug, Clone)]
+pub struct CertInfo {
+    pub serial: Vec<u8>,
+    pub issuer: Vec<Attribute>,
+    pub subject: Vec<Attribute>,
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    /// DNS names and IP addresses the certificate is valid for.
+    pub names: Vec<String>,
+    pub is_ca: bool,
+    pub fingerprint: Fingerprint,
+}
+
+impl CertInfo {
+    pub fn parse(der: &[u8]) -> Result<Self> {
+        let fingerprint = Fingerprint::of(der);
+        yasna::parse_ber(der, |r| {
+            r.read_sequence(|r| {
+                let info = r.next().read_sequence(|r| read_tbs(r, fingerprint))?;
+                // Signature algorithm and value.
+                r.next().read_der()?;
+                r.next().read_der()?;
+                Ok(info)
+            })
+        })
+        .map_err(|e| anyhow!("malformed certificate: {}", e))
+    }
+
+    pub fn common_name(&self) -> Option<&str> {
+        common_name(&self.subject)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.not_after < Utc::now()
+    }
+}
+
+/// Formats a distinguished name like `CN=Paradise CA, O=Paradise`,
+/// most specific attribute first.
+pub fn display_name(name: &[Attribute]) -> String {
+    name.iter()
+        .rev()
+        .map(|attr| format!("{}={}", short_name(&attr.oid), attr.value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn common_name(name: &[Attribute]) -> Option<&str> {
+    name.iter()
+        .find(|attr| attr.oid == OID_COMMON_NAME)
+        .map(|attr| attr.value.as_str())
+}
+
+fn short_name(oid: &[u64]) -> String {
+    match oid {
+        [2, 5, 4, 3] => "CN".into(),
+        [2, 5, 4, 6] => "C".into(),
+        [2, 5, 4, 7] => "L".into(),
+        [2, 5, 4, 8] => "ST".into(),
+        [2, 5, 4, 10] => "O".into(),
+        [2, 5, 4, 11] => "OU".into(),
+        _ => oid
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join("."),
+    }
+}
+
+fn read_tbs(r: &mut BERReaderSeq, fingerprint: Fingerprint) -> yasna::ASN1Result<CertInfo> {
+    r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_i64()))?;
+    let serial = r.next().read_tagged_der()?.value().to_vec();
+    // Signature algorithm, repeated in the outer certificate.
+    r.next().read_der()?;
+    let issuer = read_name(r.next())?;
+    let (not_before, not_after) = r.next().read_sequence(|r| {
+        let not_before = read_time(r.next())?;
+        let not_after = read_time(r.next())?;
+        Ok((not_before, not_after))
+    })?;
+    let subject = read_name(r.next())?;
+    // Subject public key.
+    r.next().read_der()?;
+    // Issuer and subject unique identifiers.
+    r.read_optional(|r| r.read_tagged_implicit(Tag::context(1), |r| r.read_bitvec_bytes()))?;
+    r.read_optional(|r| r.read_tagged_implicit(Tag::context(2), |r| r.read_bitvec_bytes()))?;
+    let mut names = Vec::new();
+    let mut is_ca = false;
+    r.read_optional(|r| {
+        r.read_tagged(Tag::context(3), |r| {
+            r.read_sequence_of(|r| {
+                r.read_sequence(|r| {
+                    let oid = r.next().read_oid()?;
+                    r.read_optional(|r| r.read_bool())?;
+                    let value = r.next().read_bytes()?;
+                    if oid == ObjectIdentifier::from_slice(OID_SUBJECT_ALT_NAME) {
+                        names = yasna::parse_ber(&value, read_alt_names)?;
+                    } else if oid == ObjectIdentifier::from_slice(OID_BASIC_CONSTRAINTS) {
+                        is_ca = yasna::parse_ber(&value, |r| {
+                            r.read_sequence(|r| {
+                                let is_ca = r.read_optional(|r| r.read_bool())?;
+                                r.read_optional(|r| r.read_u64())?;
+                                Ok(is_ca.unwrap_or(false))
+                            })
+                        })?;
+                    }
+                    Ok(())
+                })
+            })
+        })
+    })?;
+    Ok(CertInfo {
+        serial,
+        issuer,
+        subject,
+        not_before,
+        not_after,
+        names,
+        is_ca,
+        fingerprint,
+    })
+}
+
+fn read_name(r: BERReader) -> yasna::ASN1Result<Vec<Attribute>> {
+    let mut name = Vec::new();
+    r.read_sequence_of(|r| {
+        r.read_set_of(|r| {
+            r.read_sequence(|r| {
+                let oid = r.next().read_oid()?;
+                // Any of the directory string types. BMPString is
+                // rare enough to not bother decoding.
+                let value = r.next().read_tagged_der()?;
+                name.push(Attribute {
+                    oid: oid.components().clone(),
+                    value: String::from_utf8_lossy(value.value()).into_owned(),
+                });
+                Ok(())
+            })
+        })
+    })?;
+    Ok(name)
+}
+
+fn read_time(r: BERReader) -> yasna::ASN1Result<DateTime<Utc>> {
+    if r.lookahead_tag()? == yasna::tags::TAG_UTCTIME {
+        Ok(*r.read_utctime()?.datetime())
+    } else {
+        Ok(*r.read_generalized_time()?.datetime())
+    }
+}
+
+fn read_alt_names(r: BERReader) -> yasna::ASN1Result<Vec<String>> {
+    let mut names = Vec::new();
+    r.read_sequence_of(|r| {
+        let name = r.read_tagged_der()?;
+        match name.tag() {
+            // dNSName
+            Tag { tag_number: 2, .. } => {
+                names.push(String::from_utf8_lossy(name.value()).into_owned())
+            }
+            // iPAddress
+            Tag { tag_number: 7, .. } => match name.value().len() {
+                4 => {
+                    let mut octets = [0u8; 4];
+                    octets.copy_from_slice(name.value());
+                    names.push(std::net::Ipv4Addr::from(octets).to_string());
+                }
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(name.value());
+                    names.push(std::net::Ipv6Addr::from(octets).to_string());
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(names)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_rcgen_certificate() {
+        let mut params = rcgen::CertificateParams::new(vec![
+            String::from("studio-a.local"),
+            String::from("10.0.0.7"),
+        ]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "studio-a");
+        params.subject_alt_names[1] = rcgen::SanType::IpAddress("10.0.0.7".parse().unwrap());
+        params.not_before = rcgen::date_time_ymd(2020, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+        params.serial_number = Some(1234);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let info = CertInfo::parse(&der).unwrap();
+        assert_eq!(info.common_name(), Some("studio-a"));
+        assert_eq!(info.names, vec!["studio-a.local", "10.0.0.7"]);
+        assert_eq!(info.not_before, rcgen::date_time_ymd(2020, 1, 1));
+        assert_eq!(info.not_after, rcgen::date_time_ymd(2030, 1, 1));
+        assert_eq!(info.serial, vec![0x04, 0xd2]);
+        assert_eq!(info.fingerprint, Fingerprint::of(&der));
+        assert!(!info.is_ca);
+        assert!(CertInfo::parse(&der[..der.len() - 1]).is_err());
+    }
+}
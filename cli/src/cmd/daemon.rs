@@ -12,6 +12,8 @@ pub struct DaemonArgs {
 
     #[clap(long = "port", short = "p", default_value = "8080")]
     port: u16,
+    // No --cert/--key (see cert::TlsArgs) until the daemon accepts
+    // connections: only patch takes explicit certificate paths so far.
 }
 
 pub async fn main(_args: DaemonArgs) -> Result<(), anyhow::Error> {
@@ -91,7 +93,7 @@ pub async fn main(_args: DaemonArgs) -> Result<(), anyhow::Error> {
     input_stream.play()?;
     */
     println!("Playing for 10000 seconds... ");
-    std::thread::sleep(std::time::Duration::from_secs(10000));
+    tokio::time::delay_for(std::time::Duration::from_secs(10000)).await;
     /*
     drop(input_stream);
     drop(output_stream);
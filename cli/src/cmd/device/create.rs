@@ -1,7 +1,11 @@
 use anyhow::{anyhow, Error, Result, Context, bail};
 use cpal::traits::{DeviceTrait};
+use paradise_core::channels::ChannelMap;
+use paradise_core::codec::{lossless::LosslessConfig, opus::OpusConfig, CodecConfig};
 use paradise_core::device::{DeviceSpec, Endpoint};
+use paradise_core::stream::quic::TLS;
 use super::platform;
+use super::super::cert::TlsArgs;
 
 /// Create a virtual audio device
 #[derive(clap::Clap)]
@@ -17,6 +21,34 @@ pub struct CreateArgs {
     /// Destination address for receiving audio
     #[clap(long = "dest", short = "d")]
     dest: String,
+
+    #[clap(flatten)]
+    tls: TlsArgs,
+
+    /// Skip verifying the destination's certificate. NOTE, this
+    /// is vulnerable to MITM attacks.
+    #[clap(long = "insecure")]
+    insecure: bool,
+
+    /// Compress audio with Opus at this bitrate (bits per second)
+    /// instead of sending uncompressed PCM
+    #[clap(long = "opus-bitrate")]
+    opus_bitrate: Option<u32>,
+
+    /// Compress audio losslessly instead of sending uncompressed PCM
+    #[clap(long = "lossless")]
+    lossless: bool,
+
+    /// Output channel to send, numbered from zero. May be given
+    /// more than once, and the channels are sent in the order
+    /// given. Default is every channel.
+    #[clap(long = "channel")]
+    channels: Vec<u16>,
+
+    /// Serve the device's stream metrics over HTTP on this
+    /// address, e.g. 127.0.0.1:9101
+    #[clap(long = "metrics")]
+    metrics: Option<std::net::SocketAddr>,
 }
 
 pub async fn main(args: CreateArgs) -> Result<()> {
@@ -25,16 +57,42 @@ pub async fn main(args: CreateArgs) -> Result<()> {
         &args.name, &args.dest, args.yes,
     );
 
+    let codec = match (args.opus_bitrate, args.lossless) {
+        (Some(_), true) => bail!("--opus-bitrate and --lossless are mutually exclusive"),
+        (Some(bitrate), false) => CodecConfig::Opus(OpusConfig {
+            bitrate: Some(bitrate),
+            ..Default::default()
+        }),
+        (None, true) => CodecConfig::Lossless(LosslessConfig::default()),
+        (None, false) => CodecConfig::Pcm,
+    };
+    let tls = args.tls.tls()?.map(absolute).transpose()?;
+    if args.insecure && tls.as_ref().map_or(false, |tls| tls.cacert.is_some() || !tls.pins.is_empty()) {
+        bail!("--insecure can't be combined with --cacert or --pin");
+    }
+    let outputs = 2;
+    let channels = match args.channels.len() {
+        0 => None,
+        _ => {
+            ChannelMap::new(&args.channels, outputs)?;
+            Some(args.channels.clone())
+        },
+    };
     let device = DeviceSpec {
         name: args.name.clone(),
-        outputs: 2,
+        outputs,
         inputs: 2,
         endpoints: vec![Endpoint {
             name: String::from("default"),
-            insecure: true,
+            insecure: args.insecure,
+            tls,
             addr: args.dest.clone(),
+            codec,
+            channels,
+            ..Default::default()
         }],
         display_name: format!("{} (Paradise)", &args.name),
+        metrics: args.metrics,
     };
 
     platform::install_device(&device).await?;
@@ -45,3 +103,15 @@ pub async fn main(args: CreateArgs) -> Result<()> {
 
     Ok(())
 }
+
+/// Makes the paths in `tls` absolute, since the driver doesn't
+/// run in this directory.
+fn absolute(mut tls: TLS) -> Result<TLS> {
+    for path in vec![&mut tls.cacert, &mut tls.cert, &mut tls.key].into_iter().flatten() {
+        *path = std::fs::canonicalize(&*path)
+            .with_context(|| path.clone())?
+            .to_string_lossy()
+            .into_owned();
+    }
+    Ok(tls)
+}
@@ -22,16 +22,13 @@ use std::{
     sync::{Arc, mpsc, Mutex, atomic::{AtomicU64, Ordering}},
     fs,
 };
-use paradise_core::{Frame, device::{DeviceSpec, Endpoint}};
+use paradise_core::{device::{DeviceSpec, Endpoint}, stream::header::Header};
 use crossbeam::channel::{Sender, Receiver};
 use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 use std::time::{Duration, SystemTime};
 use futures::{StreamExt, TryFutureExt};
 
-#[allow(unused)]
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-27"];
-
 lazy_static! {
     static ref CORE_AUDIO_LOCK: Mutex<()> = Mutex::new(());
     //static ref LAST_CORE_AUDIO_RESTART: Arc<Mutex<Option<SystemTime>>> = Arc::new(Mutex::new(None));
@@ -247,6 +244,7 @@ mod test {
                 name: String::from("test"),
                 addr: "127.0.0.1:5000".into(),
                 insecure: true,
+                ..Default::default()
             }],
             ..Default::default()
         };
@@ -273,7 +271,7 @@ mod test {
             let mut server_config = ServerConfig::default();
             server_config.transport = Arc::new(transport_config);
             let mut server_config = ServerConfigBuilder::new(server_config);
-            server_config.protocols(ALPN_QUIC_HTTP);
+            server_config.protocols(paradise_core::stream::quic::ALPN);
             let dirs = directories::ProjectDirs::from("org", "quinn", "quinn-examples").unwrap();
             let path = dirs.data_local_dir();
             let cert_path = path.join("cert.der");
@@ -320,6 +318,7 @@ mod test {
                 name: String::from("test"),
                 addr: addr.to_string(),
                 insecure: true,
+                ..Default::default()
             }],
             ..Default::default()
         };
@@ -357,7 +356,7 @@ mod test {
         let mut server_config = ServerConfig::default();
         server_config.transport = Arc::new(transport_config);
         let mut server_config = ServerConfigBuilder::new(server_config);
-        server_config.protocols(ALPN_QUIC_HTTP);
+        server_config.protocols(paradise_core::stream::quic::ALPN);
         let dirs = directories::ProjectDirs::from("org", "quinn", "quinn-examples").unwrap();
         let path = dirs.data_local_dir();
         let cert_path = path.join("cert.der");
@@ -394,7 +393,8 @@ mod test {
             } = conn.await.expect("failed to accept incoming connection");
             send_conn.send(())?;
             while let Some(data) = datagrams.next().await {
-                let frame: Frame = bincode::deserialize(data?.as_ref())?;
+                let data = data?;
+                Header::parse(data.as_ref())?;
                 let mut send_data = send_data.lock().unwrap();
                 if let (s, true) = &*send_data {
                     s.send(())?;
@@ -437,6 +437,7 @@ mod test {
                 name: String::from("test"),
                 addr: addr.to_string(),
                 insecure: true,
+                ..Default::default()
             }],
             ..Default::default()
         };
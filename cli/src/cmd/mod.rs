@@ -1,12 +1,15 @@
 use clap::Clap;
 
 pub mod apply;
+pub mod catalog;
+pub mod cert;
 pub mod daemon;
 pub mod device;
-//pub mod echo;
 pub mod info;
+pub mod netem;
 pub mod patch;
 pub mod reconcile;
+pub mod relay;
 
 #[derive(Clap)]
 pub enum SubCommand {
@@ -14,6 +17,14 @@ pub enum SubCommand {
     #[clap(name = "apply")]
     Apply(apply::ApplyArgs),
 
+    /// List streams announced on the network
+    #[clap(name = "catalog")]
+    Catalog(catalog::CatalogArgs),
+
+    /// Manage certificates and keys for securing streams
+    #[clap(name = "cert")]
+    Cert(cert::CertArgs),
+
     /// Runs the daemon
     #[clap(name = "daemon")]
     Daemon(daemon::DaemonArgs),
@@ -30,14 +41,14 @@ pub enum SubCommand {
     #[clap(name = "list")]
     List(device::list::ListArgs),
 
-    /// Proxies a local port to a destination address
-    //#[clap(name = "echo")]
-    //Echo(echo::EchoArgs),
-
     /// Enumerate IO details on all audio devices
     #[clap(name = "info")]
     Info(info::InfoArgs),
 
+    /// Proxy a local port to another over an impaired network
+    #[clap(name = "netem")]
+    Netem(netem::NetemArgs),
+
     /// Patch mode
     #[clap(name = "patch")]
     Patch(patch::PatchArgs),
@@ -45,6 +56,10 @@ pub enum SubCommand {
     /// Reconcile system drivers with config
     #[clap(name = "reconcile")]
     Reconcile(reconcile::ReconcileArgs),
+
+    /// Relay streams between transports
+    #[clap(name = "relay")]
+    Relay(relay::RelayArgs),
 }
 
 /// Bare metal daemon for Paradise audio engine
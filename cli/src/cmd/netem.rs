@@ -0,0 +1,97 @@
+use anyhow::{Context, Result};
+use paradise_core::stream::{
+    addr::{Protocol, StreamAddr},
+    netem::{tcp::TcpProxy, udp::UdpProxy, NetemConfig},
+};
+use std::{net::SocketAddr, time::Duration};
+
+/// Proxies a local port to another, impairing the traffic
+/// both ways, to see how streams hold up over a bad network
+#[derive(clap::Clap)]
+pub struct NetemArgs {
+    /// Local address to listen on, e.g. 127.0.0.1:30001
+    #[clap(long = "listen")]
+    listen: SocketAddr,
+
+    /// Address to forward to. QUIC unless a protocol is
+    /// given, e.g. 127.0.0.1:30000/TCP. UDP, QUIC and RTP
+    /// are forwarded datagram by datagram.
+    #[clap(long = "upstream")]
+    upstream: String,
+
+    /// Percentage of packets lost
+    #[clap(long = "loss", default_value = "0")]
+    loss: f64,
+
+    /// Average number of packets lost in a row, for bursty
+    /// loss. Default is independent losses.
+    #[clap(long = "loss-burst")]
+    loss_burst: Option<f64>,
+
+    /// Milliseconds each packet is delayed by
+    #[clap(long = "delay", default_value = "0")]
+    delay: u64,
+
+    /// Milliseconds the delay varies by either way
+    #[clap(long = "jitter", default_value = "0")]
+    jitter: u64,
+
+    /// Percentage of packets that skip the delay and overtake
+    /// the ones before them. Needs --delay.
+    #[clap(long = "reorder", default_value = "0")]
+    reorder: f64,
+
+    /// Percentage of packets delivered twice
+    #[clap(long = "duplicate", default_value = "0")]
+    duplicate: f64,
+
+    /// Bandwidth cap in kbit/s. Default is unlimited.
+    #[clap(long = "rate")]
+    rate: Option<u64>,
+
+    /// Seed for the random choices, to repeat a run exactly
+    #[clap(long = "seed")]
+    seed: Option<u64>,
+}
+
+enum Proxy {
+    Udp(std::sync::Arc<UdpProxy>),
+    Tcp(std::sync::Arc<TcpProxy>),
+}
+
+pub async fn main(args: NetemArgs) -> Result<()> {
+    let upstream = match args.upstream.parse::<SocketAddr>() {
+        Ok(addr) => StreamAddr::new(addr, Protocol::Quic),
+        Err(_) => args.upstream.parse()?,
+    };
+    let config = NetemConfig {
+        loss: args.loss / 100.0,
+        loss_burst: args.loss_burst,
+        delay: Duration::from_millis(args.delay),
+        jitter: Duration::from_millis(args.jitter),
+        reorder: args.reorder / 100.0,
+        duplicate: args.duplicate / 100.0,
+        rate: args.rate.map(|rate| rate * 1000),
+        seed: args.seed,
+    };
+    config.validate()?;
+    let proxy = match upstream.protocol {
+        Protocol::Udp | Protocol::Quic | Protocol::Rtp => Proxy::Udp(
+            UdpProxy::new(args.listen, upstream.addr, &config)
+                .with_context(|| format!("failed to listen on {}", args.listen))?,
+        ),
+        Protocol::Tcp => Proxy::Tcp(
+            TcpProxy::new(args.listen, upstream.addr, &config)
+                .with_context(|| format!("failed to listen on {}", args.listen))?,
+        ),
+    };
+    info!("{} -> {} with {:?}", args.listen, upstream, config);
+    // Proxy until interrupted.
+    crate::util::interrupted().await?;
+    info!("shutting down");
+    match proxy {
+        Proxy::Udp(proxy) => proxy.shutdown().await,
+        Proxy::Tcp(proxy) => proxy.shutdown().await,
+    }
+    Ok(())
+}
@@ -6,15 +6,31 @@ use std::{
     str,
     sync::Arc,
 };
-use futures::future::{Abortable, AbortHandle};
 
+use super::cert::TlsArgs;
 use anyhow::{anyhow, Context, Result};
-use futures::{StreamExt, TryFutureExt};
-use paradise_core::Frame;
-use ringbuf::{RingBuffer, Producer};
-use signal_hook::{iterator::Signals, SIGINT};
-
-const LATENCY_MS: f32 = 300.0;
+use paradise_core::channels::ChannelMap;
+use paradise_core::stream::{
+    addr::{Protocol, StreamAddr},
+    catalog::{self, Catalog},
+    crypto::Key,
+    header::{SampleFormat, StreamFormat},
+    mdns::{self, Advertiser, Service, MDNS_ADDR},
+    metrics::{Direction, Metrics},
+    multicast::MulticastConfig,
+    rtp::RtpConfig,
+    rx::{
+        jitter::{JitterBuffer, JitterConfig},
+        quic::QuicRxStream,
+        rtp::RtpRxStream,
+        udp::UdpRxStream,
+        RxStream,
+    },
+    sap::{self, SAP_ADDR},
+    sdp::SessionDescription,
+    stats::StreamStats,
+};
+use std::time::Duration;
 
 /// A subcommand for controlling testing
 #[derive(clap::Clap)]
@@ -31,16 +47,70 @@ pub struct PatchArgs {
     /// Source network interface, e.g. 0.0.0.0:30000
     /// for all interfaces port 30000. Only defined
     /// when patching from network to a device output.
+    /// QUIC unless a protocol is given, e.g.
+    /// 239.69.1.1:5004/UDP to join a multicast group or
+    /// 239.69.1.1:5004/RTP for an AES67 stream. Streams
+    /// announced over SAP are given by name, e.g.
+    /// "sap:Studio A" (tip: run catalog).
     #[clap(long = "source")]
     source: String,
 
+    /// UDP only: file holding the pre-shared key the
+    /// datagrams are encrypted with
+    #[clap(long = "psk")]
+    psk: Option<String>,
+
+    /// UDP and RTP only: interface to join a multicast
+    /// group on, by IPv4 address or IPv6 interface index.
+    /// Also used to listen for SAP announcements.
+    #[clap(long = "interface")]
+    interface: Option<String>,
+
+    /// RTP only: SDP file describing the stream, e.g. as
+    /// exported by an AES67 device. Default is L24 at
+    /// 48 kHz in 1 ms packets with payload type 96.
+    #[clap(long = "sdp")]
+    sdp: Option<String>,
+
+    /// Advertise the listener over mDNS under this name, so
+    /// senders can find it as mdns://<name> whatever its
+    /// address. Unicast sources only.
+    #[clap(long = "advertise")]
+    advertise: Option<String>,
+
+    /// Serve the stream's statistics, and process metrics, over
+    /// HTTP on this address, e.g. 127.0.0.1:9100
+    #[clap(long = "metrics")]
+    metrics: Option<SocketAddr>,
+
     /// QUIC only: enable stateless retry
     #[clap(long = "stateless-retry")]
     stateless_retry: bool,
-}
 
-#[allow(unused)]
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-27"];
+    #[clap(flatten)]
+    tls: TlsArgs,
+
+    /// Lower bound on the adaptive playout delay, in milliseconds.
+    #[clap(long = "min-latency", default_value = "2")]
+    min_latency: u64,
+
+    /// Upper bound on the adaptive playout delay, in milliseconds.
+    #[clap(long = "max-latency", default_value = "500")]
+    max_latency: u64,
+
+    /// Play the stream at its nominal rate instead of following
+    /// the sender's clock.
+    #[clap(long = "no-drift-compensation")]
+    no_drift_compensation: bool,
+
+    /// Device channel to play the next channel of the stream on,
+    /// numbered from zero. May be given more than once, e.g.
+    /// --channel 4 --channel 5 plays a stereo stream on the
+    /// fifth and sixth channels. Default is all of the device's
+    /// channels in order.
+    #[clap(long = "channel")]
+    channels: Vec<u16>,
+}
 
 fn get_device(name: &Option<String>, host: &cpal::Host) -> Result<cpal::Device> {
     match name {
@@ -84,6 +154,180 @@ fn get_device(name: &Option<String>, host: &cpal::Host) -> Result<cpal::Device>
     }
 }
 
+/// The stream being played, by transport.
+enum Source {
+    Quic(Arc<QuicRxStream<f32>>),
+    Udp(Arc<UdpRxStream<f32>>),
+    Rtp(Arc<RtpRxStream<f32>>),
+}
+
+/// Prefix of sources given by the name they're announced with.
+const SAP_SOURCE: &str = "sap:";
+
+/// Listens for the stream announced as `name`, for a little longer
+/// than senders usually take to repeat their announcements.
+async fn discover(name: &str, multicast: &MulticastConfig) -> Result<SessionDescription> {
+    let catalog = Catalog::new(*SAP_ADDR, multicast, catalog::DEFAULT_TIMEOUT)?;
+    info!("waiting for \"{}\" to be announced", name);
+    let sdp = catalog.wait_for(name, sap::DEFAULT_INTERVAL + Duration::from_secs(5)).await;
+    catalog.shutdown().await;
+    sdp.ok_or_else(|| anyhow!("no stream named \"{}\" was announced (tip: run catalog)", name))
+}
+
+impl Source {
+    async fn new(args: &PatchArgs, format: StreamFormat, config: JitterConfig) -> Result<Self> {
+        let multicast = MulticastConfig {
+            interface: match &args.interface {
+                Some(interface) => Some(
+                    interface
+                        .parse()
+                        .with_context(|| format!("invalid interface \"{}\"", interface))?,
+                ),
+                None => None,
+            },
+            ..Default::default()
+        };
+        let (source, announced) = match args.source.strip_prefix(SAP_SOURCE) {
+            Some(name) => {
+                if args.sdp.is_some() {
+                    return Err(anyhow!("--sdp can't be used with announced sources"));
+                }
+                let sdp = discover(name, &multicast).await?;
+                info!("found \"{}\" at {}", name, sdp.dest);
+                (StreamAddr::new(sdp.dest, Protocol::Rtp), Some(sdp))
+            },
+            None => match args.source.parse::<SocketAddr>() {
+                Ok(addr) => (StreamAddr::new(addr, Protocol::Quic), None),
+                Err(_) => (args.source.parse()?, None),
+            },
+        };
+        if source.protocol != Protocol::Udp && args.psk.is_some() {
+            return Err(anyhow!("--psk is only for UDP sources"));
+        }
+        if !matches!(source.protocol, Protocol::Udp | Protocol::Rtp) && args.interface.is_some() {
+            return Err(anyhow!("--interface is only for UDP and RTP sources"));
+        }
+        if source.protocol != Protocol::Rtp && args.sdp.is_some() {
+            return Err(anyhow!("--sdp is only for RTP sources"));
+        }
+        match source.protocol {
+            Protocol::Quic => Ok(Source::Quic(QuicRxStream::new(
+                source.addr,
+                format,
+                config,
+                args.tls.tls()?.as_ref(),
+            )?)),
+            Protocol::Udp => {
+                let key = match &args.psk {
+                    Some(path) => Some(Key::read(path)?),
+                    None => None,
+                };
+                Ok(Source::Udp(UdpRxStream::new(
+                    source.addr,
+                    format,
+                    config,
+                    key.as_ref(),
+                    &multicast,
+                )?))
+            },
+            Protocol::Rtp => {
+                let rtp = match (announced, &args.sdp) {
+                    (Some(sdp), _) => sdp.rtp,
+                    (None, Some(path)) => {
+                        fs::read_to_string(path)
+                            .with_context(|| format!("failed to read \"{}\"", path))?
+                            .parse::<SessionDescription>()
+                            .with_context(|| format!("invalid SDP in \"{}\"", path))?
+                            .rtp
+                    },
+                    (None, None) => RtpConfig {
+                        channels: format.channels,
+                        ..Default::default()
+                    },
+                };
+                Ok(Source::Rtp(RtpRxStream::new(
+                    source.addr,
+                    format,
+                    config,
+                    &rtp,
+                    &multicast,
+                )?))
+            },
+            protocol => Err(anyhow!("{} sources are not supported", protocol)),
+        }
+    }
+
+    fn protocol(&self) -> Protocol {
+        match self {
+            Source::Quic(_) => Protocol::Quic,
+            Source::Udp(_) => Protocol::Udp,
+            Source::Rtp(_) => Protocol::Rtp,
+        }
+    }
+
+    fn receiver(&self) -> Arc<dyn RxStream<f32> + Send + Sync> {
+        match self {
+            Source::Quic(rx) => rx.clone(),
+            Source::Udp(rx) => rx.clone(),
+            Source::Rtp(rx) => rx.clone(),
+        }
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        match self {
+            Source::Quic(rx) => rx.local_addr(),
+            Source::Udp(rx) => rx.local_addr(),
+            Source::Rtp(rx) => rx.local_addr(),
+        }
+    }
+
+    fn stats(&self) -> &Arc<StreamStats> {
+        match self {
+            Source::Quic(rx) => rx.stats(),
+            Source::Udp(rx) => rx.stats(),
+            Source::Rtp(rx) => rx.stats(),
+        }
+    }
+
+    fn jitter(&self) -> &Arc<std::sync::Mutex<JitterBuffer<f32>>> {
+        match self {
+            Source::Quic(rx) => rx.jitter(),
+            Source::Udp(rx) => rx.jitter(),
+            Source::Rtp(rx) => rx.jitter(),
+        }
+    }
+
+    async fn shutdown(&self) {
+        match self {
+            Source::Quic(rx) => rx.shutdown().await,
+            Source::Udp(rx) => rx.shutdown().await,
+            Source::Rtp(rx) => rx.shutdown().await,
+        }
+    }
+}
+
+/// Advertises the listener over mDNS as `name`, along with its format
+/// and the fingerprint of its certificate.
+fn advertise(name: &str, args: &PatchArgs, rx: &Source, format: StreamFormat) -> Result<Arc<Advertiser>> {
+    let addr = rx.local_addr();
+    if addr.ip().is_multicast() {
+        return Err(anyhow!("--advertise is only for unicast sources"));
+    }
+    let fingerprint = match rx {
+        Source::Quic(_) => args.tls.fingerprint()?,
+        _ => None,
+    };
+    let advertiser = Advertiser::new(*MDNS_ADDR, &MulticastConfig::default())?;
+    advertiser.advertise(Service {
+        channels: Some(format.channels),
+        sample_rate: Some(format.sample_rate),
+        fingerprint,
+        ..Service::new(name, addr.port(), rx.protocol())
+    })?;
+    info!("advertising as {}{}", mdns::SCHEME, name);
+    Ok(advertiser)
+}
+
 fn get_host(name: &Option<String>) -> Result<cpal::Host> {
     match name {
         Some(name) => {
@@ -102,111 +346,88 @@ fn get_host(name: &Option<String>) -> Result<cpal::Host> {
 pub async fn main(args: PatchArgs) -> Result<()> {
     let host = get_host(&args.host)?;
     let device = get_device(&args.device, &host)?;
-    let addr: SocketAddr = args.source.parse()?;
     let config: cpal::StreamConfig = device.default_output_config()?.into();
-    let latency_frames = (LATENCY_MS / 1_000.0) * config.sample_rate.0 as f32;
-    let latency_samples = latency_frames as usize * config.channels as usize;
-    let ring = RingBuffer::new(latency_samples * 2);
-    let (producer, mut consumer) = ring.split();
-    let (abort_handle, abort_registration) = AbortHandle::new_pair();
-    let future = Abortable::new(async move {
-        server_entry(addr, producer).await
-    }, abort_registration);
-    tokio::spawn(async move {
-        // Future should eventually be aborted. For whatever
-        // reason, it's not yielding an error. This code works
-        // and this discrepancy is trivial.
-        // TODO: make sure server exiting with error results in error
-        assert!(future.await.is_err());
-    });
-    let _guard = scopeguard::guard((), move |_| {
-        abort_handle.abort();
-    });
-    let conf = device.default_output_config().unwrap();
-    let conf: cpal::StreamConfig = conf.into();
+    let map = match args.channels.len() {
+        0 => ChannelMap::identity(config.channels),
+        _ => ChannelMap::new(&args.channels, config.channels)?,
+    };
+    let format = StreamFormat::new(SampleFormat::F32, map.channels(), config.sample_rate.0);
+    let rx = Source::new(&args, format, JitterConfig {
+        min_delay: Duration::from_millis(args.min_latency),
+        max_delay: Duration::from_millis(args.max_latency),
+        drift_compensation: !args.no_drift_compensation,
+        ..Default::default()
+    }).await?;
+    info!("listening on {}", rx.local_addr());
+    let advertiser = match &args.advertise {
+        Some(name) => Some(advertise(name, &args, &rx, format)?),
+        None => None,
+    };
+    let metrics_server = match args.metrics {
+        Some(addr) => {
+            let metrics = Arc::new(Metrics::new());
+            metrics.register(&args.source, Direction::Rx, rx.protocol(), rx.stats().clone());
+            Some(crate::util::serve_metrics(addr, metrics)?)
+        },
+        None => None,
+    };
+    // Read without locking the jitter buffer, which the receive task
+    // holds while inserting packets: process is the callback's only
+    // locked call.
+    let stats = rx.stats().clone();
+    let receiver = rx.receiver();
+    // The stream's channels, before they're spread over the device's.
+    let mut scratch = Vec::new();
     let output_data_fn = move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-        let mut input_fell_behind = None;
-        for sample in data {
-            *sample = match consumer.pop() {
-                Ok(s) => s,
-                Err(err) => {
-                    input_fell_behind = Some(err);
-                    0.0
-                }
-            };
+        let underruns = stats.snapshot().underruns;
+        let amt = if map.is_identity() {
+            receiver.process(data)
+        } else {
+            let frames = data.len() / map.width() as usize;
+            scratch.resize(frames * map.channels() as usize, 0.0);
+            let amt = receiver.process(&mut scratch);
+            map.scatter(&scratch[..amt], data) * map.width() as usize
+        };
+        for sample in &mut data[amt..] {
+            *sample = 0.0;
         }
-        if let Some(err) = input_fell_behind {
+        let after = stats.snapshot();
+        if after.underruns != underruns {
             error!(
-                "input stream fell behind: {:?}: try increasing latency",
-                err
+                "input stream fell behind: rebuffering with {:?} target latency",
+                after.target_delay
             );
         }
     };
     let err_fn = |err| error!("an error occurred on stream: {}", err);
-    let output_stream = device.build_output_stream(&conf, output_data_fn, err_fn)?;
+    let output_stream = device.build_output_stream(&config, output_data_fn, err_fn)?;
     output_stream.play()?;
-    let signals = Signals::new(&[SIGINT])?;
-    loop {
-        for _ in signals.forever() {
-            return Ok(());
-        }
-        std::thread::yield_now();
+    // Play until interrupted.
+    crate::util::interrupted().await?;
+    let stats = rx.stats().snapshot();
+    info!(
+        "received {} packets ({} bytes), {} lost, {} reordered, {} duplicates, {} late",
+        stats.packets_received,
+        stats.bytes_received,
+        stats.lost,
+        stats.reordered,
+        stats.duplicates,
+        stats.late
+    );
+    info!(
+        "concealed {} frames, {} underruns, {} overruns, {:?} jitter, {:.1} ppm clock drift",
+        stats.concealed_frames,
+        stats.underruns,
+        stats.overruns,
+        stats.jitter,
+        rx.jitter().lock().unwrap().drift()
+    );
+    if let Some(advertiser) = advertiser {
+        advertiser.shutdown().await;
     }
-    Ok(())
-}
-
-async fn server_entry(addr: SocketAddr, mut producer: Producer<f32>) -> Result<()> {
-    let mut transport_config = quinn::TransportConfig::default();
-    transport_config.stream_window_uni(0);
-    let mut server_config = quinn::ServerConfig::default();
-    server_config.transport = std::sync::Arc::new(transport_config);
-    let mut server_config = quinn::ServerConfigBuilder::new(server_config);
-    server_config.protocols(ALPN_QUIC_HTTP);
-    let dirs = directories::ProjectDirs::from("org", "quinn", "quinn-examples").unwrap();
-    let path = dirs.data_local_dir();
-    let cert_path = path.join("cert.der");
-    let key_path = path.join("key.der");
-    let (cert, key) = match fs::read(&cert_path).and_then(|x| Ok((x, fs::read(&key_path)?))) {
-        Ok(x) => x,
-        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-            info!("generating self-signed certificate");
-            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-            let key = cert.serialize_private_key_der();
-            let cert = cert.serialize_der().unwrap();
-            fs::create_dir_all(&path).context("failed to create certificate directory")?;
-            fs::write(&cert_path, &cert).context("failed to write certificate")?;
-            fs::write(&key_path, &key).context("failed to write private key")?;
-            (cert, key)
-        }
-        Err(e) => {
-            panic!("failed to read certificate: {}", e);
-        }
-    };
-    let key = quinn::PrivateKey::from_der(&key)?;
-    let cert = quinn::Certificate::from_der(&cert)?;
-    server_config.certificate(quinn::CertificateChain::from_certs(vec![cert]), key)?;
-    let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
-    let mut incoming = {
-        let (endpoint, incoming) = endpoint.bind(&addr)?;
-        info!("listening on {}", endpoint.local_addr()?);
-        incoming
-    };
-    while let Some(conn) = incoming.next().await {
-        let quinn::NewConnection {
-            //connection,
-            mut datagrams,
-            ..
-        } = conn.await?;
-        while let Some(data) = datagrams.next().await {
-            let frame: Frame = bincode::deserialize(data?.as_ref())?;
-            // TODO: verify timestamp
-            if frame.buffer.len() % 4 != 0 {
-                return Err(anyhow!("encountered buffer with non-divisible by four length"))
-            }
-            let samples = unsafe { std::slice::from_raw_parts(frame.buffer.as_ptr() as *const f32, frame.buffer.len()/4) };
-            producer.push_slice(samples).map_err(|e| anyhow!("{:?}", e))?;
-        }
+    if let Some(server) = metrics_server {
+        server.shutdown().await;
     }
+    rx.shutdown().await;
     Ok(())
-}
\ No newline at end of file
+}
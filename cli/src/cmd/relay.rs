@@ -0,0 +1,407 @@
+use std::{convert::TryFrom, fs, net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::api::{self, Config, Destination, Listener};
+use anyhow::{anyhow, Context, Result};
+use paradise_core::buffer::ring::RingBuffer;
+use paradise_core::channels::ChannelMap;
+use paradise_core::stream::{
+    addr::{Protocol, StreamAddr},
+    crypto::Key,
+    header::{SampleFormat, StreamFormat},
+    mdns::{self, MDNS_ADDR},
+    metrics::{Direction, Metrics},
+    relay::{self, Relay},
+    rtp::RtpConfig,
+    rx::{
+        jitter::JitterConfig,
+        quic::QuicRxStream,
+        rtp::RtpRxStream,
+        tcp::TcpRxStream,
+        udp::UdpRxStream,
+        RxStream,
+    },
+    stats::StreamStats,
+    tx::{
+        quic::QuicTxStream,
+        rtp::RtpTxStream,
+        tcp::TcpTxStream,
+        udp::UdpTxStream,
+        TxStream,
+    },
+};
+
+/// Sample rate relays run at unless configured otherwise.
+const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+/// How long to look for destinations advertised over mDNS.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Receive streams over one transport and send them on
+/// over others, as the relays in a config file describe
+#[derive(clap::Clap)]
+pub struct RelayArgs {
+    /// Path to configuration file. On Windows, the default
+    /// value is set to C:\ProgramData\paradise\config.yaml
+    #[clap(
+        long = "filename",
+        short = "f",
+        default_value = "/etc/paradise/config.yaml"
+    )]
+    filename: String,
+
+    /// Only run the relay with this name. May be given more
+    /// than once. Default is every relay in the config.
+    #[clap(long = "name")]
+    names: Vec<String>,
+
+    /// Serve the statistics of every stream relayed, and
+    /// process metrics, over HTTP on this address, e.g.
+    /// 127.0.0.1:9100
+    #[clap(long = "metrics")]
+    metrics: Option<SocketAddr>,
+}
+
+/// The stream being relayed, by transport.
+enum Source {
+    Quic(Arc<QuicRxStream<f32>>),
+    Udp(Arc<UdpRxStream<f32>>),
+    Tcp(Arc<TcpRxStream<f32>>),
+    Rtp(Arc<RtpRxStream<f32>>),
+}
+
+impl Source {
+    fn new(listener: &Listener, format: StreamFormat) -> Result<Self> {
+        let addr: StreamAddr = listener.addr.parse()
+            .with_context(|| format!("invalid listener address \"{}\"", listener.addr))?;
+        if listener.channels.is_some() {
+            return Err(anyhow!("relay listeners receive every channel"));
+        }
+        if addr.protocol != Protocol::Quic && listener.tls.is_some() {
+            return Err(anyhow!("tls is only for QUIC listeners"));
+        }
+        if addr.protocol != Protocol::Udp && listener.psk.is_some() {
+            return Err(anyhow!("psk is only for UDP listeners"));
+        }
+        if addr.protocol != Protocol::Rtp && listener.rtp.is_some() {
+            return Err(anyhow!("rtp is only for RTP listeners"));
+        }
+        let multicast = listener.multicast.unwrap_or_default();
+        let config = JitterConfig::default();
+        match addr.protocol {
+            Protocol::Quic => Ok(Source::Quic(QuicRxStream::new(
+                addr.addr,
+                format,
+                config,
+                listener.tls.as_ref(),
+            )?)),
+            Protocol::Udp => {
+                let key = match &listener.psk {
+                    Some(path) => Some(Key::read(path)?),
+                    None => None,
+                };
+                Ok(Source::Udp(UdpRxStream::new(
+                    addr.addr,
+                    format,
+                    config,
+                    key.as_ref(),
+                    &multicast,
+                )?))
+            },
+            Protocol::Tcp => Ok(Source::Tcp(TcpRxStream::new(addr.addr, format, config)?)),
+            Protocol::Rtp => {
+                let rtp = listener.rtp.unwrap_or(RtpConfig {
+                    channels: format.channels,
+                    ..Default::default()
+                });
+                Ok(Source::Rtp(RtpRxStream::new(
+                    addr.addr,
+                    format,
+                    config,
+                    &rtp,
+                    &multicast,
+                )?))
+            },
+        }
+    }
+
+    fn protocol(&self) -> Protocol {
+        match self {
+            Source::Quic(_) => Protocol::Quic,
+            Source::Udp(_) => Protocol::Udp,
+            Source::Tcp(_) => Protocol::Tcp,
+            Source::Rtp(_) => Protocol::Rtp,
+        }
+    }
+
+    fn receiver(&self) -> Arc<dyn RxStream<f32> + Send + Sync> {
+        match self {
+            Source::Quic(rx) => rx.clone(),
+            Source::Udp(rx) => rx.clone(),
+            Source::Tcp(rx) => rx.clone(),
+            Source::Rtp(rx) => rx.clone(),
+        }
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        match self {
+            Source::Quic(rx) => rx.local_addr(),
+            Source::Udp(rx) => rx.local_addr(),
+            Source::Tcp(rx) => rx.local_addr(),
+            Source::Rtp(rx) => rx.local_addr(),
+        }
+    }
+
+    fn stats(&self) -> &Arc<StreamStats> {
+        match self {
+            Source::Quic(rx) => rx.stats(),
+            Source::Udp(rx) => rx.stats(),
+            Source::Tcp(rx) => rx.stats(),
+            Source::Rtp(rx) => rx.stats(),
+        }
+    }
+
+    async fn shutdown(&self) {
+        match self {
+            Source::Quic(rx) => rx.shutdown().await,
+            Source::Udp(rx) => rx.shutdown().await,
+            Source::Tcp(rx) => rx.shutdown().await,
+            Source::Rtp(rx) => rx.shutdown().await,
+        }
+    }
+}
+
+/// A stream the relay sends to, by transport.
+enum Sink {
+    Quic(Arc<QuicTxStream<RingBuffer<f32>, f32>>),
+    Udp(Arc<UdpTxStream<RingBuffer<f32>, f32>>),
+    Tcp(Arc<TcpTxStream<RingBuffer<f32>, f32>>),
+    Rtp(Arc<RtpTxStream<RingBuffer<f32>, f32>>),
+}
+
+impl Sink {
+    /// Starts streaming to `dest`, which is sent the channels it
+    /// selects from the `channels` relayed at `sample_rate`.
+    async fn new(dest: &Destination, channels: u16, sample_rate: u32) -> Result<(Self, ChannelMap, StreamAddr)> {
+        let map = match &dest.channels {
+            Some(selected) => {
+                let selected = selected.iter()
+                    .map(|&c| u16::try_from(c).map_err(|_| anyhow!("channel {} is out of range for a stream with {} channels", c, channels)))
+                    .collect::<Result<Vec<u16>>>()?;
+                ChannelMap::new(&selected, channels)?
+            },
+            None => ChannelMap::identity(channels),
+        };
+        let multicast = dest.multicast.unwrap_or_default();
+        let addr: StreamAddr = match dest.addr.strip_prefix(mdns::SCHEME) {
+            Some(name) => {
+                let endpoint = mdns::resolve(name, *MDNS_ADDR, &multicast, RESOLVE_TIMEOUT).await?
+                    .ok_or_else(|| anyhow!("no endpoint named \"{}\" was advertised (tip: run catalog --mdns)", name))?;
+                info!("found \"{}\" at {}", name, endpoint.addr);
+                endpoint.addr
+            },
+            None => dest.addr.parse()
+                .with_context(|| format!("invalid destination address \"{}\"", dest.addr))?,
+        };
+        if addr.protocol != Protocol::Quic && (dest.tls.is_some() || dest.insecure.is_some()) {
+            return Err(anyhow!("tls is only for QUIC destinations"));
+        }
+        if addr.protocol != Protocol::Udp && dest.psk.is_some() {
+            return Err(anyhow!("psk is only for UDP destinations"));
+        }
+        if addr.protocol != Protocol::Rtp && dest.rtp.is_some() {
+            return Err(anyhow!("rtp is only for RTP destinations"));
+        }
+        let format = StreamFormat::new(
+            dest.sample_format.unwrap_or(SampleFormat::F32),
+            map.channels(),
+            sample_rate,
+        );
+        let codec = dest.codec.unwrap_or_default();
+        let dither = dest.dither.unwrap_or(false);
+        let sink = match addr.protocol {
+            Protocol::Quic => Sink::Quic(QuicTxStream::new(
+                addr.addr,
+                format,
+                codec,
+                dest.fec,
+                dither,
+                dest.tls.as_ref(),
+                dest.insecure.unwrap_or(false),
+            )?),
+            Protocol::Udp => {
+                let key = match &dest.psk {
+                    Some(path) => Some(Key::read(path)?),
+                    None => None,
+                };
+                Sink::Udp(UdpTxStream::new(
+                    addr.addr,
+                    format,
+                    codec,
+                    dest.fec,
+                    dither,
+                    key.as_ref(),
+                    &multicast,
+                )?)
+            },
+            Protocol::Tcp => Sink::Tcp(TcpTxStream::new(addr.addr, format, codec, dither)?),
+            Protocol::Rtp => {
+                if dest.codec.is_some() || dest.fec.is_some() || dest.sample_format.is_some() {
+                    return Err(anyhow!("codec, fec and sampleFormat don't apply to RTP"));
+                }
+                let rtp = dest.rtp.unwrap_or(RtpConfig {
+                    channels: map.channels(),
+                    sample_rate,
+                    ..Default::default()
+                });
+                // RTP carries the samples as they are.
+                if rtp.channels != map.channels() || rtp.sample_rate != sample_rate {
+                    return Err(anyhow!(
+                        "RTP stream is {} channels at {} Hz, relaying {} at {} Hz",
+                        rtp.channels,
+                        rtp.sample_rate,
+                        map.channels(),
+                        sample_rate
+                    ));
+                }
+                Sink::Rtp(RtpTxStream::new(addr.addr, &rtp, dither, &multicast)?)
+            },
+        };
+        Ok((sink, map, addr))
+    }
+
+    fn sender(&self) -> Arc<dyn TxStream<f32> + Send + Sync> {
+        match self {
+            Sink::Quic(tx) => tx.clone(),
+            Sink::Udp(tx) => tx.clone(),
+            Sink::Tcp(tx) => tx.clone(),
+            Sink::Rtp(tx) => tx.clone(),
+        }
+    }
+
+    fn stats(&self) -> &Arc<StreamStats> {
+        match self {
+            Sink::Quic(tx) => tx.stats(),
+            Sink::Udp(tx) => tx.stats(),
+            Sink::Tcp(tx) => tx.stats(),
+            Sink::Rtp(tx) => tx.stats(),
+        }
+    }
+
+    async fn shutdown(&self) {
+        match self {
+            Sink::Quic(tx) => tx.shutdown().await,
+            Sink::Udp(tx) => tx.shutdown().await,
+            Sink::Tcp(tx) => tx.shutdown().await,
+            Sink::Rtp(tx) => tx.shutdown().await,
+        }
+    }
+}
+
+/// A relay and the streams it connects.
+struct Running {
+    name: String,
+    relay: Arc<Relay>,
+    source: Source,
+    sinks: Vec<Sink>,
+}
+
+impl Running {
+    async fn start(config: &api::Relay, metrics: &Metrics) -> Result<Self> {
+        let channels = u16::try_from(config.channels)
+            .map_err(|_| anyhow!("relay \"{}\": too many channels ({})", config.name, config.channels))?;
+        let sample_rate = config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        let format = StreamFormat::new(SampleFormat::F32, channels, sample_rate);
+        let source = Source::new(&config.listener, format)
+            .with_context(|| format!("relay \"{}\"", config.name))?;
+        info!("relay \"{}\": listening on {}/{}", config.name, source.local_addr(), source.protocol());
+        metrics.register(&config.name, Direction::Rx, source.protocol(), source.stats().clone());
+        let mut sinks: Vec<Sink> = Vec::new();
+        let mut destinations = Vec::new();
+        for dest in &config.destinations {
+            let (sink, map, addr) = match Sink::new(dest, channels, sample_rate).await {
+                Ok(sink) => sink,
+                Err(e) => {
+                    for sink in &sinks {
+                        sink.shutdown().await;
+                    }
+                    source.shutdown().await;
+                    return Err(e.context(format!("relay \"{}\" to {}", config.name, dest.addr)));
+                },
+            };
+            info!("relay \"{}\": sending to {}", config.name, addr);
+            let name = format!("{} -> {}", config.name, addr);
+            metrics.register(&name, Direction::Tx, addr.protocol, sink.stats().clone());
+            destinations.push(relay::Destination::new(sink.sender(), map));
+            sinks.push(sink);
+        }
+        let relay = Relay::new(source.receiver(), channels, sample_rate, destinations, relay::DEFAULT_PERIOD)?;
+        Ok(Self {
+            name: config.name.clone(),
+            relay,
+            source,
+            sinks,
+        })
+    }
+
+    async fn shutdown(&self) {
+        self.relay.shutdown().await;
+        for sink in &self.sinks {
+            sink.shutdown().await;
+        }
+        self.source.shutdown().await;
+        let stats = self.source.stats().snapshot();
+        info!(
+            "relay \"{}\": received {} packets ({} bytes), {} lost",
+            self.name,
+            stats.packets_received,
+            stats.bytes_received,
+            stats.lost
+        );
+    }
+}
+
+pub async fn main(args: RelayArgs) -> Result<()> {
+    let doc = fs::read_to_string(&args.filename)
+        .with_context(|| format!("failed to read \"{}\"", args.filename))?;
+    let config = Config::from_yaml(&doc)
+        .with_context(|| format!("invalid config \"{}\"", args.filename))?
+        .resolve();
+    let relays: Vec<api::Relay> = config.relays
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|r| args.names.is_empty() || args.names.contains(&r.name))
+        .collect();
+    if let Some(name) = args.names.iter().find(|name| !relays.iter().any(|r| &r.name == *name)) {
+        return Err(anyhow!("no relay named \"{}\" in \"{}\"", name, args.filename));
+    }
+    if relays.is_empty() {
+        return Err(anyhow!("no relays in \"{}\"", args.filename));
+    }
+    let metrics = Arc::new(Metrics::new());
+    let metrics_server = match args.metrics {
+        Some(addr) => Some(crate::util::serve_metrics(addr, metrics.clone())?),
+        None => None,
+    };
+    let mut running = Vec::new();
+    for relay in &relays {
+        match Running::start(relay, &metrics).await {
+            Ok(relay) => running.push(relay),
+            Err(e) => {
+                for relay in &running {
+                    relay.shutdown().await;
+                }
+                return Err(e);
+            },
+        }
+    }
+    // Relay until interrupted.
+    crate::util::interrupted().await?;
+    info!("shutting down");
+    for relay in &running {
+        relay.shutdown().await;
+    }
+    if let Some(server) = metrics_server {
+        server.shutdown().await;
+    }
+    Ok(())
+}
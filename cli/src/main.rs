@@ -30,13 +30,17 @@ fn main() {
             let opts: cmd::Opts = cmd::Opts::parse();
             match opts.subcmd {
                 cmd::SubCommand::Apply(args) => cmd::apply::main(args).await.unwrap(),
+                cmd::SubCommand::Catalog(args) => cmd::catalog::main(args).await.unwrap(),
+                cmd::SubCommand::Cert(args) => cmd::cert::main(args).await.unwrap(),
                 cmd::SubCommand::Daemon(args) => cmd::daemon::main(args).await.unwrap(),
                 cmd::SubCommand::Create(args) => cmd::device::create::main(args).await.unwrap(),
                 cmd::SubCommand::Delete(args) => cmd::device::delete::main(args).await.unwrap(),
                 cmd::SubCommand::List(args) => cmd::device::list::main(args).await.unwrap(),
                 cmd::SubCommand::Info(args) => cmd::info::main(args).await.unwrap(),
+                cmd::SubCommand::Netem(args) => cmd::netem::main(args).await.unwrap(),
                 cmd::SubCommand::Patch(args) => cmd::patch::main(args).await.unwrap(),
                 cmd::SubCommand::Reconcile(args) => cmd::reconcile::main(args).await.unwrap(),
+                cmd::SubCommand::Relay(args) => cmd::relay::main(args).await.unwrap(),
             };
         });
 }
@@ -7,3 +7,24 @@ pub fn get_host_by_name(name: &str) -> Result<cpal::Host, anyhow::Error> {
     }
     Err(anyhow::Error::msg(format!("host \"{}\" not found", name)))
 }
+
+/// Waits for SIGINT on the blocking pool, so the runtime's workers
+/// keep running the streams meanwhile.
+pub async fn interrupted() -> Result<(), anyhow::Error> {
+    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGINT])?;
+    tokio::task::spawn_blocking(move || {
+        signals.forever().next();
+    }).await?;
+    Ok(())
+}
+
+/// Serves `metrics` over HTTP on `addr` until shut down.
+pub fn serve_metrics(
+    addr: std::net::SocketAddr,
+    metrics: std::sync::Arc<paradise_core::stream::metrics::Metrics>,
+) -> Result<std::sync::Arc<paradise_core::stream::metrics::MetricsServer>, anyhow::Error> {
+    let server = paradise_core::stream::metrics::MetricsServer::new(addr, metrics)
+        .map_err(|e| anyhow::Error::msg(format!("cannot serve metrics on {}: {}", addr, e)))?;
+    info!("serving metrics on http://{}{}", server.local_addr(), paradise_core::stream::metrics::PATH);
+    Ok(server)
+}
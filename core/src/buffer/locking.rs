@@ -2,6 +2,7 @@ use super::*;
 
 pub struct LockingBuffer<T> {
     state: std::sync::Mutex<Vec<T>>,
+    channels: usize,
 }
 
 unsafe impl<T> std::marker::Send for LockingBuffer<T> {}
@@ -9,15 +10,21 @@ unsafe impl<T> std::marker::Send for LockingBuffer<T> {}
 unsafe impl<T> std::marker::Sync for LockingBuffer<T> {}
 
 impl<T> super::Buffer<T> for LockingBuffer<T> where T: Clone {
-    fn new() -> Self {
+    fn with_channels(channels: usize) -> Self {
         Self {
             state: std::sync::Mutex::new(vec![]),
+            channels: channels.max(1),
         }
     }
 
     fn flush(&self, output_buffer: &mut [T]) -> usize {
         let mut state = self.state.lock().unwrap();
-        std::mem::replace(&mut *state, vec![]).len()
+        let n = std::cmp::min(state.len(), output_buffer.len());
+        let n = n - n % self.channels;
+        for (out, sample) in output_buffer.iter_mut().zip(state.drain(..n)) {
+            *out = sample;
+        }
+        n
     }
 
     fn accumulate(&self, in_samples: &[T]) {
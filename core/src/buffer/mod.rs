@@ -1,13 +1,202 @@
 pub mod locking;
+pub mod ring;
 
+/// Hands samples from the thread producing them to the one sending
+/// them. Implementations must pass the conformance tests below.
 pub trait Buffer<T>
     where
         Self: std::marker::Sync + std::marker::Send,
         T: Clone,
 {
-    fn new() -> Self;
+    fn new() -> Self
+    where
+        Self: Sized,
+    {
+        Self::with_channels(1)
+    }
+
+    /// Creates a buffer of interleaved frames of `channels` samples
+    /// each. Frames are only ever dropped or flushed whole.
+    fn with_channels(channels: usize) -> Self;
 
+    /// Appends `samples`. Never blocks for long. A bounded buffer that
+    /// runs out of room drops the frames that don't fit, so whatever
+    /// is flushed is always in order and without gaps.
     fn accumulate(&self, samples: &[T]);
 
+    /// Moves the oldest frames into `output_buffer` and returns how
+    /// many samples were written, always a whole number of frames.
+    /// Frames that don't fit stay buffered.
     fn flush(&self, output_buffer: &mut [T]) -> usize;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Generates the tests every `Buffer` implementation must pass.
+    macro_rules! conformance {
+        ($name:ident, $buffer:ty) => {
+            mod $name {
+                use super::*;
+
+                type B = $buffer;
+
+                fn ramp(start: usize, len: usize) -> Vec<f32> {
+                    (start..start + len).map(|i| i as f32).collect()
+                }
+
+                fn drain(buf: &B) -> Vec<f32> {
+                    let mut out = Vec::new();
+                    let mut chunk = [0.0f32; 100];
+                    loop {
+                        let n = buf.flush(&mut chunk);
+                        if n == 0 {
+                            return out;
+                        }
+                        out.extend_from_slice(&chunk[..n]);
+                    }
+                }
+
+                #[test]
+                fn starts_empty() {
+                    let buf = B::new();
+                    let mut out = [0.0f32; 16];
+                    assert_eq!(buf.flush(&mut out), 0);
+                }
+
+                #[test]
+                fn preserves_order() {
+                    let buf = B::new();
+                    buf.accumulate(&ramp(0, 10));
+                    buf.accumulate(&ramp(10, 5));
+                    let mut out = [0.0f32; 15];
+                    assert_eq!(buf.flush(&mut out), 15);
+                    assert_eq!(&out[..], &ramp(0, 15)[..]);
+                    assert_eq!(buf.flush(&mut out), 0);
+                }
+
+                #[test]
+                fn partial_flush() {
+                    let buf = B::new();
+                    buf.accumulate(&ramp(0, 10));
+                    let mut out = [-1.0f32; 4];
+                    assert_eq!(buf.flush(&mut out), 4);
+                    assert_eq!(&out[..], &ramp(0, 4)[..]);
+                    let mut out = [-1.0f32; 16];
+                    assert_eq!(buf.flush(&mut out), 6);
+                    assert_eq!(&out[..6], &ramp(4, 6)[..]);
+                    // Nothing past the flushed samples is touched.
+                    assert_eq!(&out[6..], &[-1.0f32; 10][..]);
+                }
+
+                #[test]
+                fn wraps_around() {
+                    let buf = B::new();
+                    let mut next = 0;
+                    let mut expected = 0;
+                    let mut out = vec![0.0f32; 1000];
+                    // Far more than any buffer holds, in sizes that don't
+                    // line up with a power of two.
+                    for i in 0..2000 {
+                        let len = 1 + (i * 37) % 701;
+                        buf.accumulate(&ramp(next, len));
+                        next += len;
+                        let n = buf.flush(&mut out[..len]);
+                        assert_eq!(&out[..n], &ramp(expected, n)[..]);
+                        expected += n;
+                    }
+                    let rest = drain(&buf);
+                    assert_eq!(rest, ramp(expected, next - expected));
+                }
+
+                #[test]
+                fn overflow_keeps_a_prefix() {
+                    let buf = B::new();
+                    let input = ramp(0, 1 << 20);
+                    for chunk in input.chunks(4096) {
+                        buf.accumulate(chunk);
+                    }
+                    let flushed = drain(&buf);
+                    assert!(!flushed.is_empty());
+                    assert_eq!(&flushed[..], &input[..flushed.len()]);
+                    // Still usable once drained.
+                    buf.accumulate(&ramp(7, 3));
+                    assert_eq!(drain(&buf), ramp(7, 3));
+                }
+
+                #[test]
+                fn overflow_keeps_whole_frames() {
+                    let buf = B::with_channels(3);
+                    let input = ramp(0, 3 << 20);
+                    // Not a multiple of the frame size, like the
+                    // capacity of a bounded buffer.
+                    let mut chunk = [0.0f32; 100];
+                    let mut flushed = Vec::new();
+                    for (i, frames) in input.chunks(3 * 1000).enumerate() {
+                        buf.accumulate(frames);
+                        if i % 100 == 99 {
+                            let n = buf.flush(&mut chunk);
+                            assert_eq!(n % 3, 0);
+                            flushed.extend_from_slice(&chunk[..n]);
+                        }
+                    }
+                    loop {
+                        let n = buf.flush(&mut chunk);
+                        if n == 0 {
+                            break;
+                        }
+                        assert_eq!(n % 3, 0);
+                        flushed.extend_from_slice(&chunk[..n]);
+                    }
+                    // Every frame arrives whole, its channels in place,
+                    // even after frames were dropped.
+                    assert_eq!(flushed.len() % 3, 0);
+                    for frame in flushed.chunks(3) {
+                        assert_eq!(frame[0] as usize % 3, 0);
+                        assert_eq!(frame[1], frame[0] + 1.0);
+                        assert_eq!(frame[2], frame[0] + 2.0);
+                    }
+                    assert!(flushed.windows(2).all(|w| w[0] < w[1]));
+                }
+
+                #[test]
+                fn concurrent_producer_and_consumer() {
+                    const TOTAL: usize = 200_000;
+                    let buf = std::sync::Arc::new(B::new());
+                    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+                    let producer = {
+                        let buf = buf.clone();
+                        let done = done.clone();
+                        std::thread::spawn(move || {
+                            let mut next = 0;
+                            while next < TOTAL {
+                                let len = std::cmp::min(256, TOTAL - next);
+                                buf.accumulate(&ramp(next, len));
+                                next += len;
+                                // Give the consumer a chance to keep up.
+                                std::thread::yield_now();
+                            }
+                            done.store(true, std::sync::atomic::Ordering::Release);
+                        })
+                    };
+                    let mut received = Vec::with_capacity(TOTAL);
+                    let mut out = [0.0f32; 300];
+                    while !done.load(std::sync::atomic::Ordering::Acquire) {
+                        let n = buf.flush(&mut out);
+                        received.extend_from_slice(&out[..n]);
+                    }
+                    producer.join().unwrap();
+                    received.extend(drain(&buf));
+                    assert!(!received.is_empty());
+                    // Anything dropped on overflow comes off the end of
+                    // an accumulate, so what arrives is still ordered.
+                    assert!(received.windows(2).all(|w| w[0] < w[1]));
+                }
+            }
+        };
+    }
+
+    conformance!(locking, crate::buffer::locking::LockingBuffer<f32>);
+    conformance!(ring, crate::buffer::ring::RingBuffer<f32>);
+}
@@ -0,0 +1,178 @@
+use super::*;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// Capacity, in samples, of buffers created with
+/// `Buffer::with_channels`.
+pub const DEFAULT_CAPACITY: usize = 1 << 16;
+
+/// Wait-free single-producer/single-consumer ring buffer.
+///
+/// Neither `accumulate` nor `flush` blocks or allocates, so both are
+/// safe to call from realtime audio callbacks. One thread may
+/// accumulate while another flushes; calling the same side from two
+/// threads at once panics. When the buffer is full, frames that don't
+/// fit are dropped from the end of `accumulate`'s input and counted.
+pub struct RingBuffer<T> {
+    slots: Box<[UnsafeCell<T>]>,
+    mask: usize,
+    /// Samples per frame. The capacity needn't be a multiple of it.
+    channels: usize,
+    /// Total samples written. Only the producer stores to it.
+    head: AtomicUsize,
+    /// Total samples read. Only the consumer stores to it.
+    tail: AtomicUsize,
+    producing: AtomicBool,
+    consuming: AtomicBool,
+    dropped: AtomicU64,
+}
+
+// Each slot is accessed by at most one side at a time: the producer
+// only writes slots between `head` and `tail + capacity`, the consumer
+// only reads those between `tail` and `head`, and the `producing` and
+// `consuming` flags rule out a second thread on either side.
+unsafe impl<T: Send> std::marker::Send for RingBuffer<T> {}
+
+unsafe impl<T: Send> std::marker::Sync for RingBuffer<T> {}
+
+impl<T> RingBuffer<T>
+where
+    T: Clone + Default,
+{
+    /// Creates a buffer of frames of `channels` samples holding at
+    /// least `capacity` samples. The capacity is rounded up to a power
+    /// of two.
+    pub fn with_capacity(capacity: usize, channels: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        Self {
+            slots: (0..capacity)
+                .map(|_| UnsafeCell::new(T::default()))
+                .collect(),
+            mask: capacity - 1,
+            channels: channels.max(1),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producing: AtomicBool::new(false),
+            consuming: AtomicBool::new(false),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Number of samples waiting to be flushed.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Samples discarded because the buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Buffer<T> for RingBuffer<T>
+where
+    T: Clone + Default + Send,
+{
+    fn with_channels(channels: usize) -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY, channels)
+    }
+
+    fn accumulate(&self, samples: &[T]) {
+        let _guard = Side::enter(&self.producing, "accumulate");
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let free = self.capacity() - head.wrapping_sub(tail);
+        let n = std::cmp::min(free, samples.len());
+        // Splitting a frame would shift the channels of every frame
+        // after it.
+        let n = n - n % self.channels;
+        for (i, sample) in samples[..n].iter().enumerate() {
+            let slot = &self.slots[head.wrapping_add(i) & self.mask];
+            unsafe { *slot.get() = sample.clone() };
+        }
+        self.head.store(head.wrapping_add(n), Ordering::Release);
+        if n < samples.len() {
+            self.dropped
+                .fetch_add((samples.len() - n) as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self, output_buffer: &mut [T]) -> usize {
+        let _guard = Side::enter(&self.consuming, "flush");
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let n = std::cmp::min(head.wrapping_sub(tail), output_buffer.len());
+        let n = n - n % self.channels;
+        for (i, out) in output_buffer[..n].iter_mut().enumerate() {
+            let slot = &self.slots[tail.wrapping_add(i) & self.mask];
+            *out = unsafe { (*slot.get()).clone() };
+        }
+        self.tail.store(tail.wrapping_add(n), Ordering::Release);
+        n
+    }
+}
+
+/// Marks one side of the buffer as in use for the guard's lifetime.
+struct Side<'a>(&'a AtomicBool);
+
+impl<'a> Side<'a> {
+    fn enter(flag: &'a AtomicBool, name: &str) -> Self {
+        if flag.swap(true, Ordering::Acquire) {
+            panic!("RingBuffer::{} called from two threads at once", name);
+        }
+        Side(flag)
+    }
+}
+
+impl<'a> Drop for Side<'a> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounds_capacity_up() {
+        assert_eq!(RingBuffer::<f32>::with_capacity(0, 1).capacity(), 1);
+        assert_eq!(RingBuffer::<f32>::with_capacity(1000, 2).capacity(), 1024);
+        assert_eq!(RingBuffer::<f32>::new().capacity(), DEFAULT_CAPACITY);
+    }
+
+    #[test]
+    fn counts_dropped_samples() {
+        let buf = RingBuffer::with_capacity(4, 1);
+        buf.accumulate(&[1.0f32, 2.0, 3.0]);
+        buf.accumulate(&[4.0, 5.0, 6.0]);
+        assert_eq!(buf.len(), 4);
+        assert_eq!(buf.dropped(), 2);
+        let mut out = [0.0f32; 8];
+        assert_eq!(buf.flush(&mut out), 4);
+        assert_eq!(&out[..4], &[1.0, 2.0, 3.0, 4.0]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn drops_whole_frames() {
+        let buf = RingBuffer::with_capacity(8, 3);
+        buf.accumulate(&[1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        // Room for two more samples, but not for a frame.
+        buf.accumulate(&[7.0, 8.0, 9.0]);
+        assert_eq!(buf.len(), 6);
+        assert_eq!(buf.dropped(), 3);
+        let mut out = [0.0f32; 5];
+        assert_eq!(buf.flush(&mut out), 3);
+        assert_eq!(&out[..3], &[1.0, 2.0, 3.0]);
+    }
+}
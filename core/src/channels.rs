@@ -0,0 +1,199 @@
+//! Channel selection and mapping.
+//!
+//! A device's audio is interleaved across all of its channels, but a
+//! stream often only carries a few of them, e.g. one recorder taking
+//! channels 5 and 6 of a 32-channel interface. A [`ChannelMap`] pairs
+//! each channel of a stream with a channel of the device: senders
+//! gather the selected channels before encoding, so a stream costs
+//! only the bandwidth of what it carries, and receivers scatter the
+//! stream's channels onto the device channels they're mapped to.
+//!
+//! Channels are numbered from zero.
+use crate::sample::Sample;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChannelError {
+    /// A map must select at least one channel.
+    Empty,
+    /// The device has no such channel.
+    OutOfRange { channel: u16, channels: u16 },
+    /// A device channel was mapped more than once.
+    Duplicate(u16),
+}
+
+impl std::fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelError::Empty => write!(f, "no channels selected"),
+            ChannelError::OutOfRange { channel, channels } => write!(
+                f,
+                "channel {} is out of range for a device with {} channels",
+                channel, channels
+            ),
+            ChannelError::Duplicate(channel) => {
+                write!(f, "channel {} is selected more than once", channel)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+/// Maps the channels of a stream onto those of a device. Stream
+/// channel `i` is device channel `channels[i]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelMap {
+    channels: Vec<u16>,
+    /// Channels of the device.
+    width: u16,
+    /// Each device channel is the stream channel of the same number,
+    /// so audio passes through untouched.
+    identity: bool,
+}
+
+impl ChannelMap {
+    /// Maps the stream's channels onto `channels` of a device with
+    /// `width` channels, in order.
+    pub fn new(channels: &[u16], width: u16) -> Result<Self, ChannelError> {
+        if channels.is_empty() {
+            return Err(ChannelError::Empty);
+        }
+        for (i, &channel) in channels.iter().enumerate() {
+            if channel >= width {
+                return Err(ChannelError::OutOfRange {
+                    channel,
+                    channels: width,
+                });
+            }
+            if channels[..i].contains(&channel) {
+                return Err(ChannelError::Duplicate(channel));
+            }
+        }
+        let identity = channels.len() == width as usize
+            && channels.iter().enumerate().all(|(i, &c)| i == c as usize);
+        Ok(Self {
+            channels: channels.to_vec(),
+            width,
+            identity,
+        })
+    }
+
+    /// Maps every channel of a device with `width` channels to the
+    /// stream channel of the same number.
+    pub fn identity(width: u16) -> Self {
+        Self {
+            channels: (0..width).collect(),
+            width,
+            identity: true,
+        }
+    }
+
+    /// Channels of the stream.
+    pub fn channels(&self) -> u16 {
+        self.channels.len() as u16
+    }
+
+    /// Channels of the device.
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.identity
+    }
+
+    /// Selects the stream's channels from interleaved device frames.
+    /// Returns `input` itself when nothing needs selecting, otherwise
+    /// fills and returns `scratch`, which keeps its allocation from
+    /// one call to the next. A partial frame at the end is dropped.
+    pub fn gather<'a, T: Copy>(&self, input: &'a [T], scratch: &'a mut Vec<T>) -> &'a [T] {
+        if self.identity {
+            return input;
+        }
+        scratch.clear();
+        let width = self.width as usize;
+        scratch.reserve(input.len() / width * self.channels.len());
+        for frame in input.chunks_exact(width) {
+            scratch.extend(self.channels.iter().map(|&c| frame[c as usize]));
+        }
+        &scratch[..]
+    }
+
+    /// Spreads interleaved stream frames over the device channels
+    /// they're mapped to, silencing the others. Returns the number of
+    /// frames written, which is limited by the room in `output`.
+    pub fn scatter<T: Sample>(&self, input: &[T], output: &mut [T]) -> usize {
+        let (channels, width) = (self.channels.len(), self.width as usize);
+        let frames = std::cmp::min(input.len() / channels, output.len() / width);
+        if self.identity {
+            output[..frames * width].copy_from_slice(&input[..frames * width]);
+            return frames;
+        }
+        let frames_in = input.chunks_exact(channels);
+        for (frame_in, frame_out) in frames_in.zip(output.chunks_exact_mut(width)) {
+            for sample in frame_out.iter_mut() {
+                *sample = T::default();
+            }
+            for (&sample, &c) in frame_in.iter().zip(&self.channels) {
+                frame_out[c as usize] = sample;
+            }
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rejects_invalid_maps() {
+        assert_eq!(ChannelMap::new(&[], 2), Err(ChannelError::Empty));
+        assert_eq!(
+            ChannelMap::new(&[0, 2], 2),
+            Err(ChannelError::OutOfRange {
+                channel: 2,
+                channels: 2
+            })
+        );
+        assert_eq!(
+            ChannelMap::new(&[1, 0, 1], 4),
+            Err(ChannelError::Duplicate(1))
+        );
+        assert!(ChannelMap::new(&[0, 1], 2).unwrap().is_identity());
+        assert!(!ChannelMap::new(&[1, 0], 2).unwrap().is_identity());
+        assert!(!ChannelMap::new(&[0, 1], 4).unwrap().is_identity());
+    }
+
+    #[test]
+    fn gathers_selected_channels() {
+        // Three frames of four channels, sample = 10 * frame + channel.
+        let input = (0..3)
+            .flat_map(|f| (0..4).map(move |c| (10 * f + c) as f32))
+            .collect::<Vec<_>>();
+        let map = ChannelMap::new(&[3, 1], 4).unwrap();
+        assert_eq!(map.channels(), 2);
+        let mut scratch = Vec::new();
+        assert_eq!(
+            map.gather(&input, &mut scratch),
+            &[3.0, 1.0, 13.0, 11.0, 23.0, 21.0]
+        );
+        let identity = ChannelMap::identity(4);
+        assert_eq!(identity.gather(&input, &mut scratch), &input[..]);
+    }
+
+    #[test]
+    fn scatters_onto_device_channels() {
+        let map = ChannelMap::new(&[2, 0], 3).unwrap();
+        let mut output = [9.0f32; 7];
+        let frames = map.scatter(&[1.0, 2.0, 3.0, 4.0], &mut output);
+        assert_eq!(frames, 2);
+        assert_eq!(output, [2.0, 0.0, 1.0, 4.0, 0.0, 3.0, 9.0]);
+        let mut output = [0.0f32; 4];
+        assert_eq!(
+            ChannelMap::identity(2).scatter(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &mut output),
+            2
+        );
+        assert_eq!(output, [1.0, 2.0, 3.0, 4.0]);
+    }
+}
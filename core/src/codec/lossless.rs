@@ -0,0 +1,1029 @@
+//! Lossless compression in the style of FLAC, for tracking and
+//! mastering sessions where every bit of the source has to arrive.
+//!
+//! Each packet carries one block of audio. The block's float samples
+//! are first mapped onto integers: audio that came from a 16 or 24-bit
+//! converter consists of exact multiples of a power of two, so scaling
+//! by that power loses nothing. Blocks that cannot be mapped exactly
+//! (NaN, infinities, negative zero or values far outside [-1, 1]) are
+//! sent as raw floats instead.
+//!
+//! Every channel of an integer block is coded as whichever of a
+//! constant, verbatim, fixed polynomial or LPC subframe is smallest,
+//! with the prediction residual Rice coded in partitions as FLAC does.
+//! Decoding reproduces the input bit for bit.
+//!
+//! Block layout, after the packet header:
+//!
+//! ```text
+//!  0      1      2      3      4
+//! +------+------+------+------+------+---------------------------
+//! | type |    frames   | shift| width| subframes (bitstream) ...
+//! +------+------+------+------+------+---------------------------
+//! ```
+//!
+//! `type` is 0 for raw floats (followed directly by little-endian
+//! samples) or 1 for integers. Integer samples are `value / 2^shift`,
+//! and `width` is the number of bits needed to hold any of them.
+use super::*;
+use crate::stream::fec::PARITY_OVERHEAD;
+use crate::stream::tx::udp::MAX_DATAGRAM;
+
+/// Highest LPC order the format can describe.
+pub const MAX_LPC_ORDER: u8 = 32;
+
+/// Highest residual partition order the encoder tries.
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Largest Rice parameter, which also bounds the unary part of any
+/// residual from a 32-bit sample to a single bit.
+const MAX_RICE_PARAM: u32 = 31;
+
+const BLOCK_RAW: u8 = 0;
+const BLOCK_INTEGER: u8 = 1;
+
+const SUBFRAME_CONSTANT: u64 = 0;
+const SUBFRAME_VERBATIM: u64 = 1;
+const SUBFRAME_FIXED: u64 = 2;
+const SUBFRAME_LPC: u64 = 3;
+
+/// Coefficients of FLAC's fixed polynomial predictors, by order.
+const FIXED: [&[i32]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LosslessConfig {
+    /// Duration of the audio in each packet, in milliseconds.
+    pub frame_ms: f32,
+    /// Highest LPC order to try. Higher orders compress a little
+    /// better at the cost of CPU time. Zero uses only the fixed
+    /// predictors.
+    pub max_lpc_order: u8,
+}
+
+impl Default for LosslessConfig {
+    fn default() -> Self {
+        Self {
+            frame_ms: 10.0,
+            max_lpc_order: 8,
+        }
+    }
+}
+
+/// Size of a block sent as raw floats, which no block exceeds.
+fn max_block_len(frames: usize, channels: usize) -> usize {
+    3 + frames * channels * 4
+}
+
+pub struct LosslessEncoder {
+    channels: usize,
+    frame_size: usize,
+    max_lpc_order: usize,
+    /// Interleaved samples of the current block as integers.
+    ints: Vec<i32>,
+    /// One channel of the current block.
+    channel: Vec<i32>,
+    residual: Vec<i32>,
+    bits: BitWriter,
+}
+
+impl LosslessEncoder {
+    pub fn new(config: &LosslessConfig, format: StreamFormat) -> Result<Self, CodecError> {
+        if config.max_lpc_order > MAX_LPC_ORDER {
+            return Err(CodecError::InvalidPredictorOrder(config.max_lpc_order));
+        }
+        if format.channels == 0 {
+            return Err(CodecError::UnsupportedChannels {
+                codec: Codec::Lossless,
+                channels: format.channels,
+            });
+        }
+        let channels = format.channels as usize;
+        let frame_size = (format.sample_rate as f32 * config.frame_ms / 1000.0) as usize;
+        // The packet, and any FEC parity packet built from it, has to
+        // fit in a UDP datagram.
+        let overhead = 2 * HEADER_LEN + PARITY_OVERHEAD;
+        if frame_size == 0
+            || frame_size > u16::MAX as usize
+            || overhead + max_block_len(frame_size, channels) > MAX_DATAGRAM
+        {
+            return Err(CodecError::InvalidFrameDuration(config.frame_ms));
+        }
+        Ok(Self {
+            channels,
+            frame_size,
+            max_lpc_order: config.max_lpc_order as usize,
+            ints: Vec::new(),
+            channel: Vec::new(),
+            residual: Vec::new(),
+            bits: BitWriter::default(),
+        })
+    }
+
+    /// Codes the block in `self.ints` into `self.bits`, returning the
+    /// width of its samples.
+    fn encode_integers(&mut self, frames: usize) -> u32 {
+        let width = self
+            .ints
+            .iter()
+            .map(|v| signed_width(*v))
+            .max()
+            .unwrap_or(1);
+        self.bits.clear();
+        for c in 0..self.channels {
+            self.channel.clear();
+            self.channel
+                .extend(self.ints.iter().skip(c).step_by(self.channels).take(frames));
+            encode_subframe(
+                &self.channel,
+                width,
+                self.max_lpc_order,
+                &mut self.residual,
+                &mut self.bits,
+            );
+        }
+        self.bits.flush();
+        width
+    }
+}
+
+impl Encoder for LosslessEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Lossless
+    }
+
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn max_packet_len(&self) -> usize {
+        max_block_len(self.frame_size, self.channels)
+    }
+
+    fn encode(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, CodecError> {
+        let frames = input.len() / self.channels;
+        let raw_len = max_block_len(frames, self.channels);
+        if frames * self.channels != input.len() || frames > u16::MAX as usize {
+            return Err(CodecError::FrameCount {
+                expected: self.frame_size,
+                actual: frames,
+            });
+        }
+        if output.len() < raw_len {
+            return Err(CodecError::BufferTooSmall {
+                needed: raw_len,
+                available: output.len(),
+            });
+        }
+        output[1..3].copy_from_slice(&(frames as u16).to_be_bytes());
+        if let Some(shift) = to_integers(input, &mut self.ints) {
+            let width = self.encode_integers(frames);
+            let len = 5 + self.bits.bytes.len();
+            if len < raw_len {
+                output[0] = BLOCK_INTEGER;
+                output[3] = shift;
+                output[4] = width as u8;
+                output[5..len].copy_from_slice(&self.bits.bytes[..]);
+                return Ok(len);
+            }
+        }
+        output[0] = BLOCK_RAW;
+        for (sample, dest) in input.iter().zip(output[3..raw_len].chunks_exact_mut(4)) {
+            dest.copy_from_slice(&sample.to_le_bytes());
+        }
+        Ok(raw_len)
+    }
+}
+
+pub struct LosslessDecoder {
+    channels: usize,
+    channel: Vec<i32>,
+}
+
+impl LosslessDecoder {
+    pub fn new(format: StreamFormat) -> Result<Self, CodecError> {
+        if format.channels == 0 {
+            return Err(CodecError::UnsupportedChannels {
+                codec: Codec::Lossless,
+                channels: format.channels,
+            });
+        }
+        Ok(Self {
+            channels: format.channels as usize,
+            channel: Vec::new(),
+        })
+    }
+}
+
+impl Decoder for LosslessDecoder {
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, CodecError> {
+        if input.len() < 3 {
+            return Err(CodecError::Corrupt);
+        }
+        let frames = u16::from_be_bytes([input[1], input[2]]) as usize;
+        let len = frames * self.channels;
+        if len > output.len() {
+            return Err(CodecError::FrameCount {
+                expected: output.len() / self.channels,
+                actual: frames,
+            });
+        }
+        match input[0] {
+            BLOCK_RAW => {
+                if input.len() != max_block_len(frames, self.channels) {
+                    return Err(CodecError::Corrupt);
+                }
+                for (sample, src) in output[..len].iter_mut().zip(input[3..].chunks_exact(4)) {
+                    *sample = f32::from_le_bytes([src[0], src[1], src[2], src[3]]);
+                }
+            }
+            BLOCK_INTEGER => {
+                if input.len() < 5 || input[4] == 0 || input[4] > 32 {
+                    return Err(CodecError::Corrupt);
+                }
+                // Exact, as the shift is at most 255.
+                let scale = 2f64.powi(-(input[3] as i32));
+                let width = input[4] as u32;
+                let mut bits = BitReader::new(&input[5..]);
+                for c in 0..self.channels {
+                    decode_subframe(&mut bits, frames, width, &mut self.channel)?;
+                    for (i, v) in self.channel.iter().enumerate() {
+                        output[i * self.channels + c] = (*v as f64 * scale) as f32;
+                    }
+                }
+            }
+            _ => return Err(CodecError::Corrupt),
+        }
+        Ok(frames)
+    }
+}
+
+/// Number of fractional bits needed to write `x` as an integer
+/// multiple of a power of two.
+fn fraction_bits(x: f32) -> u32 {
+    if x == 0.0 {
+        return 0;
+    }
+    let bits = x.to_bits();
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    // x = mantissa * 2^-(offset), counting the implicit leading one
+    // of normal numbers.
+    let (mantissa, offset) = if exponent == 0 {
+        (mantissa, 149)
+    } else {
+        (mantissa | 0x80_0000, 150 - exponent)
+    };
+    (offset - mantissa.trailing_zeros() as i32).max(0) as u32
+}
+
+/// Maps a block of float samples onto integers, returning the shift
+/// that scales them back, or `None` if the block cannot be
+/// represented exactly.
+fn to_integers(input: &[f32], ints: &mut Vec<i32>) -> Option<u8> {
+    if input.iter().any(|x| !x.is_finite()) {
+        return None;
+    }
+    let shift = input.iter().map(|x| fraction_bits(*x)).max().unwrap_or(0);
+    // Both directions are exact multiplications by powers of two.
+    let scale = 2f64.powi(shift as i32);
+    let unscale = 2f64.powi(-(shift as i32));
+    ints.clear();
+    for x in input {
+        let v = *x as f64 * scale;
+        if !(i32::MIN as f64..=i32::MAX as f64).contains(&v) {
+            return None;
+        }
+        let v = v as i32;
+        // Catches negative zero, which maps to the same integer as
+        // positive zero.
+        if ((v as f64 * unscale) as f32).to_bits() != x.to_bits() {
+            return None;
+        }
+        ints.push(v);
+    }
+    Some(shift as u8)
+}
+
+/// Bits needed to hold `v` in two's complement.
+fn signed_width(v: i32) -> u32 {
+    let magnitude = if v < 0 { !v } else { v } as u32;
+    33 - magnitude.leading_zeros()
+}
+
+fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn unzigzag(v: u32) -> i32 {
+    (v >> 1) as i32 ^ -((v & 1) as i32)
+}
+
+enum Predictor {
+    Fixed(usize),
+    Lpc {
+        coefs: Vec<i32>,
+        precision: u32,
+        shift: u32,
+    },
+}
+
+impl Predictor {
+    fn coefs(&self) -> &[i32] {
+        match self {
+            Predictor::Fixed(order) => FIXED[*order],
+            Predictor::Lpc { coefs, .. } => &coefs[..],
+        }
+    }
+
+    fn shift(&self) -> u32 {
+        match self {
+            Predictor::Fixed(_) => 0,
+            Predictor::Lpc { shift, .. } => *shift,
+        }
+    }
+
+    /// Bits used by the subframe before its residual.
+    fn header_bits(&self, width: u32) -> u64 {
+        let order = self.coefs().len() as u64;
+        let warmup = order * width as u64;
+        match self {
+            Predictor::Fixed(_) => 2 + 3 + warmup,
+            Predictor::Lpc { precision, .. } => 2 + 5 + 4 + 4 + order * *precision as u64 + warmup,
+        }
+    }
+}
+
+/// Computes the prediction residual of `x`, or returns false if it
+/// does not fit in 32 bits.
+fn residual(x: &[i32], coefs: &[i32], shift: u32, out: &mut Vec<i32>) -> bool {
+    let order = coefs.len();
+    out.clear();
+    for i in order..x.len() {
+        let prediction: i64 = coefs
+            .iter()
+            .enumerate()
+            .map(|(j, c)| *c as i64 * x[i - 1 - j] as i64)
+            .sum();
+        let r = x[i] as i64 - (prediction >> shift);
+        if r < i32::MIN as i64 || r > i32::MAX as i64 {
+            return false;
+        }
+        out.push(r as i32);
+    }
+    true
+}
+
+/// Finds the Rice parameter that codes `residual` in the fewest bits.
+fn rice_param(residual: &[i32]) -> (u32, u64) {
+    if residual.is_empty() {
+        return (0, 0);
+    }
+    let sum: u64 = residual.iter().map(|r| zigzag(*r) as u64).sum();
+    let mean = sum / residual.len() as u64;
+    let estimate = (64 - mean.leading_zeros())
+        .saturating_sub(1)
+        .min(MAX_RICE_PARAM);
+    let cost = |k: u32| -> u64 {
+        residual.len() as u64 * (k as u64 + 1)
+            + residual
+                .iter()
+                .map(|r| (zigzag(*r) >> k) as u64)
+                .sum::<u64>()
+    };
+    (estimate.saturating_sub(1)..=(estimate + 1).min(MAX_RICE_PARAM))
+        .map(|k| (k, cost(k)))
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap()
+}
+
+/// Splits the residual into `2^order` partitions, returning the bits
+/// needed to code it and each partition's Rice parameter, or `None`
+/// if the block cannot be split that way.
+fn partition(residual: &[i32], frames: usize, order: usize, p: u32) -> Option<(u64, Vec<u32>)> {
+    let len = frames >> p;
+    if frames.trailing_zeros() < p || len < order {
+        return None;
+    }
+    let mut bits = 4;
+    let mut params = Vec::with_capacity(1 << p);
+    let mut start = 0;
+    for i in 0..1 << p {
+        let end = start + if i == 0 { len - order } else { len };
+        let (k, cost) = rice_param(&residual[start..end]);
+        bits += 5 + cost;
+        params.push(k);
+        start = end;
+    }
+    Some((bits, params))
+}
+
+/// Linear predictors of every order up to `max_order`, estimated from
+/// the windowed autocorrelation of `x` with Levinson-Durbin.
+fn lpc_coefficients(x: &[i32], max_order: usize) -> Vec<Vec<f64>> {
+    let n = x.len();
+    // Welch window.
+    let half = (n as f64 - 1.0) / 2.0;
+    let windowed: Vec<f64> = x
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let t = if half > 0.0 {
+                (i as f64 - half) / half
+            } else {
+                0.0
+            };
+            *v as f64 * (1.0 - t * t)
+        })
+        .collect();
+    let autoc: Vec<f64> = (0..=max_order)
+        .map(|lag| (lag..n).map(|i| windowed[i] * windowed[i - lag]).sum())
+        .collect();
+    let mut result = Vec::with_capacity(max_order);
+    if autoc[0] == 0.0 {
+        return result;
+    }
+    let mut lpc = vec![0.0; max_order];
+    let mut err = autoc[0];
+    for i in 0..max_order {
+        let mut r = -autoc[i + 1];
+        for j in 0..i {
+            r -= lpc[j] * autoc[i - j];
+        }
+        r /= err;
+        lpc[i] = r;
+        for j in 0..i / 2 {
+            let tmp = lpc[j];
+            lpc[j] += r * lpc[i - 1 - j];
+            lpc[i - 1 - j] += r * tmp;
+        }
+        if i % 2 == 1 {
+            lpc[i / 2] += lpc[i / 2] * r;
+        }
+        err *= 1.0 - r * r;
+        result.push(lpc[..=i].iter().map(|c| -c).collect());
+        if err <= 0.0 {
+            break;
+        }
+    }
+    result
+}
+
+/// Quantizes LPC coefficients to `precision` bit integers, returning
+/// them with the shift that scales them back.
+fn quantize(coefs: &[f64], precision: u32) -> Option<(Vec<i32>, u32)> {
+    let max = coefs.iter().fold(0.0f64, |max, c| max.max(c.abs()));
+    if max <= 0.0 || !max.is_finite() {
+        return None;
+    }
+    let shift = precision as i32 - 2 - max.log2().floor() as i32;
+    if shift < 0 {
+        return None;
+    }
+    let shift = shift.min(15) as u32;
+    let limit = 1i64 << (precision - 1);
+    let mut error = 0.0;
+    let quantized = coefs
+        .iter()
+        .map(|c| {
+            // Carry the rounding error into the next coefficient.
+            error += c * (1u32 << shift) as f64;
+            let q = (error.round() as i64).clamp(-limit, limit - 1);
+            error -= q as f64;
+            q as i32
+        })
+        .collect();
+    Some((quantized, shift))
+}
+
+fn lpc_precision(frames: usize, width: u32) -> u32 {
+    if width > 16 {
+        return 15;
+    }
+    match frames {
+        0..=192 => 7,
+        193..=384 => 8,
+        385..=576 => 9,
+        577..=1152 => 10,
+        1153..=2304 => 11,
+        2305..=4608 => 12,
+        _ => 13,
+    }
+}
+
+fn encode_subframe(
+    x: &[i32],
+    width: u32,
+    max_lpc_order: usize,
+    scratch: &mut Vec<i32>,
+    bits: &mut BitWriter,
+) {
+    let frames = x.len();
+    if x.iter().all(|v| *v == x[0]) {
+        bits.write(SUBFRAME_CONSTANT, 2);
+        bits.write_signed(x[0], width);
+        return;
+    }
+    // Pick the predictor whose residual codes smallest as a single
+    // partition, which tracks the final size closely enough.
+    let mut candidates: Vec<Predictor> = (0..FIXED.len())
+        .filter(|order| *order < frames)
+        .map(Predictor::Fixed)
+        .collect();
+    let max_lpc_order = max_lpc_order.min(frames - 1);
+    if max_lpc_order > 0 {
+        let precision = lpc_precision(frames, width);
+        candidates.extend(
+            lpc_coefficients(x, max_lpc_order)
+                .iter()
+                .filter_map(|coefs| quantize(coefs, precision))
+                .map(|(coefs, shift)| Predictor::Lpc {
+                    coefs,
+                    precision,
+                    shift,
+                }),
+        );
+    }
+    let mut best: Option<(u64, Predictor)> = None;
+    for predictor in candidates {
+        if !residual(x, predictor.coefs(), predictor.shift(), scratch) {
+            continue;
+        }
+        let cost = predictor.header_bits(width) + 9 + rice_param(scratch).1;
+        match &best {
+            Some((best, _)) if *best <= cost => {}
+            _ => best = Some((cost, predictor)),
+        }
+    }
+    let verbatim = 2 + frames as u64 * width as u64;
+    let predictor = match best {
+        Some((cost, predictor)) if cost < verbatim => predictor,
+        _ => {
+            bits.write(SUBFRAME_VERBATIM, 2);
+            for v in x {
+                bits.write_signed(*v, width);
+            }
+            return;
+        }
+    };
+    let coefs = predictor.coefs();
+    let order = coefs.len();
+    residual(x, coefs, predictor.shift(), scratch);
+    let (p, params) = (0..=MAX_PARTITION_ORDER)
+        .filter_map(|p| {
+            partition(scratch, frames, order, p).map(|(cost, params)| (cost, p, params))
+        })
+        .min_by_key(|(cost, _, _)| *cost)
+        .map(|(_, p, params)| (p, params))
+        .unwrap();
+    match &predictor {
+        Predictor::Fixed(order) => {
+            bits.write(SUBFRAME_FIXED, 2);
+            bits.write(*order as u64, 3);
+        }
+        Predictor::Lpc {
+            coefs,
+            precision,
+            shift,
+        } => {
+            bits.write(SUBFRAME_LPC, 2);
+            bits.write(coefs.len() as u64 - 1, 5);
+            bits.write(*precision as u64 - 1, 4);
+            bits.write(*shift as u64, 4);
+            for c in coefs {
+                bits.write_signed(*c, *precision);
+            }
+        }
+    }
+    for v in &x[..order] {
+        bits.write_signed(*v, width);
+    }
+    bits.write(p as u64, 4);
+    let len = frames >> p;
+    let mut start = 0;
+    for (i, k) in params.iter().enumerate() {
+        let end = start + if i == 0 { len - order } else { len };
+        bits.write(*k as u64, 5);
+        for r in &scratch[start..end] {
+            let v = zigzag(*r) as u64;
+            bits.write_unary(v >> k);
+            bits.write(v & ((1 << k) - 1), *k);
+        }
+        start = end;
+    }
+}
+
+fn decode_subframe(
+    bits: &mut BitReader,
+    frames: usize,
+    width: u32,
+    x: &mut Vec<i32>,
+) -> Result<(), CodecError> {
+    x.clear();
+    let lpc_coefs;
+    let (coefs, shift) = match bits.read(2)? {
+        SUBFRAME_CONSTANT => {
+            let v = bits.read_signed(width)?;
+            x.resize(frames, v);
+            return Ok(());
+        }
+        SUBFRAME_VERBATIM => {
+            for _ in 0..frames {
+                x.push(bits.read_signed(width)?);
+            }
+            return Ok(());
+        }
+        SUBFRAME_FIXED => {
+            let order = bits.read(3)? as usize;
+            if order >= FIXED.len() {
+                return Err(CodecError::Corrupt);
+            }
+            (FIXED[order], 0)
+        }
+        _ => {
+            let order = bits.read(5)? as usize + 1;
+            let precision = bits.read(4)? as u32 + 1;
+            let shift = bits.read(4)? as u32;
+            lpc_coefs = (0..order)
+                .map(|_| bits.read_signed(precision))
+                .collect::<Result<Vec<_>, _>>()?;
+            (&lpc_coefs[..], shift)
+        }
+    };
+    let order = coefs.len();
+    if order > frames {
+        return Err(CodecError::Corrupt);
+    }
+    for _ in 0..order {
+        x.push(bits.read_signed(width)?);
+    }
+    let p = bits.read(4)? as u32;
+    let len = frames >> p;
+    if frames.trailing_zeros() < p || len < order {
+        return Err(CodecError::Corrupt);
+    }
+    for i in 0..1 << p {
+        let count = if i == 0 { len - order } else { len };
+        let k = bits.read(5)? as u32;
+        for _ in 0..count {
+            let q = bits.read_unary()?;
+            let v = (q << k) | bits.read(k)?;
+            if v > u32::MAX as u64 {
+                return Err(CodecError::Corrupt);
+            }
+            let n = x.len();
+            let prediction: i64 = coefs
+                .iter()
+                .enumerate()
+                .map(|(j, c)| *c as i64 * x[n - 1 - j] as i64)
+                .sum();
+            let v = unzigzag(v as u32) as i64 + (prediction >> shift);
+            if v < i32::MIN as i64 || v > i32::MAX as i64 {
+                return Err(CodecError::Corrupt);
+            }
+            x.push(v as i32);
+        }
+    }
+    Ok(())
+}
+
+/// Writes big-endian bit fields.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.acc = 0;
+        self.count = 0;
+    }
+
+    /// Writes the low `bits` bits of `value`, up to 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.count += bits;
+        while self.count >= 8 {
+            self.count -= 8;
+            self.bytes.push((self.acc >> self.count) as u8);
+        }
+        self.acc &= (1 << self.count) - 1;
+    }
+
+    fn write_signed(&mut self, value: i32, bits: u32) {
+        self.write(value as u32 as u64, bits);
+    }
+
+    /// Writes `q` zeros followed by a one.
+    fn write_unary(&mut self, mut q: u64) {
+        while q >= 32 {
+            self.write(0, 32);
+            q -= 32;
+        }
+        self.write(1, q as u32 + 1);
+    }
+
+    /// Pads the final byte with zeros.
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits.
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u64, CodecError> {
+        if self.pos + bits as usize > self.data.len() * 8 {
+            return Err(CodecError::Corrupt);
+        }
+        let mut value = 0u64;
+        let mut left = bits;
+        while left > 0 {
+            let offset = (self.pos % 8) as u32;
+            let take = (8 - offset).min(left);
+            let byte = self.data[self.pos / 8] as u64;
+            value = (value << take) | ((byte >> (8 - offset - take)) & ((1 << take) - 1));
+            left -= take;
+            self.pos += take as usize;
+        }
+        Ok(value)
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Result<i32, CodecError> {
+        let v = self.read(bits)?;
+        Ok(((v << (64 - bits)) as i64 >> (64 - bits)) as i32)
+    }
+
+    /// Counts zeros up to the next one.
+    fn read_unary(&mut self) -> Result<u64, CodecError> {
+        let mut q = 0;
+        loop {
+            let byte = *self.data.get(self.pos / 8).ok_or(CodecError::Corrupt)?;
+            let offset = (self.pos % 8) as u32;
+            let rest = byte << offset;
+            if rest == 0 {
+                q += 8 - offset as u64;
+                self.pos += 8 - offset as usize;
+                continue;
+            }
+            let zeros = rest.leading_zeros();
+            q += zeros as u64;
+            self.pos += zeros as usize + 1;
+            return Ok(q);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::header::SampleFormat;
+
+    fn format(channels: u16) -> StreamFormat {
+        StreamFormat::new(SampleFormat::F32, channels, 48_000)
+    }
+
+    /// Deterministic white noise in [-1, 1).
+    fn noise(seed: u32) -> impl FnMut() -> f32 {
+        let mut state = seed | 1;
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as i32) as f32 / 2_147_483_648.0
+        }
+    }
+
+    /// Quantizes to `bits` bit integers, as a converter would.
+    fn quantize_to(v: f32, bits: u32) -> f32 {
+        let scale = (1u32 << (bits - 1)) as f32;
+        (v * scale).round().clamp(-scale, scale - 1.0) / scale
+    }
+
+    /// A tone plus a little noise, quantized to `bits`.
+    fn music(frames: usize, channels: usize, bits: u32) -> Vec<f32> {
+        let mut noise = noise(7);
+        (0..frames * channels)
+            .map(|i| {
+                let t = (i / channels) as f32 / 48_000.0;
+                let phase = (i % channels) as f32;
+                let v = 0.4 * (2.0 * std::f32::consts::PI * 440.0 * t + phase).sin()
+                    + 0.2 * (2.0 * std::f32::consts::PI * 1_234.0 * t).sin()
+                    + 0.001 * noise();
+                quantize_to(v, bits)
+            })
+            .collect()
+    }
+
+    /// Encodes and decodes a single block, returning the encoded size.
+    fn round_trip(input: &[f32], channels: u16) -> usize {
+        let mut encoder =
+            LosslessEncoder::new(&LosslessConfig::default(), format(channels)).unwrap();
+        let mut decoder = LosslessDecoder::new(format(channels)).unwrap();
+        let mut packet = vec![0; max_block_len(input.len() / channels as usize, channels as usize)];
+        let len = encoder.encode(input, &mut packet[..]).unwrap();
+        let mut output = vec![0.0; input.len()];
+        let frames = decoder.decode(&packet[..len], &mut output[..]).unwrap();
+        assert_eq!(frames * channels as usize, input.len());
+        for (i, (a, b)) in input.iter().zip(output.iter()).enumerate() {
+            assert_eq!(a.to_bits(), b.to_bits(), "sample {}: {} != {}", i, a, b);
+        }
+        len
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let config = LosslessConfig {
+            max_lpc_order: 33,
+            ..Default::default()
+        };
+        assert_eq!(
+            LosslessEncoder::new(&config, format(2)).err(),
+            Some(CodecError::InvalidPredictorOrder(33))
+        );
+        // Too long to fit in a datagram.
+        let config = LosslessConfig {
+            frame_ms: 100.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            LosslessEncoder::new(&config, format(8)).err(),
+            Some(CodecError::InvalidFrameDuration(100.0))
+        );
+    }
+
+    #[test]
+    fn compresses_24_bit_audio() {
+        let input = music(480, 2, 24);
+        let len = round_trip(&input[..], 2);
+        let raw = input.len() * 3;
+        assert!(
+            len < raw * 3 / 4,
+            "{} bytes for {} of 24-bit audio",
+            len,
+            raw
+        );
+    }
+
+    #[test]
+    fn compresses_16_bit_audio() {
+        let input = music(480, 2, 16);
+        let len = round_trip(&input[..], 2);
+        let raw = input.len() * 2;
+        assert!(
+            len < raw * 3 / 4,
+            "{} bytes for {} of 16-bit audio",
+            len,
+            raw
+        );
+    }
+
+    #[test]
+    fn round_trips_i24_samples() {
+        // 24-bit integers carried left-justified in i32, including
+        // both ends of the range.
+        let mut noise = noise(3);
+        let mut input: Vec<i32> = (0..960)
+            .map(|_| ((noise() * 8_388_608.0) as i32).clamp(-8_388_608, 8_388_607) << 8)
+            .collect();
+        input[0] = -8_388_608 << 8;
+        input[1] = 8_388_607 << 8;
+        let floats: Vec<f32> = input.iter().map(|v| v.to_f32()).collect();
+        round_trip(&floats[..], 2);
+        let restored: Vec<i32> = floats.iter().map(|v| i32::from_f32(*v)).collect();
+        assert_eq!(restored, input);
+    }
+
+    #[test]
+    fn round_trips_full_precision_floats() {
+        // Noise uses every mantissa bit, so it is coded at a large
+        // shift or sent raw, but either way arrives intact.
+        let mut noise = noise(11);
+        let input: Vec<f32> = (0..960).map(|_| noise()).collect();
+        round_trip(&input[..], 2);
+        let input: Vec<f32> = (0..960).map(|_| noise() * 1e-30).collect();
+        round_trip(&input[..], 2);
+    }
+
+    #[test]
+    fn round_trips_special_values() {
+        let specials = [
+            0.0,
+            -0.0,
+            1.0,
+            -1.0,
+            f32::MIN_POSITIVE,
+            f32::MIN_POSITIVE / 8.0,
+            f32::MAX,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            1e10,
+        ];
+        // The last is a NaN with a payload.
+        let music = music(480, 2, 24);
+        for v in specials.iter().chain(&[f32::from_bits(0x7fc0_1234)]) {
+            let mut input = music.clone();
+            input[37] = *v;
+            round_trip(&input[..], 2);
+        }
+    }
+
+    #[test]
+    fn round_trips_edge_cases() {
+        // Silence, a lone frame, odd block sizes and many channels.
+        round_trip(&[0.0; 960], 2);
+        round_trip(&[0.25; 6], 6);
+        round_trip(&[0.5, -0.5], 2);
+        round_trip(&music(1, 1, 24)[..], 1);
+        round_trip(&music(3, 1, 24)[..], 1);
+        round_trip(&music(441, 2, 24)[..], 2);
+        round_trip(&music(480, 8, 16)[..], 8);
+        // Full scale square wave, whose prediction residual is large.
+        let square: Vec<f32> = (0..960)
+            .map(|i| if i % 4 < 2 { 1.0 } else { -1.0 })
+            .collect();
+        round_trip(&square[..], 1);
+        // Alternating integer extremes overflow naive predictors.
+        let extremes: Vec<f32> = (0..480)
+            .map(|i| {
+                if i % 2 == 0 {
+                    i32::MIN as f32
+                } else {
+                    2_147_483_520.0
+                }
+            })
+            .collect();
+        round_trip(&extremes[..], 1);
+    }
+
+    #[test]
+    fn round_trips_through_packetizer() {
+        let config = CodecConfig::Lossless(LosslessConfig::default());
+        let encoder = config.encoder(format(2)).unwrap().unwrap();
+        assert_eq!(encoder.frame_size(), 480);
+        let mut packetizer = Packetizer::new(encoder, format(2), 9);
+        let input = music(4_800, 2, 24);
+        let mut packets = Vec::new();
+        packetizer
+            .push(&input[..], |_, packet| packets.push(packet.to_vec()))
+            .unwrap();
+        assert_eq!(packets.len(), 10);
+        let mut decoder = StreamDecoder::new();
+        let mut output = Vec::new();
+        for packet in &packets {
+            let (hdr, payload) = Header::parse(&packet[..]).unwrap();
+            assert_eq!(hdr.codec, Codec::Lossless);
+            output.extend_from_slice(decoder.decode(&hdr, payload).unwrap());
+        }
+        assert_eq!(output, input);
+    }
+
+    #[test]
+    fn rejects_corrupt_packets() {
+        let input = music(480, 2, 24);
+        let mut encoder = LosslessEncoder::new(&LosslessConfig::default(), format(2)).unwrap();
+        let mut packet = vec![0; encoder.max_packet_len()];
+        let len = encoder.encode(&input[..], &mut packet[..]).unwrap();
+        let mut decoder = LosslessDecoder::new(format(2)).unwrap();
+        let mut output = vec![0.0; input.len()];
+        // Truncation anywhere is caught rather than read past.
+        for end in 0..len {
+            assert!(decoder.decode(&packet[..end], &mut output[..]).is_err());
+        }
+        // More frames than the header promised.
+        let mut output = vec![0.0; input.len() - 2];
+        assert_eq!(
+            decoder.decode(&packet[..len], &mut output[..]),
+            Err(CodecError::FrameCount {
+                expected: 479,
+                actual: 480
+            })
+        );
+        // Garbage never panics.
+        let mut noise = noise(5);
+        let mut output = vec![0.0; input.len()];
+        for _ in 0..200 {
+            for b in packet[5..len].iter_mut() {
+                *b = (noise() * 128.0) as i8 as u8;
+            }
+            let _ = decoder.decode(&packet[..len], &mut output[..]);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! Audio codecs for network streams.
+//!
+//! By default streams carry raw PCM. A sender may instead compress its
+//! audio with one of the codecs here, in which case every packet's
+//! header names the codec used for its payload. Receivers create a
+//! matching decoder on the fly, so only the sending side needs to be
+//! configured.
+use crate::sample::Sample;
+use crate::stream::header::{Header, StreamFormat, HEADER_LEN};
+
+pub mod lossless;
+pub mod opus;
+
+/// Identifies the encoding of a packet's payload on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Uncompressed interleaved samples in the stream's sample format.
+    Pcm,
+    Opus,
+    /// FLAC-style compression that decodes bit for bit.
+    Lossless,
+}
+
+impl Codec {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Codec::Pcm),
+            1 => Some(Codec::Opus),
+            2 => Some(Codec::Lossless),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Codec::Pcm => 0,
+            Codec::Opus => 1,
+            Codec::Lossless => 2,
+        }
+    }
+
+    /// Name of the codec, as in the `type` of its config.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Codec::Pcm => "pcm",
+            Codec::Opus => "opus",
+            Codec::Lossless => "lossless",
+        }
+    }
+}
+
+/// Codec used by a sender, e.g.
+///
+/// ```yaml
+/// codec:
+///   type: opus
+///   bitrate: 128000
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CodecConfig {
+    Pcm,
+    Opus(opus::OpusConfig),
+    Lossless(lossless::LosslessConfig),
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig::Pcm
+    }
+}
+
+impl CodecConfig {
+    pub fn codec(&self) -> Codec {
+        match self {
+            CodecConfig::Pcm => Codec::Pcm,
+            CodecConfig::Opus(_) => Codec::Opus,
+            CodecConfig::Lossless(_) => Codec::Lossless,
+        }
+    }
+
+    /// Creates an encoder for audio in the given format, or `None`
+    /// if samples are sent uncompressed.
+    pub fn encoder(&self, format: StreamFormat) -> Result<Option<Box<dyn Encoder>>, CodecError> {
+        match self {
+            CodecConfig::Pcm => Ok(None),
+            CodecConfig::Opus(config) => Ok(Some(Box::new(opus::OpusEncoder::new(
+                config, format,
+            )?))),
+            CodecConfig::Lossless(config) => Ok(Some(Box::new(
+                lossless::LosslessEncoder::new(config, format)?,
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    UnsupportedSampleRate { codec: Codec, sample_rate: u32 },
+    UnsupportedChannels { codec: Codec, channels: u16 },
+    InvalidFrameDuration(f32),
+    InvalidPredictorOrder(u8),
+    /// The packet decoded to a different number of frames than its
+    /// header claims.
+    FrameCount { expected: usize, actual: usize },
+    BufferTooSmall { needed: usize, available: usize },
+    /// The packet could not be decoded.
+    Corrupt,
+    /// An error reported by the codec library.
+    Library(String),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::UnsupportedSampleRate { codec, sample_rate } => write!(
+                f,
+                "{:?} does not support a sample rate of {} Hz",
+                codec, sample_rate
+            ),
+            CodecError::UnsupportedChannels { codec, channels } => {
+                write!(f, "{:?} does not support {} channels", codec, channels)
+            }
+            CodecError::InvalidFrameDuration(ms) => {
+                write!(f, "invalid codec frame duration of {} ms", ms)
+            }
+            CodecError::InvalidPredictorOrder(order) => {
+                write!(f, "invalid predictor order {}", order)
+            }
+            CodecError::FrameCount { expected, actual } => write!(
+                f,
+                "packet decoded to {} frames, expected {}",
+                actual, expected
+            ),
+            CodecError::BufferTooSmall { needed, available } => write!(
+                f,
+                "encoded packet needs {} bytes but only {} are available",
+                needed, available
+            ),
+            CodecError::Corrupt => write!(f, "corrupt packet"),
+            CodecError::Library(e) => write!(f, "codec error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+pub trait Encoder: Send {
+    fn codec(&self) -> Codec;
+
+    /// Number of frames that must be passed to each `encode` call.
+    fn frame_size(&self) -> usize;
+
+    /// Largest packet a single `encode` call may produce.
+    fn max_packet_len(&self) -> usize;
+
+    /// Compresses exactly `frame_size` interleaved frames into
+    /// `output`, returning the number of bytes written.
+    fn encode(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, CodecError>;
+}
+
+pub trait Decoder: Send {
+    /// Decompresses a packet into interleaved samples, returning the
+    /// number of frames written to `output`.
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, CodecError>;
+}
+
+/// Creates a decoder for packets produced by `codec`. Returns `None`
+/// for uncompressed PCM.
+pub fn decoder(codec: Codec, format: StreamFormat) -> Result<Option<Box<dyn Decoder>>, CodecError> {
+    match codec {
+        Codec::Pcm => Ok(None),
+        Codec::Opus => Ok(Some(Box::new(opus::OpusDecoder::new(format)?))),
+        Codec::Lossless => Ok(Some(Box::new(lossless::LosslessDecoder::new(format)?))),
+    }
+}
+
+/// Splits outgoing audio into codec-sized frames and wraps each
+/// encoded frame in a packet header.
+pub struct Packetizer {
+    encoder: Box<dyn Encoder>,
+    hdr: Header,
+    /// Interleaved samples waiting for a full frame.
+    pending: Vec<f32>,
+    buf: Vec<u8>,
+}
+
+impl Packetizer {
+    pub fn new(encoder: Box<dyn Encoder>, format: StreamFormat, stream_id: u32) -> Self {
+        let hdr = Header {
+            flags: crate::stream::header::FLAG_DISCONTINUITY,
+            stream_id,
+            sequence: 0,
+            timestamp: 0,
+            format,
+            codec: encoder.codec(),
+            frames: encoder.frame_size() as u16,
+        };
+        let buf = vec![0; HEADER_LEN + encoder.max_packet_len()];
+        Self {
+            encoder,
+            hdr,
+            pending: Vec::new(),
+            buf,
+        }
+    }
+
+    /// Queues interleaved samples, passing each complete packet to
+    /// `emit` along with its header.
+    pub fn push<T, F>(&mut self, samples: &[T], mut emit: F) -> Result<(), CodecError>
+    where
+        T: Sample,
+        F: FnMut(&Header, &[u8]),
+    {
+        self.pending.extend(samples.iter().map(|s| s.to_f32()));
+        let frame_len = self.encoder.frame_size() * self.hdr.format.channels as usize;
+        let mut consumed = 0;
+        let mut result = Ok(());
+        while self.pending.len() - consumed >= frame_len {
+            let input = &self.pending[consumed..consumed + frame_len];
+            consumed += frame_len;
+            let hdr_len = self.hdr.write(&mut self.buf[..]);
+            match self.encoder.encode(input, &mut self.buf[hdr_len..]) {
+                Ok(amt) => emit(&self.hdr, &self.buf[..hdr_len + amt]),
+                // The frame is dropped but the clock keeps running, so
+                // the receiver sees it as a lost packet.
+                Err(e) => result = Err(e),
+            }
+            self.hdr.flags = 0;
+            self.hdr.sequence = self.hdr.sequence.wrapping_add(1);
+            self.hdr.timestamp += self.hdr.frames as u64;
+        }
+        self.pending.drain(..consumed);
+        result
+    }
+}
+
+/// Decodes the payloads of an incoming stream, creating a decoder
+/// for whichever codec and sample rate the sender chose.
+#[derive(Default)]
+pub struct StreamDecoder {
+    current: Option<(u32, Codec, StreamFormat, Box<dyn Decoder>)>,
+    samples: Vec<f32>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes a compressed packet, returning its interleaved samples.
+    /// Must not be called for PCM packets.
+    pub fn decode(&mut self, hdr: &Header, payload: &[u8]) -> Result<&[f32], CodecError> {
+        match &self.current {
+            Some((stream_id, codec, format, _))
+                if *stream_id == hdr.stream_id && *codec == hdr.codec && *format == hdr.format => {}
+            _ => {
+                // Decoders carry state between packets, so a new
+                // stream needs a fresh one.
+                self.current = decoder(hdr.codec, hdr.format)?
+                    .map(|decoder| (hdr.stream_id, hdr.codec, hdr.format, decoder));
+            }
+        }
+        let decoder = match &mut self.current {
+            Some((_, _, _, decoder)) => decoder,
+            None => return Ok(&[]),
+        };
+        let channels = hdr.format.channels as usize;
+        let expected = hdr.frames as usize;
+        self.samples.resize(expected * channels, 0.0);
+        let actual = decoder.decode(payload, &mut self.samples[..])?;
+        if actual != expected {
+            return Err(CodecError::FrameCount { expected, actual });
+        }
+        Ok(&self.samples[..])
+    }
+}
@@ -0,0 +1,240 @@
+//! Opus, for sending audio over links that cannot carry raw PCM.
+//!
+//! Opus only runs at 8, 12, 16, 24 and 48 kHz with one or two
+//! channels, and encodes fixed-duration frames of 2.5 to 60 ms.
+//! Shorter frames lower latency at the cost of compression.
+use super::*;
+use std::convert::TryFrom;
+
+/// Frame durations supported by the encoder, in milliseconds.
+const FRAME_DURATIONS: [f32; 6] = [2.5, 5.0, 10.0, 20.0, 40.0, 60.0];
+
+/// Largest encoded packet a single frame may produce, as recommended
+/// by the Opus documentation.
+const MAX_PACKET: usize = 4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OpusConfig {
+    /// Target bitrate in bits per second. Opus chooses one based on
+    /// the channel count if unset.
+    pub bitrate: Option<u32>,
+    /// Duration of the audio in each packet, in milliseconds.
+    pub frame_ms: f32,
+    /// Trade quality for the lowest possible algorithmic delay.
+    pub low_delay: bool,
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        Self {
+            bitrate: None,
+            frame_ms: 10.0,
+            low_delay: false,
+        }
+    }
+}
+
+fn sample_rate(format: &StreamFormat) -> Result<audiopus::SampleRate, CodecError> {
+    audiopus::SampleRate::try_from(format.sample_rate as i32).map_err(|_| {
+        CodecError::UnsupportedSampleRate {
+            codec: Codec::Opus,
+            sample_rate: format.sample_rate,
+        }
+    })
+}
+
+fn channels(format: &StreamFormat) -> Result<audiopus::Channels, CodecError> {
+    match format.channels {
+        1 => Ok(audiopus::Channels::Mono),
+        2 => Ok(audiopus::Channels::Stereo),
+        channels => Err(CodecError::UnsupportedChannels {
+            codec: Codec::Opus,
+            channels,
+        }),
+    }
+}
+
+fn library_error(e: audiopus::Error) -> CodecError {
+    CodecError::Library(e.to_string())
+}
+
+pub struct OpusEncoder {
+    encoder: audiopus::coder::Encoder,
+    frame_size: usize,
+}
+
+impl OpusEncoder {
+    pub fn new(config: &OpusConfig, format: StreamFormat) -> Result<Self, CodecError> {
+        if !FRAME_DURATIONS.contains(&config.frame_ms) {
+            return Err(CodecError::InvalidFrameDuration(config.frame_ms));
+        }
+        let application = if config.low_delay {
+            audiopus::Application::LowDelay
+        } else {
+            audiopus::Application::Audio
+        };
+        let mut encoder = audiopus::coder::Encoder::new(
+            sample_rate(&format)?,
+            channels(&format)?,
+            application,
+        )
+        .map_err(library_error)?;
+        if let Some(bitrate) = config.bitrate {
+            encoder
+                .set_bitrate(audiopus::Bitrate::BitsPerSecond(bitrate as i32))
+                .map_err(library_error)?;
+        }
+        let frame_size = (format.sample_rate as f32 * config.frame_ms / 1000.0) as usize;
+        Ok(Self {
+            encoder,
+            frame_size,
+        })
+    }
+}
+
+impl Encoder for OpusEncoder {
+    fn codec(&self) -> Codec {
+        Codec::Opus
+    }
+
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn max_packet_len(&self) -> usize {
+        MAX_PACKET
+    }
+
+    fn encode(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, CodecError> {
+        self.encoder
+            .encode_float(input, output)
+            .map_err(library_error)
+    }
+}
+
+pub struct OpusDecoder {
+    decoder: audiopus::coder::Decoder,
+}
+
+impl OpusDecoder {
+    pub fn new(format: StreamFormat) -> Result<Self, CodecError> {
+        let decoder = audiopus::coder::Decoder::new(sample_rate(&format)?, channels(&format)?)
+            .map_err(library_error)?;
+        Ok(Self { decoder })
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn decode(&mut self, input: &[u8], output: &mut [f32]) -> Result<usize, CodecError> {
+        let packet = audiopus::packet::Packet::try_from(input).map_err(library_error)?;
+        let output = audiopus::MutSignals::try_from(output).map_err(library_error)?;
+        self.decoder
+            .decode_float(Some(packet), output, false)
+            .map_err(library_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::header::SampleFormat;
+
+    fn stereo() -> StreamFormat {
+        StreamFormat::new(SampleFormat::F32, 2, 48_000)
+    }
+
+    fn sine(frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames * channels)
+            .map(|i| {
+                let t = (i / channels) as f32 / 48_000.0;
+                0.5 * (2.0 * std::f32::consts::PI * 440.0 * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_unsupported_formats() {
+        let config = OpusConfig::default();
+        let cd = StreamFormat::new(SampleFormat::F32, 2, 44_100);
+        assert!(matches!(
+            OpusEncoder::new(&config, cd),
+            Err(CodecError::UnsupportedSampleRate { .. })
+        ));
+        let surround = StreamFormat::new(SampleFormat::F32, 6, 48_000);
+        assert!(matches!(
+            OpusEncoder::new(&config, surround),
+            Err(CodecError::UnsupportedChannels { .. })
+        ));
+        let config = OpusConfig {
+            frame_ms: 7.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            OpusEncoder::new(&config, stereo()).err(),
+            Some(CodecError::InvalidFrameDuration(7.0))
+        );
+    }
+
+    #[test]
+    fn packetizes_whole_frames() {
+        let encoder = CodecConfig::Opus(OpusConfig::default())
+            .encoder(stereo())
+            .unwrap()
+            .unwrap();
+        assert_eq!(encoder.frame_size(), 480);
+        let mut packetizer = Packetizer::new(encoder, stereo(), 7);
+        let input = sine(1000, 2);
+        let mut packets = Vec::new();
+        packetizer
+            .push(&input[..], |hdr, packet| packets.push((*hdr, packet.to_vec())))
+            .unwrap();
+        // 1000 frames make two 480 frame packets, the rest is held.
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].0.codec, Codec::Opus);
+        assert!(packets[0].0.is_discontinuity());
+        assert!(!packets[1].0.is_discontinuity());
+        assert_eq!(packets[1].0.sequence, 1);
+        assert_eq!(packets[1].0.timestamp, 480);
+        packetizer
+            .push(&input[..], |hdr, packet| packets.push((*hdr, packet.to_vec())))
+            .unwrap();
+        assert_eq!(packets.len(), 4);
+        // Compressed well below the size of the raw samples.
+        let raw = 480 * stereo().bytes_per_frame();
+        for (_, packet) in &packets {
+            let (hdr, payload) = Header::parse(&packet[..]).unwrap();
+            assert_eq!(hdr.frames, 480);
+            assert!(payload.len() < raw / 4);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let encoder = CodecConfig::Opus(OpusConfig {
+            bitrate: Some(128_000),
+            ..Default::default()
+        })
+        .encoder(stereo())
+        .unwrap()
+        .unwrap();
+        let mut packetizer = Packetizer::new(encoder, stereo(), 7);
+        let input = sine(9_600, 2);
+        let mut packets = Vec::new();
+        packetizer
+            .push(&input[..], |_, packet| packets.push(packet.to_vec()))
+            .unwrap();
+        let mut decoder = StreamDecoder::new();
+        let mut output = Vec::new();
+        for packet in &packets {
+            let (hdr, payload) = Header::parse(&packet[..]).unwrap();
+            output.extend_from_slice(decoder.decode(&hdr, payload).unwrap());
+        }
+        assert_eq!(output.len(), input.len());
+        // Lossy, but the signal survives once the codec has settled.
+        let power = |s: &[f32]| s.iter().map(|v| v * v).sum::<f32>() / s.len() as f32;
+        let tail = input.len() / 2;
+        let ratio = power(&output[tail..]) / power(&input[tail..]);
+        assert!(ratio > 0.8 && ratio < 1.2, "power ratio {}", ratio);
+    }
+}
@@ -4,6 +4,9 @@ use serde::{Deserialize, Serialize};
 use std::default::Default;
 use std::path::PathBuf;
 use std::process::Command;
+use crate::codec::CodecConfig;
+use crate::stream::header::SampleFormat;
+use crate::stream::quic::TLS;
 use quinn::{
     ServerConfig,
     ServerConfigBuilder,
@@ -25,7 +28,32 @@ pub struct Endpoint {
 
     pub addr: String,
 
+    /// Skip verifying the endpoint's certificate. NOTE, this is
+    /// vulnerable to MITM attacks.
     pub insecure: bool,
+
+    /// How the endpoint's certificate is verified, and the client
+    /// certificate presented to it, if any.
+    #[serde(default)]
+    pub tls: Option<TLS>,
+
+    /// Compression applied to audio sent to this endpoint.
+    #[serde(default)]
+    pub codec: CodecConfig,
+
+    /// Sample format of uncompressed audio sent to this endpoint.
+    /// The device produces `f32`, so anything else is converted.
+    #[serde(default, rename = "sampleFormat")]
+    pub sample_format: SampleFormat,
+
+    /// Apply TPDF dither when `sample_format` is an integer format.
+    #[serde(default)]
+    pub dither: bool,
+
+    /// Device channels sent to this endpoint, in the order they're
+    /// sent, numbered from zero. All of them if unset.
+    #[serde(default)]
+    pub channels: Option<Vec<u16>>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -40,6 +68,12 @@ pub struct DeviceSpec {
     pub outputs: u16,
 
     pub endpoints: Vec<Endpoint>,
+
+    /// Address the driver serves metrics on (see
+    /// [`crate::stream::metrics`]), e.g. 127.0.0.1:9101. None are
+    /// served unless given.
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
 }
 
 impl DeviceSpec {
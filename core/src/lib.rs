@@ -13,7 +13,6 @@
 extern crate serde;
 #[macro_use]
 extern crate log;
-#[macro_use]
 extern crate tokio;
 #[macro_use]
 extern crate crossbeam;
@@ -22,11 +21,10 @@ extern crate lazy_static;
 
 //pub mod editor;
 //pub mod runtime;
+pub mod buffer;
+pub mod channels;
+pub mod codec;
 pub mod device;
-//pub mod stream;
-
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub struct Frame {
-    pub buffer: Vec<u8>,
-    pub sample_time: f64,
-}
\ No newline at end of file
+pub mod resample;
+pub mod sample;
+pub mod stream;
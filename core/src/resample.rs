@@ -0,0 +1,298 @@
+//! Sample rate conversion.
+//!
+//! Streams carry their sample rate in every packet header, and
+//! receivers convert to whatever rate the local device runs at. The
+//! converter is a polyphase windowed-sinc filter: a Kaiser-windowed
+//! low-pass is tabulated at `PHASES` fractional offsets and the
+//! coefficients for any position in between are interpolated from the
+//! two nearest rows. The cutoff sits just below the lower of the two
+//! Nyquist frequencies, so downsampling doesn't alias.
+//!
+//! The ratio can be nudged while running to follow a clock that drifts
+//! relative to the nominal rates.
+
+/// Number of fractional offsets the filter is tabulated at.
+const PHASES: usize = 256;
+
+/// Filter length when upsampling. Downsampling narrows the passband,
+/// so the filter is stretched by the conversion ratio to keep the
+/// same transition width relative to the output rate.
+const BASE_TAPS: usize = 128;
+
+/// Kaiser window shape, trading transition width for stopband
+/// attenuation. 9 gives roughly 90 dB.
+const KAISER_BETA: f64 = 9.0;
+
+/// Cutoff as a fraction of the lower Nyquist frequency.
+const CUTOFF: f64 = 0.95;
+
+pub struct Resampler {
+    channels: usize,
+    /// Input frames per output frame at the nominal rates.
+    nominal: f64,
+    /// Input frames advanced per output frame.
+    step: f64,
+    taps: usize,
+    /// `PHASES + 1` rows of `taps` coefficients. Row `p` is the filter
+    /// for an output that lies `p / PHASES` of a frame past an input.
+    filter: Vec<f32>,
+    /// Coefficients interpolated for the current output frame.
+    row: Vec<f32>,
+    /// Pending input, interleaved.
+    queue: Vec<f32>,
+    /// Position of the next output frame in `queue`, in frames.
+    pos: f64,
+}
+
+impl Resampler {
+    /// Converts interleaved audio with `channels` channels from
+    /// `from` Hz to `to` Hz.
+    pub fn new(from: u32, to: u32, channels: usize) -> Self {
+        assert!(from > 0 && to > 0, "sample rates must be positive");
+        assert!(channels > 0, "resampler needs at least one channel");
+        let step = from as f64 / to as f64;
+        let taps = (BASE_TAPS as f64 * step.max(1.0)).ceil() as usize;
+        let taps = taps + taps % 2;
+        let cutoff = CUTOFF * step.recip().min(1.0);
+        let mut filter = vec![0.0f32; (PHASES + 1) * taps];
+        let half = (taps / 2) as f64;
+        for (p, row) in filter.chunks_mut(taps).enumerate() {
+            let frac = p as f64 / PHASES as f64;
+            let coefficients = (0..taps)
+                .map(|j| {
+                    // Distance from the output position to input j.
+                    let x = frac + half - 1.0 - j as f64;
+                    cutoff * sinc(cutoff * x) * kaiser(x / half)
+                })
+                .collect::<Vec<_>>();
+            // Normalize each row so that DC passes at unity gain
+            // regardless of phase.
+            let sum: f64 = coefficients.iter().sum();
+            for (c, v) in row.iter_mut().zip(coefficients) {
+                *c = (v / sum) as f32;
+            }
+        }
+        let mut resampler = Self {
+            channels,
+            nominal: step,
+            step,
+            taps,
+            filter,
+            row: vec![0.0; taps],
+            queue: Vec::new(),
+            pos: 0.0,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    /// Forgets all pending input.
+    pub fn reset(&mut self) {
+        // Prime with silence so that the first output frame lines up
+        // with the first input frame.
+        let primed = self.taps / 2 - 1;
+        self.queue.clear();
+        self.queue.resize(primed * self.channels, 0.0);
+        self.pos = primed as f64;
+    }
+
+    /// Consumes input `ratio` times as fast as the nominal rates call
+    /// for. Small adjustments around 1 track clock drift; the filter
+    /// is designed for the nominal ratio.
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.step = self.nominal * ratio;
+    }
+
+    /// Number of input frames that must be pushed before `pull` can
+    /// produce `frames` output frames.
+    pub fn input_frames(&self, frames: usize) -> usize {
+        if frames == 0 {
+            return 0;
+        }
+        let last = (self.pos + (frames - 1) as f64 * self.step).floor() as usize;
+        (last + self.taps / 2 + 1).saturating_sub(self.queued())
+    }
+
+    /// Queues interleaved input.
+    pub fn push(&mut self, input: &[f32]) {
+        self.queue.extend_from_slice(input);
+    }
+
+    /// Fills `output` with as many interleaved frames as the pending
+    /// input allows and returns the number of samples written.
+    pub fn pull(&mut self, output: &mut [f32]) -> usize {
+        let channels = self.channels;
+        let half = self.taps / 2;
+        let queued = self.queued();
+        let mut written = 0;
+        for frame in output.chunks_exact_mut(channels) {
+            let i = self.pos.floor() as usize;
+            if i + half >= queued {
+                break;
+            }
+            self.interpolate(self.pos - i as f64);
+            let start = (i + 1 - half) * channels;
+            for (ch, out) in frame.iter_mut().enumerate() {
+                *out = self
+                    .row
+                    .iter()
+                    .zip(self.queue[start + ch..].iter().step_by(channels))
+                    .map(|(c, x)| c * x)
+                    .sum();
+            }
+            self.pos += self.step;
+            written += channels;
+        }
+        // Drop input that no future output frame reaches back to.
+        let consumed = (self.pos.floor() as usize + 1)
+            .saturating_sub(half)
+            .min(queued);
+        self.queue.drain(..consumed * channels);
+        self.pos -= consumed as f64;
+        written
+    }
+
+    fn queued(&self) -> usize {
+        self.queue.len() / self.channels
+    }
+
+    /// Computes the filter for an output `frac` of a frame past an
+    /// input frame into `self.row`.
+    fn interpolate(&mut self, frac: f64) {
+        let phase = frac * PHASES as f64;
+        let p = (phase.floor() as usize).min(PHASES - 1);
+        let t = (phase - p as f64) as f32;
+        let a = &self.filter[p * self.taps..(p + 1) * self.taps];
+        let b = &self.filter[(p + 1) * self.taps..(p + 2) * self.taps];
+        for ((c, a), b) in self.row.iter_mut().zip(a).zip(b) {
+            *c = a + (b - a) * t;
+        }
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// Kaiser window over [-1, 1].
+fn kaiser(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    bessel_i0(KAISER_BETA * (1.0 - x * x).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+/// Zeroth order modified Bessel function of the first kind.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (2.0 * std::f64::consts::PI * freq * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    /// Feeds `input` through in callback-sized chunks.
+    fn run(resampler: &mut Resampler, input: &[f32], channels: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        let mut chunk = vec![0.0f32; 64 * channels];
+        for block in input.chunks(100 * channels) {
+            resampler.push(block);
+            loop {
+                let n = resampler.pull(&mut chunk);
+                output.extend_from_slice(&chunk[..n]);
+                if n < chunk.len() {
+                    break;
+                }
+            }
+        }
+        output
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let sum: f64 = samples.iter().map(|&s| s as f64 * s as f64).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn converts_44100_to_48000() {
+        let mut resampler = Resampler::new(44_100, 48_000, 1);
+        let input = sine(1000.0, 44_100, 44_100);
+        let output = run(&mut resampler, &input, 1);
+        // The tail of the input is still waiting for lookahead.
+        assert!(output.len() <= 48_000 && output.len() > 48_000 - resampler.taps);
+        let reference = sine(1000.0, 48_000, output.len());
+        // Skip the start, where the filter is still reading silence.
+        let error = output[200..]
+            .iter()
+            .zip(&reference[200..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0f32, f32::max);
+        assert!(error < 1e-3, "max error {}", error);
+    }
+
+    #[test]
+    fn reports_input_needed() {
+        let mut resampler = Resampler::new(48_000, 44_100, 2);
+        let mut output = vec![0.0f32; 2 * 441];
+        for _ in 0..10 {
+            let needed = resampler.input_frames(441);
+            resampler.push(&vec![0.5; 2 * needed]);
+            assert_eq!(resampler.pull(&mut output), output.len());
+            let needed = resampler.input_frames(441);
+            assert!((479..=481).contains(&needed));
+        }
+        assert!(output.iter().all(|&s| (s - 0.5).abs() < 1e-4));
+    }
+
+    #[test]
+    fn rejects_aliases_when_downsampling() {
+        let mut resampler = Resampler::new(96_000, 44_100, 1);
+        // Above the output Nyquist frequency, so it must be filtered
+        // out rather than folding down to 14.1 kHz.
+        let input = sine(30_000.0, 96_000, 96_000);
+        let output = run(&mut resampler, &input, 1);
+        assert!(rms(&output[1000..]) < 1e-3);
+        let mut resampler = Resampler::new(96_000, 44_100, 1);
+        let input = sine(10_000.0, 96_000, 96_000);
+        let output = run(&mut resampler, &input, 1);
+        assert!((rms(&output[1000..]) - 0.5f64.sqrt()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn keeps_channels_separate() {
+        let mut resampler = Resampler::new(44_100, 48_000, 2);
+        let input = sine(440.0, 44_100, 4410)
+            .into_iter()
+            .flat_map(|s| vec![s, 0.0])
+            .collect::<Vec<_>>();
+        let output = run(&mut resampler, &input, 2);
+        let left = output.iter().step_by(2).cloned().collect::<Vec<_>>();
+        let right = output
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(rms(&left[200..]) > 0.7);
+        assert_eq!(rms(&right), 0.0);
+    }
+}
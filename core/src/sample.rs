@@ -0,0 +1,506 @@
+//! Sample types and their wire encoding.
+//!
+//! Streams declare their sample format in every packet header and
+//! always encode samples little-endian, regardless of the host. Each
+//! end converts between the wire format and whatever type it works
+//! in locally, so a sender with 24-bit hardware can send `I24`
+//! samples to a receiver that mixes in `f32`.
+use crate::stream::header::SampleFormat;
+
+/// A single audio sample. Signal processing (concealment, mixing,
+/// resampling) happens in floating point, so every sample type must
+/// be convertible to and from a normalized `f32` in [-1, 1].
+pub trait Sample: Copy + Default + Send + 'static {
+    /// Wire format with the same representation as this type.
+    const FORMAT: SampleFormat;
+
+    fn to_f32(self) -> f32;
+
+    fn from_f32(v: f32) -> Self;
+
+    fn to_f64(self) -> f64;
+
+    /// Rounds and clamps to the range of the type.
+    fn from_f64(v: f64) -> Self;
+
+    /// The sample left-justified in 32 bits. Integer types convert
+    /// between each other through this without floating point.
+    fn to_i32(self) -> i32 {
+        i32::from_f64(self.to_f64())
+    }
+
+    /// Inverse of `to_i32`. Integer types narrower than 32 bits drop
+    /// the low bits; use [`convert`] to round or dither instead.
+    fn from_i32(v: i32) -> Self {
+        Self::from_f64(v.to_f64())
+    }
+
+    /// Writes the sample little-endian into the start of `out`, which
+    /// must hold at least `FORMAT.bytes_per_sample()` bytes.
+    fn write_le(self, out: &mut [u8]);
+
+    /// Reads a little-endian sample from the start of `bytes`.
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+impl Sample for f32 {
+    const FORMAT: SampleFormat = SampleFormat::F32;
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+
+    fn write_le(self, out: &mut [u8]) {
+        out[..4].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+impl Sample for f64 {
+    const FORMAT: SampleFormat = SampleFormat::F64;
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(v: f32) -> Self {
+        v as f64
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn from_f64(v: f64) -> Self {
+        v
+    }
+
+    fn write_le(self, out: &mut [u8]) {
+        out[..8].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        let mut v = [0u8; 8];
+        v.copy_from_slice(&bytes[..8]);
+        f64::from_le_bytes(v)
+    }
+}
+
+impl Sample for i16 {
+    const FORMAT: SampleFormat = SampleFormat::I16;
+
+    fn to_f32(self) -> f32 {
+        self as f32 / 32_768.0
+    }
+
+    fn from_f32(v: f32) -> Self {
+        (v * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64 / 32_768.0
+    }
+
+    fn from_f64(v: f64) -> Self {
+        (v * 32_768.0).round().clamp(-32_768.0, 32_767.0) as i16
+    }
+
+    fn to_i32(self) -> i32 {
+        (self as i32) << 16
+    }
+
+    fn from_i32(v: i32) -> Self {
+        (v >> 16) as i16
+    }
+
+    fn write_le(self, out: &mut [u8]) {
+        out[..2].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        i16::from_le_bytes([bytes[0], bytes[1]])
+    }
+}
+
+/// A 24-bit integer sample, the native format of most audio
+/// interfaces. It is held right-justified in an `i32` and packed
+/// into three bytes on the wire.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct I24(i32);
+
+impl I24 {
+    pub const MIN: I24 = I24(-8_388_608);
+    pub const MAX: I24 = I24(8_388_607);
+
+    /// Clamps `v` to the 24-bit range.
+    pub fn new(v: i32) -> Self {
+        I24(v.clamp(Self::MIN.0, Self::MAX.0))
+    }
+
+    pub fn get(self) -> i32 {
+        self.0
+    }
+}
+
+impl Sample for I24 {
+    const FORMAT: SampleFormat = SampleFormat::I24;
+
+    fn to_f32(self) -> f32 {
+        self.0 as f32 / 8_388_608.0
+    }
+
+    fn from_f32(v: f32) -> Self {
+        Self::from_f64(v as f64)
+    }
+
+    fn to_f64(self) -> f64 {
+        self.0 as f64 / 8_388_608.0
+    }
+
+    fn from_f64(v: f64) -> Self {
+        I24((v * 8_388_608.0).round().clamp(-8_388_608.0, 8_388_607.0) as i32)
+    }
+
+    fn to_i32(self) -> i32 {
+        self.0 << 8
+    }
+
+    fn from_i32(v: i32) -> Self {
+        I24(v >> 8)
+    }
+
+    fn write_le(self, out: &mut [u8]) {
+        out[..3].copy_from_slice(&self.0.to_le_bytes()[..3]);
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        // Sign extend from the top byte.
+        let sign = if bytes[2] & 0x80 != 0 { 0xff } else { 0 };
+        I24(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], sign]))
+    }
+}
+
+impl Sample for i32 {
+    const FORMAT: SampleFormat = SampleFormat::I32;
+
+    fn to_f32(self) -> f32 {
+        (self as f64 / 2_147_483_648.0) as f32
+    }
+
+    fn from_f32(v: f32) -> Self {
+        (v as f64 * 2_147_483_648.0)
+            .round()
+            .clamp(-2_147_483_648.0, 2_147_483_647.0) as i32
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64 / 2_147_483_648.0
+    }
+
+    fn from_f64(v: f64) -> Self {
+        (v * 2_147_483_648.0)
+            .round()
+            .clamp(-2_147_483_648.0, 2_147_483_647.0) as i32
+    }
+
+    fn to_i32(self) -> i32 {
+        self
+    }
+
+    fn from_i32(v: i32) -> Self {
+        v
+    }
+
+    fn write_le(self, out: &mut [u8]) {
+        out[..4].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn read_le(bytes: &[u8]) -> Self {
+        i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+}
+
+/// Source of triangular (TPDF) dither noise. Adding it before reducing
+/// the bit depth turns quantization distortion into a constant,
+/// signal-independent noise floor.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new(seed: u32) -> Self {
+        // Xorshift must not start at zero.
+        Self { state: seed | 1 }
+    }
+
+    fn next(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    /// Noise in (-1, 1), in units of the target's least significant
+    /// bit.
+    fn tpdf(&mut self) -> f64 {
+        let scale = 1.0 / 4_294_967_296.0;
+        (self.next() as f64 - self.next() as f64) * scale
+    }
+
+    /// Integer noise in (-lsb, lsb).
+    fn tpdf_int(&mut self, lsb: i64) -> i64 {
+        (self.next() as i64 % lsb) - (self.next() as i64 % lsb)
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new(0x9E37_79B9)
+    }
+}
+
+/// Converts a single sample. See [`convert`].
+pub fn convert_sample<S: Sample, D: Sample>(s: S, dither: Option<&mut Dither>) -> D {
+    if S::FORMAT.is_float() || D::FORMAT.is_float() {
+        let v = match dither {
+            // Float to integer always loses precision.
+            Some(dither) if !D::FORMAT.is_float() => {
+                s.to_f64() + dither.tpdf() / (1u64 << (D::FORMAT.bits() - 1)) as f64
+            }
+            _ => s.to_f64(),
+        };
+        return D::from_f64(v);
+    }
+    if D::FORMAT.bits() >= S::FORMAT.bits() {
+        return D::from_i32(s.to_i32());
+    }
+    // Round to nearest (plus any dither) before the low bits are
+    // dropped.
+    let lsb = 1i64 << (32 - D::FORMAT.bits());
+    let noise = match dither {
+        Some(dither) => dither.tpdf_int(lsb),
+        None => 0,
+    };
+    let v = (s.to_i32() as i64 + lsb / 2 + noise).clamp(i32::MIN as i64, i32::MAX as i64);
+    D::from_i32(v as i32)
+}
+
+/// Converts samples between types, e.g. `f32` from a mixer to `I24`
+/// for an interface. Integer types convert between each other without
+/// passing through floating point, and widening conversions are
+/// exact. When the output has less precision than the input, samples
+/// are rounded to nearest, with TPDF dither added first if `dither`
+/// is given. Converts as many samples as fit in `output`.
+pub fn convert<S: Sample, D: Sample>(
+    input: &[S],
+    output: &mut [D],
+    mut dither: Option<&mut Dither>,
+) {
+    for (s, d) in input.iter().zip(output.iter_mut()) {
+        *d = convert_sample(*s, dither.as_deref_mut());
+    }
+}
+
+/// Encodes samples little-endian in the wire format `format`,
+/// converting them from `T` as [`convert`] does. Returns the number of
+/// bytes written. Panics if `out` is too small.
+pub fn encode<T: Sample>(
+    samples: &[T],
+    format: SampleFormat,
+    out: &mut [u8],
+    dither: Option<&mut Dither>,
+) -> usize {
+    match format {
+        SampleFormat::I16 => encode_as::<T, i16>(samples, out, dither),
+        SampleFormat::I24 => encode_as::<T, I24>(samples, out, dither),
+        SampleFormat::I32 => encode_as::<T, i32>(samples, out, dither),
+        SampleFormat::F32 => encode_as::<T, f32>(samples, out, dither),
+        SampleFormat::F64 => encode_as::<T, f64>(samples, out, dither),
+    }
+}
+
+fn encode_as<S: Sample, D: Sample>(
+    samples: &[S],
+    out: &mut [u8],
+    mut dither: Option<&mut Dither>,
+) -> usize {
+    let size = D::FORMAT.bytes_per_sample();
+    let len = samples.len() * size;
+    for (s, dest) in samples.iter().zip(out[..len].chunks_exact_mut(size)) {
+        convert_sample::<S, D>(*s, dither.as_deref_mut()).write_le(dest);
+    }
+    len
+}
+
+/// Decodes little-endian samples in the wire format `format` into
+/// `out`, converting them to `T`. Returns the number of samples
+/// decoded, which is limited by the size of `out`.
+pub fn decode<T: Sample>(bytes: &[u8], format: SampleFormat, out: &mut [T]) -> usize {
+    match format {
+        SampleFormat::I16 => decode_as::<i16, T>(bytes, out),
+        SampleFormat::I24 => decode_as::<I24, T>(bytes, out),
+        SampleFormat::I32 => decode_as::<i32, T>(bytes, out),
+        SampleFormat::F32 => decode_as::<f32, T>(bytes, out),
+        SampleFormat::F64 => decode_as::<f64, T>(bytes, out),
+    }
+}
+
+fn decode_as<S: Sample, D: Sample>(bytes: &[u8], out: &mut [D]) -> usize {
+    let mut count = 0;
+    for (src, d) in bytes
+        .chunks_exact(S::FORMAT.bytes_per_sample())
+        .zip(out.iter_mut())
+    {
+        *d = convert_sample(S::read_le(src), None);
+        count += 1;
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn round_trip<T: Sample + PartialEq + std::fmt::Debug>(samples: &[T]) {
+        let mut bytes = vec![0u8; samples.len() * T::FORMAT.bytes_per_sample()];
+        assert_eq!(
+            encode(samples, T::FORMAT, &mut bytes[..], None),
+            bytes.len()
+        );
+        let mut out = vec![T::default(); samples.len()];
+        assert_eq!(decode(&bytes[..], T::FORMAT, &mut out[..]), samples.len());
+        assert_eq!(&out[..], samples);
+    }
+
+    #[test]
+    fn wire_is_little_endian() {
+        let mut bytes = [0u8; 3];
+        encode(
+            &[I24::new(0x123456)],
+            SampleFormat::I24,
+            &mut bytes[..],
+            None,
+        );
+        assert_eq!(bytes, [0x56, 0x34, 0x12]);
+        let mut bytes = [0u8; 2];
+        encode(&[0x1234i16], SampleFormat::I16, &mut bytes[..], None);
+        assert_eq!(bytes, [0x34, 0x12]);
+        let mut bytes = [0u8; 4];
+        encode(&[1.0f32], SampleFormat::F32, &mut bytes[..], None);
+        assert_eq!(bytes, [0x00, 0x00, 0x80, 0x3f]);
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        round_trip(&[0i16, 1, -1, i16::MIN, i16::MAX]);
+        round_trip(&[I24::new(0), I24::new(1), I24::new(-1), I24::MIN, I24::MAX]);
+        round_trip(&[0i32, 1, -1, i32::MIN, i32::MAX]);
+        round_trip(&[0.0f32, -0.5, 1.0, f32::MIN_POSITIVE]);
+        round_trip(&[0.0f64, -0.5, 1.0, 1e-300]);
+    }
+
+    #[test]
+    fn i24_clamps_and_sign_extends() {
+        assert_eq!(I24::new(9_000_000), I24::MAX);
+        assert_eq!(I24::new(-9_000_000), I24::MIN);
+        assert_eq!(I24::read_le(&[0xff, 0xff, 0xff]).get(), -1);
+        assert_eq!(I24::read_le(&[0x00, 0x00, 0x80]), I24::MIN);
+    }
+
+    #[test]
+    fn widening_is_exact() {
+        let input = [I24::MIN, I24::new(-1), I24::new(12_345), I24::MAX];
+        let mut wide = [0i32; 4];
+        convert(&input[..], &mut wide[..], None);
+        assert_eq!(wide, [i32::MIN, -256, 12_345 << 8, 8_388_607 << 8]);
+        let mut floats = [0.0f32; 4];
+        convert(&input[..], &mut floats[..], None);
+        let mut back = [I24::default(); 4];
+        convert(&floats[..], &mut back[..], None);
+        assert_eq!(back, input);
+        // 16-bit audio survives a trip through 24 bits.
+        let input = [i16::MIN, -1, 0, 1, i16::MAX];
+        let mut mid = [I24::default(); 5];
+        let mut back = [0i16; 5];
+        convert(&input[..], &mut mid[..], None);
+        convert(&mid[..], &mut back[..], None);
+        assert_eq!(back, input);
+    }
+
+    #[test]
+    fn narrowing_rounds_to_nearest() {
+        // 0x7f and 0x80 are just under and exactly half of an i16 step.
+        let input = [
+            I24::new(0x1_007f),
+            I24::new(0x1_0080),
+            I24::new(-0x1_0081),
+            I24::MAX,
+        ];
+        let mut out = [0i16; 4];
+        convert(&input[..], &mut out[..], None);
+        assert_eq!(out, [0x100, 0x101, -0x101, i16::MAX]);
+        let mut out = [I24::default(); 3];
+        convert(&[1.0f32, -1.0, 0.5 / 8_388_608.0][..], &mut out[..], None);
+        assert_eq!(out, [I24::MAX, I24::MIN, I24::new(1)]);
+    }
+
+    #[test]
+    fn dither_is_unbiased_and_bounded() {
+        let mut dither = Dither::default();
+        // A level a third of the way between two 16-bit steps.
+        let level = 100.0 / 32_768.0 + 1.0 / (3.0 * 32_768.0);
+        let input = vec![level; 30_000];
+        let mut out = vec![0i16; input.len()];
+        convert(&input[..], &mut out[..], Some(&mut dither));
+        assert!(out.iter().all(|v| (99..=101).contains(v)));
+        let mean = out.iter().map(|v| *v as f64).sum::<f64>() / out.len() as f64;
+        assert!((mean - 100.333).abs() < 0.02, "mean {}", mean);
+        // Without dither the fraction is lost.
+        convert(&input[..], &mut out[..], None);
+        assert!(out.iter().all(|v| *v == 100));
+        // Integer narrowing dithers too.
+        let input = vec![I24::new((100 << 8) + 85); 30_000];
+        convert(&input[..], &mut out[..], Some(&mut dither));
+        let mean = out.iter().map(|v| *v as f64).sum::<f64>() / out.len() as f64;
+        assert!((mean - 100.332).abs() < 0.02, "mean {}", mean);
+    }
+
+    #[test]
+    fn widening_ignores_dither() {
+        let mut dither = Dither::default();
+        let input = [I24::new(-5), I24::new(77)];
+        let mut out = [0i32; 2];
+        convert(&input[..], &mut out[..], Some(&mut dither));
+        assert_eq!(out, [-5 << 8, 77 << 8]);
+    }
+
+    #[test]
+    fn converts_between_wire_formats() {
+        // A receiver working in f32 decodes a 24-bit stream.
+        let input = [I24::new(-4_194_304), I24::new(0), I24::new(4_194_304)];
+        let mut bytes = [0u8; 9];
+        encode(&input[..], SampleFormat::I24, &mut bytes[..], None);
+        let mut out = [0.0f32; 3];
+        assert_eq!(decode(&bytes[..], SampleFormat::I24, &mut out[..]), 3);
+        assert_eq!(out, [-0.5, 0.0, 0.5]);
+        // A sender working in f32 encodes 16-bit samples.
+        let mut bytes = [0u8; 6];
+        assert_eq!(encode(&out[..], SampleFormat::I16, &mut bytes[..], None), 6);
+        assert_eq!(bytes, [0x00, 0xc0, 0x00, 0x00, 0x00, 0x40]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Stream addresses as written in configs and on the command line,
+//! `<IP>:<PORT>/<PROTOCOL>`, e.g. `239.69.1.1:5004/UDP` or
+//! `[::1]:20000/QUIC`.
+use std::net::SocketAddr;
+
+/// Transport carrying a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Quic,
+    /// RTP, as used by AES67 (see [`super::rtp`]).
+    Rtp,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Udp => "UDP",
+            Protocol::Tcp => "TCP",
+            Protocol::Quic => "QUIC",
+            Protocol::Rtp => "RTP",
+        }
+    }
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = AddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "UDP" => Ok(Protocol::Udp),
+            "TCP" => Ok(Protocol::Tcp),
+            "QUIC" => Ok(Protocol::Quic),
+            "RTP" => Ok(Protocol::Rtp),
+            _ => Err(AddrError::UnknownProtocol(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrError {
+    /// The address has no `/<PROTOCOL>` suffix.
+    MissingProtocol,
+    UnknownProtocol(String),
+    InvalidAddr(std::net::AddrParseError),
+}
+
+impl std::fmt::Display for AddrError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddrError::MissingProtocol => {
+                write!(f, "missing protocol (expected <IP>:<PORT>/<PROTOCOL>)")
+            }
+            AddrError::UnknownProtocol(p) => {
+                write!(
+                    f,
+                    "unknown protocol \"{}\" (expected UDP, TCP, QUIC or RTP)",
+                    p
+                )
+            }
+            AddrError::InvalidAddr(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AddrError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamAddr {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+}
+
+impl StreamAddr {
+    pub fn new(addr: SocketAddr, protocol: Protocol) -> Self {
+        Self { addr, protocol }
+    }
+
+    /// Returns true if the stream is sent to a multicast group.
+    pub fn is_multicast(&self) -> bool {
+        self.addr.ip().is_multicast()
+    }
+}
+
+impl std::str::FromStr for StreamAddr {
+    type Err = AddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let i = s.rfind('/').ok_or(AddrError::MissingProtocol)?;
+        let protocol = s[i + 1..].parse()?;
+        let addr = s[..i].parse().map_err(AddrError::InvalidAddr)?;
+        Ok(StreamAddr { addr, protocol })
+    }
+}
+
+impl std::fmt::Display for StreamAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.protocol)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let addr: StreamAddr = "239.69.1.1:5004/UDP".parse().unwrap();
+        assert_eq!(addr.addr, "239.69.1.1:5004".parse().unwrap());
+        assert_eq!(addr.protocol, Protocol::Udp);
+        assert!(addr.is_multicast());
+        assert_eq!(addr.to_string(), "239.69.1.1:5004/UDP");
+        let addr: StreamAddr = "[ff02::1:5]:5004/quic".parse().unwrap();
+        assert_eq!(addr.protocol, Protocol::Quic);
+        assert!(addr.is_multicast());
+        assert_eq!(addr.to_string(), "[ff02::1:5]:5004/QUIC");
+        let addr: StreamAddr = "239.69.1.1:5004/rtp".parse().unwrap();
+        assert_eq!(addr.protocol, Protocol::Rtp);
+        assert_eq!(addr.to_string(), "239.69.1.1:5004/RTP");
+        assert!(!"127.0.0.1:2000/TCP"
+            .parse::<StreamAddr>()
+            .unwrap()
+            .is_multicast());
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            "127.0.0.1:2000".parse::<StreamAddr>(),
+            Err(AddrError::MissingProtocol)
+        );
+        assert_eq!(
+            "127.0.0.1:2000/SCTP".parse::<StreamAddr>(),
+            Err(AddrError::UnknownProtocol("SCTP".into()))
+        );
+        assert!(matches!(
+            "localhost:2000/UDP".parse::<StreamAddr>(),
+            Err(AddrError::InvalidAddr(_))
+        ));
+    }
+}
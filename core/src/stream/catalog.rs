@@ -0,0 +1,275 @@
+//! Live catalog of the streams announced over SAP (see [`super::sap`]),
+//! by Paradise senders and third-party AES67 devices alike.
+//! Announcements that aren't L16 or L24 audio over RTP are skipped.
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::sap::SapPacket;
+use crate::stream::sdp::SessionDescription;
+use crate::stream::shutdown::Shutdown;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+
+/// How long a stream stays in the catalog without being announced
+/// again. Senders usually announce every 30 seconds.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A stream as last announced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub sdp: SessionDescription,
+    /// Address of the host that announced it.
+    pub announcer: IpAddr,
+    pub last_seen: Instant,
+    /// Hash of the announcement, which withdrawals refer to.
+    hash: u16,
+}
+
+/// Entries by SDP origin and session id, which identify a session
+/// across changes to its description.
+type Entries = HashMap<(IpAddr, u64), Entry>;
+
+pub struct Catalog {
+    addr: SocketAddr,
+    shutdown: Shutdown,
+    entries: std::sync::Arc<std::sync::Mutex<Entries>>,
+    /// Notified whenever an announcement arrives.
+    updated: std::sync::Arc<Notify>,
+    timeout: Duration,
+}
+
+impl Catalog {
+    /// Joins `group`, usually [`super::sap::SAP_ADDR`], and collects
+    /// announcements until shut down. Must be called from within a
+    /// tokio runtime.
+    pub fn new(
+        group: SocketAddr,
+        multicast: &MulticastConfig,
+        timeout: Duration,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        let sock = multicast::receiver(group, multicast)?;
+        sock.set_nonblocking(true)?;
+        let addr = sock.local_addr()?;
+        let entries = std::sync::Arc::new(std::sync::Mutex::new(Entries::new()));
+        let updated = std::sync::Arc::new(Notify::new());
+        let shutdown = {
+            let (entries, updated) = (entries.clone(), updated.clone());
+            Shutdown::spawn(move |stop| Self::entry(entries, updated, sock, timeout, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            addr,
+            shutdown,
+            entries,
+            updated,
+            timeout,
+        }))
+    }
+
+    /// Address the catalog is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Streams announced within the timeout, by name.
+    pub fn entries(&self) -> Vec<Entry> {
+        let now = Instant::now();
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| now.duration_since(entry.last_seen) < self.timeout)
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.sdp.name.cmp(&b.sdp.name));
+        entries
+    }
+
+    /// Looks up a stream by its session name.
+    pub fn find(&self, name: &str) -> Option<SessionDescription> {
+        self.entries()
+            .into_iter()
+            .find(|entry| entry.sdp.name == name)
+            .map(|entry| entry.sdp)
+    }
+
+    /// Waits up to `timeout` for a stream named `name` to be
+    /// announced.
+    pub async fn wait_for(&self, name: &str, timeout: Duration) -> Option<SessionDescription> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Some(sdp) = self.find(name) {
+                return Some(sdp);
+            }
+            tokio::select! {
+                _ = self.updated.notified() => {}
+                _ = tokio::time::delay_until(deadline) => return self.find(name),
+            }
+        }
+    }
+
+    /// Stops listening and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        entries: std::sync::Arc<std::sync::Mutex<Entries>>,
+        updated: std::sync::Arc<Notify>,
+        sock: std::net::UdpSocket,
+        timeout: Duration,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("sap rx: {}", e);
+                return;
+            }
+        };
+        const BUFFER_SIZE: usize = 65_536;
+        let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+        loop {
+            let result = tokio::select! {
+                // Stopped, or the catalog was dropped.
+                _ = &mut stop => return,
+                result = sock.recv_from(&mut buf[..]) => result,
+            };
+            let (amt, src) = match result {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("sap rx recv_from: {:?}", e);
+                    continue;
+                }
+            };
+            let packet = match SapPacket::parse(&buf[..amt]) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    debug!("sap rx: ignoring announcement from {}: {}", src, e);
+                    continue;
+                }
+            };
+            update(&mut entries.lock().unwrap(), packet, timeout);
+            updated.notify();
+        }
+    }
+}
+
+/// Applies an announcement and forgets streams that have timed out.
+fn update(entries: &mut Entries, packet: SapPacket, timeout: Duration) {
+    let now = Instant::now();
+    entries.retain(|_, entry| now.duration_since(entry.last_seen) < timeout);
+    if packet.deletion {
+        // Withdrawals may carry only part of the SDP, so they're
+        // matched on the announcement they refer to.
+        entries.retain(|_, entry| !(entry.announcer == packet.origin && entry.hash == packet.hash));
+        return;
+    }
+    let sdp = match packet.sdp.parse::<SessionDescription>() {
+        Ok(sdp) => sdp,
+        Err(e) => {
+            debug!("sap rx: ignoring stream from {}: {}", packet.origin, e);
+            return;
+        }
+    };
+    entries.insert(
+        (sdp.origin, sdp.session_id),
+        Entry {
+            sdp,
+            announcer: packet.origin,
+            last_seen: now,
+            hash: packet.hash,
+        },
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::multicast::Interface;
+    use crate::stream::rtp::RtpConfig;
+    use crate::stream::sap::Announcer;
+
+    fn sdp(session_id: u64, name: &str) -> SessionDescription {
+        SessionDescription {
+            session_id,
+            session_version: 1,
+            origin: "127.0.0.1".parse().unwrap(),
+            name: String::from(name),
+            dest: "239.69.1.1:5004".parse().unwrap(),
+            ttl: Some(1),
+            rtp: RtpConfig::default(),
+        }
+    }
+
+    #[test]
+    fn updates_and_expires() {
+        let mut entries = Entries::new();
+        let timeout = Duration::from_secs(60);
+        update(&mut entries, SapPacket::new(&sdp(1, "a"), false), timeout);
+        update(&mut entries, SapPacket::new(&sdp(2, "b"), false), timeout);
+        assert_eq!(entries.len(), 2);
+        // A new version replaces the old one.
+        let mut changed = sdp(1, "a");
+        changed.session_version = 2;
+        update(&mut entries, SapPacket::new(&changed, false), timeout);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[&(changed.origin, 1)].sdp, changed);
+        // Withdrawing the old version leaves the new one.
+        update(&mut entries, SapPacket::new(&sdp(1, "a"), true), timeout);
+        assert_eq!(entries.len(), 2);
+        update(&mut entries, SapPacket::new(&changed, true), timeout);
+        assert_eq!(entries.len(), 1);
+        // Anything that isn't a supported stream is skipped.
+        let mut packet = SapPacket::new(&sdp(3, "c"), false);
+        packet.sdp = packet.sdp.replace("L24", "opus");
+        update(&mut entries, packet, timeout);
+        assert_eq!(entries.len(), 1);
+        update(
+            &mut entries,
+            SapPacket::new(&sdp(3, "c"), false),
+            Duration::from_secs(0),
+        );
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&(changed.origin, 3)));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn discovers_announced_streams() {
+        // Stay on the loopback interface so the test doesn't depend
+        // on the network.
+        let multicast = MulticastConfig {
+            ttl: 0,
+            interface: Some(Interface::Addr(std::net::Ipv4Addr::LOCALHOST)),
+        };
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group: SocketAddr = ([239, 255, 69, 2], port).into();
+        let catalog = Catalog::new(group, &multicast, DEFAULT_TIMEOUT).unwrap();
+        let announcer = Announcer::new(group, Duration::from_secs(30), &multicast).unwrap();
+        announcer.announce(sdp(1, "Studio A"));
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            catalog.wait_for("Studio A", timeout).await,
+            Some(sdp(1, "Studio A"))
+        );
+        announcer.withdraw(1);
+        let deadline = Instant::now() + timeout;
+        while catalog.find("Studio A").is_some() {
+            assert!(Instant::now() < deadline, "not withdrawn");
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        // Shutting down withdraws everything still announced.
+        announcer.announce(sdp(2, "Studio B"));
+        assert!(catalog.wait_for("Studio B", timeout).await.is_some());
+        announcer.shutdown().await;
+        while !catalog.entries().is_empty() {
+            assert!(Instant::now() < deadline, "not withdrawn on shutdown");
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        catalog.shutdown().await;
+    }
+}
@@ -0,0 +1,176 @@
+//! Messages exchanged on the reliable control stream of a QUIC
+//! connection, alongside the unreliable datagrams carrying audio.
+//!
+//! Each message is sent as a length-delimited frame (see
+//! [`super::framing`]) starting with a one byte tag. The sender opens
+//! the control stream and introduces its stream with `Hello`. The
+//! receiver answers with `Reject` if it can't play the stream, and
+//! then closes the connection.
+//!
+//! ```text
+//!  Hello:   | 1 | version | chans (2) | sample rate (4) | format | codec |
+//!  Reject:  | 2 | reason (UTF-8) ... |
+//! ```
+
+use crate::codec::Codec;
+use crate::stream::header::{SampleFormat, StreamFormat, VERSION};
+
+const TAG_HELLO: u8 = 1;
+const TAG_REJECT: u8 = 2;
+
+const HELLO_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// Describes the audio the sender is about to stream.
+    Hello {
+        /// Packet header version the sender speaks.
+        version: u8,
+        format: StreamFormat,
+        codec: Codec,
+    },
+    /// The receiver won't play the stream, and why.
+    Reject(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlError {
+    Empty,
+    UnknownTag(u8),
+    /// The message is shorter or longer than its tag requires.
+    Length {
+        expected: usize,
+        actual: usize,
+    },
+    UnknownSampleFormat(u8),
+    UnknownCodec(u8),
+    InvalidReason,
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::Empty => write!(f, "empty control message"),
+            ControlError::UnknownTag(v) => write!(f, "unknown control message {}", v),
+            ControlError::Length { expected, actual } => write!(
+                f,
+                "control message is {} bytes, expected {}",
+                actual, expected
+            ),
+            ControlError::UnknownSampleFormat(v) => write!(f, "unknown sample format {}", v),
+            ControlError::UnknownCodec(v) => write!(f, "unknown codec {}", v),
+            ControlError::InvalidReason => write!(f, "rejection reason is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+impl Control {
+    /// Introduces a stream of the given format from this sender.
+    pub fn hello(format: StreamFormat, codec: Codec) -> Self {
+        Control::Hello {
+            version: VERSION,
+            format,
+            codec,
+        }
+    }
+
+    /// Appends the encoded message to `out`.
+    pub fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Control::Hello {
+                version,
+                format,
+                codec,
+            } => {
+                out.push(TAG_HELLO);
+                out.push(*version);
+                out.extend_from_slice(&format.channels.to_be_bytes());
+                out.extend_from_slice(&format.sample_rate.to_be_bytes());
+                out.push(format.sample_format.as_u8());
+                out.push(codec.as_u8());
+            }
+            Control::Reject(reason) => {
+                out.push(TAG_REJECT);
+                out.extend_from_slice(reason.as_bytes());
+            }
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, ControlError> {
+        match buf.first() {
+            None => Err(ControlError::Empty),
+            Some(&TAG_HELLO) => {
+                if buf.len() != HELLO_LEN {
+                    return Err(ControlError::Length {
+                        expected: HELLO_LEN,
+                        actual: buf.len(),
+                    });
+                }
+                let sample_format = SampleFormat::from_u8(buf[8])
+                    .ok_or(ControlError::UnknownSampleFormat(buf[8]))?;
+                let codec = Codec::from_u8(buf[9]).ok_or(ControlError::UnknownCodec(buf[9]))?;
+                Ok(Control::Hello {
+                    version: buf[1],
+                    format: StreamFormat {
+                        sample_format,
+                        channels: u16::from_be_bytes([buf[2], buf[3]]),
+                        sample_rate: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+                    },
+                    codec,
+                })
+            }
+            Some(&TAG_REJECT) => std::str::from_utf8(&buf[1..])
+                .map(|reason| Control::Reject(reason.to_owned()))
+                .map_err(|_| ControlError::InvalidReason),
+            Some(&tag) => Err(ControlError::UnknownTag(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            Control::hello(
+                StreamFormat::new(SampleFormat::I24, 8, 96_000),
+                Codec::Lossless,
+            ),
+            Control::Reject(String::from("expected 2 channels")),
+            Control::Reject(String::new()),
+        ];
+        for message in messages {
+            let mut buf = Vec::new();
+            message.write(&mut buf);
+            assert_eq!(Control::parse(&buf).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        assert_eq!(Control::parse(&[]), Err(ControlError::Empty));
+        assert_eq!(Control::parse(&[9]), Err(ControlError::UnknownTag(9)));
+        let mut buf = Vec::new();
+        Control::hello(StreamFormat::new(SampleFormat::F32, 2, 48_000), Codec::Pcm).write(&mut buf);
+        assert_eq!(
+            Control::parse(&buf[..5]),
+            Err(ControlError::Length {
+                expected: HELLO_LEN,
+                actual: 5
+            })
+        );
+        buf[8] = 0;
+        assert_eq!(
+            Control::parse(&buf),
+            Err(ControlError::UnknownSampleFormat(0))
+        );
+        assert_eq!(
+            Control::parse(&[TAG_REJECT, 0xff]),
+            Err(ControlError::InvalidReason)
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! Authenticated encryption of datagrams with a pre-shared key.
+//!
+//! Sealed datagrams keep their header in the clear, so receivers
+//! can still tell streams apart, and append a trailer:
+//!
+//! ```text
+//!  0           30                     n        n + 16  n + 24    n + 32
+//!  +-----------+----------------------+---------+-------+---------+
+//!  |  header   | payload (encrypted)  |   tag   | salt  | counter |
+//!  +-----------+----------------------+---------+-------+---------+
+//! ```
+//!
+//! The header, with [`FLAG_ENCRYPTED`] set, the salt and the counter
+//! are authenticated along with the payload by ChaCha20-Poly1305.
+//!
+//! Every stream is keyed separately: the sender picks a random salt
+//! when it starts and the key is derived from the pre-shared key and
+//! that salt with HKDF-SHA256. The nonce is the counter, the sequence
+//! number of the datagram within the stream. It counts every datagram,
+//! FEC parity included, so it never repeats under the same key.
+//!
+//! Receivers drop datagrams whose counter was already seen or is too
+//! far behind the newest one. A sender restarting with a new salt is
+//! followed as long as it started no earlier than the stream being
+//! received, which the first four bytes of the salt record as Unix
+//! time. This stops an old stream being replayed in place of the
+//! current one, except to a receiver that restarted since.
+use crate::stream::header::{FLAG_ENCRYPTED, HEADER_LEN};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+
+/// Bytes sealing adds to a datagram.
+pub const SEAL_OVERHEAD: usize = TAG_LEN + SALT_LEN + 8;
+
+const TAG_LEN: usize = 16;
+
+const SALT_LEN: usize = 8;
+
+const KEY_LEN: usize = 32;
+
+/// Domain separation for stream keys derived from a pre-shared key.
+const KDF_INFO: &[u8] = b"paradise udp v1";
+
+/// How far behind the newest datagram of a stream a datagram may
+/// arrive before it's dropped.
+pub const REPLAY_WINDOW: u64 = 128;
+
+/// A pre-shared key, written as 64 hex digits, e.g. as generated by
+/// `paradise cert psk` or `openssl rand -hex 32`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; KEY_LEN]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key doesn't have 32 bytes.
+    Length(usize),
+    InvalidDigit(char),
+}
+
+impl std::fmt::Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::Length(n) => write!(f, "key has {} hex digits, expected 64", n),
+            KeyError::InvalidDigit(c) => write!(f, "invalid hex digit '{}'", c),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+impl Key {
+    /// Generates a random key.
+    pub fn generate() -> Self {
+        Key(random())
+    }
+
+    /// Reads a key from a file holding its hex digits.
+    pub fn read(path: &str) -> std::io::Result<Self> {
+        std::fs::read_to_string(path)?.parse().map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+        })
+    }
+
+    /// The key of the stream started with `salt`.
+    fn derive(&self, salt: &[u8]) -> LessSafeKey {
+        let prk = ring::hkdf::Salt::new(ring::hkdf::HKDF_SHA256, salt).extract(&self.0);
+        let okm = prk
+            .expand(&[KDF_INFO], &CHACHA20_POLY1305)
+            .expect("key length is valid for HKDF-SHA256");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+impl std::str::FromStr for Key {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s
+            .trim()
+            .chars()
+            .map(|c| {
+                c.to_digit(16)
+                    .map(|d| d as u8)
+                    .ok_or(KeyError::InvalidDigit(c))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if digits.len() != 2 * KEY_LEN {
+            return Err(KeyError::Length(digits.len()));
+        }
+        let mut key = [0u8; KEY_LEN];
+        for (b, pair) in key.iter_mut().zip(digits.chunks(2)) {
+            *b = pair[0] << 4 | pair[1];
+        }
+        Ok(Key(key))
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keep keys out of logs.
+        write!(f, "Key(..)")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SealError {
+    /// The datagram is too short to be sealed.
+    Truncated(usize),
+    /// The datagram was sent in the clear.
+    NotEncrypted,
+    /// The datagram was already received, or is too old to tell.
+    Replayed(u64),
+    /// The datagram belongs to a stream that started before the one
+    /// being received.
+    Stale,
+    /// The datagram was sealed with another key or tampered with.
+    Authentication,
+}
+
+impl std::fmt::Display for SealError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SealError::Truncated(len) => write!(
+                f,
+                "datagram too short to be encrypted ({} < {} bytes)",
+                len,
+                HEADER_LEN + SEAL_OVERHEAD
+            ),
+            SealError::NotEncrypted => write!(f, "datagram is not encrypted"),
+            SealError::Replayed(counter) => write!(f, "replayed datagram {}", counter),
+            SealError::Stale => write!(f, "datagram from an earlier stream"),
+            SealError::Authentication => write!(f, "authentication failed (wrong key?)"),
+        }
+    }
+}
+
+impl std::error::Error for SealError {}
+
+/// Returns true if `datagram` claims to be sealed.
+pub fn is_sealed(datagram: &[u8]) -> bool {
+    datagram.len() > 3 && datagram[3] & FLAG_ENCRYPTED != 0
+}
+
+/// Seals the datagrams of a single outgoing stream.
+pub struct Sealer {
+    key: LessSafeKey,
+    salt: [u8; SALT_LEN],
+    counter: u64,
+    buf: Vec<u8>,
+}
+
+impl Sealer {
+    pub fn new(psk: &Key) -> Self {
+        let mut salt: [u8; SALT_LEN] = random();
+        salt[..4].copy_from_slice(&unix_time().to_be_bytes());
+        Self {
+            key: psk.derive(&salt),
+            salt,
+            counter: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Encrypts `datagram`, which must start with a header, and
+    /// returns the sealed datagram.
+    pub fn seal(&mut self, datagram: &[u8]) -> &[u8] {
+        assert!(datagram.len() >= HEADER_LEN, "datagram has no header");
+        let counter = self.counter;
+        self.counter += 1;
+        self.buf.clear();
+        self.buf.extend_from_slice(datagram);
+        self.buf[3] |= FLAG_ENCRYPTED;
+        let aad = aad(&self.buf[..HEADER_LEN], &self.salt, counter);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                nonce(counter),
+                Aad::from(&aad[..]),
+                &mut self.buf[HEADER_LEN..],
+            )
+            .expect("datagram fits in a single seal");
+        self.buf.extend_from_slice(tag.as_ref());
+        self.buf.extend_from_slice(&self.salt);
+        self.buf.extend_from_slice(&counter.to_be_bytes());
+        &self.buf[..]
+    }
+}
+
+/// Opens the datagrams of incoming streams sealed with the same
+/// pre-shared key, following the newest stream.
+pub struct Opener {
+    psk: Key,
+    stream: Option<Stream>,
+}
+
+struct Stream {
+    salt: [u8; SALT_LEN],
+    key: LessSafeKey,
+    window: ReplayWindow,
+}
+
+impl Opener {
+    pub fn new(psk: &Key) -> Self {
+        Self {
+            psk: psk.clone(),
+            stream: None,
+        }
+    }
+
+    /// Authenticates and decrypts `datagram` in place, returning the
+    /// length of the plain datagram at its start.
+    pub fn open(&mut self, datagram: &mut [u8]) -> Result<usize, SealError> {
+        let len = datagram.len();
+        if len < HEADER_LEN + SEAL_OVERHEAD {
+            return Err(SealError::Truncated(len));
+        }
+        if !is_sealed(datagram) {
+            return Err(SealError::NotEncrypted);
+        }
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(&datagram[len - SALT_LEN - 8..len - 8]);
+        let counter = read_u64(&datagram[len - 8..]);
+        let aad = aad(&datagram[..HEADER_LEN], &salt, counter);
+        let sealed = &mut datagram[HEADER_LEN..len - SALT_LEN - 8];
+        match &mut self.stream {
+            Some(stream) if stream.salt == salt => {
+                if !stream.window.check(counter) {
+                    return Err(SealError::Replayed(counter));
+                }
+                stream
+                    .key
+                    .open_in_place(nonce(counter), Aad::from(&aad[..]), sealed)
+                    .map_err(|_| SealError::Authentication)?;
+                stream.window.accept(counter);
+            }
+            stream => {
+                if let Some(stream) = stream {
+                    if started(&salt) < started(&stream.salt) {
+                        return Err(SealError::Stale);
+                    }
+                }
+                let key = self.psk.derive(&salt);
+                key.open_in_place(nonce(counter), Aad::from(&aad[..]), sealed)
+                    .map_err(|_| SealError::Authentication)?;
+                let mut window = ReplayWindow::new();
+                window.accept(counter);
+                *stream = Some(Stream { salt, key, window });
+            }
+        }
+        datagram[3] &= !FLAG_ENCRYPTED;
+        Ok(len - SEAL_OVERHEAD)
+    }
+}
+
+/// Tracks which of the most recent datagrams of a stream were
+/// received, as described by RFC 4303 section 3.4.3.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    /// One past the newest counter accepted.
+    top: u64,
+    /// Bit `i` is set if `top - 1 - i` was accepted.
+    seen: u128,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns false if `counter` must be dropped.
+    fn check(&self, counter: u64) -> bool {
+        if counter >= self.top {
+            return true;
+        }
+        let age = self.top - 1 - counter;
+        age < REPLAY_WINDOW && self.seen & (1 << age) == 0
+    }
+
+    fn accept(&mut self, counter: u64) {
+        if counter >= self.top {
+            let shift = counter + 1 - self.top;
+            self.seen = if shift < REPLAY_WINDOW {
+                self.seen << shift
+            } else {
+                0
+            };
+            self.seen |= 1;
+            self.top = counter + 1;
+        } else {
+            self.seen |= 1 << (self.top - 1 - counter);
+        }
+    }
+}
+
+fn aad(header: &[u8], salt: &[u8; SALT_LEN], counter: u64) -> [u8; HEADER_LEN + SALT_LEN + 8] {
+    let mut aad = [0u8; HEADER_LEN + SALT_LEN + 8];
+    aad[..HEADER_LEN].copy_from_slice(header);
+    aad[HEADER_LEN..HEADER_LEN + SALT_LEN].copy_from_slice(salt);
+    aad[HEADER_LEN + SALT_LEN..].copy_from_slice(&counter.to_be_bytes());
+    aad
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// Unix time a stream started, as recorded in its salt.
+fn started(salt: &[u8; SALT_LEN]) -> u32 {
+    u32::from_be_bytes([salt[0], salt[1], salt[2], salt[3]])
+}
+
+fn unix_time() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+pub(crate) fn random<T: ring::rand::RandomlyConstructable>() -> T {
+    ring::rand::generate(&ring::rand::SystemRandom::new())
+        .expect("system random number generator failed")
+        .expose()
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(b);
+    u64::from_be_bytes(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::stream::header::{Header, SampleFormat, StreamFormat};
+
+    fn datagram(sequence: u32) -> Vec<u8> {
+        let hdr = Header {
+            flags: 0,
+            stream_id: 1,
+            sequence,
+            timestamp: sequence as u64 * 4,
+            format: StreamFormat::new(SampleFormat::I16, 1, 48_000),
+            codec: Codec::Pcm,
+            frames: 4,
+        };
+        let mut buf = vec![sequence as u8; HEADER_LEN + hdr.payload_len()];
+        hdr.write(&mut buf[..]);
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = Key::generate();
+        let mut sealer = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        let plain = datagram(7);
+        let mut sealed = sealer.seal(&plain).to_vec();
+        assert_eq!(sealed.len(), plain.len() + SEAL_OVERHEAD);
+        assert_eq!(sealed[..3], plain[..3]);
+        assert_ne!(sealed[HEADER_LEN..plain.len()], plain[HEADER_LEN..]);
+        let len = opener.open(&mut sealed[..]).unwrap();
+        assert_eq!(sealed[..len], plain[..]);
+        Header::parse(&sealed[..len]).unwrap();
+    }
+
+    #[test]
+    fn rejects_forgeries() {
+        let key = Key::generate();
+        let mut sealer = Sealer::new(&key);
+        let sealed = sealer.seal(&datagram(0)).to_vec();
+        // Wrong key.
+        let mut copy = sealed.clone();
+        assert_eq!(
+            Opener::new(&Key::generate()).open(&mut copy[..]),
+            Err(SealError::Authentication)
+        );
+        // Any byte changed, header and trailer included.
+        for i in 0..sealed.len() {
+            let mut copy = sealed.clone();
+            copy[i] ^= 1;
+            assert_eq!(
+                Opener::new(&key).open(&mut copy[..]),
+                Err(SealError::Authentication),
+                "byte {}",
+                i
+            );
+        }
+        let mut plain = datagram(0);
+        plain.resize(plain.len() + SEAL_OVERHEAD, 0);
+        assert_eq!(
+            Opener::new(&key).open(&mut plain[..]),
+            Err(SealError::NotEncrypted)
+        );
+        assert_eq!(
+            Opener::new(&key).open(&mut [0u8; 40][..]),
+            Err(SealError::Truncated(40))
+        );
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let key = Key::generate();
+        let mut sealer = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        let sealed = (0..200)
+            .map(|i| sealer.seal(&datagram(i)).to_vec())
+            .collect::<Vec<_>>();
+        let mut open = |i: usize| opener.open(&mut sealed[i].clone()[..]).map(|_| ());
+        assert_eq!(open(0), Ok(()));
+        assert_eq!(open(0), Err(SealError::Replayed(0)));
+        assert_eq!(open(150), Ok(()));
+        // Reordered within the window.
+        assert_eq!(open(100), Ok(()));
+        assert_eq!(open(30), Ok(()));
+        assert_eq!(open(100), Err(SealError::Replayed(100)));
+        // Too old to tell.
+        assert_eq!(open(5), Err(SealError::Replayed(5)));
+        assert_eq!(open(151), Ok(()));
+    }
+
+    #[test]
+    fn follows_newer_streams() {
+        let key = Key::generate();
+        let mut old = Sealer::new(&key);
+        // Pretend the first stream started a minute ago.
+        old.salt[..4].copy_from_slice(&(unix_time() - 60).to_be_bytes());
+        old.key = key.derive(&old.salt);
+        let mut new = Sealer::new(&key);
+        let mut opener = Opener::new(&key);
+        let mut first = old.seal(&datagram(0)).to_vec();
+        let mut second = old.seal(&datagram(1)).to_vec();
+        assert!(opener.open(&mut first[..]).is_ok());
+        assert!(opener
+            .open(&mut new.seal(&datagram(0)).to_vec()[..])
+            .is_ok());
+        assert_eq!(opener.open(&mut second[..]), Err(SealError::Stale));
+    }
+
+    #[test]
+    fn parses_key() {
+        let key = Key::generate();
+        assert_eq!(key.to_string().parse::<Key>().unwrap(), key);
+        assert_eq!(
+            format!(" {}\n", key).parse::<Key>().unwrap(),
+            key,
+            "surrounding whitespace is ignored"
+        );
+        assert_eq!("abc".parse::<Key>(), Err(KeyError::Length(3)));
+        assert_eq!(
+            format!("g{}", &key.to_string()[1..]).parse::<Key>(),
+            Err(KeyError::InvalidDigit('g'))
+        );
+        assert_eq!(format!("{:?}", key), "Key(..)");
+    }
+}
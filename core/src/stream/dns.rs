@@ -0,0 +1,389 @@
+//! The subset of the DNS message format (RFC 1035) that multicast DNS
+//! service discovery needs (see [`super::mdns`]): questions, and
+//! A, AAAA, PTR, SRV and TXT records. Names are written without
+//! compression, and compressed names are followed when reading.
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Top bit of the class: unicast response wanted in questions,
+/// cache flush in records (RFC 6762).
+const CLASS_FLAG: u16 = 1 << 15;
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_AUTHORITATIVE: u16 = 1 << 10;
+const HEADER_LEN: usize = 12;
+/// Longest chain of compression pointers followed in one name.
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    /// The message ends in the middle of something.
+    Truncated,
+    /// A name has a label that's too long, a loop of compression
+    /// pointers or isn't UTF-8.
+    InvalidName,
+}
+
+impl std::fmt::Display for DnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnsError::Truncated => write!(f, "message truncated"),
+            DnsError::InvalidName => write!(f, "invalid name"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub rtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    /// `key=value` strings.
+    Txt(Vec<String>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// A record of a type that isn't needed, by type.
+    Other(u16),
+}
+
+impl RData {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => TYPE_A,
+            RData::Aaaa(_) => TYPE_AAAA,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Txt(_) => TYPE_TXT,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Other(rtype) => *rtype,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    /// Seconds the record may be cached for. Zero withdraws it.
+    pub ttl: u32,
+    /// Replaces, rather than adds to, cached records of the same name
+    /// and type.
+    pub flush: bool,
+    pub data: RData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub response: bool,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    /// Records the responder expects to be useful alongside the
+    /// answers, e.g. the address of an SRV target.
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        let flags = if self.response {
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE
+        } else {
+            0
+        };
+        for v in &[
+            self.id,
+            flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            0,
+            self.additionals.len() as u16,
+        ] {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        for question in &self.questions {
+            write_name(&mut buf, &question.name);
+            buf.extend_from_slice(&question.rtype.to_be_bytes());
+            buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(self.additionals.iter()) {
+            write_record(&mut buf, record);
+        }
+        buf
+    }
+
+    /// Decodes a message. Records in the authority section, which
+    /// carry probes' proposed records, are skipped.
+    pub fn parse(buf: &[u8]) -> Result<Self, DnsError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DnsError::Truncated);
+        }
+        let read_u16 = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let mut msg = Message {
+            id: read_u16(0),
+            response: read_u16(2) & FLAG_RESPONSE != 0,
+            ..Default::default()
+        };
+        let counts = [read_u16(4), read_u16(6), read_u16(8), read_u16(10)];
+        let mut pos = HEADER_LEN;
+        for _ in 0..counts[0] {
+            let name = read_name(buf, &mut pos)?;
+            let rtype = read_u16_at(buf, pos)?;
+            pos += 4;
+            msg.questions.push(Question { name, rtype });
+        }
+        for _ in 0..counts[1] {
+            msg.answers.push(read_record(buf, &mut pos)?);
+        }
+        for _ in 0..counts[2] {
+            read_record(buf, &mut pos)?;
+        }
+        for _ in 0..counts[3] {
+            msg.additionals.push(read_record(buf, &mut pos)?);
+        }
+        Ok(msg)
+    }
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        // Labels are at most 63 bytes.
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &Record) {
+    write_name(buf, &record.name);
+    buf.extend_from_slice(&record.data.rtype().to_be_bytes());
+    let class = if record.flush {
+        CLASS_IN | CLASS_FLAG
+    } else {
+        CLASS_IN
+    };
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&record.ttl.to_be_bytes());
+    let len_pos = buf.len();
+    buf.extend_from_slice(&[0, 0]);
+    match &record.data {
+        RData::A(ip) => buf.extend_from_slice(&ip.octets()),
+        RData::Aaaa(ip) => buf.extend_from_slice(&ip.octets()),
+        RData::Ptr(name) => write_name(buf, name),
+        RData::Txt(strings) => {
+            for s in strings {
+                let s = &s.as_bytes()[..s.len().min(255)];
+                buf.push(s.len() as u8);
+                buf.extend_from_slice(s);
+            }
+            // An empty TXT record still holds one empty string.
+            if strings.is_empty() {
+                buf.push(0);
+            }
+        }
+        RData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        } => {
+            buf.extend_from_slice(&priority.to_be_bytes());
+            buf.extend_from_slice(&weight.to_be_bytes());
+            buf.extend_from_slice(&port.to_be_bytes());
+            write_name(buf, target);
+        }
+        RData::Other(_) => {}
+    }
+    let len = (buf.len() - len_pos - 2) as u16;
+    buf[len_pos..len_pos + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+fn read_u16_at(buf: &[u8], pos: usize) -> Result<u16, DnsError> {
+    match buf.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err(DnsError::Truncated),
+    }
+}
+
+/// Reads the name at `pos`, leaving `pos` just past it.
+fn read_name(buf: &[u8], pos: &mut usize) -> Result<String, DnsError> {
+    let mut labels: Vec<&str> = Vec::new();
+    let mut cursor = *pos;
+    let mut pointers = 0;
+    loop {
+        let len = *buf.get(cursor).ok_or(DnsError::Truncated)? as usize;
+        match len {
+            0 => {
+                if pointers == 0 {
+                    *pos = cursor + 1;
+                }
+                return Ok(labels.join("."));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let offset = read_u16_at(buf, cursor)? as usize & 0x3fff;
+                if pointers == 0 {
+                    *pos = cursor + 2;
+                }
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(DnsError::InvalidName);
+                }
+                cursor = offset;
+            }
+            len if len > 63 => return Err(DnsError::InvalidName),
+            len => {
+                let label = buf
+                    .get(cursor + 1..cursor + 1 + len)
+                    .ok_or(DnsError::Truncated)?;
+                labels.push(std::str::from_utf8(label).map_err(|_| DnsError::InvalidName)?);
+                cursor += 1 + len;
+            }
+        }
+    }
+}
+
+fn read_record(buf: &[u8], pos: &mut usize) -> Result<Record, DnsError> {
+    let name = read_name(buf, pos)?;
+    let rtype = read_u16_at(buf, *pos)?;
+    let class = read_u16_at(buf, *pos + 2)?;
+    let ttl = (read_u16_at(buf, *pos + 4)? as u32) << 16 | read_u16_at(buf, *pos + 6)? as u32;
+    let len = read_u16_at(buf, *pos + 8)? as usize;
+    let start = *pos + 10;
+    let end = start + len;
+    let rdata = buf.get(start..end).ok_or(DnsError::Truncated)?;
+    let data = match rtype {
+        TYPE_A if len == 4 => RData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+        TYPE_AAAA if len == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(rdata);
+            RData::Aaaa(Ipv6Addr::from(octets))
+        }
+        TYPE_PTR => RData::Ptr(read_name(buf, &mut start.clone())?),
+        TYPE_TXT => {
+            let mut strings = Vec::new();
+            let mut i = 0;
+            while i < rdata.len() {
+                let n = rdata[i] as usize;
+                let s = rdata.get(i + 1..i + 1 + n).ok_or(DnsError::Truncated)?;
+                if n > 0 {
+                    strings.push(String::from_utf8_lossy(s).into_owned());
+                }
+                i += 1 + n;
+            }
+            RData::Txt(strings)
+        }
+        TYPE_SRV if len >= 7 => RData::Srv {
+            priority: read_u16_at(buf, start)?,
+            weight: read_u16_at(buf, start + 2)?,
+            port: read_u16_at(buf, start + 4)?,
+            target: read_name(buf, &mut (start + 6))?,
+        },
+        rtype => RData::Other(rtype),
+    };
+    *pos = end;
+    Ok(Record {
+        name,
+        ttl,
+        flush: class & CLASS_FLAG != 0,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let msg = Message {
+            id: 0,
+            response: true,
+            questions: vec![Question {
+                name: String::from("_paradise._udp.local"),
+                rtype: TYPE_PTR,
+            }],
+            answers: vec![Record {
+                name: String::from("_paradise._udp.local"),
+                ttl: 4500,
+                flush: false,
+                data: RData::Ptr(String::from("studio-b-rack._paradise._udp.local")),
+            }],
+            additionals: vec![
+                Record {
+                    name: String::from("studio-b-rack._paradise._udp.local"),
+                    ttl: 120,
+                    flush: true,
+                    data: RData::Srv {
+                        priority: 0,
+                        weight: 0,
+                        port: 20000,
+                        target: String::from("studio-b-rack.local"),
+                    },
+                },
+                Record {
+                    name: String::from("studio-b-rack._paradise._udp.local"),
+                    ttl: 4500,
+                    flush: true,
+                    data: RData::Txt(vec![String::from("channels=2"), String::from("rate=48000")]),
+                },
+                Record {
+                    name: String::from("studio-b-rack.local"),
+                    ttl: 120,
+                    flush: true,
+                    data: RData::A(Ipv4Addr::new(192, 168, 1, 20)),
+                },
+                Record {
+                    name: String::from("studio-b-rack.local"),
+                    ttl: 120,
+                    flush: true,
+                    data: RData::Aaaa("fe80::1".parse().unwrap()),
+                },
+            ],
+        };
+        assert_eq!(Message::parse(&msg.to_bytes()).unwrap(), msg);
+    }
+
+    #[test]
+    fn follows_compressed_names() {
+        let mut buf = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // _paradise._udp.local PTR studio._paradise._udp.local, the
+        // latter pointing back into the former.
+        write_name(&mut buf, "_paradise._udp.local");
+        buf.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 9]);
+        buf.extend_from_slice(&[6, b's', b't', b'u', b'd', b'i', b'o', 0xc0, 12]);
+        // A record for a name that is entirely a pointer.
+        buf.extend_from_slice(&[0xc0, 12, 0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 10, 0, 0, 1]);
+        let msg = Message::parse(&buf).unwrap();
+        assert!(msg.response);
+        assert_eq!(
+            msg.answers[0].data,
+            RData::Ptr(String::from("studio._paradise._udp.local"))
+        );
+        assert_eq!(msg.answers[1].name, "_paradise._udp.local");
+        assert!(msg.answers[1].flush);
+        assert_eq!(msg.answers[1].data, RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        // A pointer to itself.
+        let mut looped = buf[..12].to_vec();
+        looped[7] = 1;
+        looped.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Message::parse(&looped), Err(DnsError::InvalidName));
+        assert_eq!(Message::parse(&buf[..40]), Err(DnsError::Truncated));
+    }
+}
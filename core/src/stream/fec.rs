@@ -0,0 +1,789 @@
+//! Forward error correction for datagram transports.
+//!
+//! The sender splits its packets into groups of `group` consecutive
+//! sequence numbers and follows each group with `parity` parity
+//! packets. A receiver can rebuild up to `parity` packets lost from
+//! a group, trading bandwidth for fewer dropouts on lossy links.
+//!
+//! Whole datagrams, header included, are protected. Before coding,
+//! each one is prefixed with its length and zero-padded to the size
+//! of the largest block in the group:
+//!
+//! ```text
+//!  0       2                               2 + n           L
+//!  +-------+-------------------------------+---------------+
+//!  |   n   |       datagram (n bytes)      |    padding    |
+//!  +-------+-------------------------------+---------------+
+//! ```
+//!
+//! A parity packet is a regular header with [`FLAG_PARITY`] set and
+//! the sequence number of the first packet in its group, followed by
+//!
+//! ```text
+//!  0       1       2       3       4
+//!  +-------+-------+-------+-------+---------------------------
+//!  |scheme | group |parity | index |  coded block (L bytes) ...
+//!  +-------+-------+-------+-------+---------------------------
+//! ```
+//!
+//! With [`FecScheme::Xor`] the single parity block is the XOR of the
+//! group's blocks. [`FecScheme::ReedSolomon`] uses a systematic Cauchy
+//! Reed-Solomon code over GF(2^8), which recovers any combination of
+//! up to `parity` losses within a group.
+use crate::stream::header::{Header, FLAG_PARITY, HEADER_LEN};
+
+/// Bytes a parity packet's payload adds on top of the largest
+/// datagram it protects.
+pub const PARITY_OVERHEAD: usize = 6;
+
+/// Number of recent packets a decoder retains for reconstruction.
+const WINDOW: usize = 512;
+
+/// Number of groups a decoder tracks at once.
+const MAX_GROUPS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FecScheme {
+    /// One parity packet per group. Repairs a single loss.
+    Xor,
+    /// Any number of parity packets per group. Repairs as many
+    /// losses as there are parity packets, at a higher CPU cost.
+    ReedSolomon,
+}
+
+impl FecScheme {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(FecScheme::Xor),
+            2 => Some(FecScheme::ReedSolomon),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            FecScheme::Xor => 1,
+            FecScheme::ReedSolomon => 2,
+        }
+    }
+}
+
+/// Per-destination FEC settings, e.g.
+///
+/// ```yaml
+/// fec:
+///   scheme: reedSolomon
+///   group: 10
+///   parity: 2
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FecConfig {
+    pub scheme: FecScheme,
+    /// Number of audio packets protected by each group.
+    pub group: u8,
+    /// Number of parity packets sent after each group.
+    #[serde(default = "default_parity")]
+    pub parity: u8,
+}
+
+fn default_parity() -> u8 {
+    1
+}
+
+impl FecConfig {
+    pub fn validate(&self) -> Result<(), FecError> {
+        if self.group == 0 {
+            return Err(FecError::EmptyGroup);
+        }
+        let valid = match self.scheme {
+            FecScheme::Xor => self.parity == 1,
+            // Cauchy matrix rows and columns need distinct field
+            // elements, so the whole group must fit in GF(2^8).
+            FecScheme::ReedSolomon => {
+                self.parity > 0 && self.group as usize + self.parity as usize <= 255
+            }
+        };
+        if !valid {
+            return Err(FecError::ParityCount {
+                scheme: self.scheme,
+                group: self.group,
+                parity: self.parity,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FecError {
+    EmptyGroup,
+    /// The scheme cannot produce this many parity packets.
+    ParityCount {
+        scheme: FecScheme,
+        group: u8,
+        parity: u8,
+    },
+    /// The parity payload is smaller than its own header.
+    Truncated(usize),
+    UnknownScheme(u8),
+    ParityIndex {
+        index: u8,
+        parity: u8,
+    },
+    /// A parity packet disagrees with earlier ones for the same group.
+    GroupMismatch(u32),
+}
+
+impl std::fmt::Display for FecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FecError::EmptyGroup => write!(f, "fec group must contain at least one packet"),
+            FecError::ParityCount {
+                scheme,
+                group,
+                parity,
+            } => write!(
+                f,
+                "{:?} fec cannot send {} parity packets per group of {}",
+                scheme, parity, group
+            ),
+            FecError::Truncated(len) => write!(
+                f,
+                "parity payload too short ({} < {} bytes)",
+                len, PARITY_OVERHEAD
+            ),
+            FecError::UnknownScheme(v) => write!(f, "unknown fec scheme {}", v),
+            FecError::ParityIndex { index, parity } => write!(
+                f,
+                "parity index {} out of range for {} parity packets",
+                index, parity
+            ),
+            FecError::GroupMismatch(base) => write!(
+                f,
+                "inconsistent parity packets for group starting at {}",
+                base
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FecError {}
+
+/// Produces parity packets for an outgoing stream.
+pub struct FecEncoder {
+    config: FecConfig,
+    /// Length-prefixed copies of the packets in the current group.
+    blocks: Vec<Vec<u8>>,
+    len: usize,
+    /// Header of the first packet in the current group.
+    first: Option<Header>,
+    out: Vec<u8>,
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig) -> Result<Self, FecError> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            blocks: vec![Vec::new(); config.group as usize],
+            len: 0,
+            first: None,
+            out: Vec::new(),
+        })
+    }
+
+    pub fn config(&self) -> FecConfig {
+        self.config
+    }
+
+    /// Adds an outgoing datagram, described by `hdr`, to the current
+    /// group. Once the group is complete its parity packets are
+    /// passed to `emit`, which should send them like any other packet.
+    pub fn push<F>(&mut self, hdr: &Header, packet: &[u8], mut emit: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        if let Some(first) = &self.first {
+            // Groups cover consecutive packets of a single stream.
+            let next = first.sequence.wrapping_add(self.len as u32);
+            if first.stream_id != hdr.stream_id || hdr.sequence != next {
+                self.len = 0;
+                self.first = None;
+            }
+        }
+        if self.first.is_none() {
+            self.first = Some(*hdr);
+        }
+        let block = &mut self.blocks[self.len];
+        block.clear();
+        block.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        block.extend_from_slice(packet);
+        self.len += 1;
+        if self.len < self.config.group as usize {
+            return;
+        }
+        let first = self.first.take().unwrap();
+        self.len = 0;
+        let block_len = self.blocks.iter().map(|b| b.len()).max().unwrap_or(0);
+        let parity_hdr = Header {
+            flags: FLAG_PARITY,
+            frames: 0,
+            ..first
+        };
+        for row in 0..self.config.parity {
+            self.out.clear();
+            self.out.resize(HEADER_LEN + 4 + block_len, 0);
+            parity_hdr.write(&mut self.out[..]);
+            self.out[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&[
+                self.config.scheme.as_u8(),
+                self.config.group,
+                self.config.parity,
+                row,
+            ]);
+            let coded = &mut self.out[HEADER_LEN + 4..];
+            for (col, block) in self.blocks.iter().enumerate() {
+                let c = coefficient(&self.config, row, col as u8);
+                gf::mul_add(coded, block, c);
+            }
+            emit(&self.out[..]);
+        }
+    }
+}
+
+struct Group {
+    config: FecConfig,
+    /// Received parity blocks, indexed by parity row.
+    blocks: Vec<Option<Vec<u8>>>,
+    /// Every packet in the group is accounted for.
+    done: bool,
+}
+
+/// Rebuilds lost packets of an incoming stream from its parity
+/// packets. Recent packets are retained regardless of whether the
+/// sender uses FEC, so that the first group can be repaired too.
+pub struct FecDecoder {
+    stream_id: Option<u32>,
+    packets: std::collections::HashMap<u32, Vec<u8>>,
+    order: std::collections::VecDeque<u32>,
+    groups: std::collections::HashMap<u32, Group>,
+    group_order: std::collections::VecDeque<u32>,
+    recovered: u64,
+}
+
+impl Default for FecDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self {
+            stream_id: None,
+            packets: std::collections::HashMap::new(),
+            order: std::collections::VecDeque::with_capacity(WINDOW),
+            groups: std::collections::HashMap::new(),
+            group_order: std::collections::VecDeque::with_capacity(MAX_GROUPS),
+            recovered: 0,
+        }
+    }
+
+    /// Number of packets rebuilt from parity.
+    pub fn recovered(&self) -> u64 {
+        self.recovered
+    }
+
+    /// Records a received audio datagram. Any packets it allows to be
+    /// rebuilt are passed to `emit`.
+    pub fn data<F>(&mut self, hdr: &Header, packet: &[u8], mut emit: F)
+    where
+        F: FnMut(&[u8]),
+    {
+        if self.stream_id != Some(hdr.stream_id) {
+            self.reset();
+            self.stream_id = Some(hdr.stream_id);
+        }
+        if !self.store(hdr.sequence, packet) {
+            return;
+        }
+        // A late packet can complete a group whose parity is waiting.
+        for i in 0..self.group_order.len() {
+            let base = self.group_order[i];
+            let size = self.groups[&base].config.group as u32;
+            if hdr.sequence.wrapping_sub(base) < size {
+                self.recover(base, &mut emit);
+            }
+        }
+    }
+
+    /// Records a received parity packet. Any packets it allows to be
+    /// rebuilt are passed to `emit`.
+    pub fn parity<F>(&mut self, hdr: &Header, payload: &[u8], mut emit: F) -> Result<(), FecError>
+    where
+        F: FnMut(&[u8]),
+    {
+        if payload.len() < PARITY_OVERHEAD {
+            return Err(FecError::Truncated(payload.len()));
+        }
+        let scheme = FecScheme::from_u8(payload[0]).ok_or(FecError::UnknownScheme(payload[0]))?;
+        let config = FecConfig {
+            scheme,
+            group: payload[1],
+            parity: payload[2],
+        };
+        config.validate()?;
+        let index = payload[3];
+        if index >= config.parity {
+            return Err(FecError::ParityIndex {
+                index,
+                parity: config.parity,
+            });
+        }
+        let block = &payload[4..];
+        if self.stream_id != Some(hdr.stream_id) {
+            self.reset();
+            self.stream_id = Some(hdr.stream_id);
+        }
+        let base = hdr.sequence;
+        if !self.groups.contains_key(&base) {
+            if self.group_order.len() == MAX_GROUPS {
+                if let Some(oldest) = self.group_order.pop_front() {
+                    self.groups.remove(&oldest);
+                }
+            }
+            self.groups.insert(
+                base,
+                Group {
+                    config,
+                    blocks: vec![None; config.parity as usize],
+                    done: false,
+                },
+            );
+            self.group_order.push_back(base);
+        }
+        let group = self.groups.get_mut(&base).unwrap();
+        if group.config != config
+            || group
+                .blocks
+                .iter()
+                .flatten()
+                .any(|b| b.len() != block.len())
+        {
+            return Err(FecError::GroupMismatch(base));
+        }
+        if group.done || group.blocks[index as usize].is_some() {
+            return Ok(());
+        }
+        group.blocks[index as usize] = Some(block.to_vec());
+        self.recover(base, &mut emit);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.stream_id = None;
+        self.packets.clear();
+        self.order.clear();
+        self.groups.clear();
+        self.group_order.clear();
+    }
+
+    /// Retains a packet for reconstructing its neighbours. Returns
+    /// false if it was already present.
+    fn store(&mut self, sequence: u32, packet: &[u8]) -> bool {
+        if self.packets.contains_key(&sequence) {
+            return false;
+        }
+        let mut copy = Vec::new();
+        if self.order.len() == WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                // Reuse the evicted allocation.
+                copy = self.packets.remove(&oldest).unwrap_or_default();
+            }
+        }
+        copy.clear();
+        copy.extend_from_slice(packet);
+        self.packets.insert(sequence, copy);
+        self.order.push_back(sequence);
+        true
+    }
+
+    fn recover<F>(&mut self, base: u32, emit: &mut F)
+    where
+        F: FnMut(&[u8]),
+    {
+        let group = match self.groups.get_mut(&base) {
+            Some(group) if !group.done => group,
+            _ => return,
+        };
+        let config = group.config;
+        let packets = &self.packets;
+        let missing: Vec<u8> = (0..config.group)
+            .filter(|col| !packets.contains_key(&base.wrapping_add(*col as u32)))
+            .collect();
+        if missing.is_empty() {
+            group.done = true;
+            return;
+        }
+        let rows: Vec<u8> = (0..config.parity)
+            .filter(|row| group.blocks[*row as usize].is_some())
+            .take(missing.len())
+            .collect();
+        if rows.len() < missing.len() {
+            return;
+        }
+        // Whatever happens next, this group has nothing more to offer.
+        group.done = true;
+        // Remove the contribution of the packets that did arrive,
+        // leaving a system of equations in the missing ones.
+        let mut syndromes: Vec<Vec<u8>> = rows
+            .iter()
+            .map(|row| group.blocks[*row as usize].clone().unwrap())
+            .collect();
+        let block_len = syndromes[0].len();
+        let mut block = Vec::with_capacity(block_len);
+        for col in (0..config.group).filter(|col| !missing.contains(col)) {
+            let packet = &packets[&base.wrapping_add(col as u32)];
+            if 2 + packet.len() > block_len {
+                // Not the packet this parity was computed over.
+                return;
+            }
+            block.clear();
+            block.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            block.extend_from_slice(packet);
+            for (syndrome, row) in syndromes.iter_mut().zip(&rows) {
+                gf::mul_add(syndrome, &block, coefficient(&config, *row, col));
+            }
+        }
+        let mut matrix: Vec<Vec<u8>> = rows
+            .iter()
+            .map(|row| {
+                missing
+                    .iter()
+                    .map(|col| coefficient(&config, *row, *col))
+                    .collect()
+            })
+            .collect();
+        if !gf::invert(&mut matrix) {
+            return;
+        }
+        for (i, col) in missing.iter().enumerate() {
+            block.clear();
+            block.resize(block_len, 0);
+            for (syndrome, c) in syndromes.iter().zip(&matrix[i]) {
+                gf::mul_add(&mut block, syndrome, *c);
+            }
+            let len = u16::from_be_bytes([block[0], block[1]]) as usize;
+            if 2 + len > block_len {
+                continue;
+            }
+            self.recovered += 1;
+            emit(&block[2..2 + len]);
+            let packet = block[2..2 + len].to_vec();
+            self.store(base.wrapping_add(*col as u32), &packet);
+        }
+    }
+}
+
+/// Weight of data block `col` in parity block `row`.
+fn coefficient(config: &FecConfig, row: u8, col: u8) -> u8 {
+    match config.scheme {
+        FecScheme::Xor => 1,
+        // Cauchy matrix 1 / (x_row + y_col) with x_row = group + row
+        // and y_col = col. Every square submatrix is invertible, so
+        // any `parity` losses can be solved for.
+        FecScheme::ReedSolomon => gf::inv((config.group + row) ^ col),
+    }
+}
+
+/// Arithmetic in GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1.
+mod gf {
+    const POLY: u16 = 0x11d;
+
+    const fn tables() -> ([u8; 512], [u8; 256]) {
+        let mut exp = [0u8; 512];
+        let mut log = [0u8; 256];
+        let mut x: u16 = 1;
+        let mut i = 0;
+        while i < 255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= POLY;
+            }
+            i += 1;
+        }
+        (exp, log)
+    }
+
+    const TABLES: ([u8; 512], [u8; 256]) = tables();
+    static EXP: [u8; 512] = TABLES.0;
+    static LOG: [u8; 256] = TABLES.1;
+
+    pub fn mul(a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            return 0;
+        }
+        EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+    }
+
+    pub fn inv(a: u8) -> u8 {
+        debug_assert!(a != 0);
+        EXP[255 - LOG[a as usize] as usize]
+    }
+
+    /// dst += c * src, where `src` is implicitly zero-padded to the
+    /// length of `dst`.
+    pub fn mul_add(dst: &mut [u8], src: &[u8], c: u8) {
+        match c {
+            0 => {}
+            1 => dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= *s),
+            _ => dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= mul(c, *s)),
+        }
+    }
+
+    /// Inverts a square matrix in place by Gauss-Jordan elimination.
+    /// Returns false if it is singular.
+    pub fn invert(m: &mut [Vec<u8>]) -> bool {
+        let n = m.len();
+        let mut inv: Vec<Vec<u8>> = (0..n)
+            .map(|i| (0..n).map(|j| (i == j) as u8).collect())
+            .collect();
+        for col in 0..n {
+            let pivot = match (col..n).find(|row| m[*row][col] != 0) {
+                Some(row) => row,
+                None => return false,
+            };
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = self::inv(m[col][col]);
+            for j in 0..n {
+                m[col][j] = mul(m[col][j], scale);
+                inv[col][j] = mul(inv[col][j], scale);
+            }
+            for row in 0..n {
+                let factor = m[row][col];
+                if row == col || factor == 0 {
+                    continue;
+                }
+                for j in 0..n {
+                    m[row][j] ^= mul(factor, m[col][j]);
+                    inv[row][j] ^= mul(factor, inv[col][j]);
+                }
+            }
+        }
+        m.iter_mut().zip(inv).for_each(|(row, inv)| *row = inv);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::stream::header::{SampleFormat, StreamFormat};
+
+    fn packet(sequence: u32, frames: u16) -> (Header, Vec<u8>) {
+        let hdr = Header {
+            flags: 0,
+            stream_id: 42,
+            sequence,
+            timestamp: sequence as u64 * 64,
+            format: StreamFormat::new(SampleFormat::I16, 1, 48_000),
+            codec: Codec::Pcm,
+            frames,
+        };
+        let mut buf = vec![0u8; HEADER_LEN + hdr.payload_len()];
+        hdr.write(&mut buf[..]);
+        for (i, b) in buf[HEADER_LEN..].iter_mut().enumerate() {
+            *b = (sequence as usize * 31 + i * 7) as u8;
+        }
+        (hdr, buf)
+    }
+
+    /// Encodes `count` packets of varying size starting at `first`,
+    /// returning the data and parity datagrams.
+    fn encode(config: FecConfig, first: u32, count: u32) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut encoder = FecEncoder::new(config).unwrap();
+        let mut data = Vec::new();
+        let mut parity = Vec::new();
+        for seq in (0..count).map(|i| first.wrapping_add(i)) {
+            let (hdr, buf) = packet(seq, 8 + (seq % 5) as u16 * 3);
+            encoder.push(&hdr, &buf[..], |p| parity.push(p.to_vec()));
+            data.push(buf);
+        }
+        (data, parity)
+    }
+
+    /// Feeds every packet except the lost ones through a decoder in
+    /// the order they were sent, returning the packets it rebuilt.
+    fn decode(
+        config: FecConfig,
+        data: &[Vec<u8>],
+        parity: &[Vec<u8>],
+        lost: &[usize],
+    ) -> Vec<Vec<u8>> {
+        let mut decoder = FecDecoder::new();
+        let mut out = Vec::new();
+        let group = config.group as usize;
+        for (g, chunk) in data.chunks(group).enumerate() {
+            for (i, d) in chunk.iter().enumerate() {
+                if lost.contains(&(g * group + i)) {
+                    continue;
+                }
+                let (hdr, _) = Header::parse(&d[..]).unwrap();
+                decoder.data(&hdr, &d[..], |p| out.push(p.to_vec()));
+            }
+            let sent = config.parity as usize;
+            for p in parity.iter().skip(g * sent).take(sent) {
+                let (hdr, payload) = Header::parse(&p[..]).unwrap();
+                assert!(hdr.is_parity());
+                decoder
+                    .parity(&hdr, payload, |p| out.push(p.to_vec()))
+                    .unwrap();
+            }
+        }
+        assert_eq!(decoder.recovered(), out.len() as u64);
+        out
+    }
+
+    fn xor(group: u8) -> FecConfig {
+        FecConfig {
+            scheme: FecScheme::Xor,
+            group,
+            parity: 1,
+        }
+    }
+
+    fn rs(group: u8, parity: u8) -> FecConfig {
+        FecConfig {
+            scheme: FecScheme::ReedSolomon,
+            group,
+            parity,
+        }
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(xor(4).validate().is_ok());
+        assert!(rs(200, 55).validate().is_ok());
+        assert_eq!(xor(0).validate(), Err(FecError::EmptyGroup));
+        assert!(rs(4, 0).validate().is_err());
+        assert!(rs(200, 56).validate().is_err());
+        assert!(FecConfig {
+            parity: 2,
+            ..xor(4)
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn sends_parity_per_group() {
+        let (data, parity) = encode(rs(4, 2), 0, 10);
+        assert_eq!(data.len(), 10);
+        // The trailing partial group is unprotected.
+        assert_eq!(parity.len(), 4);
+        let (hdr, payload) = Header::parse(&parity[2][..]).unwrap();
+        assert_eq!(hdr.sequence, 4);
+        assert_eq!(hdr.frames, 0);
+        assert_eq!(&payload[..4], &[2, 4, 2, 0]);
+        let largest = data[4..8].iter().map(|d| d.len()).max().unwrap();
+        assert_eq!(payload.len(), PARITY_OVERHEAD + largest);
+    }
+
+    #[test]
+    fn xor_recovers_single_loss() {
+        let config = xor(4);
+        let (data, parity) = encode(config, 0, 8);
+        for lost in 0..8 {
+            assert_eq!(
+                decode(config, &data, &parity, &[lost]),
+                vec![data[lost].clone()]
+            );
+        }
+        // Two losses in one group cannot be repaired.
+        assert!(decode(config, &data, &parity, &[1, 2]).is_empty());
+        // One loss in each group can.
+        assert_eq!(decode(config, &data, &parity, &[1, 6]).len(), 2);
+    }
+
+    #[test]
+    fn reed_solomon_recovers_any_losses() {
+        let config = rs(5, 2);
+        let (data, parity) = encode(config, 100, 5);
+        for a in 0..5 {
+            for b in a..5 {
+                let lost = if a == b { vec![a] } else { vec![a, b] };
+                let mut out = decode(config, &data, &parity, &lost);
+                out.sort_by_key(|p| Header::parse(&p[..]).unwrap().0.sequence);
+                let expected: Vec<Vec<u8>> = lost.iter().map(|i| data[*i].clone()).collect();
+                assert_eq!(out, expected);
+            }
+        }
+        assert!(decode(config, &data, &parity, &[0, 1, 2]).is_empty());
+        // Losing parity packets reduces how much can be repaired.
+        assert_eq!(
+            decode(config, &data, &parity[1..], &[3]),
+            vec![data[3].clone()]
+        );
+        assert!(decode(config, &data, &parity[1..], &[3, 4]).is_empty());
+    }
+
+    #[test]
+    fn recovers_when_parity_arrives_last() {
+        // The second group straddles the sequence number wrap.
+        let (data, parity) = encode(xor(3), u32::MAX - 2, 6);
+        let mut decoder = FecDecoder::new();
+        let mut out = Vec::new();
+        let feed = |decoder: &mut FecDecoder, p: &Vec<u8>, out: &mut Vec<Vec<u8>>| {
+            let (hdr, payload) = Header::parse(&p[..]).unwrap();
+            if hdr.is_parity() {
+                decoder
+                    .parity(&hdr, payload, |p| out.push(p.to_vec()))
+                    .unwrap();
+            } else {
+                decoder.data(&hdr, &p[..], |p| out.push(p.to_vec()));
+            }
+        };
+        for p in data[..3].iter().chain(&parity[..1]) {
+            feed(&mut decoder, p, &mut out);
+        }
+        feed(&mut decoder, &data[3], &mut out);
+        feed(&mut decoder, &data[5], &mut out);
+        assert!(out.is_empty());
+        feed(&mut decoder, &parity[1], &mut out);
+        assert_eq!(out, vec![data[4].clone()]);
+        // The original showing up late is not reported again.
+        feed(&mut decoder, &data[4], &mut out);
+        assert_eq!(out.len(), 1);
+    }
+
+    #[test]
+    fn rejects_malformed_parity() {
+        let (_, parity) = encode(xor(2), 0, 2);
+        let (hdr, payload) = Header::parse(&parity[0][..]).unwrap();
+        let mut decoder = FecDecoder::new();
+        assert_eq!(
+            decoder.parity(&hdr, &payload[..3], |_| {}),
+            Err(FecError::Truncated(3))
+        );
+        let mut bad = payload.to_vec();
+        bad[0] = 9;
+        assert_eq!(
+            decoder.parity(&hdr, &bad[..], |_| {}),
+            Err(FecError::UnknownScheme(9))
+        );
+        let mut bad = payload.to_vec();
+        bad[3] = 1;
+        assert_eq!(
+            decoder.parity(&hdr, &bad[..], |_| {}),
+            Err(FecError::ParityIndex {
+                index: 1,
+                parity: 1
+            })
+        );
+    }
+}
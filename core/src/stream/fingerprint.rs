@@ -0,0 +1,99 @@
+//! SHA-256 certificate fingerprints, used to pin the certificate of a
+//! peer instead of verifying it against a certificate authority.
+//!
+//! Fingerprints are written as 32 hex encoded bytes, optionally
+//! separated by colons, as printed by
+//! `openssl x509 -noout -fingerprint -sha256`.
+
+/// SHA-256 digest of a DER encoded certificate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint(pub [u8; 32]);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FingerprintError {
+    /// The fingerprint doesn't have 32 bytes.
+    Length(usize),
+    InvalidDigit(char),
+}
+
+impl std::fmt::Display for FingerprintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FingerprintError::Length(n) => {
+                write!(f, "fingerprint has {} hex digits, expected 64", n)
+            }
+            FingerprintError::InvalidDigit(c) => write!(f, "invalid hex digit '{}'", c),
+        }
+    }
+}
+
+impl std::error::Error for FingerprintError {}
+
+impl Fingerprint {
+    pub fn of(der: &[u8]) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, der);
+        let mut fingerprint = [0u8; 32];
+        fingerprint.copy_from_slice(digest.as_ref());
+        Fingerprint(fingerprint)
+    }
+}
+
+impl std::str::FromStr for Fingerprint {
+    type Err = FingerprintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s
+            .chars()
+            .filter(|&c| c != ':')
+            .map(|c| {
+                c.to_digit(16)
+                    .map(|d| d as u8)
+                    .ok_or(FingerprintError::InvalidDigit(c))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if digits.len() != 64 {
+            return Err(FingerprintError::Length(digits.len()));
+        }
+        let mut fingerprint = [0u8; 32];
+        for (b, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            *b = pair[0] << 4 | pair[1];
+        }
+        Ok(Fingerprint(fingerprint))
+    }
+}
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02X}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        // echo -n abc | sha256sum
+        let fingerprint = Fingerprint::of(b"abc");
+        let hex = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(hex.parse::<Fingerprint>().unwrap(), fingerprint);
+        let printed = fingerprint.to_string();
+        assert!(printed.starts_with("BA:78:16:BF:"));
+        assert_eq!(printed.parse::<Fingerprint>().unwrap(), fingerprint);
+        assert_eq!(
+            "abc".parse::<Fingerprint>(),
+            Err(FingerprintError::Length(3))
+        );
+        assert_eq!(
+            hex.replace('b', "x").parse::<Fingerprint>(),
+            Err(FingerprintError::InvalidDigit('x'))
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Length-delimited framing for stream oriented transports.
+//!
+//! Each packet is prefixed with its length as a big-endian `u32`, so
+//! the same packets sent as UDP datagrams can be carried over a byte
+//! stream.
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Largest packet accepted. Anything longer means the stream is
+/// corrupt or isn't ours.
+pub const MAX_FRAME: usize = 1 << 16;
+
+/// Length of the prefix before each packet.
+pub const PREFIX_LEN: usize = 4;
+
+/// Appends `packet` to `out` as a single frame.
+pub fn frame(packet: &[u8], out: &mut Vec<u8>) {
+    debug_assert!(packet.len() <= MAX_FRAME);
+    out.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+    out.extend_from_slice(packet);
+}
+
+/// Splits a byte stream back into frames.
+///
+/// Bytes are buffered between calls, so `next` may be cancelled, e.g.
+/// when it loses a `select!`, without losing track of frame bounds.
+pub struct FrameReader {
+    buf: Vec<u8>,
+    /// Start of the first unconsumed byte.
+    start: usize,
+    /// End of the bytes read so far.
+    end: usize,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; PREFIX_LEN + MAX_FRAME],
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// Reads until a whole frame is buffered and returns it. Returns
+    /// `None` if the stream ended cleanly between frames.
+    pub async fn next<R>(&mut self, reader: &mut R) -> std::io::Result<Option<&[u8]>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        loop {
+            if let Some(len) = self.buffered()? {
+                let frame = self.start + PREFIX_LEN..self.start + PREFIX_LEN + len;
+                self.start = frame.end;
+                return Ok(Some(&self.buf[frame]));
+            }
+            if self.end == self.buf.len() {
+                self.buf.copy_within(self.start..self.end, 0);
+                self.end -= self.start;
+                self.start = 0;
+            }
+            match reader.read(&mut self.buf[self.end..]).await? {
+                0 if self.start == self.end => return Ok(None),
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n => self.end += n,
+            }
+        }
+    }
+
+    /// Forgets any partial frame, e.g. when switching connections.
+    pub fn reset(&mut self) {
+        self.start = 0;
+        self.end = 0;
+    }
+
+    /// Length of the first frame if all of it has been read.
+    fn buffered(&self) -> std::io::Result<Option<usize>> {
+        let available = &self.buf[self.start..self.end];
+        if available.len() < PREFIX_LEN {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; PREFIX_LEN];
+        prefix.copy_from_slice(&available[..PREFIX_LEN]);
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > MAX_FRAME {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame of {} bytes exceeds {}", len, MAX_FRAME),
+            ));
+        }
+        if available.len() < PREFIX_LEN + len {
+            return Ok(None);
+        }
+        Ok(Some(len))
+    }
+}
+
+impl Default for FrameReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let mut stream = Vec::new();
+        frame(b"hello", &mut stream);
+        frame(b"", &mut stream);
+        frame(&[7u8; 300], &mut stream);
+        let mut reader = &stream[..];
+        let mut frames = FrameReader::new();
+        assert_eq!(frames.next(&mut reader).await.unwrap(), Some(&b"hello"[..]));
+        assert_eq!(frames.next(&mut reader).await.unwrap(), Some(&b""[..]));
+        assert_eq!(
+            frames.next(&mut reader).await.unwrap(),
+            Some(&[7u8; 300][..])
+        );
+        assert_eq!(frames.next(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reassembles_across_reads() {
+        let mut stream = Vec::new();
+        for i in 0..100u8 {
+            frame(&vec![i; 1000 + i as usize], &mut stream);
+        }
+        let mut reader = Trickle(&stream[..]);
+        let mut frames = FrameReader::new();
+        for i in 0..100u8 {
+            let frame = frames.next(&mut reader).await.unwrap().unwrap();
+            assert_eq!(frame, &vec![i; 1000 + i as usize][..]);
+        }
+        assert_eq!(frames.next(&mut reader).await.unwrap(), None);
+    }
+
+    /// Reads at most 777 bytes at a time, so frames straddle reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> AsyncRead for Trickle<'a> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let n = std::cmp::min(std::cmp::min(777, buf.len()), self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            std::task::Poll::Ready(Ok(n))
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_frames() {
+        let oversized = ((MAX_FRAME + 1) as u32).to_be_bytes();
+        let err = FrameReader::new()
+            .next(&mut &oversized[..])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let mut truncated = Vec::new();
+        frame(b"hello", &mut truncated);
+        truncated.truncate(7);
+        let err = FrameReader::new()
+            .next(&mut &truncated[..])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,492 @@
+//! Versioned packet header shared by every audio transport.
+//!
+//! Each datagram (or length-delimited frame, for stream-oriented
+//! transports) begins with a fixed-size header in network byte
+//! order, immediately followed by the payload:
+//!
+//! ```text
+//!  0       2       3       4               8               12
+//!  +-------+-------+-------+---------------+---------------+
+//!  | magic |version| flags |   stream id   |   sequence    |
+//!  +-------+-------+-------+---------------+---------------+
+//!  12                              20              24
+//!  +-------------------------------+---------------+
+//!  |           timestamp           |  sample rate  |
+//!  +-------------------------------+---------------+
+//!  24      26      28      29      30
+//!  +-------+-------+-------+-------+
+//!  | chans | frames| format| codec |
+//!  +-------+-------+-------+-------+
+//! ```
+//!
+//! The timestamp is the sender's sample clock, i.e. the index of the
+//! first frame in the payload since the stream started.
+//!
+//! With [`Codec::Pcm`] the payload is `frames` interleaved
+//! little-endian samples in the stream's sample format. Other codecs
+//! carry a compressed payload that decodes to `frames` frames of that
+//! format.
+//!
+//! Packets with [`FLAG_PARITY`] set carry forward error correction
+//! data instead of audio (see [`super::fec`]). Their frame count is
+//! zero and the payload length is not tied to the stream format.
+//!
+//! Packets with [`FLAG_ENCRYPTED`] set have an encrypted payload
+//! followed by an authentication trailer (see [`super::crypto`]).
+
+use crate::codec::Codec;
+
+/// Identifies a Paradise audio packet ("PD").
+pub const MAGIC: u16 = 0x5044;
+
+/// Current protocol version. Packets with any other version
+/// are rejected.
+pub const VERSION: u8 = 1;
+
+/// Size of the encoded header in bytes.
+pub const HEADER_LEN: usize = 30;
+
+/// The sender reset its sample clock (e.g. after an xrun or a
+/// restart). Receivers should resynchronize instead of treating
+/// the jump in timestamps as loss.
+pub const FLAG_DISCONTINUITY: u8 = 1 << 0;
+
+/// The payload is FEC parity protecting the group of packets that
+/// starts at this packet's sequence number.
+pub const FLAG_PARITY: u8 = 1 << 1;
+
+/// The payload is encrypted with a pre-shared key. Receivers open
+/// the packet before parsing anything but the header.
+pub const FLAG_ENCRYPTED: u8 = 1 << 2;
+
+/// Wire encoding of individual samples in the payload. Samples are
+/// always little-endian (see [`crate::sample`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    I16,
+    /// Packed into three bytes.
+    I24,
+    I32,
+    F32,
+    F64,
+}
+
+impl Default for SampleFormat {
+    fn default() -> Self {
+        SampleFormat::F32
+    }
+}
+
+impl SampleFormat {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(SampleFormat::I16),
+            2 => Some(SampleFormat::I24),
+            3 => Some(SampleFormat::I32),
+            4 => Some(SampleFormat::F32),
+            5 => Some(SampleFormat::F64),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            SampleFormat::I16 => 1,
+            SampleFormat::I24 => 2,
+            SampleFormat::I32 => 3,
+            SampleFormat::F32 => 4,
+            SampleFormat::F64 => 5,
+        }
+    }
+
+    /// Number of bytes a single sample occupies on the wire.
+    pub fn bytes_per_sample(&self) -> usize {
+        match self {
+            SampleFormat::I16 => 2,
+            SampleFormat::I24 => 3,
+            SampleFormat::I32 => 4,
+            SampleFormat::F32 => 4,
+            SampleFormat::F64 => 8,
+        }
+    }
+
+    /// Number of bits in a sample.
+    pub fn bits(&self) -> u32 {
+        match self {
+            SampleFormat::I16 => 16,
+            SampleFormat::I24 => 24,
+            SampleFormat::I32 => 32,
+            SampleFormat::F32 => 32,
+            SampleFormat::F64 => 64,
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, SampleFormat::F32 | SampleFormat::F64)
+    }
+}
+
+/// Describes the audio carried by a stream. Both ends of a stream
+/// must agree on the channel count, otherwise packets are rejected.
+/// The sample format and rate are chosen by the sender and converted
+/// by the receiver as needed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamFormat {
+    pub sample_format: SampleFormat,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+impl StreamFormat {
+    pub fn new(sample_format: SampleFormat, channels: u16, sample_rate: u32) -> Self {
+        Self {
+            sample_format,
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Number of bytes a single interleaved frame occupies on the wire.
+    pub fn bytes_per_frame(&self) -> usize {
+        self.sample_format.bytes_per_sample() * self.channels as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The buffer is smaller than a header.
+    Truncated(usize),
+    BadMagic(u16),
+    UnsupportedVersion(u8),
+    UnknownSampleFormat(u8),
+    UnknownCodec(u8),
+    ZeroChannels,
+    ZeroSampleRate,
+    /// The payload size does not match the frame count.
+    PayloadLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// The packet is well-formed but describes a stream
+    /// other than the one the receiver was configured for.
+    FormatMismatch {
+        expected: StreamFormat,
+        actual: StreamFormat,
+    },
+}
+
+impl std::fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderError::Truncated(len) => write!(
+                f,
+                "packet too short for header ({} < {} bytes)",
+                len, HEADER_LEN
+            ),
+            HeaderError::BadMagic(magic) => write!(f, "bad magic 0x{:04x}", magic),
+            HeaderError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported protocol version {} (expected {})",
+                v, VERSION
+            ),
+            HeaderError::UnknownSampleFormat(v) => write!(f, "unknown sample format {}", v),
+            HeaderError::UnknownCodec(v) => write!(f, "unknown codec {}", v),
+            HeaderError::ZeroChannels => write!(f, "channel count is zero"),
+            HeaderError::ZeroSampleRate => write!(f, "sample rate is zero"),
+            HeaderError::PayloadLength { expected, actual } => write!(
+                f,
+                "payload length mismatch (got {} bytes, expected {})",
+                actual, expected
+            ),
+            HeaderError::FormatMismatch { expected, actual } => write!(
+                f,
+                "stream format mismatch (got {:?}, expected {:?})",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub flags: u8,
+    pub stream_id: u32,
+    pub sequence: u32,
+    pub timestamp: u64,
+    pub format: StreamFormat,
+    /// Encoding of the payload.
+    pub codec: Codec,
+    /// Number of interleaved frames in the payload.
+    pub frames: u16,
+}
+
+impl Header {
+    /// Encodes the header into the start of `buf`, returning the
+    /// number of bytes written. Panics if `buf` is too small.
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        if buf.len() < HEADER_LEN {
+            panic!("tx buffer overflow");
+        }
+        buf[0..2].copy_from_slice(&MAGIC.to_be_bytes());
+        buf[2] = VERSION;
+        buf[3] = self.flags;
+        buf[4..8].copy_from_slice(&self.stream_id.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[12..20].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[20..24].copy_from_slice(&self.format.sample_rate.to_be_bytes());
+        buf[24..26].copy_from_slice(&self.format.channels.to_be_bytes());
+        buf[26..28].copy_from_slice(&self.frames.to_be_bytes());
+        buf[28] = self.format.sample_format.as_u8();
+        buf[29] = self.codec.as_u8();
+        HEADER_LEN
+    }
+
+    /// Decodes a packet, returning the header and a view of the
+    /// payload that follows it. No data is copied.
+    pub fn parse(buf: &[u8]) -> Result<(Header, &[u8]), HeaderError> {
+        if buf.len() < HEADER_LEN {
+            return Err(HeaderError::Truncated(buf.len()));
+        }
+        let magic = read_u16(&buf[0..2]);
+        if magic != MAGIC {
+            return Err(HeaderError::BadMagic(magic));
+        }
+        if buf[2] != VERSION {
+            return Err(HeaderError::UnsupportedVersion(buf[2]));
+        }
+        let sample_format =
+            SampleFormat::from_u8(buf[28]).ok_or(HeaderError::UnknownSampleFormat(buf[28]))?;
+        let codec = Codec::from_u8(buf[29]).ok_or(HeaderError::UnknownCodec(buf[29]))?;
+        let channels = read_u16(&buf[24..26]);
+        if channels == 0 {
+            return Err(HeaderError::ZeroChannels);
+        }
+        let sample_rate = read_u32(&buf[20..24]);
+        if sample_rate == 0 {
+            return Err(HeaderError::ZeroSampleRate);
+        }
+        let hdr = Header {
+            flags: buf[3],
+            stream_id: read_u32(&buf[4..8]),
+            sequence: read_u32(&buf[8..12]),
+            timestamp: read_u64(&buf[12..20]),
+            format: StreamFormat {
+                sample_format,
+                channels,
+                sample_rate,
+            },
+            codec,
+            frames: read_u16(&buf[26..28]),
+        };
+        let payload = &buf[HEADER_LEN..];
+        let expected = hdr.payload_len();
+        if hdr.codec == Codec::Pcm && !hdr.is_parity() && payload.len() != expected {
+            return Err(HeaderError::PayloadLength {
+                expected,
+                actual: payload.len(),
+            });
+        }
+        Ok((hdr, payload))
+    }
+
+    /// Size in bytes of the payload described by this header, if it
+    /// is uncompressed PCM.
+    pub fn payload_len(&self) -> usize {
+        self.frames as usize * self.format.bytes_per_frame()
+    }
+
+    /// Rejects packets whose channel count differs from the expected
+    /// stream format. Receivers convert from any sample format and
+    /// resample from any sample rate.
+    pub fn verify(&self, expected: &StreamFormat) -> Result<(), HeaderError> {
+        if self.format.channels != expected.channels {
+            return Err(HeaderError::FormatMismatch {
+                expected: *expected,
+                actual: self.format,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn is_discontinuity(&self) -> bool {
+        self.flags & FLAG_DISCONTINUITY != 0
+    }
+
+    pub fn is_parity(&self) -> bool {
+        self.flags & FLAG_PARITY != 0
+    }
+}
+
+/// Generates an identifier for a new outgoing stream. It only needs
+/// to be unlikely to collide with other senders on the same link.
+pub fn new_stream_id() -> u32 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.write_u32(std::process::id());
+    hasher.finish() as u32
+}
+
+fn read_u16(b: &[u8]) -> u16 {
+    u16::from_be_bytes([b[0], b[1]])
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    let mut v = [0u8; 8];
+    v.copy_from_slice(b);
+    u64::from_be_bytes(v)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stereo() -> StreamFormat {
+        StreamFormat::new(SampleFormat::F32, 2, 48_000)
+    }
+
+    fn packet(frames: u16) -> Vec<u8> {
+        let hdr = Header {
+            flags: FLAG_DISCONTINUITY,
+            stream_id: 0xDEADBEEF,
+            sequence: 7,
+            timestamp: 1 << 40,
+            format: stereo(),
+            codec: Codec::Pcm,
+            frames,
+        };
+        let mut buf = vec![0u8; HEADER_LEN + hdr.payload_len()];
+        assert_eq!(hdr.write(&mut buf[..]), HEADER_LEN);
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let buf = packet(64);
+        let (hdr, payload) = Header::parse(&buf[..]).unwrap();
+        assert_eq!(hdr.stream_id, 0xDEADBEEF);
+        assert_eq!(hdr.sequence, 7);
+        assert_eq!(hdr.timestamp, 1 << 40);
+        assert_eq!(hdr.format, stereo());
+        assert_eq!(hdr.frames, 64);
+        assert!(hdr.is_discontinuity());
+        assert_eq!(payload.len(), 64 * 8);
+        assert_eq!(payload.as_ptr(), buf[HEADER_LEN..].as_ptr());
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = packet(0);
+        assert_eq!(
+            Header::parse(&buf[..HEADER_LEN - 1]),
+            Err(HeaderError::Truncated(HEADER_LEN - 1))
+        );
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut buf = packet(1);
+        buf[0] = 0;
+        assert_eq!(Header::parse(&buf[..]), Err(HeaderError::BadMagic(0x0044)));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut buf = packet(1);
+        buf[2] = VERSION + 1;
+        assert_eq!(
+            Header::parse(&buf[..]),
+            Err(HeaderError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn unknown_sample_format() {
+        let mut buf = packet(1);
+        buf[28] = 0xFF;
+        assert_eq!(
+            Header::parse(&buf[..]),
+            Err(HeaderError::UnknownSampleFormat(0xFF))
+        );
+    }
+
+    #[test]
+    fn unknown_codec() {
+        let mut buf = packet(1);
+        buf[29] = 0xFF;
+        assert_eq!(Header::parse(&buf[..]), Err(HeaderError::UnknownCodec(0xFF)));
+    }
+
+    #[test]
+    fn zero_sample_rate() {
+        let mut buf = packet(1);
+        buf[20..24].copy_from_slice(&[0; 4]);
+        assert_eq!(Header::parse(&buf[..]), Err(HeaderError::ZeroSampleRate));
+    }
+
+    #[test]
+    fn compressed_payload_length() {
+        let mut buf = packet(0);
+        buf[29] = Codec::Opus.as_u8();
+        buf.extend_from_slice(&[0u8; 17]);
+        let (hdr, payload) = Header::parse(&buf[..]).unwrap();
+        assert_eq!(hdr.codec, Codec::Opus);
+        assert_eq!(payload.len(), 17);
+    }
+
+    #[test]
+    fn payload_length() {
+        let mut buf = packet(4);
+        buf.pop();
+        assert_eq!(
+            Header::parse(&buf[..]),
+            Err(HeaderError::PayloadLength {
+                expected: 32,
+                actual: 31
+            })
+        );
+    }
+
+    #[test]
+    fn parity_payload_length() {
+        let mut buf = packet(0);
+        buf[3] = FLAG_PARITY;
+        buf.extend_from_slice(&[0u8; 17]);
+        let (hdr, payload) = Header::parse(&buf[..]).unwrap();
+        assert!(hdr.is_parity());
+        assert_eq!(payload.len(), 17);
+    }
+
+    #[test]
+    fn format_mismatch() {
+        let buf = packet(1);
+        let (hdr, _) = Header::parse(&buf[..]).unwrap();
+        let mono = StreamFormat::new(SampleFormat::F32, 1, 48_000);
+        assert!(hdr.verify(&stereo()).is_ok());
+        assert_eq!(
+            hdr.verify(&mono),
+            Err(HeaderError::FormatMismatch {
+                expected: mono,
+                actual: stereo(),
+            })
+        );
+        // Receivers convert from whatever sample format is sent.
+        let i24 = StreamFormat::new(SampleFormat::I24, 2, 48_000);
+        assert!(hdr.verify(&i24).is_ok());
+        // ...and resample from whatever rate.
+        let cd = StreamFormat::new(SampleFormat::F32, 2, 44_100);
+        assert!(hdr.verify(&cd).is_ok());
+    }
+}
@@ -0,0 +1,743 @@
+//! Multicast DNS service discovery (RFC 6762 and RFC 6763), so
+//! endpoints can be found by name instead of by an address that DHCP
+//! may change. Each endpoint is advertised as an instance of
+//! `_paradise._udp`, with TXT records describing the stream:
+//!
+//! ```text
+//! studio-b-rack._paradise._udp.local. SRV 0 0 20000 studio-b-rack.local.
+//! studio-b-rack._paradise._udp.local. TXT "txtvers=1" "proto=QUIC" "channels=2"
+//!                                         "rate=48000" "codec=pcm" "fingerprint=3A:7F:..."
+//! ```
+//!
+//! Configs refer to endpoints as `mdns://studio-b-rack`, which
+//! [`resolve`] turns into an address.
+use crate::codec::Codec;
+use crate::stream::addr::{Protocol, StreamAddr};
+use crate::stream::dns::{self, Message, Question, RData, Record};
+use crate::stream::fingerprint::Fingerprint;
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::shutdown::Shutdown;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+
+lazy_static! {
+    /// Group and port of multicast DNS.
+    pub static ref MDNS_ADDR: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(224, 0, 0, 251)), 5353);
+}
+
+/// Prefix of addresses given by the name an endpoint is advertised
+/// with, e.g. `mdns://studio-b-rack`.
+pub const SCHEME: &str = "mdns://";
+
+/// Service type Paradise endpoints are advertised as.
+pub const SERVICE_TYPE: &str = "_paradise._udp.local";
+
+/// Lists the service types advertised on the network (RFC 6763 §9).
+const META_QUERY: &str = "_services._dns-sd._udp.local";
+
+/// TTL of records naming a host, in seconds (RFC 6762 §10).
+const HOST_TTL: u32 = 120;
+/// TTL of other records, in seconds.
+const OTHER_TTL: u32 = 4500;
+/// Longest TTL of answers to one-shot queries (RFC 6762 §6.7).
+const LEGACY_TTL: u32 = 10;
+/// How often a query is repeated while waiting for answers.
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MdnsError {
+    /// Instance names must be a single DNS label: at most 63 bytes and
+    /// without dots.
+    InvalidName(String),
+}
+
+impl std::fmt::Display for MdnsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MdnsError::InvalidName(name) => write!(
+                f,
+                "invalid name \"{}\" (expected at most 63 bytes without dots)",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MdnsError {}
+
+/// An advertised endpoint. Everything but its name, port and protocol
+/// is optional, and only informs whoever connects to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// Instance name, e.g. `studio-b-rack`.
+    pub name: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    pub channels: Option<u16>,
+    pub sample_rate: Option<u32>,
+    pub codec: Option<Codec>,
+    /// Fingerprint of the endpoint's certificate, for pinning it.
+    pub fingerprint: Option<Fingerprint>,
+}
+
+impl Service {
+    pub fn new(name: &str, port: u16, protocol: Protocol) -> Self {
+        Self {
+            name: name.to_string(),
+            port,
+            protocol,
+            channels: None,
+            sample_rate: None,
+            codec: None,
+            fingerprint: None,
+        }
+    }
+
+    fn instance(&self) -> String {
+        format!("{}.{}", self.name, SERVICE_TYPE)
+    }
+
+    fn host(&self) -> String {
+        format!("{}.local", self.name)
+    }
+
+    fn txt(&self) -> Vec<String> {
+        let mut txt = vec![
+            String::from("txtvers=1"),
+            format!("proto={}", self.protocol),
+        ];
+        if let Some(channels) = self.channels {
+            txt.push(format!("channels={}", channels));
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            txt.push(format!("rate={}", sample_rate));
+        }
+        if let Some(codec) = self.codec {
+            txt.push(format!("codec={}", codec.as_str()));
+        }
+        if let Some(fingerprint) = self.fingerprint {
+            txt.push(format!("fingerprint={}", fingerprint));
+        }
+        txt
+    }
+
+    /// Reads a service from its TXT record. Unknown keys and values
+    /// that don't parse are skipped, so later versions can add to
+    /// them.
+    fn from_txt(name: &str, port: u16, txt: &[String]) -> Self {
+        let mut service = Service::new(name, port, Protocol::Udp);
+        for entry in txt {
+            let (key, value) = match entry.find('=') {
+                Some(i) => (&entry[..i], &entry[i + 1..]),
+                None => continue,
+            };
+            match key.to_ascii_lowercase().as_str() {
+                "proto" => {
+                    if let Ok(protocol) = value.parse() {
+                        service.protocol = protocol;
+                    }
+                }
+                "channels" => service.channels = value.parse().ok(),
+                "rate" => service.sample_rate = value.parse().ok(),
+                "codec" => {
+                    service.codec = [Codec::Pcm, Codec::Opus, Codec::Lossless]
+                        .iter()
+                        .copied()
+                        .find(|codec| codec.as_str() == value)
+                }
+                "fingerprint" => service.fingerprint = value.parse().ok(),
+                _ => {}
+            }
+        }
+        service
+    }
+}
+
+/// A service found on the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub service: Service,
+    pub addr: StreamAddr,
+}
+
+#[derive(Default)]
+struct Services {
+    active: HashMap<String, Service>,
+    /// Withdrawn since the last announcement.
+    withdrawn: Vec<Service>,
+}
+
+/// Advertises a set of services, answering queries for them until
+/// it's shut down. Services are announced as soon as they're added,
+/// and withdrawn with a goodbye (records with a TTL of zero) so
+/// caches forget them right away.
+pub struct Advertiser {
+    shutdown: Shutdown,
+    services: std::sync::Arc<std::sync::Mutex<Services>>,
+    /// Wakes the task when the set of services changes.
+    wake: std::sync::Arc<Notify>,
+}
+
+impl Advertiser {
+    /// Answers queries sent to `group`, usually [`MDNS_ADDR`]. Must be
+    /// called from within a tokio runtime.
+    pub fn new(
+        group: SocketAddr,
+        multicast: &MulticastConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        // Responses are sent with a TTL of 255, which lets receivers
+        // check they came from the local link (RFC 6762 §11).
+        let config = MulticastConfig {
+            ttl: 255,
+            ..*multicast
+        };
+        let sock = multicast::receiver(group, &config)?;
+        sock.set_nonblocking(true)?;
+        let host = multicast::source_addr(group, multicast);
+        let services = std::sync::Arc::new(std::sync::Mutex::new(Services::default()));
+        let wake = std::sync::Arc::new(Notify::new());
+        let shutdown = {
+            let (services, wake) = (services.clone(), wake.clone());
+            Shutdown::spawn(move |stop| Self::entry(services, wake, sock, group, host, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            shutdown,
+            services,
+            wake,
+        }))
+    }
+
+    /// Starts advertising `service`, replacing any service with the
+    /// same name.
+    pub fn advertise(&self, service: Service) -> Result<(), MdnsError> {
+        if service.name.is_empty() || service.name.len() > 63 || service.name.contains('.') {
+            return Err(MdnsError::InvalidName(service.name));
+        }
+        self.services
+            .lock()
+            .unwrap()
+            .active
+            .insert(service.name.clone(), service);
+        self.wake.notify();
+        Ok(())
+    }
+
+    /// Stops advertising the service and tells listeners it's gone.
+    pub fn withdraw(&self, name: &str) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.active.remove(name) {
+            services.withdrawn.push(service);
+            self.wake.notify();
+        }
+    }
+
+    /// Services currently advertised.
+    pub fn services(&self) -> Vec<Service> {
+        self.services
+            .lock()
+            .unwrap()
+            .active
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Withdraws every service and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        services: std::sync::Arc<std::sync::Mutex<Services>>,
+        wake: std::sync::Arc<Notify>,
+        sock: std::net::UdpSocket,
+        group: SocketAddr,
+        host: IpAddr,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("mdns: {}", e);
+                return;
+            }
+        };
+        const BUFFER_SIZE: usize = 9000;
+        let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+        loop {
+            let (stopped, query) = tokio::select! {
+                // Stopped, or the advertiser was dropped.
+                _ = &mut stop => (true, None),
+                _ = wake.notified() => (false, None),
+                result = sock.recv_from(&mut buf[..]) => match result {
+                    Ok((amt, src)) => (false, Some((amt, src))),
+                    Err(e) => {
+                        error!("mdns recv_from: {:?}", e);
+                        continue;
+                    }
+                },
+            };
+            let replies = match query {
+                Some((amt, src)) => {
+                    let query = match Message::parse(&buf[..amt]) {
+                        Ok(query) if !query.response => query,
+                        Ok(_) => continue,
+                        Err(e) => {
+                            debug!("mdns: ignoring query from {}: {}", src, e);
+                            continue;
+                        }
+                    };
+                    let mut reply = answer(&services.lock().unwrap().active, host, &query);
+                    if reply.answers.is_empty() {
+                        continue;
+                    }
+                    if src.port() == group.port() {
+                        vec![(reply, group)]
+                    } else {
+                        // One-shot queries from other ports are
+                        // answered directly (RFC 6762 §6.7).
+                        reply.id = query.id;
+                        reply.questions = query.questions;
+                        for record in reply.answers.iter_mut().chain(reply.additionals.iter_mut()) {
+                            record.flush = false;
+                            record.ttl = record.ttl.min(LEGACY_TTL);
+                        }
+                        vec![(reply, src)]
+                    }
+                }
+                None => {
+                    let mut services = services.lock().unwrap();
+                    if stopped {
+                        let active = services
+                            .active
+                            .drain()
+                            .map(|(_, service)| service)
+                            .collect::<Vec<_>>();
+                        services.withdrawn.extend(active);
+                    }
+                    let withdrawn = services.withdrawn.drain(..).collect::<Vec<_>>();
+                    withdrawn
+                        .iter()
+                        .map(|service| announcement(service, host, 0))
+                        .chain(
+                            services
+                                .active
+                                .values()
+                                .map(|service| announcement(service, host, 1)),
+                        )
+                        .map(|msg| (msg, group))
+                        .collect()
+                }
+            };
+            for (msg, dest) in &replies {
+                if let Err(e) = sock.send_to(&msg.to_bytes(), dest).await {
+                    warn!("mdns: send to {}: {}", dest, e);
+                }
+            }
+            if stopped {
+                return;
+            }
+        }
+    }
+}
+
+/// The PTR record pointing to a service, and its SRV, TXT and address
+/// records. A `scale` of zero makes them a goodbye.
+fn records(service: &Service, host: IpAddr, scale: u32) -> (Record, Vec<Record>) {
+    let instance = service.instance();
+    let ptr = Record {
+        name: String::from(SERVICE_TYPE),
+        ttl: OTHER_TTL * scale,
+        flush: false,
+        data: RData::Ptr(instance.clone()),
+    };
+    let mut records = vec![
+        Record {
+            name: instance.clone(),
+            ttl: HOST_TTL * scale,
+            flush: true,
+            data: RData::Srv {
+                priority: 0,
+                weight: 0,
+                port: service.port,
+                target: service.host(),
+            },
+        },
+        Record {
+            name: instance,
+            ttl: OTHER_TTL * scale,
+            flush: true,
+            data: RData::Txt(service.txt()),
+        },
+    ];
+    // Without a route there's no address to give, and resolvers use
+    // the one answers come from instead.
+    if !host.is_unspecified() {
+        records.push(Record {
+            name: service.host(),
+            ttl: HOST_TTL * scale,
+            flush: true,
+            data: match host {
+                IpAddr::V4(ip) => RData::A(ip),
+                IpAddr::V6(ip) => RData::Aaaa(ip),
+            },
+        });
+    }
+    (ptr, records)
+}
+
+/// An unsolicited response announcing a service, or withdrawing it if
+/// `scale` is zero.
+fn announcement(service: &Service, host: IpAddr, scale: u32) -> Message {
+    let (ptr, records) = records(service, host, scale);
+    Message {
+        response: true,
+        answers: std::iter::once(ptr).chain(records).collect(),
+        ..Default::default()
+    }
+}
+
+/// Answers the questions in `query` about `services`, with the records
+/// needed to connect to them as additionals.
+fn answer(services: &HashMap<String, Service>, host: IpAddr, query: &Message) -> Message {
+    let mut reply = Message {
+        response: true,
+        ..Default::default()
+    };
+    let matches = |question: &Question, rtype: u16| {
+        question.rtype == rtype || question.rtype == dns::TYPE_ANY
+    };
+    for question in &query.questions {
+        let name = question.name.as_str();
+        if name.eq_ignore_ascii_case(META_QUERY)
+            && matches(question, dns::TYPE_PTR)
+            && !services.is_empty()
+        {
+            reply.answers.push(Record {
+                name: String::from(META_QUERY),
+                ttl: OTHER_TTL,
+                flush: false,
+                data: RData::Ptr(String::from(SERVICE_TYPE)),
+            });
+        }
+        for service in services.values() {
+            let (ptr, records) = records(service, host, 1);
+            if name.eq_ignore_ascii_case(SERVICE_TYPE) && matches(question, dns::TYPE_PTR) {
+                reply.answers.push(ptr);
+                reply.additionals.extend(records);
+                continue;
+            }
+            for record in records {
+                if record.name.eq_ignore_ascii_case(name) && matches(question, record.data.rtype())
+                {
+                    reply.answers.push(record);
+                } else if record.name == service.host() || record.name == service.instance() {
+                    reply.additionals.push(record);
+                }
+            }
+        }
+    }
+    if reply.answers.is_empty() {
+        reply.additionals.clear();
+    }
+    let answers = reply.answers.clone();
+    reply.additionals.retain(|record| !answers.contains(record));
+    reply.additionals.dedup();
+    reply
+}
+
+/// Reads the services described by a response. `src` is the address
+/// it came from, used for services whose host has no address record.
+fn endpoints(msg: &Message, src: IpAddr) -> Vec<Endpoint> {
+    let records = msg
+        .answers
+        .iter()
+        .chain(msg.additionals.iter())
+        .filter(|record| record.ttl > 0)
+        .collect::<Vec<_>>();
+    let suffix = format!(".{}", SERVICE_TYPE);
+    let mut endpoints = Vec::new();
+    for record in &records {
+        let (port, target) = match &record.data {
+            RData::Srv { port, target, .. } => (*port, target),
+            _ => continue,
+        };
+        let lower = record.name.to_ascii_lowercase();
+        if !lower.ends_with(&suffix) {
+            continue;
+        }
+        let name = &record.name[..record.name.len() - suffix.len()];
+        let txt = records
+            .iter()
+            .find_map(|r| match &r.data {
+                RData::Txt(txt) if r.name.eq_ignore_ascii_case(&record.name) => Some(txt.clone()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let addr = |v6: bool| {
+            records.iter().find_map(|r| match r.data {
+                _ if !r.name.eq_ignore_ascii_case(target) => None,
+                RData::A(ip) if !v6 => Some(IpAddr::V4(ip)),
+                RData::Aaaa(ip) if v6 => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+        };
+        let ip = addr(false).or_else(|| addr(true)).unwrap_or(src);
+        let service = Service::from_txt(name, port, &txt);
+        endpoints.push(Endpoint {
+            addr: StreamAddr::new((ip, port).into(), service.protocol),
+            service,
+        });
+    }
+    endpoints
+}
+
+/// Repeats `questions` to `group` until `timeout` elapses or `done`
+/// is satisfied, collecting the services described by the answers.
+async fn query<F>(
+    questions: Vec<Question>,
+    group: SocketAddr,
+    multicast: &MulticastConfig,
+    timeout: Duration,
+    done: F,
+) -> std::io::Result<Vec<Endpoint>>
+where
+    F: Fn(&[Endpoint]) -> bool,
+{
+    // Queries sent from a port other than the group's are answered
+    // directly, so there's no need to join the group and compete
+    // with any responder on this host for its port.
+    let sock = multicast::sender(group, multicast)?;
+    sock.set_nonblocking(true)?;
+    let mut sock = tokio::net::UdpSocket::from_std(sock)?;
+    let query = Message {
+        questions,
+        ..Default::default()
+    }
+    .to_bytes();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut found: Vec<Endpoint> = Vec::new();
+    const BUFFER_SIZE: usize = 9000;
+    let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+    while tokio::time::Instant::now() < deadline {
+        sock.send_to(&query, &group).await?;
+        let resend = tokio::time::Instant::now() + QUERY_INTERVAL;
+        loop {
+            let (amt, src) = tokio::select! {
+                result = sock.recv_from(&mut buf[..]) => match result {
+                    Ok(value) => value,
+                    Err(e) => {
+                        debug!("mdns query recv_from: {:?}", e);
+                        continue;
+                    }
+                },
+                _ = tokio::time::delay_until(resend.min(deadline)) => break,
+            };
+            let msg = match Message::parse(&buf[..amt]) {
+                Ok(msg) if msg.response => msg,
+                _ => continue,
+            };
+            for endpoint in endpoints(&msg, src.ip()) {
+                found.retain(|e| e.service.name != endpoint.service.name);
+                found.push(endpoint);
+            }
+            if done(&found) {
+                return Ok(found);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Looks for the endpoint advertised as `name` on `group`, usually
+/// [`MDNS_ADDR`], for up to `timeout`.
+pub async fn resolve(
+    name: &str,
+    group: SocketAddr,
+    multicast: &MulticastConfig,
+    timeout: Duration,
+) -> std::io::Result<Option<Endpoint>> {
+    let instance = format!("{}.{}", name, SERVICE_TYPE);
+    let questions = vec![
+        Question {
+            name: instance.clone(),
+            rtype: dns::TYPE_SRV,
+        },
+        Question {
+            name: instance,
+            rtype: dns::TYPE_TXT,
+        },
+    ];
+    let is_match = |e: &Endpoint| e.service.name.eq_ignore_ascii_case(name);
+    let found = query(questions, group, multicast, timeout, |found| {
+        found.iter().any(is_match)
+    })
+    .await?;
+    Ok(found.into_iter().find(is_match))
+}
+
+/// Lists the endpoints advertised on `group` that answer within
+/// `timeout`, by name.
+pub async fn browse(
+    group: SocketAddr,
+    multicast: &MulticastConfig,
+    timeout: Duration,
+) -> std::io::Result<Vec<Endpoint>> {
+    let questions = vec![Question {
+        name: String::from(SERVICE_TYPE),
+        rtype: dns::TYPE_PTR,
+    }];
+    let mut found = query(questions, group, multicast, timeout, |_| false).await?;
+    found.sort_by(|a, b| a.service.name.cmp(&b.service.name));
+    Ok(found)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::multicast::Interface;
+
+    fn service(name: &str) -> Service {
+        Service {
+            channels: Some(2),
+            sample_rate: Some(48000),
+            codec: Some(Codec::Opus),
+            fingerprint: Some(Fingerprint([0x3a; 32])),
+            ..Service::new(name, 20000, Protocol::Quic)
+        }
+    }
+
+    #[test]
+    fn txt_round_trip() {
+        let service = service("studio-b-rack");
+        assert_eq!(
+            Service::from_txt("studio-b-rack", 20000, &service.txt()),
+            service
+        );
+        // Anything unknown or malformed is skipped.
+        let txt = ["proto=RTP", "channels=many", "color=red", "flag"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            Service::from_txt("aes67", 5004, &txt),
+            Service::new("aes67", 5004, Protocol::Rtp)
+        );
+    }
+
+    #[test]
+    fn answers_queries() {
+        let host: IpAddr = "192.168.1.20".parse().unwrap();
+        let mut services = HashMap::new();
+        services.insert(String::from("studio-b-rack"), service("studio-b-rack"));
+        let query = |name: &str, rtype: u16| Message {
+            questions: vec![Question {
+                name: name.to_string(),
+                rtype,
+            }],
+            ..Default::default()
+        };
+        // Browsing gives everything needed to connect in one go.
+        let reply = answer(&services, host, &query(SERVICE_TYPE, dns::TYPE_PTR));
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.additionals.len(), 3);
+        let endpoint = Endpoint {
+            service: service("studio-b-rack"),
+            addr: "192.168.1.20:20000/QUIC".parse().unwrap(),
+        };
+        assert_eq!(
+            endpoints(&reply, "10.0.0.1".parse().unwrap()),
+            vec![endpoint.clone()]
+        );
+        let reply = answer(
+            &services,
+            host,
+            &query("Studio-B-Rack._paradise._udp.local", dns::TYPE_SRV),
+        );
+        assert_eq!(reply.answers.len(), 1);
+        assert_eq!(reply.additionals.len(), 2);
+        assert_eq!(reply.answers[0].data.rtype(), dns::TYPE_SRV);
+        let reply = answer(
+            &services,
+            host,
+            &query("studio-b-rack.local", dns::TYPE_ANY),
+        );
+        assert_eq!(
+            reply.answers[0].data,
+            RData::A("192.168.1.20".parse().unwrap())
+        );
+        let reply = answer(&services, host, &query(META_QUERY, dns::TYPE_PTR));
+        assert_eq!(
+            reply.answers[0].data,
+            RData::Ptr(String::from(SERVICE_TYPE))
+        );
+        // Questions about anything else go unanswered.
+        let reply = answer(&services, host, &query("other.local", dns::TYPE_A));
+        assert!(reply.answers.is_empty() && reply.additionals.is_empty());
+        // A goodbye describes nothing, and answers without an address
+        // record use the address they came from.
+        assert!(endpoints(&announcement(&service("a"), host, 0), host).is_empty());
+        let reply = answer(
+            &services,
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            &query(SERVICE_TYPE, dns::TYPE_PTR),
+        );
+        assert_eq!(
+            endpoints(&reply, "10.0.0.1".parse().unwrap())[0].addr,
+            "10.0.0.1:20000/QUIC".parse().unwrap()
+        );
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn advertises_and_resolves() {
+        // Stay on the loopback interface so the test doesn't depend
+        // on the network.
+        let multicast = MulticastConfig {
+            ttl: 0,
+            interface: Some(Interface::Addr(Ipv4Addr::LOCALHOST)),
+        };
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group: SocketAddr = ([239, 255, 69, 3], port).into();
+        let advertiser = Advertiser::new(group, &multicast).unwrap();
+        assert!(advertiser
+            .advertise(Service::new("studio.b", 20000, Protocol::Udp))
+            .is_err());
+        advertiser.advertise(service("studio-b-rack")).unwrap();
+        advertiser
+            .advertise(Service::new("monitors", 20001, Protocol::Udp))
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        let endpoint = resolve("studio-b-rack", group, &multicast, timeout)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(endpoint.service, service("studio-b-rack"));
+        assert_eq!(endpoint.addr, "127.0.0.1:20000/QUIC".parse().unwrap());
+        let found = browse(group, &multicast, Duration::from_millis(500))
+            .await
+            .unwrap();
+        assert_eq!(
+            found.iter().map(|e| e.addr.to_string()).collect::<Vec<_>>(),
+            vec!["127.0.0.1:20001/UDP", "127.0.0.1:20000/QUIC"]
+        );
+        advertiser.withdraw("studio-b-rack");
+        assert_eq!(advertiser.services().len(), 1);
+        let missing = resolve(
+            "studio-b-rack",
+            group,
+            &multicast,
+            Duration::from_millis(500),
+        )
+        .await
+        .unwrap();
+        assert_eq!(missing, None);
+        advertiser.shutdown().await;
+    }
+}
@@ -0,0 +1,574 @@
+//! Stream statistics in the Prometheus text exposition format, served
+//! over plain HTTP at `/metrics`.
+//!
+//! Streams are registered with a [`Metrics`] registry by name, and
+//! their [`StreamStats`] are read each time the endpoint is scraped,
+//! so scraping never touches the audio path. Process metrics follow
+//! the names Prometheus client libraries use, and are only available
+//! on Linux.
+use crate::stream::addr::Protocol;
+use crate::stream::backoff::Backoff;
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::{ConnectionState, Snapshot, StreamStats};
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Path the metrics are served at.
+pub const PATH: &str = "/metrics";
+
+/// Content type of the text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Longest request head read before giving up on a client.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long a client has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether a stream sends or receives audio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Tx => "tx",
+            Direction::Rx => "rx",
+        }
+    }
+}
+
+/// A registered stream.
+struct Entry {
+    name: String,
+    direction: Direction,
+    protocol: Protocol,
+    stats: std::sync::Arc<StreamStats>,
+}
+
+/// Reads a value from a stream's counters.
+type Value = fn(&Snapshot) -> f64;
+
+/// Metrics kept for every stream: name, type, help, the direction of
+/// the streams they apply to and how to read them.
+const STREAM_METRICS: &[(&str, &str, &str, Direction, Value)] = &[
+    (
+        "paradise_stream_packets_sent_total",
+        "counter",
+        "Packets handed to the network, including FEC parity.",
+        Direction::Tx,
+        |s| s.packets_sent as f64,
+    ),
+    (
+        "paradise_stream_sent_bytes_total",
+        "counter",
+        "Bytes handed to the network.",
+        Direction::Tx,
+        |s| s.bytes_sent as f64,
+    ),
+    (
+        "paradise_stream_packets_received_total",
+        "counter",
+        "Packets that arrived, whether or not they could be played.",
+        Direction::Rx,
+        |s| s.packets_received as f64,
+    ),
+    (
+        "paradise_stream_received_bytes_total",
+        "counter",
+        "Bytes that arrived.",
+        Direction::Rx,
+        |s| s.bytes_received as f64,
+    ),
+    (
+        "paradise_stream_lost_packets_total",
+        "counter",
+        "Packets that never arrived.",
+        Direction::Rx,
+        |s| s.lost as f64,
+    ),
+    (
+        "paradise_stream_reordered_packets_total",
+        "counter",
+        "Packets that arrived after one sent later than them.",
+        Direction::Rx,
+        |s| s.reordered as f64,
+    ),
+    (
+        "paradise_stream_duplicate_packets_total",
+        "counter",
+        "Packets that arrived more than once.",
+        Direction::Rx,
+        |s| s.duplicates as f64,
+    ),
+    (
+        "paradise_stream_late_packets_total",
+        "counter",
+        "Packets discarded for arriving after their playout time.",
+        Direction::Rx,
+        |s| s.late as f64,
+    ),
+    (
+        "paradise_stream_concealed_frames_total",
+        "counter",
+        "Frames synthesized to cover lost packets and underruns.",
+        Direction::Rx,
+        |s| s.concealed_frames as f64,
+    ),
+    (
+        "paradise_stream_underruns_total",
+        "counter",
+        "Times the jitter buffer ran dry and had to rebuffer.",
+        Direction::Rx,
+        |s| s.underruns as f64,
+    ),
+    (
+        "paradise_stream_overruns_total",
+        "counter",
+        "Times the jitter buffer overfilled and audio was skipped.",
+        Direction::Rx,
+        |s| s.overruns as f64,
+    ),
+    (
+        "paradise_stream_jitter_seconds",
+        "gauge",
+        "Smoothed interarrival jitter.",
+        Direction::Rx,
+        |s| s.jitter.as_secs_f64(),
+    ),
+    (
+        "paradise_stream_buffer_seconds",
+        "gauge",
+        "Audio held in the jitter buffer ahead of playout.",
+        Direction::Rx,
+        |s| s.latency.as_secs_f64(),
+    ),
+    (
+        "paradise_stream_buffer_target_seconds",
+        "gauge",
+        "Audio the jitter buffer aims to hold.",
+        Direction::Rx,
+        |s| s.target_delay.as_secs_f64(),
+    ),
+];
+
+/// Streams whose statistics are exported, by name.
+#[derive(Default)]
+pub struct Metrics {
+    entries: std::sync::Mutex<Vec<Entry>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports `stats` as the stream `name`, replacing any stream
+    /// registered under the same name and direction.
+    pub fn register(
+        &self,
+        name: &str,
+        direction: Direction,
+        protocol: Protocol,
+        stats: std::sync::Arc<StreamStats>,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.name != name || entry.direction != direction);
+        entries.push(Entry {
+            name: name.to_owned(),
+            direction,
+            protocol,
+            stats,
+        });
+        entries.sort_by(|a, b| (&a.name, a.direction).cmp(&(&b.name, b.direction)));
+    }
+
+    /// Stops exporting the stream `name`.
+    pub fn unregister(&self, name: &str, direction: Direction) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|entry| entry.name != name || entry.direction != direction);
+    }
+
+    /// Renders every metric in the text exposition format.
+    pub fn render(&self) -> String {
+        let streams = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                let labels = format!(
+                    "stream=\"{}\",direction=\"{}\",protocol=\"{}\"",
+                    escape(&entry.name),
+                    entry.direction.as_str(),
+                    entry.protocol.as_str().to_ascii_lowercase()
+                );
+                (labels, entry.direction, entry.stats.snapshot())
+            })
+            .collect::<Vec<_>>();
+        let mut out = String::new();
+        header(
+            &mut out,
+            "paradise_build_info",
+            "gauge",
+            "Version of the running build.",
+        );
+        let _ = writeln!(
+            out,
+            "paradise_build_info{{version=\"{}\"}} 1",
+            env!("CARGO_PKG_VERSION")
+        );
+        for (name, kind, help, applies, value) in STREAM_METRICS {
+            header(&mut out, name, kind, help);
+            for (labels, direction, snapshot) in &streams {
+                if applies == direction {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value(snapshot));
+                }
+            }
+        }
+        header(
+            &mut out,
+            "paradise_stream_connection_state",
+            "gauge",
+            "Connection state of connection-oriented streams, 1 for the current state.",
+        );
+        for (labels, _, snapshot) in &streams {
+            if snapshot.connection == ConnectionState::None {
+                continue;
+            }
+            for state in &ConnectionState::ALL {
+                let _ = writeln!(
+                    out,
+                    "paradise_stream_connection_state{{{},state=\"{}\"}} {}",
+                    labels,
+                    state.as_str(),
+                    (*state == snapshot.connection) as u8
+                );
+            }
+        }
+        if let Some(process) = process::Process::current() {
+            process.render(&mut out);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(target_os = "linux")]
+mod process {
+    use super::header;
+    use std::fmt::Write;
+
+    /// Resource usage of this process, from `/proc/self`.
+    pub struct Process {
+        cpu_seconds: f64,
+        resident_bytes: u64,
+        virtual_bytes: u64,
+        start_time: f64,
+        open_fds: usize,
+    }
+
+    impl Process {
+        pub fn current() -> Option<Self> {
+            let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+            // The command name may hold spaces and parentheses, so
+            // fields are counted from the last parenthesis, starting
+            // with the third.
+            let fields = stat[stat.rfind(')')? + 1..]
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            let field = |n: usize| -> Option<u64> { fields.get(n - 3)?.parse().ok() };
+            let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+            let boot_time = std::fs::read_to_string("/proc/stat")
+                .ok()?
+                .lines()
+                .find_map(|line| line.strip_prefix("btime "))?
+                .trim()
+                .parse::<f64>()
+                .ok()?;
+            Some(Self {
+                cpu_seconds: (field(14)? + field(15)?) as f64 / ticks,
+                resident_bytes: field(24)? * page_size,
+                virtual_bytes: field(23)?,
+                start_time: boot_time + field(22)? as f64 / ticks,
+                open_fds: std::fs::read_dir("/proc/self/fd").ok()?.count(),
+            })
+        }
+
+        pub fn render(&self, out: &mut String) {
+            let metrics: [(&str, &str, &str, f64); 5] = [
+                (
+                    "process_cpu_seconds_total",
+                    "counter",
+                    "Total user and system CPU time spent in seconds.",
+                    self.cpu_seconds,
+                ),
+                (
+                    "process_resident_memory_bytes",
+                    "gauge",
+                    "Resident memory size in bytes.",
+                    self.resident_bytes as f64,
+                ),
+                (
+                    "process_virtual_memory_bytes",
+                    "gauge",
+                    "Virtual memory size in bytes.",
+                    self.virtual_bytes as f64,
+                ),
+                (
+                    "process_start_time_seconds",
+                    "gauge",
+                    "Start time of the process since unix epoch in seconds.",
+                    self.start_time,
+                ),
+                (
+                    "process_open_fds",
+                    "gauge",
+                    "Number of open file descriptors.",
+                    self.open_fds as f64,
+                ),
+            ];
+            for (name, kind, help, value) in &metrics {
+                header(out, name, kind, help);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod process {
+    pub struct Process;
+
+    impl Process {
+        pub fn current() -> Option<Self> {
+            None
+        }
+
+        pub fn render(&self, _out: &mut String) {}
+    }
+}
+
+/// Serves a [`Metrics`] registry over HTTP until shut down.
+pub struct MetricsServer {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+}
+
+impl MetricsServer {
+    /// Listens on `addr`. Must be called from within a tokio runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        metrics: std::sync::Arc<Metrics>,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let shutdown = Shutdown::spawn(move |stop| Self::entry(metrics, listener, stop));
+        Ok(std::sync::Arc::new(Self { addr, shutdown }))
+    }
+
+    /// Address the server is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting requests and waits until the listener is
+    /// closed. Requests in progress are still answered.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        metrics: std::sync::Arc<Metrics>,
+        listener: std::net::TcpListener,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("metrics: {}", e);
+                return;
+            }
+        };
+        let mut backoff = Backoff::default();
+        loop {
+            let result = tokio::select! {
+                // Stopped, or the server was dropped.
+                _ = &mut stop => return,
+                result = listener.accept() => result,
+            };
+            match result {
+                Ok((sock, src)) => {
+                    backoff.reset();
+                    let metrics = metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(sock, &metrics).await {
+                            debug!("metrics: request from {}: {}", src, e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    // e.g. out of file descriptors.
+                    let delay = backoff.next_delay();
+                    warn!("metrics: accept: {}, retrying in {:?}", e, delay);
+                    tokio::select! {
+                        _ = &mut stop => return,
+                        _ = tokio::time::delay_for(delay) => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Answers a single request, then closes the connection.
+async fn serve(mut sock: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut sock))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"))??;
+    let (status, content_type, body) = respond(&head, metrics);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    sock.write_all(response.as_bytes()).await?;
+    sock.shutdown(std::net::Shutdown::Write)
+}
+
+/// Reads the request line and headers.
+async fn read_head(sock: &mut TcpStream) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_REQUEST_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "request too long",
+            ));
+        }
+        let amt = sock.read(&mut buf).await?;
+        if amt == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..amt]);
+    }
+    String::from_utf8(head).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Status, content type and body of the response to a request.
+fn respond(head: &str, metrics: &Metrics) -> (&'static str, &'static str, String) {
+    const TEXT: &str = "text/plain; charset=utf-8";
+    let mut request = head.lines().next().unwrap_or("").split(' ');
+    let (method, target) = (request.next(), request.next());
+    let path = target.map(|target| target.split('?').next().unwrap());
+    match (method, path) {
+        (Some("GET"), Some(PATH)) => ("200 OK", CONTENT_TYPE, metrics.render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", TEXT, "not found\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            TEXT,
+            "only GET is supported\n".to_owned(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_streams() {
+        let metrics = Metrics::new();
+        let rx = std::sync::Arc::new(StreamStats::new());
+        rx.received(100);
+        rx.received(100);
+        rx.set_connection(ConnectionState::Connected);
+        let tx = std::sync::Arc::new(StreamStats::new());
+        tx.sent(60);
+        metrics.register("studio \"a\"", Direction::Rx, Protocol::Quic, rx);
+        metrics.register("monitor", Direction::Tx, Protocol::Udp, tx);
+        let text = metrics.render();
+        let rx_labels = "stream=\"studio \\\"a\\\"\",direction=\"rx\",protocol=\"quic\"";
+        let tx_labels = "stream=\"monitor\",direction=\"tx\",protocol=\"udp\"";
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"# TYPE paradise_stream_packets_received_total counter"));
+        assert!(lines.contains(&&*format!(
+            "paradise_stream_received_bytes_total{{{}}} 200",
+            rx_labels
+        )));
+        assert!(lines.contains(&&*format!(
+            "paradise_stream_packets_sent_total{{{}}} 1",
+            tx_labels
+        )));
+        assert!(lines.contains(&&*format!(
+            "paradise_stream_connection_state{{{},state=\"connected\"}} 1",
+            rx_labels
+        )));
+        assert!(lines.contains(&&*format!(
+            "paradise_stream_connection_state{{{},state=\"backoff\"}} 0",
+            rx_labels
+        )));
+        // Receive-side metrics aren't kept for senders, and UDP has no
+        // connection.
+        assert!(!text.contains(&format!("underruns_total{{{}}}", tx_labels)));
+        assert!(!text.contains(&format!("connection_state{{{}", tx_labels)));
+        metrics.unregister("monitor", Direction::Tx);
+        assert!(!metrics.render().contains("monitor"));
+    }
+
+    async fn get(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut sock = TcpStream::connect(addr).await.unwrap();
+        sock.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        sock.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn serves_metrics() {
+        let metrics = std::sync::Arc::new(Metrics::new());
+        metrics.register(
+            "studio",
+            Direction::Rx,
+            Protocol::Tcp,
+            std::sync::Arc::new(StreamStats::new()),
+        );
+        let server = MetricsServer::new("127.0.0.1:0".parse().unwrap(), metrics).unwrap();
+        let addr = server.local_addr();
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.contains("paradise_stream_underruns_total{stream=\"studio\""));
+        let response = get(addr, "GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get(addr, "POST /metrics HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        server.shutdown().await;
+    }
+}
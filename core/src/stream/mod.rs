@@ -1,5 +1,27 @@
-pub mod buffer;
+pub use crate::buffer;
+
+pub mod addr;
+pub mod backoff;
+pub mod catalog;
+pub mod control;
+pub mod crypto;
+pub mod dns;
+pub mod fec;
+pub mod fingerprint;
+pub mod framing;
+pub mod header;
+pub mod mdns;
+pub mod metrics;
+pub mod multicast;
+pub mod netem;
+pub mod quic;
+pub mod relay;
+pub mod rtp;
 pub mod rx;
+pub mod sap;
+pub mod sdp;
+pub mod shutdown;
+pub mod stats;
 pub mod tx;
 
 fn cycle(parity: &std::sync::atomic::AtomicUsize) -> usize {
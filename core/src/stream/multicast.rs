@@ -0,0 +1,249 @@
+//! Sockets for datagram streams sent to, or received from, IPv4 and
+//! IPv6 multicast groups. A single sender can then feed any number of
+//! receivers on the LAN, each of which joins the group.
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// Multicast settings of a stream, e.g.
+///
+/// ```yaml
+/// multicast:
+///   ttl: 4
+///   interface: 192.168.1.20
+/// ```
+///
+/// They only apply when the stream's address is a multicast group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MulticastConfig {
+    /// Number of routers datagrams may cross. The default of 1 keeps
+    /// them on the local network.
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+
+    /// Network interface to send on or join the group on, instead of
+    /// the one the system picks.
+    #[serde(default)]
+    pub interface: Option<Interface>,
+}
+
+fn default_ttl() -> u32 {
+    1
+}
+
+impl Default for MulticastConfig {
+    fn default() -> Self {
+        Self {
+            ttl: default_ttl(),
+            interface: None,
+        }
+    }
+}
+
+/// A network interface, identified by one of its IPv4 addresses for
+/// IPv4 groups or by its index (see `ip link`) for IPv6 groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Interface {
+    Index(u32),
+    Addr(Ipv4Addr),
+}
+
+impl std::str::FromStr for Interface {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(index) => Ok(Interface::Index(index)),
+            Err(_) => s.parse().map(Interface::Addr),
+        }
+    }
+}
+
+impl std::fmt::Display for Interface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Interface::Index(index) => write!(f, "{}", index),
+            Interface::Addr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Binds a socket to send datagrams to `dest`, configured for its
+/// multicast group if it is one.
+pub(crate) fn sender(dest: SocketAddr, config: &MulticastConfig) -> std::io::Result<UdpSocket> {
+    let sock = Socket::new(domain(dest), Type::dgram(), Some(Protocol::udp()))?;
+    let any: SocketAddr = match dest {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    sock.bind(&SockAddr::from(any))?;
+    match dest.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            sock.set_multicast_ttl_v4(config.ttl)?;
+            match config.interface {
+                Some(Interface::Addr(addr)) => sock.set_multicast_if_v4(&addr)?,
+                Some(Interface::Index(_)) => return Err(ipv4_index()),
+                None => {}
+            }
+        }
+        IpAddr::V6(group) if group.is_multicast() => {
+            sock.set_multicast_hops_v6(config.ttl)?;
+            match config.interface {
+                Some(Interface::Index(index)) => sock.set_multicast_if_v6(index)?,
+                Some(Interface::Addr(_)) => return Err(ipv6_addr()),
+                None => {}
+            }
+        }
+        _ => {}
+    }
+    Ok(sock.into_udp_socket())
+}
+
+/// Binds a socket to receive datagrams sent to `addr`, joining its
+/// multicast group if it is one. Other sockets on the same host may
+/// join the group on the same port, e.g. to record a stream that is
+/// also being played. The socket sends to the group with the config's
+/// TTL and interface, e.g. to answer queries from its members.
+pub(crate) fn receiver(addr: SocketAddr, config: &MulticastConfig) -> std::io::Result<UdpSocket> {
+    if !addr.ip().is_multicast() {
+        return UdpSocket::bind(addr);
+    }
+    let sock = Socket::new(domain(addr), Type::dgram(), Some(Protocol::udp()))?;
+    sock.set_reuse_address(true)?;
+    #[cfg(unix)]
+    sock.set_reuse_port(true)?;
+    // Binding to the group filters out datagrams sent to other
+    // groups on the same port. Windows only binds to interfaces.
+    #[cfg(unix)]
+    let bind = addr;
+    #[cfg(not(unix))]
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, addr.port()).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, addr.port()).into(),
+    };
+    sock.bind(&SockAddr::from(bind))?;
+    match addr.ip() {
+        IpAddr::V4(group) => {
+            let interface = match config.interface {
+                Some(Interface::Addr(addr)) => addr,
+                Some(Interface::Index(_)) => return Err(ipv4_index()),
+                None => Ipv4Addr::UNSPECIFIED,
+            };
+            sock.join_multicast_v4(&group, &interface)?;
+            sock.set_multicast_ttl_v4(config.ttl)?;
+            if !interface.is_unspecified() {
+                sock.set_multicast_if_v4(&interface)?;
+            }
+        }
+        IpAddr::V6(group) => {
+            let interface = match config.interface {
+                Some(Interface::Index(index)) => index,
+                Some(Interface::Addr(_)) => return Err(ipv6_addr()),
+                None => 0,
+            };
+            sock.join_multicast_v6(&group, interface)?;
+            sock.set_multicast_hops_v6(config.ttl)?;
+            if interface != 0 {
+                sock.set_multicast_if_v6(interface)?;
+            }
+        }
+    }
+    Ok(sock.into_udp_socket())
+}
+
+/// Address datagrams to `dest` are sent from, as a receiver sees it.
+/// Unspecified if there's no route.
+pub(crate) fn source_addr(dest: SocketAddr, config: &MulticastConfig) -> IpAddr {
+    if let (IpAddr::V4(group), Some(Interface::Addr(addr))) = (dest.ip(), config.interface) {
+        if group.is_multicast() {
+            return IpAddr::V4(addr);
+        }
+    }
+    // Connecting a datagram socket sends nothing, but picks the
+    // source address from the routing table.
+    let any: SocketAddr = match dest {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    UdpSocket::bind(any)
+        .and_then(|sock| {
+            sock.connect(dest)?;
+            sock.local_addr()
+        })
+        .map(|addr| addr.ip())
+        .unwrap_or_else(|_| any.ip())
+}
+
+fn domain(addr: SocketAddr) -> Domain {
+    match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    }
+}
+
+fn ipv4_index() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "IPv4 multicast interfaces are given by address",
+    )
+}
+
+fn ipv6_addr() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "IPv6 multicast interfaces are given by index",
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_config() {
+        let config: MulticastConfig = serde_yaml::from_str("interface: 192.168.1.20").unwrap();
+        assert_eq!(config.ttl, 1);
+        assert_eq!(
+            config.interface,
+            Some(Interface::Addr(Ipv4Addr::new(192, 168, 1, 20)))
+        );
+        let config: MulticastConfig = serde_yaml::from_str("ttl: 4\ninterface: 3").unwrap();
+        assert_eq!(config.ttl, 4);
+        assert_eq!(config.interface, Some(Interface::Index(3)));
+        assert_eq!("3".parse(), Ok(Interface::Index(3)));
+        assert!("eth0".parse::<Interface>().is_err());
+    }
+
+    #[test]
+    fn rejects_mismatched_interface() {
+        let config = MulticastConfig {
+            interface: Some(Interface::Index(1)),
+            ..Default::default()
+        };
+        let group = "239.69.1.1:5004".parse().unwrap();
+        assert_eq!(
+            sender(group, &config).unwrap_err().kind(),
+            std::io::ErrorKind::InvalidInput
+        );
+        // Unicast destinations ignore the multicast settings.
+        sender("127.0.0.1:5004".parse().unwrap(), &config).unwrap();
+    }
+
+    #[test]
+    fn finds_source_addr() {
+        let config = MulticastConfig::default();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(
+            source_addr("127.0.0.1:5004".parse().unwrap(), &config),
+            localhost
+        );
+        let config = MulticastConfig {
+            interface: Some(Interface::Addr(Ipv4Addr::LOCALHOST)),
+            ..Default::default()
+        };
+        assert_eq!(
+            source_addr("239.69.1.1:5004".parse().unwrap(), &config),
+            localhost
+        );
+    }
+}
@@ -0,0 +1,498 @@
+//! Network impairment for testing streams on a single machine, after
+//! the Linux `netem` queueing discipline.
+//!
+//! A proxy sits between a sender and a receiver on local ports and
+//! holds every packet back until its delivery time, which the
+//! configured delay, jitter and bandwidth cap decide. Packets may also
+//! be dropped, either independently or in bursts following the
+//! Gilbert-Elliott model, duplicated, or sent ahead of those before
+//! them. Each direction is impaired independently.
+//!
+//! TCP carries a byte stream, so nothing is ever dropped, duplicated
+//! or reordered. A loss instead stalls the stream for as long as a
+//! retransmission would take.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::{Duration, Instant};
+
+pub mod tcp;
+pub mod udp;
+
+/// How long a lost TCP segment holds up the stream: Linux's minimum
+/// retransmission timeout.
+pub const RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+
+/// Most audio queued behind a bandwidth cap before packets are
+/// dropped, like a router's buffer overflowing.
+const MAX_BACKLOG: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetemConfig {
+    /// Fraction of packets dropped, from 0 to 1.
+    pub loss: f64,
+    /// Average number of packets lost in a row. Losses are
+    /// independent of each other if unset.
+    pub loss_burst: Option<f64>,
+    pub delay: Duration,
+    /// Packets are delayed by up to this much more or less than
+    /// `delay`, uniformly distributed.
+    pub jitter: Duration,
+    /// Fraction of packets sent without waiting out the delay, so
+    /// they overtake the packets before them.
+    pub reorder: f64,
+    /// Fraction of packets delivered twice.
+    pub duplicate: f64,
+    /// Capacity of the link in bits per second. Unlimited if unset.
+    pub rate: Option<u64>,
+    /// Seeds the random choices, to repeat a run exactly. Differs
+    /// every run if unset.
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetemError {
+    InvalidProbability {
+        name: &'static str,
+        value: f64,
+    },
+    /// Bursts are shorter than a packet, or too short to lose as
+    /// many packets as configured.
+    InvalidBurst {
+        burst: f64,
+        loss: f64,
+    },
+    /// Packets are only reordered by skipping the delay.
+    ReorderWithoutDelay,
+    InvalidRate,
+}
+
+impl std::fmt::Display for NetemError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetemError::InvalidProbability { name, value } => {
+                write!(f, "{} must be between 0 and 1, got {}", name, value)
+            }
+            NetemError::InvalidBurst { burst, loss } => write!(
+                f,
+                "loss bursts averaging {} packets can't drop {}% of packets",
+                burst,
+                loss * 100.0
+            ),
+            NetemError::ReorderWithoutDelay => write!(f, "reordering needs a delay"),
+            NetemError::InvalidRate => write!(f, "rate must be positive"),
+        }
+    }
+}
+
+impl std::error::Error for NetemError {}
+
+impl NetemConfig {
+    pub fn validate(&self) -> Result<(), NetemError> {
+        for &(name, value) in &[
+            ("loss", self.loss),
+            ("reorder", self.reorder),
+            ("duplicate", self.duplicate),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(NetemError::InvalidProbability { name, value });
+            }
+        }
+        if let Some(burst) = self.loss_burst {
+            // The chance of a burst starting can't exceed 1.
+            if burst.is_nan()
+                || burst < 1.0
+                || (self.loss < 1.0 && self.loss / (1.0 - self.loss) > burst)
+            {
+                return Err(NetemError::InvalidBurst {
+                    burst,
+                    loss: self.loss,
+                });
+            }
+        }
+        if self.reorder > 0.0 && self.delay == Duration::from_secs(0) {
+            return Err(NetemError::ReorderWithoutDelay);
+        }
+        if self.rate == Some(0) {
+            return Err(NetemError::InvalidRate);
+        }
+        Ok(())
+    }
+}
+
+/// What happened to the packets passing one way through a proxy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub delivered: u64,
+    /// Packets dropped, or for TCP the segments retransmitted.
+    pub lost: u64,
+    /// Packets dropped because too much was queued behind the
+    /// bandwidth cap.
+    pub overflows: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+/// Decides the fate of the packets going one way through a proxy.
+pub struct Impairment {
+    config: NetemConfig,
+    /// Xorshift state.
+    rng: u64,
+    /// Whether the burst loss model is in its lossy state.
+    bad: bool,
+    /// When the link is done sending what's queued on it.
+    link_free: Instant,
+    /// When the last segment of a byte stream is delivered.
+    last_delivery: Instant,
+    counters: Counters,
+}
+
+impl Impairment {
+    pub fn new(config: &NetemConfig) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            use std::hash::{BuildHasher, Hasher};
+            let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+            hasher.write_u32(std::process::id());
+            hasher.finish()
+        });
+        let now = Instant::now();
+        Self {
+            config: config.clone(),
+            // Xorshift must not start at zero.
+            rng: seed | 1,
+            bad: false,
+            link_free: now,
+            last_delivery: now,
+            counters: Counters::default(),
+        }
+    }
+
+    pub fn counters(&self) -> Counters {
+        self.counters
+    }
+
+    /// Decides the fate of a datagram of `len` bytes that arrived at
+    /// `now`, returning when each copy of it is to be delivered. Empty
+    /// if it's dropped.
+    pub fn packet(&mut self, len: usize, now: Instant) -> Vec<Instant> {
+        if self.lose() {
+            self.counters.lost += 1;
+            return Vec::new();
+        }
+        let sent = match self.transmit(len, now, true) {
+            Some(sent) => sent,
+            None => {
+                self.counters.overflows += 1;
+                return Vec::new();
+            }
+        };
+        let at = if self.chance(self.config.reorder) {
+            self.counters.reordered += 1;
+            sent
+        } else {
+            sent + self.delay()
+        };
+        self.counters.delivered += 1;
+        if self.chance(self.config.duplicate) {
+            self.counters.duplicated += 1;
+            return vec![at, at];
+        }
+        vec![at]
+    }
+
+    /// Decides when `len` bytes of a byte stream that arrived at `now`
+    /// are to be delivered. Segments are delivered in order, and a
+    /// lost one holds up the stream for [`RETRANSMIT_DELAY`].
+    pub fn segment(&mut self, len: usize, now: Instant) -> Instant {
+        let mut at = self.transmit(len, now, false).unwrap() + self.delay();
+        if self.lose() {
+            self.counters.lost += 1;
+            at += RETRANSMIT_DELAY;
+        }
+        at = std::cmp::max(at, self.last_delivery);
+        self.last_delivery = at;
+        self.counters.delivered += 1;
+        at
+    }
+
+    /// When a packet is done being sent over the link, or `None` if
+    /// the backlog is full and `drop` is set.
+    fn transmit(&mut self, len: usize, now: Instant, drop: bool) -> Option<Instant> {
+        let rate = match self.config.rate {
+            Some(rate) => rate,
+            None => return Some(now),
+        };
+        let start = std::cmp::max(now, self.link_free);
+        if drop && start - now > MAX_BACKLOG {
+            return None;
+        }
+        self.link_free = start + Duration::from_secs_f64(len as f64 * 8.0 / rate as f64);
+        Some(self.link_free)
+    }
+
+    fn delay(&mut self) -> Duration {
+        let delay = self.config.delay.as_secs_f64();
+        let jitter = self.config.jitter.as_secs_f64() * (2.0 * self.uniform() - 1.0);
+        Duration::from_secs_f64((delay + jitter).max(0.0))
+    }
+
+    fn lose(&mut self) -> bool {
+        let loss = self.config.loss;
+        match self.config.loss_burst {
+            Some(_) if loss >= 1.0 => true,
+            Some(burst) => {
+                // Stay in the lossy state for `burst` packets on
+                // average, and enter it often enough to lose `loss`
+                // of them overall.
+                let exit = 1.0 / burst;
+                let enter = loss * exit / (1.0 - loss);
+                self.bad = match self.bad {
+                    true => !self.chance(exit),
+                    false => self.chance(enter),
+                };
+                self.bad
+            }
+            None => self.chance(loss),
+        }
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.uniform() < probability
+    }
+
+    /// Uniformly distributed in [0, 1).
+    fn uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A packet, when it's due, and the order it arrived in.
+type Queued = Reverse<(Instant, u64, Option<Vec<u8>>)>;
+
+/// Packets waiting to be delivered. `None` stands for the end of a
+/// byte stream, which is delayed like the data before it.
+pub(crate) struct DelayQueue {
+    heap: BinaryHeap<Queued>,
+    /// Breaks ties between packets due at the same time, so they
+    /// leave in the order they arrived.
+    next_seq: u64,
+    /// Bytes waiting.
+    bytes: usize,
+}
+
+impl DelayQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+            bytes: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, at: Instant, packet: Option<Vec<u8>>) {
+        self.bytes += packet.as_ref().map_or(0, Vec::len);
+        self.heap.push(Reverse((at, self.next_seq, packet)));
+        self.next_seq += 1;
+    }
+
+    /// When the next packet is due, if any are waiting.
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((at, _, _))| *at)
+    }
+
+    /// Takes the next packet if it's due by `now`.
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Option<Option<Vec<u8>>> {
+        if self.next_due()? > now {
+            return None;
+        }
+        let Reverse((_, _, packet)) = self.heap.pop()?;
+        self.bytes -= packet.as_ref().map_or(0, Vec::len);
+        Some(packet)
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// The earlier of two optional times.
+pub(crate) fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Waits until `at`, or forever if there's nothing to wait for.
+pub(crate) async fn until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::delay_until(tokio::time::Instant::from_std(at)).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> NetemConfig {
+        NetemConfig {
+            seed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validates() {
+        assert!(config().validate().is_ok());
+        let invalid = [
+            NetemConfig {
+                loss: 1.5,
+                ..config()
+            },
+            NetemConfig {
+                duplicate: -0.1,
+                ..config()
+            },
+            NetemConfig {
+                loss_burst: Some(0.5),
+                ..config()
+            },
+            // Losing 90% needs bursts of 9 packets at least.
+            NetemConfig {
+                loss: 0.9,
+                loss_burst: Some(5.0),
+                ..config()
+            },
+            NetemConfig {
+                reorder: 0.1,
+                ..config()
+            },
+            NetemConfig {
+                rate: Some(0),
+                ..config()
+            },
+        ];
+        for config in &invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn loses_packets() {
+        let now = Instant::now();
+        let mut random = Impairment::new(&NetemConfig {
+            loss: 0.2,
+            ..config()
+        });
+        let lost = (0..10_000)
+            .filter(|_| random.packet(100, now).is_empty())
+            .count();
+        assert!((1800..2200).contains(&lost), "lost {}", lost);
+        assert_eq!(random.counters().lost, lost as u64);
+        // Bursts average 4 packets, and still drop 20% overall.
+        let mut bursty = Impairment::new(&NetemConfig {
+            loss: 0.2,
+            loss_burst: Some(4.0),
+            ..config()
+        });
+        let fates = (0..100_000)
+            .map(|_| bursty.packet(100, now).is_empty())
+            .collect::<Vec<_>>();
+        let lost = fates.iter().filter(|&&lost| lost).count();
+        let bursts = fates.windows(2).filter(|w| !w[0] && w[1]).count();
+        assert!((18_000..22_000).contains(&lost), "lost {}", lost);
+        let burst = lost as f64 / bursts as f64;
+        assert!((3.5..4.5).contains(&burst), "bursts of {}", burst);
+    }
+
+    #[test]
+    fn delays_and_reorders() {
+        let now = Instant::now();
+        let delay = Duration::from_millis(50);
+        let jitter = Duration::from_millis(10);
+        let mut impairment = Impairment::new(&NetemConfig {
+            delay,
+            jitter,
+            reorder: 0.25,
+            duplicate: 0.1,
+            ..config()
+        });
+        let mut copies = 0;
+        for _ in 0..1000 {
+            let at = impairment.packet(100, now);
+            for at in &at {
+                assert!(*at == now || (*at >= now + delay - jitter && *at <= now + delay + jitter));
+            }
+            copies += at.len();
+        }
+        let counters = impairment.counters();
+        assert_eq!(counters.delivered, 1000);
+        assert_eq!(copies as u64, 1000 + counters.duplicated);
+        assert!((200..300).contains(&counters.reordered));
+        assert!((50..150).contains(&counters.duplicated));
+    }
+
+    #[test]
+    fn caps_bandwidth() {
+        // 125 bytes take 10 ms at 100 kbit/s.
+        let mut impairment = Impairment::new(&NetemConfig {
+            rate: Some(100_000),
+            ..config()
+        });
+        let now = Instant::now();
+        assert_eq!(
+            impairment.packet(125, now),
+            vec![now + Duration::from_millis(10)]
+        );
+        assert_eq!(
+            impairment.packet(125, now),
+            vec![now + Duration::from_millis(20)]
+        );
+        // A second's worth of backlog overflows.
+        let delivered = (0..200)
+            .filter(|_| !impairment.packet(125, now).is_empty())
+            .count();
+        assert_eq!(delivered, 99);
+        assert_eq!(impairment.counters().overflows, 101);
+    }
+
+    #[test]
+    fn keeps_segments_in_order() {
+        let now = Instant::now();
+        let mut impairment = Impairment::new(&NetemConfig {
+            loss: 0.1,
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(20),
+            ..config()
+        });
+        let mut last = now;
+        for _ in 0..1000 {
+            let at = impairment.segment(100, now);
+            assert!(at >= last);
+            last = at;
+        }
+        assert!(impairment.counters().lost > 0);
+        assert!(last >= now + RETRANSMIT_DELAY);
+    }
+
+    #[test]
+    fn queue_delivers_in_time_order() {
+        let now = Instant::now();
+        let mut queue = DelayQueue::new();
+        queue.push(now + Duration::from_millis(2), Some(vec![2]));
+        queue.push(now + Duration::from_millis(1), Some(vec![1, 1]));
+        queue.push(now + Duration::from_millis(2), None);
+        assert_eq!(queue.bytes(), 3);
+        assert_eq!(queue.next_due(), Some(now + Duration::from_millis(1)));
+        assert_eq!(queue.pop_due(now), None);
+        let later = now + Duration::from_millis(2);
+        assert_eq!(queue.pop_due(later), Some(Some(vec![1, 1])));
+        assert_eq!(queue.pop_due(later), Some(Some(vec![2])));
+        assert_eq!(queue.pop_due(later), Some(None));
+        assert_eq!(queue.pop_due(later), None);
+        assert_eq!(queue.bytes(), 0);
+    }
+}
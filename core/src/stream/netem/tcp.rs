@@ -0,0 +1,315 @@
+//! Impairing proxy for TCP. One connection is relayed at a time, and
+//! a new one replaces it, as with `TcpRxStream`.
+use super::{earliest, until, DelayQueue, Impairment, NetemConfig};
+use crate::stream::backoff::Backoff;
+use crate::stream::shutdown::Shutdown;
+use std::time::Instant;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+const BUFFER_SIZE: usize = 65_536;
+
+/// Data held back in either direction before the proxy stops reading,
+/// which pushes back on the sender as a slow link would.
+const MAX_QUEUED: usize = 1 << 20;
+
+/// Relays connections to a local port to `upstream`, impairing the
+/// data both ways.
+pub struct TcpProxy {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+}
+
+/// A connection being relayed.
+struct Relay {
+    src: std::net::SocketAddr,
+    client: TcpStream,
+    upstream: TcpStream,
+    outbound: Impairment,
+    inbound: Impairment,
+    to_upstream: DelayQueue,
+    to_client: DelayQueue,
+    /// Whether each side is still sending.
+    client_open: bool,
+    upstream_open: bool,
+}
+
+/// What woke the proxy task.
+enum Event {
+    Accepted(std::io::Result<(TcpStream, std::net::SocketAddr)>),
+    Client(std::io::Result<usize>),
+    Upstream(std::io::Result<usize>),
+    Due,
+}
+
+impl TcpProxy {
+    /// Listens on `addr`. Must be called from within a tokio runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        upstream: std::net::SocketAddr,
+        config: &NetemConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        config
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let config = config.clone();
+        let shutdown = Shutdown::spawn(move |stop| Self::entry(listener, upstream, config, stop));
+        Ok(std::sync::Arc::new(Self { addr, shutdown }))
+    }
+
+    /// Address the proxy is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Stops relaying and waits until the listener and any
+    /// connection are closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        listener: std::net::TcpListener,
+        upstream: std::net::SocketAddr,
+        config: NetemConfig,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("netem: {}", e);
+                return;
+            }
+        };
+        let mut relay: Option<Relay> = None;
+        let mut backoff = Backoff::default();
+        let mut accept_at = tokio::time::Instant::now();
+        let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+        let mut reply: Vec<u8> = vec![0; BUFFER_SIZE];
+        loop {
+            if let Some(r) = &mut relay {
+                if let Err(e) = r.deliver(Instant::now()).await {
+                    warn!("netem: connection from {} lost: {}", r.src, e);
+                    relay = None;
+                } else if r.is_closed() {
+                    info!("netem: {} disconnected", r.src);
+                    relay = None;
+                }
+            }
+            let event = {
+                let (client, upstream, due) = match &mut relay {
+                    Some(r) => {
+                        let due = earliest(r.to_upstream.next_due(), r.to_client.next_due());
+                        let (read_client, read_upstream) = (r.read_client(), r.read_upstream());
+                        (
+                            Some(&mut r.client).filter(|_| read_client),
+                            Some(&mut r.upstream).filter(|_| read_upstream),
+                            due,
+                        )
+                    }
+                    None => (None, None, None),
+                };
+                tokio::select! {
+                    // Stopped, or the proxy was dropped.
+                    _ = &mut stop => break,
+                    result = accept(&mut listener, accept_at) => Event::Accepted(result),
+                    result = read(client, &mut buf) => Event::Client(result),
+                    result = read(upstream, &mut reply) => Event::Upstream(result),
+                    _ = until(due) => Event::Due,
+                }
+            };
+            let now = Instant::now();
+            match event {
+                Event::Accepted(Ok((client, src))) => {
+                    backoff.reset();
+                    let result = tokio::select! {
+                        _ = &mut stop => break,
+                        result = TcpStream::connect(upstream) => result,
+                    };
+                    let upstream_sock = match result {
+                        Ok(sock) => sock,
+                        Err(e) => {
+                            warn!("netem: connect to {}: {}", upstream, e);
+                            continue;
+                        }
+                    };
+                    for sock in &[&client, &upstream_sock] {
+                        if let Err(e) = sock.set_nodelay(true) {
+                            warn!("netem: {}", e);
+                        }
+                    }
+                    match &relay {
+                        Some(old) => info!("netem: {} replaces {}", src, old.src),
+                        None => info!("netem: relaying {} to {}", src, upstream),
+                    }
+                    relay = Some(Relay {
+                        src,
+                        client,
+                        upstream: upstream_sock,
+                        outbound: Impairment::new(&config),
+                        inbound: Impairment::new(&config),
+                        to_upstream: DelayQueue::new(),
+                        to_client: DelayQueue::new(),
+                        client_open: true,
+                        upstream_open: true,
+                    });
+                }
+                Event::Accepted(Err(e)) => {
+                    let delay = backoff.next_delay();
+                    warn!("netem: accept: {}, retrying in {:?}", e, delay);
+                    accept_at = tokio::time::Instant::now() + delay;
+                }
+                Event::Client(result) => {
+                    let r = relay.as_mut().unwrap();
+                    match result {
+                        Ok(amt) if amt > 0 => {
+                            let at = r.outbound.segment(amt, now);
+                            r.to_upstream.push(at, Some(buf[..amt].to_vec()));
+                        }
+                        // The end of the stream is passed on once the
+                        // data before it has been.
+                        result => {
+                            if let Err(e) = result {
+                                warn!("netem: client: {}", e);
+                            }
+                            r.client_open = false;
+                            let at = r.outbound.segment(0, now);
+                            r.to_upstream.push(at, None);
+                        }
+                    }
+                }
+                Event::Upstream(result) => {
+                    let r = relay.as_mut().unwrap();
+                    match result {
+                        Ok(amt) if amt > 0 => {
+                            let at = r.inbound.segment(amt, now);
+                            r.to_client.push(at, Some(reply[..amt].to_vec()));
+                        }
+                        result => {
+                            if let Err(e) = result {
+                                warn!("netem: upstream: {}", e);
+                            }
+                            r.upstream_open = false;
+                            let at = r.inbound.segment(0, now);
+                            r.to_client.push(at, None);
+                        }
+                    }
+                }
+                Event::Due => {}
+            }
+        }
+    }
+}
+
+impl Relay {
+    /// Whether to read from the client: only while it's sending and
+    /// not too much is held back already.
+    fn read_client(&self) -> bool {
+        self.client_open && self.to_upstream.bytes() < MAX_QUEUED
+    }
+
+    fn read_upstream(&self) -> bool {
+        self.upstream_open && self.to_client.bytes() < MAX_QUEUED
+    }
+
+    /// Writes out whatever is due by `now`.
+    async fn deliver(&mut self, now: Instant) -> std::io::Result<()> {
+        while let Some(packet) = self.to_upstream.pop_due(now) {
+            match packet {
+                Some(data) => self.upstream.write_all(&data).await?,
+                None => self.upstream.shutdown(std::net::Shutdown::Write)?,
+            }
+        }
+        while let Some(packet) = self.to_client.pop_due(now) {
+            match packet {
+                Some(data) => self.client.write_all(&data).await?,
+                None => self.client.shutdown(std::net::Shutdown::Write)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Both sides have finished sending and everything was delivered.
+    fn is_closed(&self) -> bool {
+        !self.client_open
+            && !self.upstream_open
+            && self.to_upstream.next_due().is_none()
+            && self.to_client.next_due().is_none()
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        info!("netem: outbound {:?}", self.outbound.counters());
+        info!("netem: inbound {:?}", self.inbound.counters());
+    }
+}
+
+async fn accept(
+    listener: &mut TcpListener,
+    at: tokio::time::Instant,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    tokio::time::delay_until(at).await;
+    listener.accept().await
+}
+
+/// Reads from a side of the connection, or never if it shouldn't be
+/// read from.
+async fn read(sock: Option<&mut TcpStream>, buf: &mut [u8]) -> std::io::Result<usize> {
+    match sock {
+        Some(sock) => sock.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn local() -> std::net::SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn relays_in_order() {
+        let mut upstream = TcpListener::bind(local()).await.unwrap();
+        let delay = Duration::from_millis(20);
+        let proxy = TcpProxy::new(
+            local(),
+            upstream.local_addr().unwrap(),
+            &NetemConfig {
+                loss: 0.2,
+                delay,
+                jitter: Duration::from_millis(10),
+                seed: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let echo = tokio::spawn(async move {
+            let (mut sock, _) = upstream.accept().await.unwrap();
+            let mut data = Vec::new();
+            sock.read_to_end(&mut data).await.unwrap();
+            sock.write_all(&data).await.unwrap();
+        });
+        let mut client = TcpStream::connect(proxy.local_addr()).await.unwrap();
+        let sent: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let start = Instant::now();
+        for chunk in sent.chunks(1000) {
+            client.write_all(chunk).await.unwrap();
+        }
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert!(received == sent);
+        assert!(start.elapsed() >= delay * 2);
+        echo.await.unwrap();
+        proxy.shutdown().await;
+    }
+}
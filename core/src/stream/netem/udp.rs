@@ -0,0 +1,193 @@
+//! Impairing proxy for datagram transports: UDP, RTP and QUIC.
+use super::{earliest, until, DelayQueue, Impairment, NetemConfig};
+use crate::stream::shutdown::Shutdown;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+const BUFFER_SIZE: usize = 65_536;
+
+/// Forwards datagrams from a local port to `upstream`, and the replies
+/// back to whoever sent the latest datagram, impairing both ways.
+pub struct UdpProxy {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+}
+
+/// What woke the proxy task.
+enum Event {
+    Client(std::io::Result<(usize, std::net::SocketAddr)>),
+    Upstream(std::io::Result<usize>),
+    Due,
+}
+
+impl UdpProxy {
+    /// Listens on `addr`. Must be called from within a tokio runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        upstream: std::net::SocketAddr,
+        config: &NetemConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        config
+            .validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let sock = std::net::UdpSocket::bind(addr)?;
+        sock.set_nonblocking(true)?;
+        let addr = sock.local_addr()?;
+        // Replies come back to the port datagrams are forwarded from.
+        let local: std::net::SocketAddr = if upstream.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let forward = std::net::UdpSocket::bind(local)?;
+        forward.connect(upstream)?;
+        forward.set_nonblocking(true)?;
+        let config = config.clone();
+        let shutdown =
+            Shutdown::spawn(move |stop| Self::entry(sock, forward, upstream, config, stop));
+        Ok(std::sync::Arc::new(Self { addr, shutdown }))
+    }
+
+    /// Address the proxy is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Stops forwarding and waits until the sockets are closed.
+    /// Datagrams still held back are dropped.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        sock: std::net::UdpSocket,
+        forward: std::net::UdpSocket,
+        upstream: std::net::SocketAddr,
+        config: NetemConfig,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let (mut sock, mut forward) =
+            match (UdpSocket::from_std(sock), UdpSocket::from_std(forward)) {
+                (Ok(sock), Ok(forward)) => (sock, forward),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("netem: {}", e);
+                    return;
+                }
+            };
+        let mut client: Option<std::net::SocketAddr> = None;
+        let (mut outbound, mut inbound) = (Impairment::new(&config), Impairment::new(&config));
+        let (mut to_upstream, mut to_client) = (DelayQueue::new(), DelayQueue::new());
+        let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+        let mut reply: Vec<u8> = vec![0; BUFFER_SIZE];
+        loop {
+            let now = Instant::now();
+            while let Some(Some(packet)) = to_upstream.pop_due(now) {
+                if let Err(e) = forward.send(&packet).await {
+                    // e.g. nothing is listening upstream yet.
+                    debug!("netem: send to {}: {}", upstream, e);
+                }
+            }
+            while let Some(Some(packet)) = to_client.pop_due(now) {
+                if let Some(client) = client {
+                    if let Err(e) = sock.send_to(&packet, &client).await {
+                        debug!("netem: send to {}: {}", client, e);
+                    }
+                }
+            }
+            let due = earliest(to_upstream.next_due(), to_client.next_due());
+            let event = tokio::select! {
+                // Stopped, or the proxy was dropped.
+                _ = &mut stop => break,
+                result = sock.recv_from(&mut buf[..]) => Event::Client(result),
+                result = forward.recv(&mut reply[..]) => Event::Upstream(result),
+                _ = until(due) => Event::Due,
+            };
+            let now = Instant::now();
+            match event {
+                Event::Client(Ok((amt, src))) => {
+                    if client != Some(src) {
+                        info!("netem: forwarding {} to {}", src, upstream);
+                        client = Some(src);
+                    }
+                    for at in outbound.packet(amt, now) {
+                        to_upstream.push(at, Some(buf[..amt].to_vec()));
+                    }
+                }
+                Event::Upstream(Ok(amt)) => {
+                    for at in inbound.packet(amt, now) {
+                        to_client.push(at, Some(reply[..amt].to_vec()));
+                    }
+                }
+                // Connected sockets report ICMP errors for earlier
+                // datagrams, e.g. while upstream isn't listening.
+                Event::Client(Err(e)) | Event::Upstream(Err(e)) => debug!("netem: {}", e),
+                Event::Due => {}
+            }
+        }
+        info!("netem: outbound {:?}", outbound.counters());
+        info!("netem: inbound {:?}", inbound.counters());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn local() -> std::net::SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn delays_both_ways() {
+        let mut upstream = UdpSocket::bind(local()).await.unwrap();
+        let delay = Duration::from_millis(30);
+        let proxy = UdpProxy::new(
+            local(),
+            upstream.local_addr().unwrap(),
+            &NetemConfig {
+                delay,
+                seed: Some(1),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut client = UdpSocket::bind(local()).await.unwrap();
+        let start = Instant::now();
+        client.send_to(b"ping", &proxy.local_addr()).await.unwrap();
+        let mut buf = [0; 16];
+        let (amt, src) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..amt], b"ping");
+        assert!(start.elapsed() >= delay);
+        upstream.send_to(b"pong", &src).await.unwrap();
+        let amt = client.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..amt], b"pong");
+        assert!(start.elapsed() >= delay * 2);
+        proxy.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn drops_everything() {
+        let mut upstream = UdpSocket::bind(local()).await.unwrap();
+        let proxy = UdpProxy::new(
+            local(),
+            upstream.local_addr().unwrap(),
+            &NetemConfig {
+                loss: 1.0,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut client = UdpSocket::bind(local()).await.unwrap();
+        for _ in 0..10 {
+            client.send_to(b"ping", &proxy.local_addr()).await.unwrap();
+        }
+        let mut buf = [0; 16];
+        let result =
+            tokio::time::timeout(Duration::from_millis(100), upstream.recv_from(&mut buf)).await;
+        assert!(result.is_err());
+        proxy.shutdown().await;
+    }
+}
@@ -0,0 +1,354 @@
+//! Endpoint configuration shared by the QUIC streams.
+//!
+//! Audio travels in unreliable QUIC datagrams, so late packets are
+//! dropped instead of retransmitted, while the connection itself
+//! provides encryption, authentication and a reliable control stream
+//! (see [`super::control`]).
+use super::fingerprint::Fingerprint;
+use quinn::{
+    Certificate, CertificateChain, ClientConfig, ClientConfigBuilder, PrivateKey, ServerConfig,
+    ServerConfigBuilder, TransportConfig,
+};
+
+/// Application protocol negotiated by both ends.
+pub const ALPN: &[&[u8]] = &[b"paradise/1"];
+
+/// Server name used when a destination doesn't specify one. The
+/// ephemeral certificate of a listener without TLS settings is
+/// issued for it.
+pub const DEFAULT_SERVER_NAME: &str = "localhost";
+
+/// TLS settings of a QUIC listener or destination, e.g.
+///
+/// ```yaml
+/// tls:
+///   cert: /etc/paradise/cert.pem
+///   key: /etc/paradise/key.pem
+///   cacert: /etc/paradise/ca.pem
+/// ```
+///
+/// A destination verifies the listener against `cacert`, or the
+/// system's web PKI roots if it isn't set, and presents `cert` for
+/// mutual TLS if it is set. A listener with `cacert` or `pins` only
+/// accepts clients presenting a matching certificate. Certificates
+/// and keys may be PEM or DER encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TLS {
+    /// Certificate authority the peer's certificate must chain to.
+    pub cacert: Option<String>,
+    /// Certificate chain presented to the peer, leaf first. A
+    /// listener without one presents an ephemeral self-signed
+    /// certificate.
+    pub cert: Option<String>,
+    /// Private key for `cert`.
+    pub key: Option<String>,
+    /// SHA-256 fingerprints of the peer certificates to accept. When
+    /// set without `cacert`, the peer's certificate is trusted only
+    /// for matching one, which suits self-signed certificates.
+    #[serde(default)]
+    pub pins: Vec<String>,
+    /// Name the listener's certificate must be valid for.
+    /// `DEFAULT_SERVER_NAME` if unset. Destinations only.
+    #[serde(rename = "serverName")]
+    pub server_name: Option<String>,
+}
+
+impl TLS {
+    /// Name a destination expects the listener's certificate to be
+    /// valid for.
+    pub fn server_name(tls: Option<&TLS>) -> &str {
+        tls.and_then(|tls| tls.server_name.as_deref())
+            .unwrap_or(DEFAULT_SERVER_NAME)
+    }
+
+    fn pins(&self) -> std::io::Result<Vec<Fingerprint>> {
+        self.pins
+            .iter()
+            .map(|pin| {
+                pin.parse()
+                    .map_err(|e| invalid_input(format!("tls.pins: {}: {}", pin, e)))
+            })
+            .collect()
+    }
+}
+
+/// Dummy certificate verifier that treats any certificate as valid.
+/// NOTE, such verification is vulnerable to MITM attacks.
+struct SkipServerVerification;
+
+impl rustls::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &rustls::RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+/// Accepts a peer whose leaf certificate is pinned, after verifying
+/// the chain against a certificate authority if there is one.
+struct PinnedVerification<V> {
+    pins: Vec<Fingerprint>,
+    inner: Option<V>,
+}
+
+impl<V> PinnedVerification<V> {
+    fn check(&self, presented_certs: &[rustls::Certificate]) -> Result<(), rustls::TLSError> {
+        let leaf = presented_certs
+            .first()
+            .ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        let fingerprint = Fingerprint::of(&leaf.0);
+        if self.pins.contains(&fingerprint) {
+            Ok(())
+        } else {
+            Err(rustls::TLSError::General(format!(
+                "certificate {} is not pinned",
+                fingerprint
+            )))
+        }
+    }
+}
+
+impl rustls::ServerCertVerifier for PinnedVerification<rustls::WebPKIVerifier> {
+    fn verify_server_cert(
+        &self,
+        roots: &rustls::RootCertStore,
+        presented_certs: &[rustls::Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        if let Some(inner) = &self.inner {
+            inner.verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        }
+        self.check(presented_certs)?;
+        Ok(rustls::ServerCertVerified::assertion())
+    }
+}
+
+impl rustls::ClientCertVerifier
+    for PinnedVerification<std::sync::Arc<dyn rustls::ClientCertVerifier>>
+{
+    fn client_auth_root_subjects(
+        &self,
+        sni: Option<&webpki::DNSName>,
+    ) -> Option<rustls::DistinguishedNames> {
+        match &self.inner {
+            Some(inner) => inner.client_auth_root_subjects(sni),
+            None => Some(rustls::DistinguishedNames::new()),
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[rustls::Certificate],
+        sni: Option<&webpki::DNSName>,
+    ) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+        if let Some(inner) = &self.inner {
+            inner.verify_client_cert(presented_certs, sni)?;
+        }
+        self.check(presented_certs)?;
+        Ok(rustls::ClientCertVerified::assertion())
+    }
+}
+
+/// Configures a listener. Without a certificate it presents an
+/// ephemeral self-signed one for `DEFAULT_SERVER_NAME`.
+pub fn server_config(tls: Option<&TLS>) -> std::io::Result<ServerConfig> {
+    let mut transport_config = TransportConfig::default();
+    transport_config.stream_window_uni(0);
+    let mut server_config = ServerConfigBuilder::new(ServerConfig {
+        transport: std::sync::Arc::new(transport_config),
+        ..Default::default()
+    });
+    server_config.protocols(ALPN);
+    let (chain, key) = match tls.and_then(|tls| tls.cert.as_ref().map(|cert| (tls, cert))) {
+        Some((tls, cert)) => {
+            let key = tls
+                .key
+                .as_ref()
+                .ok_or_else(|| invalid_input("tls.key is required with tls.cert"))?;
+            let certs = read_certs(cert)?;
+            info!(
+                "presenting certificate {} ({})",
+                cert,
+                Fingerprint::of(&certs[0].0)
+            );
+            let chain = certs
+                .iter()
+                .map(|cert| Certificate::from_der(&cert.0))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid_input(format!("{}: {}", cert, e)))?;
+            let key = PrivateKey::from_der(&read_key(key)?.0)
+                .map_err(|e| invalid_input(format!("{}: {}", key, e)))?;
+            (CertificateChain::from_certs(chain), key)
+        }
+        None => {
+            let cert = rcgen::generate_simple_self_signed(vec![DEFAULT_SERVER_NAME.into()])
+                .map_err(invalid_input)?;
+            let der = cert.serialize_der().map_err(invalid_input)?;
+            info!(
+                "presenting an ephemeral self-signed certificate ({})",
+                Fingerprint::of(&der)
+            );
+            let chain = CertificateChain::from_certs(vec![
+                Certificate::from_der(&der).map_err(invalid_input)?
+            ]);
+            let key =
+                PrivateKey::from_der(&cert.serialize_private_key_der()).map_err(invalid_input)?;
+            (chain, key)
+        }
+    };
+    server_config
+        .certificate(chain, key)
+        .map_err(invalid_input)?;
+    let mut config = server_config.build();
+    if let Some(tls) = tls {
+        let pins = tls.pins()?;
+        let inner = match &tls.cacert {
+            Some(cacert) => Some(rustls::AllowAnyAuthenticatedClient::new(read_roots(
+                cacert,
+            )?)),
+            None => None,
+        };
+        let verifier = if pins.is_empty() {
+            inner
+        } else {
+            Some(std::sync::Arc::new(PinnedVerification { pins, inner }) as _)
+        };
+        if let Some(verifier) = verifier {
+            std::sync::Arc::get_mut(&mut config.crypto)
+                .unwrap()
+                .set_client_certificate_verifier(verifier);
+        }
+    }
+    Ok(config)
+}
+
+/// Configures a destination. The listener's certificate is checked
+/// as `TLS` describes, unless `insecure` is set, in which case it
+/// isn't verified at all.
+pub fn client_config(tls: Option<&TLS>, insecure: bool) -> std::io::Result<ClientConfig> {
+    let mut builder = ClientConfigBuilder::default();
+    builder.protocols(ALPN);
+    let mut config = builder.build();
+    let tls_config: &mut rustls::ClientConfig =
+        std::sync::Arc::get_mut(&mut config.crypto).unwrap();
+    if let Some(cert) = tls.and_then(|tls| tls.cert.as_ref()) {
+        let key = tls
+            .and_then(|tls| tls.key.as_ref())
+            .ok_or_else(|| invalid_input("tls.key is required with tls.cert"))?;
+        tls_config
+            .set_single_client_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| invalid_input(format!("{}: {}", cert, e)))?;
+    }
+    if insecure {
+        warn!("not verifying server certificates");
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(std::sync::Arc::new(SkipServerVerification));
+        return Ok(config);
+    }
+    let cacert = tls.and_then(|tls| tls.cacert.as_ref());
+    match cacert {
+        Some(cacert) => tls_config.root_store = read_roots(cacert)?,
+        None => tls_config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    let pins = match tls {
+        Some(tls) => tls.pins()?,
+        None => Vec::new(),
+    };
+    if !pins.is_empty() {
+        let inner = cacert.map(|_| rustls::WebPKIVerifier::new());
+        tls_config
+            .dangerous()
+            .set_certificate_verifier(std::sync::Arc::new(PinnedVerification { pins, inner }));
+    }
+    Ok(config)
+}
+
+fn invalid_input<E>(e: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+}
+
+fn read(path: &str) -> std::io::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(11).any(|w| w == b"-----BEGIN ")
+}
+
+/// Reads every certificate in a PEM file, or the one in a DER file.
+fn read_certs(path: &str) -> std::io::Result<Vec<rustls::Certificate>> {
+    let data = read(path)?;
+    if !is_pem(&data) {
+        return Ok(vec![rustls::Certificate(data)]);
+    }
+    match rustls::internal::pemfile::certs(&mut &data[..]) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(invalid_input(format!("{}: no certificates found", path))),
+    }
+}
+
+fn read_roots(path: &str) -> std::io::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| invalid_input(format!("{}: {:?}", path, e)))?;
+    }
+    Ok(roots)
+}
+
+/// Reads a PKCS #8 or RSA private key.
+fn read_key(path: &str) -> std::io::Result<rustls::PrivateKey> {
+    let data = read(path)?;
+    if is_pem(&data) {
+        let mut keys =
+            rustls::internal::pemfile::pkcs8_private_keys(&mut &data[..]).unwrap_or_default();
+        if keys.is_empty() {
+            keys = rustls::internal::pemfile::rsa_private_keys(&mut &data[..]).unwrap_or_default();
+        }
+        keys.into_iter()
+            .next()
+            .ok_or_else(|| invalid_input(format!("{}: no private key found", path)))
+    } else {
+        Ok(rustls::PrivateKey(data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::ServerCertVerifier;
+
+    #[test]
+    fn pins_leaf_certificate() {
+        let cert = rcgen::generate_simple_self_signed(vec![DEFAULT_SERVER_NAME.into()]).unwrap();
+        let presented = vec![rustls::Certificate(cert.serialize_der().unwrap())];
+        let other = rcgen::generate_simple_self_signed(vec![DEFAULT_SERVER_NAME.into()]).unwrap();
+        let roots = rustls::RootCertStore::empty();
+        let name = webpki::DNSNameRef::try_from_ascii_str(DEFAULT_SERVER_NAME).unwrap();
+        let verify = |pins: Vec<Fingerprint>, inner: Option<rustls::WebPKIVerifier>| {
+            PinnedVerification { pins, inner }
+                .verify_server_cert(&roots, &presented, name, &[])
+                .is_ok()
+        };
+        let pin = Fingerprint::of(&presented[0].0);
+        assert!(verify(vec![pin], None));
+        assert!(!verify(
+            vec![Fingerprint::of(&other.serialize_der().unwrap())],
+            None
+        ));
+        // Pinning doesn't loosen verification against a CA.
+        assert!(!verify(vec![pin], Some(rustls::WebPKIVerifier::new())));
+    }
+}
@@ -0,0 +1,279 @@
+//! Bridges transports by playing out a received stream and sending
+//! it on to any number of destinations, e.g. plain UDP from the local
+//! device out to QUIC with TLS, or QUIC in to multicast out.
+//!
+//! The relay pulls audio from the source on its own clock, as a sound
+//! card would, so the source's jitter buffer absorbs the jitter of the
+//! incoming link and compensates for the sender's clock drift. Each
+//! destination encodes the audio as it's configured to, so one can
+//! get Opus while another gets PCM.
+use crate::channels::ChannelMap;
+use crate::sample::Sample;
+use crate::stream::rx::RxStream;
+use crate::stream::shutdown::Shutdown;
+use crate::stream::tx::TxStream;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+
+/// How often the source is played out by default.
+pub const DEFAULT_PERIOD: Duration = Duration::from_millis(5);
+
+/// Most audio played out at once after the relay task falls behind,
+/// e.g. while the machine was suspended. The rest is skipped.
+const MAX_CATCH_UP: Duration = Duration::from_millis(100);
+
+/// A stream the relay sends to, and the channels of the source it's
+/// sent.
+pub struct Destination<T> {
+    stream: std::sync::Arc<dyn TxStream<T> + Send + Sync>,
+    map: ChannelMap,
+    scratch: Vec<T>,
+}
+
+impl<T> Destination<T>
+where
+    T: Sample,
+{
+    /// Sends the channels `map` selects from the source to `stream`.
+    /// The map's width must be the source's channel count.
+    pub fn new(stream: std::sync::Arc<dyn TxStream<T> + Send + Sync>, map: ChannelMap) -> Self {
+        Self {
+            stream,
+            map,
+            scratch: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, samples: &[T]) {
+        let Self {
+            stream,
+            map,
+            scratch,
+        } = self;
+        stream.send(map.gather(samples, scratch));
+    }
+}
+
+/// Plays out a source every period and fans the audio out to the
+/// destinations.
+pub struct Relay {
+    shutdown: Shutdown,
+}
+
+impl Relay {
+    /// Relays `channels` channels of audio at `sample_rate` from
+    /// `source`, which must play out in that format, every `period`.
+    /// Nothing is sent while the source has nothing to play. Must be
+    /// called from within a tokio runtime.
+    pub fn new<T: Sample + Sync>(
+        source: std::sync::Arc<dyn RxStream<T> + Send + Sync>,
+        channels: u16,
+        sample_rate: u32,
+        destinations: Vec<Destination<T>>,
+        period: Duration,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        if channels == 0 || sample_rate == 0 || period == Duration::from_secs(0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "relay needs channels, a sample rate and a period",
+            ));
+        }
+        if let Some(dest) = destinations.iter().find(|d| d.map.width() != channels) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "destination maps {} channels, source has {}",
+                    dest.map.width(),
+                    channels
+                ),
+            ));
+        }
+        let shutdown = Shutdown::spawn(move |stop| {
+            Self::entry(source, channels, sample_rate, destinations, period, stop)
+        });
+        Ok(std::sync::Arc::new(Self { shutdown }))
+    }
+
+    /// Stops relaying and waits until the relay task has exited. The
+    /// source and destinations are left running.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry<T: Sample + Sync>(
+        source: std::sync::Arc<dyn RxStream<T> + Send + Sync>,
+        channels: u16,
+        sample_rate: u32,
+        mut destinations: Vec<Destination<T>>,
+        period: Duration,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let channels = channels as usize;
+        let max_frames = (MAX_CATCH_UP.as_secs_f64() * sample_rate as f64) as u64;
+        let mut ticks = tokio::time::interval(period);
+        let start = Instant::now();
+        // Frames played out since `start`. Counting from a fixed start
+        // keeps timer slop from adding up.
+        let mut played: u64 = 0;
+        let mut buf: Vec<T> = Vec::new();
+        loop {
+            tokio::select! {
+                // Stopped, or the relay was dropped.
+                _ = &mut stop => break,
+                _ = ticks.tick() => {}
+            }
+            let due = (start.elapsed().as_secs_f64() * sample_rate as f64) as u64;
+            let frames = due - played;
+            played = due;
+            if frames > max_frames {
+                warn!(
+                    "relay: fell behind, skipping {} frames",
+                    frames - max_frames
+                );
+            }
+            let frames = std::cmp::min(frames, max_frames) as usize;
+            if frames == 0 {
+                continue;
+            }
+            buf.clear();
+            buf.resize(frames * channels, T::default());
+            let amt = source.process(&mut buf);
+            if amt == 0 {
+                // Buffering, or nobody is sending yet.
+                continue;
+            }
+            // Only what the source played is audio; the rest of the
+            // buffer is left as it was, e.g. while rebuffering.
+            let amt = amt - amt % channels;
+            for dest in &mut destinations {
+                dest.send(&buf[..amt]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Plays a ramp, one value per frame, once `playing` is set, and
+    /// at most `max_frames` frames at a time.
+    struct Ramp {
+        channels: usize,
+        max_frames: usize,
+        next: std::sync::Mutex<f32>,
+        playing: std::sync::atomic::AtomicBool,
+    }
+
+    impl Ramp {
+        fn new(channels: usize, max_frames: usize, playing: bool) -> std::sync::Arc<Self> {
+            std::sync::Arc::new(Self {
+                channels,
+                max_frames,
+                next: std::sync::Mutex::new(0.0),
+                playing: std::sync::atomic::AtomicBool::new(playing),
+            })
+        }
+    }
+
+    impl RxStream<f32> for Ramp {
+        fn process(&self, output_buffer: &mut [f32]) -> usize {
+            if !self.playing.load(std::sync::atomic::Ordering::SeqCst) {
+                return 0;
+            }
+            let mut next = self.next.lock().unwrap();
+            let mut amt = 0;
+            for frame in output_buffer
+                .chunks_mut(self.channels)
+                .take(self.max_frames)
+            {
+                for (i, sample) in frame.iter_mut().enumerate() {
+                    *sample = *next + i as f32 / 10.0;
+                }
+                *next += 1.0;
+                amt += frame.len();
+            }
+            amt
+        }
+    }
+
+    #[derive(Default)]
+    struct Capture {
+        samples: std::sync::Mutex<Vec<f32>>,
+    }
+
+    impl TxStream<f32> for Capture {
+        fn send(&self, payload: &[f32]) {
+            self.samples.lock().unwrap().extend_from_slice(payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn fans_out() {
+        let source = Ramp::new(2, usize::MAX, false);
+        let (both, right) = (
+            std::sync::Arc::new(Capture::default()),
+            std::sync::Arc::new(Capture::default()),
+        );
+        let relay = Relay::new(
+            source.clone(),
+            2,
+            48_000,
+            vec![
+                Destination::new(both.clone(), ChannelMap::identity(2)),
+                Destination::new(right.clone(), ChannelMap::new(&[1], 2).unwrap()),
+            ],
+            Duration::from_millis(1),
+        )
+        .unwrap();
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert!(both.samples.lock().unwrap().is_empty());
+        source
+            .playing
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        relay.shutdown().await;
+        let (both, right) = (both.samples.lock().unwrap(), right.samples.lock().unwrap());
+        let frames = both.len() / 2;
+        // Paced by the clock, give or take scheduling.
+        assert!((48 * 30..=48 * 150).contains(&frames), "{} frames", frames);
+        assert_eq!(right.len(), frames);
+        for (i, frame) in both.chunks(2).enumerate() {
+            assert_eq!(frame, [i as f32, i as f32 + 0.1]);
+            assert_eq!(right[i], i as f32 + 0.1);
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_only_what_was_played() {
+        // Plays less than the relay asks for each period.
+        let source = Ramp::new(2, 10, true);
+        let capture = std::sync::Arc::new(Capture::default());
+        let relay = Relay::new(
+            source,
+            2,
+            48_000,
+            vec![Destination::new(capture.clone(), ChannelMap::identity(2))],
+            Duration::from_millis(5),
+        )
+        .unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        relay.shutdown().await;
+        let samples = capture.samples.lock().unwrap();
+        assert!(!samples.is_empty());
+        // Nothing but the ramp: no silence from the unplayed rest.
+        for (i, frame) in samples.chunks(2).enumerate() {
+            assert_eq!(frame, [i as f32, i as f32 + 0.1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_map() {
+        let source = Ramp::new(2, usize::MAX, true);
+        let dest = Destination::new(
+            std::sync::Arc::new(Capture::default()),
+            ChannelMap::identity(4),
+        );
+        assert!(Relay::new(source, 2, 48_000, vec![dest], DEFAULT_PERIOD).is_err());
+    }
+}
@@ -0,0 +1,425 @@
+//! RTP (RFC 3550) carrying uncompressed L16 or L24 audio (RFC 3190),
+//! as used by AES67 and Dante in AES67 mode.
+//!
+//! Each datagram is a 12 byte RTP header followed by interleaved
+//! big-endian samples:
+//!
+//! ```text
+//!  0       1       2               4                               8
+//!  +-------+-------+---------------+-------------------------------+
+//!  |V P X CC|M  PT |   sequence    |           timestamp           |
+//!  +-------+-------+---------------+-------------------------------+
+//!  8                               12
+//!  +-------------------------------+-------------------------------
+//!  |             SSRC              |  samples ...
+//!  +-------------------------------+-------------------------------
+//! ```
+//!
+//! Unlike the native protocol, nothing in a packet says how many
+//! channels it carries or at what rate, so both ends are configured
+//! alike, usually from an SDP description (see [`super::sdp`]).
+use crate::sample::{self, Dither, Sample};
+use crate::stream::header::{SampleFormat, StreamFormat};
+
+/// Size of an RTP header without CSRCs or extensions.
+pub const RTP_HEADER_LEN: usize = 12;
+
+const RTP_VERSION: u8 = 2;
+
+/// Wire encoding of the samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// 16-bit big-endian integers.
+    L16,
+    /// 24-bit big-endian integers, the AES67 default.
+    L24,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::L24
+    }
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::L16 => "L16",
+            Encoding::L24 => "L24",
+        }
+    }
+
+    /// The sample format with the same precision.
+    pub fn sample_format(&self) -> SampleFormat {
+        match self {
+            Encoding::L16 => SampleFormat::I16,
+            Encoding::L24 => SampleFormat::I24,
+        }
+    }
+}
+
+/// Audio carried by each packet. AES67 requires receivers to support
+/// 1 ms, and 125 µs suits latency critical links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PacketTime {
+    #[serde(rename = "1ms")]
+    Millisecond,
+    #[serde(rename = "125us")]
+    Microseconds125,
+}
+
+impl Default for PacketTime {
+    fn default() -> Self {
+        PacketTime::Millisecond
+    }
+}
+
+impl PacketTime {
+    /// Number of frames per packet at `sample_rate`.
+    pub fn frames(&self, sample_rate: u32) -> usize {
+        match self {
+            PacketTime::Millisecond => sample_rate as usize / 1000,
+            PacketTime::Microseconds125 => sample_rate as usize / 8000,
+        }
+    }
+
+    /// Duration in milliseconds, as written in SDP.
+    pub fn as_millis(&self) -> f64 {
+        match self {
+            PacketTime::Millisecond => 1.0,
+            PacketTime::Microseconds125 => 0.125,
+        }
+    }
+}
+
+/// Describes an RTP stream, e.g.
+///
+/// ```yaml
+/// rtp:
+///   payloadType: 97
+///   encoding: L24
+///   packetTime: 125us
+///   sampleRate: 48000
+///   channels: 8
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RtpConfig {
+    /// Dynamic payload type, 96 to 127, identifying the stream's
+    /// format as given in its SDP.
+    #[serde(default = "default_payload_type")]
+    pub payload_type: u8,
+    #[serde(default)]
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub packet_time: PacketTime,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "default_channels")]
+    pub channels: u16,
+}
+
+fn default_payload_type() -> u8 {
+    96
+}
+
+fn default_sample_rate() -> u32 {
+    48_000
+}
+
+fn default_channels() -> u16 {
+    2
+}
+
+impl Default for RtpConfig {
+    fn default() -> Self {
+        Self {
+            payload_type: default_payload_type(),
+            encoding: Encoding::default(),
+            packet_time: PacketTime::default(),
+            sample_rate: default_sample_rate(),
+            channels: default_channels(),
+        }
+    }
+}
+
+impl RtpConfig {
+    /// Format of the samples on the wire, byte order aside.
+    pub fn format(&self) -> StreamFormat {
+        StreamFormat::new(
+            self.encoding.sample_format(),
+            self.channels,
+            self.sample_rate,
+        )
+    }
+
+    /// Number of frames per packet.
+    pub fn frames(&self) -> usize {
+        self.packet_time.frames(self.sample_rate)
+    }
+
+    pub fn validate(&self) -> Result<(), RtpError> {
+        if self.payload_type > 127 {
+            return Err(RtpError::InvalidPayloadType(self.payload_type));
+        }
+        if self.channels == 0 || self.frames() == 0 {
+            return Err(RtpError::InvalidFormat);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtpError {
+    /// The packet is smaller than its header says.
+    Truncated(usize),
+    UnsupportedVersion(u8),
+    /// Payload types are 7 bits.
+    InvalidPayloadType(u8),
+    /// The payload type is not the one the stream was configured for.
+    PayloadType {
+        expected: u8,
+        actual: u8,
+    },
+    /// The payload is not a whole number of frames.
+    PayloadLength(usize),
+    /// The stream has no channels, or a packet no frames.
+    InvalidFormat,
+}
+
+impl std::fmt::Display for RtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RtpError::Truncated(len) => write!(f, "packet truncated ({} bytes)", len),
+            RtpError::UnsupportedVersion(v) => write!(f, "unsupported RTP version {}", v),
+            RtpError::InvalidPayloadType(pt) => write!(f, "invalid payload type {}", pt),
+            RtpError::PayloadType { expected, actual } => write!(
+                f,
+                "payload type mismatch (got {}, expected {})",
+                actual, expected
+            ),
+            RtpError::PayloadLength(len) => {
+                write!(
+                    f,
+                    "payload of {} bytes is not a whole number of frames",
+                    len
+                )
+            }
+            RtpError::InvalidFormat => write!(f, "stream has no channels or no frames per packet"),
+        }
+    }
+}
+
+impl std::error::Error for RtpError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    /// Set on the first packet of a talkspurt, i.e. when a stream
+    /// starts.
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    /// Sample clock of the first frame in the packet.
+    pub timestamp: u32,
+    /// Identifies the sender.
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    /// Encodes the header into the start of `buf`, returning the
+    /// number of bytes written. Panics if `buf` is too small.
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        buf[0] = RTP_VERSION << 6;
+        buf[1] = (self.marker as u8) << 7 | self.payload_type & 0x7f;
+        buf[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        buf[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        RTP_HEADER_LEN
+    }
+
+    /// Decodes a packet, returning the header and the payload that
+    /// follows it, with any CSRCs, header extension and padding
+    /// skipped.
+    pub fn parse(buf: &[u8]) -> Result<(RtpHeader, &[u8]), RtpError> {
+        if buf.len() < RTP_HEADER_LEN {
+            return Err(RtpError::Truncated(buf.len()));
+        }
+        let version = buf[0] >> 6;
+        if version != RTP_VERSION {
+            return Err(RtpError::UnsupportedVersion(version));
+        }
+        let padding = buf[0] & 0x20 != 0;
+        let extension = buf[0] & 0x10 != 0;
+        let csrcs = (buf[0] & 0x0f) as usize;
+        let hdr = RtpHeader {
+            marker: buf[1] & 0x80 != 0,
+            payload_type: buf[1] & 0x7f,
+            sequence: u16::from_be_bytes([buf[2], buf[3]]),
+            timestamp: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            ssrc: u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        };
+        let mut start = RTP_HEADER_LEN + 4 * csrcs;
+        if extension {
+            if buf.len() < start + 4 {
+                return Err(RtpError::Truncated(buf.len()));
+            }
+            let words = u16::from_be_bytes([buf[start + 2], buf[start + 3]]) as usize;
+            start += 4 + 4 * words;
+        }
+        let mut end = buf.len();
+        if padding && end > start {
+            end -= buf[end - 1] as usize;
+        }
+        if end < start {
+            return Err(RtpError::Truncated(buf.len()));
+        }
+        Ok((hdr, &buf[start..end]))
+    }
+}
+
+/// Encodes samples big-endian in `encoding`, converting them from `T`
+/// as [`sample::convert`] does. Returns the number of bytes written.
+pub fn encode<T: Sample>(
+    samples: &[T],
+    encoding: Encoding,
+    out: &mut [u8],
+    dither: Option<&mut Dither>,
+) -> usize {
+    let format = encoding.sample_format();
+    let len = sample::encode(samples, format, out, dither);
+    for sample in out[..len].chunks_exact_mut(format.bytes_per_sample()) {
+        sample.reverse();
+    }
+    len
+}
+
+/// Decodes big-endian samples in `encoding` into `out`, converting
+/// them to `T`. The payload is byte swapped in place. Returns the
+/// number of samples decoded.
+pub fn decode<T: Sample>(payload: &mut [u8], encoding: Encoding, out: &mut [T]) -> usize {
+    let format = encoding.sample_format();
+    for sample in payload.chunks_exact_mut(format.bytes_per_sample()) {
+        sample.reverse();
+    }
+    sample::decode(payload, format, out)
+}
+
+/// Recovers the full value of a counter of which only the low `bits`
+/// are sent, e.g. 16-bit RTP sequence numbers, as the value closest
+/// to `prev`.
+pub(crate) fn unwrap(prev: u64, low: u64, bits: u32) -> u64 {
+    let modulus = 1u64 << bits;
+    let base = prev & !(modulus - 1);
+    let candidate = base | low;
+    if candidate > prev && candidate - prev > modulus / 2 && candidate >= modulus {
+        candidate - modulus
+    } else if candidate < prev && prev - candidate > modulus / 2 {
+        candidate + modulus
+    } else {
+        candidate
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sample::I24;
+
+    #[test]
+    fn round_trip() {
+        let hdr = RtpHeader {
+            marker: true,
+            payload_type: 97,
+            sequence: 0xfffe,
+            timestamp: 0xdeadbeef,
+            ssrc: 0x12345678,
+        };
+        let mut buf = [0u8; RTP_HEADER_LEN + 6];
+        assert_eq!(hdr.write(&mut buf[..]), RTP_HEADER_LEN);
+        assert_eq!(buf[0], 0x80);
+        assert_eq!(buf[1], 0x80 | 97);
+        let (parsed, payload) = RtpHeader::parse(&buf[..]).unwrap();
+        assert_eq!(parsed, hdr);
+        assert_eq!(payload.len(), 6);
+    }
+
+    #[test]
+    fn skips_csrcs_extension_and_padding() {
+        let mut buf = vec![0u8; RTP_HEADER_LEN];
+        RtpHeader {
+            marker: false,
+            payload_type: 96,
+            sequence: 1,
+            timestamp: 2,
+            ssrc: 3,
+        }
+        .write(&mut buf[..]);
+        // Padding, extension and one CSRC.
+        buf[0] |= 0x20 | 0x10 | 1;
+        buf.extend_from_slice(&[0, 0, 0, 9]);
+        buf.extend_from_slice(&[0xbe, 0xde, 0, 1, 1, 2, 3, 4]);
+        buf.extend_from_slice(&[0xaa, 0xbb]);
+        buf.extend_from_slice(&[0, 0, 3]);
+        let (_, payload) = RtpHeader::parse(&buf[..]).unwrap();
+        assert_eq!(payload, &[0xaa, 0xbb]);
+        buf[0] = 0x40;
+        assert_eq!(
+            RtpHeader::parse(&buf[..]),
+            Err(RtpError::UnsupportedVersion(1))
+        );
+        assert_eq!(
+            RtpHeader::parse(&buf[..RTP_HEADER_LEN - 1]),
+            Err(RtpError::Truncated(RTP_HEADER_LEN - 1))
+        );
+    }
+
+    #[test]
+    fn samples_are_big_endian() {
+        let mut bytes = [0u8; 6];
+        assert_eq!(
+            encode(
+                &[I24::new(0x123456), I24::new(-2)],
+                Encoding::L24,
+                &mut bytes[..],
+                None
+            ),
+            6
+        );
+        assert_eq!(bytes, [0x12, 0x34, 0x56, 0xff, 0xff, 0xfe]);
+        let mut out = [I24::default(); 2];
+        assert_eq!(decode(&mut bytes[..], Encoding::L24, &mut out[..]), 2);
+        assert_eq!(out, [I24::new(0x123456), I24::new(-2)]);
+        let mut bytes = [0u8; 2];
+        encode(&[0x1234i16], Encoding::L16, &mut bytes[..], None);
+        assert_eq!(bytes, [0x12, 0x34]);
+    }
+
+    #[test]
+    fn unwraps_counters() {
+        assert_eq!(unwrap(0, 1, 16), 1);
+        assert_eq!(unwrap(0xfffe, 0x0001, 16), 0x10001);
+        assert_eq!(unwrap(0x10001, 0xffff, 16), 0xffff);
+        assert_eq!(unwrap(0x10001, 0x0005, 16), 0x10005);
+        // No wrapping below zero.
+        assert_eq!(unwrap(1, 0xffff, 16), 0xffff);
+        assert_eq!(unwrap(0xffff_fff0, 0x10, 32), 0x1_0000_0010);
+    }
+
+    #[test]
+    fn packet_times() {
+        let config = RtpConfig::default();
+        assert_eq!(config.frames(), 48);
+        let config = RtpConfig {
+            packet_time: PacketTime::Microseconds125,
+            ..Default::default()
+        };
+        assert_eq!(config.frames(), 6);
+        assert_eq!(config.format().sample_format, SampleFormat::I24);
+        let config: RtpConfig =
+            serde_yaml::from_str("payloadType: 97\nencoding: L16\npacketTime: 125us").unwrap();
+        assert_eq!(config.payload_type, 97);
+        assert_eq!(config.encoding, Encoding::L16);
+        assert_eq!(config.packet_time, PacketTime::Microseconds125);
+        assert_eq!(config.channels, 2);
+    }
+}
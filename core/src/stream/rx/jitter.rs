@@ -0,0 +1,819 @@
+//! Adaptive jitter buffer for the receive side of a stream.
+//!
+//! Packets are held in timestamp order and released at the pace of the
+//! consumer (normally the audio callback). The playout delay tracks an
+//! RFC 3550 style estimate of network jitter, so it grows when the link
+//! gets noisy and shrinks again when it settles down. Gaps left by lost
+//! packets are filled in by the concealer.
+//!
+//! Buffering happens at the sender's sample rate, taken from the packet
+//! headers. If that differs from the local rate, audio is resampled on
+//! its way out, so callers always read at the rate they asked for.
+//!
+//! Even at equal nominal rates the sender's clock never runs at quite
+//! the same speed as ours, so over a long session the buffer would
+//! slowly fill up or run dry. With drift compensation the resampling
+//! ratio is steered continuously to hold the average fill at the
+//! target delay.
+use super::plc::{Concealer, Concealment};
+use crate::resample::Resampler;
+use crate::sample::{self, Sample};
+use crate::stream::header::{Header, StreamFormat};
+use crate::stream::stats::StreamStats;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Number of recent sequence numbers remembered for duplicate detection.
+const SEQUENCE_HISTORY: usize = 128;
+
+/// Target delay is this many times the jitter estimate, on top of a
+/// single packet's worth of audio.
+const JITTER_MULTIPLIER: f64 = 4.0;
+
+/// Fraction of the difference between the current and desired delay
+/// that is removed per packet when shrinking. Growing is immediate.
+const SHRINK_RATE: f64 = 1.0 / 64.0;
+
+/// Time constant of the buffer fill average that drift compensation
+/// works from, in seconds. Long enough to hide packet granularity and
+/// network jitter.
+const FILL_SMOOTHING: f64 = 1.0;
+
+/// Proportional gain of the drift controller: ratio correction per
+/// second of fill error.
+const DRIFT_KP: f64 = 0.5;
+
+/// Integral gain of the drift controller. The integral settles on the
+/// actual clock drift, so the fill error returns to zero.
+const DRIFT_KI: f64 = 0.06;
+
+/// Largest correction applied, as a fraction of the nominal ratio.
+/// Crystals are normally within 100 ppm of each other; anything past
+/// this would be audible as a pitch change.
+const MAX_DRIFT: f64 = 0.002;
+
+#[derive(Debug, Clone)]
+pub struct JitterConfig {
+    /// Delay used until enough packets have arrived to estimate jitter.
+    pub initial_delay: Duration,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// How gaps in the received audio are filled.
+    pub concealment: Concealment,
+    /// Resample continuously to absorb drift between the sender's
+    /// clock and ours.
+    pub drift_compensation: bool,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(40),
+            min_delay: Duration::from_millis(2),
+            max_delay: Duration::from_millis(500),
+            concealment: Concealment::default(),
+            drift_compensation: true,
+        }
+    }
+}
+
+/// Result of handing a packet to the jitter buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    Accepted,
+    /// A packet with the same sequence number was already received.
+    Duplicate,
+    /// The packet arrived after its playout time.
+    Late,
+}
+
+pub struct JitterBuffer<T> {
+    /// Format that audio is read in.
+    format: StreamFormat,
+    config: JitterConfig,
+    stream_id: Option<u32>,
+    /// Sample rate of the current stream. Timestamps and delays are
+    /// measured at this rate.
+    stream_rate: u32,
+    /// Converts from `stream_rate` to the local rate when they differ
+    /// or drift compensation is enabled.
+    resampler: Option<Resampler>,
+    /// Scratch space for audio on its way through the resampler.
+    played: Vec<T>,
+    resampled: Vec<f32>,
+    /// Pending packets and their sequence numbers, keyed by the
+    /// timestamp of their first frame.
+    packets: BTreeMap<u64, (u32, Vec<T>)>,
+    recent: VecDeque<u32>,
+    /// Highest sequence number received, for spotting reordering.
+    highest_sequence: Option<u32>,
+    /// Sequence number of the last packet that started playing.
+    last_sequence: Option<u32>,
+    plc: Concealer,
+    /// Timestamp of the next frame to be played. `None` until playout
+    /// has started.
+    cursor: Option<u64>,
+    buffering: bool,
+    epoch: Option<Instant>,
+    last_transit: Option<f64>,
+    /// Smoothed interarrival jitter, in seconds.
+    jitter: f64,
+    /// Target playout delay, in frames.
+    target: f64,
+    /// Smoothed buffer fill, in frames. `None` until playout starts.
+    fill: Option<f64>,
+    /// Integral of the fill error, in seconds squared.
+    drift_integral: f64,
+    /// Stream frames played per nominal frame.
+    ratio: f64,
+    packet_frames: u64,
+    stats: std::sync::Arc<StreamStats>,
+    lost_frames: u64,
+    skipped_frames: u64,
+}
+
+impl<T> JitterBuffer<T>
+where
+    T: Sample,
+{
+    pub fn new(format: StreamFormat, config: JitterConfig) -> Self {
+        let target = config.initial_delay.as_secs_f64() * format.sample_rate as f64;
+        let resampler = if config.drift_compensation {
+            let rate = format.sample_rate;
+            Some(Resampler::new(rate, rate, format.channels as usize))
+        } else {
+            None
+        };
+        Self {
+            format,
+            stream_id: None,
+            stream_rate: format.sample_rate,
+            resampler,
+            played: Vec::new(),
+            resampled: Vec::new(),
+            packets: BTreeMap::new(),
+            recent: VecDeque::with_capacity(SEQUENCE_HISTORY),
+            highest_sequence: None,
+            last_sequence: None,
+            plc: Concealer::new(config.concealment, format),
+            config,
+            cursor: None,
+            buffering: true,
+            epoch: None,
+            last_transit: None,
+            jitter: 0.0,
+            target,
+            fill: None,
+            drift_integral: 0.0,
+            ratio: 1.0,
+            packet_frames: 0,
+            stats: std::sync::Arc::new(StreamStats::new()),
+            lost_frames: 0,
+            skipped_frames: 0,
+        }
+    }
+
+    /// Drops all buffered audio and starts over as if no packets had
+    /// been received. Statistics are preserved.
+    pub fn reset(&mut self) {
+        self.stream_id = None;
+        self.packets.clear();
+        self.recent.clear();
+        self.highest_sequence = None;
+        self.last_sequence = None;
+        self.plc.reset();
+        // A new sender has a clock of its own.
+        self.fill = None;
+        self.drift_integral = 0.0;
+        self.ratio = 1.0;
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+            resampler.set_ratio(1.0);
+        }
+        self.cursor = None;
+        self.buffering = true;
+        self.epoch = None;
+        self.last_transit = None;
+        self.jitter = 0.0;
+        self.target = self.config.initial_delay.as_secs_f64() * self.rate();
+    }
+
+    /// Adds a received packet. `samples` holds the interleaved payload
+    /// described by `hdr` and `arrival` is when it came off the wire.
+    pub fn insert(&mut self, hdr: &Header, samples: &[T], arrival: Instant) -> Insert {
+        if self.stream_id != Some(hdr.stream_id)
+            || hdr.is_discontinuity()
+            || hdr.format.sample_rate != self.stream_rate
+        {
+            // New sender or the sender restarted its clock. Nothing
+            // buffered so far can be ordered against this packet.
+            self.set_stream_rate(hdr.format.sample_rate);
+            self.reset();
+            self.stream_id = Some(hdr.stream_id);
+        }
+        if self.recent.contains(&hdr.sequence) {
+            self.stats.duplicate();
+            return Insert::Duplicate;
+        }
+        match self.highest_sequence {
+            Some(highest) if (hdr.sequence.wrapping_sub(highest) as i32) < 0 => {
+                self.stats.reordered()
+            }
+            _ => self.highest_sequence = Some(hdr.sequence),
+        }
+        if self.recent.len() == SEQUENCE_HISTORY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(hdr.sequence);
+        self.update_jitter(hdr.timestamp, arrival);
+        self.packet_frames = hdr.frames as u64;
+        self.plc.set_packet_frames(hdr.frames as usize);
+        if let Some(cursor) = self.cursor {
+            if hdr.timestamp < cursor {
+                self.stats.late();
+                return Insert::Late;
+            }
+        }
+        self.packets
+            .insert(hdr.timestamp, (hdr.sequence, Vec::from(samples)));
+        Insert::Accepted
+    }
+
+    /// Fills `output` with interleaved samples that are due for playout
+    /// and returns the number of samples written. Anything past that
+    /// is left untouched, which happens while (re)buffering.
+    pub fn read(&mut self, output: &mut [T]) -> usize {
+        let amt = self.resample(output);
+        self.stats.set_concealed_frames(self.plc.concealed_frames());
+        self.stats.set_latency(self.buffered());
+        self.stats.set_target_delay(self.target_delay());
+        amt
+    }
+
+    /// Plays audio into `output` through the resampler, if any.
+    fn resample(&mut self, output: &mut [T]) -> usize {
+        let channels = self.format.channels as usize;
+        let frames = output.len() / channels;
+        let needed = match &self.resampler {
+            Some(resampler) => resampler.input_frames(frames),
+            None => return self.play(output),
+        };
+        self.resampled.clear();
+        if needed > 0 {
+            let mut played = std::mem::take(&mut self.played);
+            played.resize(needed * channels, T::default());
+            let amt = self.play(&mut played[..]);
+            self.resampled.resize(amt, 0.0);
+            sample::convert(&played[..amt], &mut self.resampled[..], None);
+            self.played = played;
+            if amt == 0 {
+                return 0;
+            }
+            if self.config.drift_compensation {
+                self.steer(frames);
+            }
+        }
+        let resampler = self.resampler.as_mut().unwrap();
+        resampler.push(&self.resampled[..]);
+        self.resampled.resize(frames * channels, 0.0);
+        let amt = resampler.pull(&mut self.resampled[..]);
+        sample::convert(&self.resampled[..amt], &mut output[..amt], None);
+        amt
+    }
+
+    /// Like `read`, but at the stream's sample rate.
+    fn play(&mut self, output: &mut [T]) -> usize {
+        let channels = self.format.channels as usize;
+        let frames = output.len() / channels;
+        if self.buffering && !self.start_playout() {
+            if self.plc.is_active() {
+                // Still fading out after running dry.
+                self.plc.conceal(&mut output[..frames * channels]);
+                return frames * channels;
+            }
+            return 0;
+        }
+        let mut cursor = match self.cursor {
+            Some(cursor) => cursor,
+            None => return 0,
+        };
+        let mut written = 0;
+        while written < frames {
+            let (ts, seq, len) = match self.packets.range(..=cursor).next_back() {
+                Some((ts, (seq, samples))) if *ts + (samples.len() / channels) as u64 > cursor => {
+                    (*ts, *seq, samples.len() / channels)
+                }
+                _ => match self.packets.range(cursor..).next() {
+                    Some((ts, _)) => {
+                        // The packet(s) in front of the next one never
+                        // arrived. Conceal the missing span.
+                        let missing = std::cmp::min((*ts - cursor) as usize, frames - written);
+                        self.plc
+                            .conceal(&mut output[written * channels..(written + missing) * channels]);
+                        self.lost_frames += missing as u64;
+                        written += missing;
+                        cursor += missing as u64;
+                        continue;
+                    }
+                    None => {
+                        // Ran dry. Give the network more slack and wait
+                        // for the buffer to fill back up.
+                        self.stats.underrun();
+                        self.buffering = true;
+                        self.target = (self.target + self.packet_frames as f64)
+                            .min(self.max_frames());
+                        self.cursor = Some(cursor);
+                        self.plc
+                            .conceal(&mut output[written * channels..frames * channels]);
+                        return frames * channels;
+                    }
+                },
+            };
+            let offset = (cursor - ts) as usize;
+            if offset == 0 {
+                if let Some(last) = self.last_sequence {
+                    self.stats.lost(seq.wrapping_sub(last).wrapping_sub(1) as u64);
+                }
+                self.last_sequence = Some(seq);
+            }
+            let amt = std::cmp::min(len - offset, frames - written);
+            {
+                let (_, samples) = &self.packets[&ts];
+                output[written * channels..(written + amt) * channels]
+                    .copy_from_slice(&samples[offset * channels..(offset + amt) * channels]);
+            }
+            self.plc
+                .played(&mut output[written * channels..(written + amt) * channels]);
+            written += amt;
+            cursor += amt as u64;
+            if offset + amt == len {
+                self.packets.remove(&ts);
+            }
+        }
+        self.cursor = Some(cursor);
+        self.shrink();
+        written * channels
+    }
+
+    /// Amount of audio currently buffered ahead of the playout cursor.
+    pub fn buffered(&self) -> Duration {
+        Duration::from_secs_f64(self.buffered_frames() as f64 / self.rate())
+    }
+
+    /// Current target playout delay.
+    pub fn target_delay(&self) -> Duration {
+        Duration::from_secs_f64(self.target / self.rate())
+    }
+
+    /// Smoothed interarrival jitter estimate.
+    pub fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    pub fn is_buffering(&self) -> bool {
+        self.buffering
+    }
+
+    /// Counters shared with whoever displays them. Transports add
+    /// the packets and bytes they receive.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.stats.snapshot().underruns
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.stats.snapshot().duplicates
+    }
+
+    pub fn late(&self) -> u64 {
+        self.stats.snapshot().late
+    }
+
+    /// Packets that never arrived, detected from gaps in the
+    /// sequence numbers of played packets.
+    pub fn lost_packets(&self) -> u64 {
+        self.stats.snapshot().lost
+    }
+
+    /// Frames that were never received and had to be concealed.
+    pub fn lost_frames(&self) -> u64 {
+        self.lost_frames
+    }
+
+    /// Frames synthesized by the concealer, covering both lost packets
+    /// and the fade-out after an underrun.
+    pub fn concealed_frames(&self) -> u64 {
+        self.plc.concealed_frames()
+    }
+
+    /// Estimated speed of the sender's clock relative to ours, in parts
+    /// per million. Zero unless drift compensation is enabled.
+    pub fn drift(&self) -> f64 {
+        (self.ratio - 1.0) * 1e6
+    }
+
+    /// Frames discarded to bring the delay back down to the target.
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped_frames
+    }
+
+    fn rate(&self) -> f64 {
+        self.stream_rate as f64
+    }
+
+    fn set_stream_rate(&mut self, rate: u32) {
+        if rate == self.stream_rate {
+            return;
+        }
+        self.stream_rate = rate;
+        self.plc.set_sample_rate(rate);
+        self.resampler = if rate == self.format.sample_rate && !self.config.drift_compensation {
+            None
+        } else {
+            Some(Resampler::new(
+                rate,
+                self.format.sample_rate,
+                self.format.channels as usize,
+            ))
+        };
+    }
+
+    fn min_frames(&self) -> f64 {
+        self.config.min_delay.as_secs_f64() * self.rate()
+    }
+
+    fn max_frames(&self) -> f64 {
+        self.config.max_delay.as_secs_f64() * self.rate()
+    }
+
+    fn start(&self) -> Option<u64> {
+        match self.cursor {
+            Some(cursor) => Some(cursor),
+            None => self.packets.keys().next().cloned(),
+        }
+    }
+
+    fn end(&self) -> Option<u64> {
+        let channels = self.format.channels as u64;
+        self.packets
+            .iter()
+            .next_back()
+            .map(|(ts, (_, samples))| *ts + samples.len() as u64 / channels)
+    }
+
+    fn buffered_frames(&self) -> u64 {
+        match (self.start(), self.end()) {
+            (Some(start), Some(end)) if end > start => end - start,
+            _ => 0,
+        }
+    }
+
+    fn start_playout(&mut self) -> bool {
+        let end = match self.end() {
+            Some(end) => end,
+            None => return false,
+        };
+        if (self.buffered_frames() as f64) < self.target {
+            return false;
+        }
+        // Begin exactly `target` frames behind the newest audio so
+        // that any excess accumulated while waiting is discarded.
+        let start = end.saturating_sub(self.target.ceil() as u64);
+        let start = match self.start() {
+            Some(first) if first > start => first,
+            _ => start,
+        };
+        self.discard_before(start);
+        self.cursor = Some(start);
+        self.buffering = false;
+        true
+    }
+
+    /// Skips ahead when the buffer holds substantially more than the
+    /// target, e.g. after a burst of delayed packets arrives at once.
+    fn shrink(&mut self) {
+        let cursor = match self.cursor {
+            Some(cursor) => cursor,
+            None => return,
+        };
+        let buffered = self.buffered_frames() as f64;
+        let slack = (self.target / 2.0).max(self.packet_frames as f64);
+        if buffered <= self.target + slack {
+            return;
+        }
+        let skip = (buffered - self.target) as u64;
+        self.stats.overrun();
+        self.skipped_frames += skip;
+        self.discard_before(cursor + skip);
+        self.cursor = Some(cursor + skip);
+    }
+
+    fn discard_before(&mut self, ts: u64) {
+        let channels = self.format.channels as u64;
+        let stale = self
+            .packets
+            .iter()
+            .take_while(|(start, (_, samples))| **start + samples.len() as u64 / channels <= ts)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in stale {
+            self.packets.remove(&start);
+        }
+    }
+
+    /// Nudges the resampling ratio after `frames` local frames were
+    /// played, so that the buffer holds the target delay on average.
+    /// A persistent error means the sender's clock runs at a different
+    /// speed than ours; the integral term learns that difference.
+    fn steer(&mut self, frames: usize) {
+        if self.buffering {
+            // Ran dry. The fill level restarts from the target.
+            self.fill = None;
+            return;
+        }
+        let dt = frames as f64 / self.format.sample_rate as f64;
+        let buffered = self.buffered_frames() as f64;
+        let fill = match self.fill {
+            Some(fill) => fill + (buffered - fill) * (dt / FILL_SMOOTHING).min(1.0),
+            None => buffered,
+        };
+        self.fill = Some(fill);
+        // Between packets the fill falls from the target by up to one
+        // packet, so aim for the middle of that range.
+        let error = (fill - (self.target - self.packet_frames as f64 / 2.0)) / self.rate();
+        let limit = MAX_DRIFT / DRIFT_KI;
+        self.drift_integral = (self.drift_integral + error * dt).clamp(-limit, limit);
+        let correction = DRIFT_KP * error + DRIFT_KI * self.drift_integral;
+        self.ratio = 1.0 + correction.clamp(-MAX_DRIFT, MAX_DRIFT);
+        if let Some(resampler) = &mut self.resampler {
+            resampler.set_ratio(self.ratio);
+        }
+    }
+
+    fn update_jitter(&mut self, timestamp: u64, arrival: Instant) {
+        let epoch = *self.epoch.get_or_insert(arrival);
+        let transit = arrival.duration_since(epoch).as_secs_f64() - timestamp as f64 / self.rate();
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+            let frame = self.packet_frames as f64;
+            let desired = (JITTER_MULTIPLIER * self.jitter * self.rate() + frame)
+                .max(self.min_frames())
+                .min(self.max_frames());
+            if desired > self.target {
+                self.target = desired;
+            } else {
+                self.target += (desired - self.target) * SHRINK_RATE;
+            }
+        }
+        self.last_transit = Some(transit);
+        self.stats.set_jitter(self.jitter());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Codec;
+    use crate::stream::header::SampleFormat;
+
+    const FRAMES: u16 = 48;
+
+    fn format() -> StreamFormat {
+        StreamFormat::new(SampleFormat::F32, 1, 48_000)
+    }
+
+    fn header(sequence: u32) -> Header {
+        Header {
+            flags: 0,
+            stream_id: 1,
+            sequence,
+            timestamp: sequence as u64 * FRAMES as u64,
+            format: format(),
+            codec: Codec::Pcm,
+            frames: FRAMES,
+        }
+    }
+
+    fn payload(sequence: u32) -> Vec<f32> {
+        (0..FRAMES)
+            .map(|i| (sequence as u64 * FRAMES as u64 + i as u64) as f32)
+            .collect()
+    }
+
+    fn config(initial_ms: u64) -> JitterConfig {
+        JitterConfig {
+            initial_delay: Duration::from_millis(initial_ms),
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(100),
+            concealment: Concealment::Silence,
+            drift_compensation: false,
+        }
+    }
+
+    /// Pins the delay so that tests don't depend on jitter estimates.
+    fn fixed(ms: u64) -> JitterConfig {
+        JitterConfig {
+            initial_delay: Duration::from_millis(ms),
+            min_delay: Duration::from_millis(ms),
+            max_delay: Duration::from_millis(ms),
+            concealment: Concealment::Silence,
+            drift_compensation: false,
+        }
+    }
+
+    /// Delivers packets exactly on schedule.
+    fn feed(jb: &mut JitterBuffer<f32>, epoch: Instant, order: &[u32]) {
+        for &seq in order {
+            let arrival = epoch + Duration::from_millis(seq as u64);
+            jb.insert(&header(seq), &payload(seq), arrival);
+        }
+    }
+
+    #[test]
+    fn waits_for_target_delay() {
+        let mut jb = JitterBuffer::new(format(), config(3));
+        let epoch = Instant::now();
+        let mut out = [0.0f32; 48];
+        feed(&mut jb, epoch, &[0, 1]);
+        assert_eq!(jb.read(&mut out), 0);
+        assert!(jb.is_buffering());
+        feed(&mut jb, epoch, &[2]);
+        assert_eq!(jb.read(&mut out), 48);
+        assert!(!jb.is_buffering());
+    }
+
+    #[test]
+    fn reorders() {
+        let mut jb = JitterBuffer::new(format(), fixed(4));
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[1, 0, 3, 2]);
+        let mut out = vec![0.0f32; 4 * FRAMES as usize];
+        assert_eq!(jb.read(&mut out), out.len());
+        let expected = (0..out.len()).map(|i| i as f32).collect::<Vec<_>>();
+        assert_eq!(out, expected);
+        let stats = jb.stats().snapshot();
+        assert_eq!(stats.reordered, 2);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.latency, jb.buffered());
+    }
+
+    #[test]
+    fn drops_duplicates() {
+        let mut jb = JitterBuffer::new(format(), config(2));
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[0, 1]);
+        assert_eq!(
+            jb.insert(&header(1), &payload(1), epoch),
+            Insert::Duplicate
+        );
+        assert_eq!(jb.duplicates(), 1);
+    }
+
+    #[test]
+    fn drops_late_packets() {
+        let mut jb = JitterBuffer::new(format(), config(2));
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[1, 2]);
+        let mut out = [0.0f32; 48];
+        assert_eq!(jb.read(&mut out), 48);
+        assert_eq!(jb.insert(&header(0), &payload(0), epoch), Insert::Late);
+        assert_eq!(jb.late(), 1);
+    }
+
+    #[test]
+    fn plays_silence_for_lost_packets() {
+        let mut jb = JitterBuffer::new(format(), fixed(4));
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[0, 2, 3]);
+        let mut out = vec![-1.0f32; 4 * FRAMES as usize];
+        assert_eq!(jb.read(&mut out), out.len());
+        assert_eq!(&out[48..96], &[0.0f32; 48][..]);
+        assert_eq!(out[96], 96.0);
+        assert_eq!(jb.lost_frames(), 48);
+        assert_eq!(jb.lost_packets(), 1);
+    }
+
+    #[test]
+    fn conceals_lost_packets() {
+        let mut jb = JitterBuffer::new(
+            format(),
+            JitterConfig {
+                concealment: Concealment::Repeat,
+                ..fixed(4)
+            },
+        );
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[0, 2, 3]);
+        let mut out = vec![0.0f32; 2 * FRAMES as usize];
+        assert_eq!(jb.read(&mut out), out.len());
+        assert_eq!(&out[48..96], &payload(0)[..]);
+        assert_eq!(jb.concealed_frames(), 48);
+    }
+
+    #[test]
+    fn underrun_grows_delay() {
+        let mut jb = JitterBuffer::new(format(), config(1));
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[0]);
+        let before = jb.target_delay();
+        let mut out = [0.0f32; 96];
+        assert_eq!(jb.read(&mut out), 96);
+        assert_eq!(jb.underruns(), 1);
+        assert_eq!(jb.concealed_frames(), 48);
+        assert!(jb.is_buffering());
+        assert!(jb.target_delay() > before);
+    }
+
+    #[test]
+    fn adapts_to_jitter() {
+        let epoch = Instant::now();
+        let mut steady = JitterBuffer::<f32>::new(format(), config(20));
+        let mut noisy = JitterBuffer::<f32>::new(format(), config(20));
+        for seq in 0..500u32 {
+            let on_time = epoch + Duration::from_millis(seq as u64);
+            steady.insert(&header(seq), &payload(seq), on_time);
+            let wobble = Duration::from_millis(if seq % 2 == 0 { 0 } else { 8 });
+            noisy.insert(&header(seq), &payload(seq), on_time + wobble);
+        }
+        assert!(steady.target_delay() < Duration::from_millis(5));
+        assert!(noisy.target_delay() > Duration::from_millis(20));
+    }
+
+    #[test]
+    fn resets_on_new_stream() {
+        let mut jb = JitterBuffer::new(format(), config(2));
+        let epoch = Instant::now();
+        feed(&mut jb, epoch, &[0, 1]);
+        let mut hdr = header(5);
+        hdr.stream_id = 2;
+        assert_eq!(jb.insert(&hdr, &payload(5), epoch), Insert::Accepted);
+        assert_eq!(jb.buffered(), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn resamples_to_local_rate() {
+        let mut jb = JitterBuffer::new(format(), fixed(4));
+        let epoch = Instant::now();
+        let mut played = Vec::new();
+        let mut out = [0.0f32; 52];
+        for seq in 0..200u32 {
+            let mut hdr = header(seq);
+            hdr.format.sample_rate = 44_100;
+            let arrival = epoch + Duration::from_secs_f64(hdr.timestamp as f64 / 44_100.0);
+            jb.insert(&hdr, &[0.5; FRAMES as usize], arrival);
+            let amt = jb.read(&mut out);
+            played.extend_from_slice(&out[..amt]);
+        }
+        // Delays are measured at the stream's rate.
+        assert_eq!(jb.target_delay(), Duration::from_millis(4));
+        assert_eq!(jb.underruns(), 0);
+        assert_eq!(jb.lost_frames(), 0);
+        // 52 frames at 48 kHz take about 47.8 frames at 44.1 kHz, so
+        // playout keeps up with the packets as they arrive.
+        assert!(played.len() > 190 * out.len());
+        assert!(played[200..].iter().all(|&s| (s - 0.5).abs() < 1e-3));
+    }
+
+    /// Plays `seconds` of a sender whose clock runs `drift` slower than
+    /// ours, reading 1 ms at a time.
+    fn drifting(jb: &mut JitterBuffer<f32>, drift: f64, seconds: u32) {
+        let epoch = Instant::now();
+        let mut out = [0.0f32; FRAMES as usize];
+        let mut seq = 0;
+        for n in 0..seconds * 1000 {
+            let now = n as f64 / 1000.0;
+            // Deliver every packet the sender has finished by now.
+            while (seq + 1) as f64 / 1000.0 * (1.0 + drift) <= now {
+                let arrival = epoch + Duration::from_secs_f64(now);
+                jb.insert(&header(seq), &[0.5; FRAMES as usize], arrival);
+                seq += 1;
+            }
+            jb.read(&mut out);
+        }
+    }
+
+    #[test]
+    fn compensates_clock_drift() {
+        let mut jb = JitterBuffer::new(format(), fixed(20));
+        drifting(&mut jb, 0.001, 30);
+        // Falls behind by 48 frames a second and runs dry.
+        assert!(jb.underruns() > 0);
+        let mut jb = JitterBuffer::new(
+            format(),
+            JitterConfig {
+                drift_compensation: true,
+                ..fixed(20)
+            },
+        );
+        drifting(&mut jb, 0.001, 10);
+        assert_eq!(jb.underruns(), 0);
+        assert_eq!(jb.skipped_frames(), 0);
+        assert!((jb.drift() + 1000.0).abs() < 100.0, "drift {}", jb.drift());
+        let error = jb.buffered().as_secs_f64() - 0.0195;
+        assert!(error.abs() < 0.002, "fill error {}", error);
+    }
+}
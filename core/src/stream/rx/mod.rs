@@ -1,5 +1,11 @@
 use super::*;
 
+pub mod jitter;
+pub mod plc;
+mod playout;
+pub mod quic;
+pub mod rtp;
+pub mod tcp;
 pub mod udp;
 
 pub trait RxStream<T> {
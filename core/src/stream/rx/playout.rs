@@ -0,0 +1,61 @@
+use super::jitter::JitterBuffer;
+use crate::codec::{Codec, CodecError, StreamDecoder};
+use crate::sample::{self, Sample};
+use crate::stream::header::{Header, StreamFormat};
+
+/// Receive side state that turns packets into queued audio.
+pub(crate) struct Playout<T> {
+    jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+    format: StreamFormat,
+    decoder: StreamDecoder,
+    samples: Vec<T>,
+}
+
+impl<T> Playout<T>
+where
+    T: Sample,
+{
+    pub(crate) fn new(
+        jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+        format: StreamFormat,
+    ) -> Self {
+        Self {
+            jitter,
+            format,
+            decoder: StreamDecoder::new(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Decodes a verified packet and queues its audio.
+    pub(crate) fn insert(&mut self, hdr: &Header, payload: &[u8]) -> Result<(), CodecError> {
+        if hdr.codec == Codec::Pcm {
+            let num_samples = hdr.frames as usize * hdr.format.channels as usize;
+            self.samples.resize(num_samples, T::default());
+            sample::decode(payload, hdr.format.sample_format, &mut self.samples[..]);
+        } else {
+            let decoded = self.decoder.decode(hdr, payload)?;
+            self.samples.resize(decoded.len(), T::default());
+            sample::convert(decoded, &mut self.samples[..], None);
+        }
+        self.jitter
+            .lock()
+            .unwrap()
+            .insert(hdr, &self.samples[..], std::time::Instant::now());
+        Ok(())
+    }
+
+    /// Queues a packet rebuilt by forward error correction.
+    pub(crate) fn recovered(&mut self, packet: &[u8]) {
+        let result = match Header::parse(packet) {
+            Ok((hdr, payload)) if hdr.verify(&self.format).is_ok() => self.insert(&hdr, payload),
+            _ => {
+                warn!("rx: discarding malformed recovered packet");
+                return;
+            }
+        };
+        if let Err(e) = result {
+            warn!("rx: discarding recovered packet: {}", e);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+//! Packet loss concealment.
+//!
+//! When audio for a span of frames never arrives, the concealer
+//! synthesizes a replacement from recently played audio instead of
+//! cutting to silence. The replacement is held at full level briefly,
+//! then faded out, and real audio is crossfaded back in once it
+//! resumes so that neither edge of the gap produces a click.
+use crate::sample::Sample;
+use crate::stream::header::StreamFormat;
+
+/// How long synthesized audio is played at full level before fading.
+const HOLD_MS: f64 = 10.0;
+
+/// Duration of the fade from synthesized audio to silence.
+const FADE_OUT_MS: f64 = 20.0;
+
+/// Duration of the crossfade from synthesized audio back to real audio.
+const FADE_IN_MS: f64 = 2.5;
+
+/// Shortest and longest periods considered by waveform matching.
+/// These bracket typical musical and vocal fundamentals.
+const MIN_PERIOD_MS: f64 = 2.5;
+const MAX_PERIOD_MS: f64 = 20.0;
+
+/// Length of the most recent audio that candidate periods are
+/// compared against.
+const MATCH_WINDOW_MS: f64 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Concealment {
+    /// Missing audio is played as silence.
+    Silence,
+    /// The last packet's worth of audio is repeated while quickly
+    /// fading to silence.
+    Fade,
+    /// The last packet's worth of audio is looped at full level for a
+    /// short while before fading.
+    Repeat,
+    /// The signal is extended by looping the most self-similar recent
+    /// period (waveform-similarity extrapolation). Works best on
+    /// periodic material such as voice and sustained notes.
+    Extrapolate,
+}
+
+impl Default for Concealment {
+    fn default() -> Self {
+        Concealment::Extrapolate
+    }
+}
+
+/// State of an ongoing concealment event.
+struct Synth {
+    /// Interleaved audio that is looped to fill the gap.
+    template: Vec<f32>,
+    /// Read position within the template, in frames.
+    pos: usize,
+    /// Frames synthesized since the gap started.
+    elapsed: usize,
+    hold: usize,
+}
+
+pub struct Concealer {
+    mode: Concealment,
+    channels: usize,
+    rate: f64,
+    /// Most recent audio, interleaved, oldest first.
+    history: Vec<f32>,
+    history_frames: usize,
+    /// Length of the most recently played packet, in frames.
+    packet_frames: usize,
+    synth: Option<Synth>,
+    /// True between the start of a gap and the next real audio.
+    concealing: bool,
+    /// Remaining frames of the fade back in to real audio.
+    recovering: usize,
+    concealed_frames: u64,
+    events: u64,
+}
+
+impl Concealer {
+    pub fn new(mode: Concealment, format: StreamFormat) -> Self {
+        let rate = format.sample_rate as f64;
+        let history_frames = ms_to_frames(MAX_PERIOD_MS + MATCH_WINDOW_MS, rate) + 1;
+        Self {
+            mode,
+            channels: format.channels as usize,
+            rate,
+            history: Vec::with_capacity(history_frames * format.channels as usize),
+            history_frames,
+            packet_frames: 0,
+            synth: None,
+            concealing: false,
+            recovering: 0,
+            concealed_frames: 0,
+            events: 0,
+        }
+    }
+
+    /// Forgets all played audio, e.g. when the sender changes.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.synth = None;
+        self.concealing = false;
+        self.recovering = 0;
+    }
+
+    /// Switches to a stream with a different sample rate. Played
+    /// audio is forgotten, as with `reset`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.rate = sample_rate as f64;
+        self.history_frames = ms_to_frames(MAX_PERIOD_MS + MATCH_WINDOW_MS, self.rate) + 1;
+        self.reset();
+    }
+
+    /// Records the length of incoming packets, which determines the
+    /// loop length for `Fade` and `Repeat`.
+    pub fn set_packet_frames(&mut self, frames: usize) {
+        self.packet_frames = frames;
+    }
+
+    /// True while the concealer is producing audible output.
+    pub fn is_active(&self) -> bool {
+        match &self.synth {
+            Some(synth) => self.gain(synth.elapsed, synth.hold) > 0.0,
+            None => false,
+        }
+    }
+
+    /// Frames of audio that were synthesized in place of missing audio.
+    pub fn concealed_frames(&self) -> u64 {
+        self.concealed_frames
+    }
+
+    /// Number of distinct gaps that were concealed.
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Must be called with every span of real audio right before it is
+    /// handed to the consumer. If a gap was just concealed, the start
+    /// of `samples` is crossfaded with the synthesized signal.
+    pub fn played<T: Sample>(&mut self, samples: &mut [T]) {
+        let frames = samples.len() / self.channels;
+        self.concealing = false;
+        if self.synth.is_some() && self.recovering == 0 {
+            self.recovering = ms_to_frames(FADE_IN_MS, self.rate);
+        }
+        if self.recovering > 0 {
+            let total = ms_to_frames(FADE_IN_MS, self.rate) as f32;
+            let amt = std::cmp::min(self.recovering, frames);
+            let mut synth = vec![0.0f32; amt * self.channels];
+            self.synthesize(&mut synth);
+            for i in 0..amt {
+                let g = 1.0 - (self.recovering - i) as f32 / total;
+                for c in 0..self.channels {
+                    let j = i * self.channels + c;
+                    let v = samples[j].to_f32() * g + synth[j] * (1.0 - g);
+                    samples[j] = T::from_f32(v);
+                }
+            }
+            self.recovering -= amt;
+            if self.recovering == 0 {
+                self.synth = None;
+            }
+        }
+        self.remember(samples);
+    }
+
+    /// Fills `output` with a stand-in for audio that never arrived.
+    pub fn conceal<T: Sample>(&mut self, output: &mut [T]) {
+        let frames = output.len() / self.channels;
+        if frames == 0 {
+            return;
+        }
+        if !self.concealing {
+            self.concealing = true;
+            self.events += 1;
+            self.synth = self.begin();
+        }
+        self.recovering = 0;
+        let mut synth = vec![0.0f32; frames * self.channels];
+        self.synthesize(&mut synth);
+        for (v, s) in output.iter_mut().zip(synth.iter()) {
+            *v = T::from_f32(*s);
+        }
+        self.concealed_frames += frames as u64;
+        self.remember(output);
+    }
+
+    fn begin(&self) -> Option<Synth> {
+        let available = self.history.len() / self.channels;
+        if available == 0 || self.mode == Concealment::Silence {
+            return None;
+        }
+        let period = match self.mode {
+            Concealment::Extrapolate => self.best_period(),
+            _ => None,
+        };
+        let (period, hold) = match (period, self.mode) {
+            (Some(period), _) => (period, ms_to_frames(HOLD_MS, self.rate)),
+            (None, Concealment::Fade) => (self.packet_frames, 0),
+            (None, _) => (self.packet_frames, ms_to_frames(HOLD_MS, self.rate)),
+        };
+        let period = std::cmp::max(1, std::cmp::min(period, available));
+        let start = (available - period) * self.channels;
+        Some(Synth {
+            template: Vec::from(&self.history[start..]),
+            pos: 0,
+            elapsed: 0,
+            hold,
+        })
+    }
+
+    /// Finds the period, in frames, at which the most recent audio best
+    /// repeats itself, measured by normalized cross-correlation.
+    fn best_period(&self) -> Option<usize> {
+        let window = ms_to_frames(MATCH_WINDOW_MS, self.rate);
+        let min = ms_to_frames(MIN_PERIOD_MS, self.rate);
+        let available = self.history.len() / self.channels;
+        let max = std::cmp::min(
+            ms_to_frames(MAX_PERIOD_MS, self.rate),
+            available.checked_sub(window)?,
+        );
+        if max < min {
+            return None;
+        }
+        let mono = (0..available)
+            .map(|i| {
+                let frame = &self.history[i * self.channels..(i + 1) * self.channels];
+                frame.iter().sum::<f32>() / self.channels as f32
+            })
+            .collect::<Vec<_>>();
+        let target = &mono[available - window..];
+        let target_energy = target.iter().map(|v| v * v).sum::<f32>();
+        if target_energy <= f32::EPSILON {
+            return None;
+        }
+        let mut best: Option<(usize, f32)> = None;
+        for lag in min..=max {
+            let candidate = &mono[available - window - lag..available - lag];
+            let energy = candidate.iter().map(|v| v * v).sum::<f32>();
+            if energy <= f32::EPSILON {
+                continue;
+            }
+            let corr = target
+                .iter()
+                .zip(candidate.iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+                / (target_energy * energy).sqrt();
+            match best {
+                Some((_, score)) if score >= corr => {}
+                _ => best = Some((lag, corr)),
+            }
+        }
+        best.map(|(lag, _)| lag)
+    }
+
+    fn synthesize(&mut self, output: &mut [f32]) {
+        let channels = self.channels;
+        let frames = output.len() / channels;
+        let fade_out = ms_to_frames(FADE_OUT_MS, self.rate);
+        let synth = match &mut self.synth {
+            Some(synth) => synth,
+            None => {
+                for v in output.iter_mut() {
+                    *v = 0.0;
+                }
+                return;
+            }
+        };
+        let period = synth.template.len() / channels;
+        for i in 0..frames {
+            let g = envelope(synth.elapsed, synth.hold, fade_out);
+            let src = &synth.template[synth.pos * channels..(synth.pos + 1) * channels];
+            for c in 0..channels {
+                output[i * channels + c] = src[c] * g;
+            }
+            synth.pos = (synth.pos + 1) % period;
+            synth.elapsed += 1;
+        }
+    }
+
+    fn gain(&self, elapsed: usize, hold: usize) -> f32 {
+        envelope(elapsed, hold, ms_to_frames(FADE_OUT_MS, self.rate))
+    }
+
+    fn remember<T: Sample>(&mut self, samples: &[T]) {
+        self.history.extend(samples.iter().map(|v| v.to_f32()));
+        let cap = self.history_frames * self.channels;
+        if self.history.len() > cap {
+            let excess = self.history.len() - cap;
+            self.history.drain(..excess);
+        }
+    }
+}
+
+fn envelope(elapsed: usize, hold: usize, fade: usize) -> f32 {
+    if elapsed < hold {
+        1.0
+    } else if elapsed >= hold + fade {
+        0.0
+    } else {
+        1.0 - (elapsed - hold) as f32 / fade as f32
+    }
+}
+
+fn ms_to_frames(ms: f64, rate: f64) -> usize {
+    (ms * rate / 1_000.0).round() as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::header::SampleFormat;
+
+    const RATE: u32 = 48_000;
+
+    fn format() -> StreamFormat {
+        StreamFormat::new(SampleFormat::F32, 1, RATE)
+    }
+
+    /// 200 Hz tone, i.e. a period of exactly 240 frames.
+    fn tone(start: usize, len: usize) -> Vec<f32> {
+        (start..start + len)
+            .map(|i| (2.0 * std::f32::consts::PI * 200.0 * i as f32 / RATE as f32).sin())
+            .collect()
+    }
+
+    fn error(a: &[f32], b: &[f32]) -> f32 {
+        a.iter().zip(b.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max)
+    }
+
+    fn primed(mode: Concealment) -> Concealer {
+        let mut plc = Concealer::new(mode, format());
+        plc.set_packet_frames(100);
+        let mut played = tone(0, 2_000);
+        plc.played(&mut played);
+        plc
+    }
+
+    #[test]
+    fn silence() {
+        let mut plc = primed(Concealment::Silence);
+        let mut out = vec![1.0f32; 100];
+        plc.conceal(&mut out);
+        assert!(out.iter().all(|v| *v == 0.0));
+        assert_eq!(plc.concealed_frames(), 100);
+    }
+
+    #[test]
+    fn extrapolate_continues_periodic_signal() {
+        let mut plc = primed(Concealment::Extrapolate);
+        let mut out = vec![0.0f32; 240];
+        plc.conceal(&mut out);
+        assert!(error(&out, &tone(2_000, 240)) < 0.01);
+        assert_eq!(plc.events(), 1);
+    }
+
+    #[test]
+    fn repeat_loops_last_packet() {
+        let mut plc = primed(Concealment::Repeat);
+        let mut out = vec![0.0f32; 200];
+        plc.conceal(&mut out);
+        let last = tone(1_900, 100);
+        assert_eq!(&out[..100], &last[..]);
+        assert_eq!(&out[100..], &last[..]);
+    }
+
+    #[test]
+    fn fades_to_silence() {
+        for &mode in &[Concealment::Fade, Concealment::Repeat, Concealment::Extrapolate] {
+            let mut plc = primed(mode);
+            let frames = ms_to_frames(HOLD_MS + FADE_OUT_MS, RATE as f64);
+            let mut out = vec![0.0f32; frames];
+            plc.conceal(&mut out);
+            assert!(!plc.is_active());
+            let mut tail = vec![1.0f32; 100];
+            plc.conceal(&mut tail);
+            assert!(tail.iter().all(|v| *v == 0.0));
+        }
+    }
+
+    #[test]
+    fn fade_starts_immediately() {
+        let mut plc = primed(Concealment::Fade);
+        let mut out = vec![0.0f32; 100];
+        plc.conceal(&mut out);
+        let last = tone(1_900, 100);
+        assert!(out[99].abs() < last[99].abs());
+    }
+
+    #[test]
+    fn crossfades_back_to_real_audio() {
+        let mut plc = primed(Concealment::Extrapolate);
+        let mut out = vec![0.0f32; 240];
+        plc.conceal(&mut out);
+        // Real audio resumes with an offset that would click if it
+        // were switched to abruptly.
+        let mut resumed = tone(2_240, 240).iter().map(|v| v + 0.5).collect::<Vec<_>>();
+        plc.played(&mut resumed);
+        assert!((resumed[0] - out[239]).abs() < 0.1);
+        assert!((resumed[239] - (tone(2_479, 1)[0] + 0.5)).abs() < 1e-6);
+        assert!(!plc.is_active());
+    }
+}
@@ -0,0 +1,576 @@
+use super::jitter::{JitterBuffer, JitterConfig};
+use super::playout::Playout;
+use super::*;
+use crate::sample::Sample;
+use crate::stream::control::Control;
+use crate::stream::fec::FecDecoder;
+use crate::stream::framing::{self, FrameReader};
+use crate::stream::header::{Header, StreamFormat};
+use crate::stream::quic::TLS;
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::{ConnectionState, StreamStats};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::sync::oneshot;
+
+/// How long a sender has to introduce itself on the control stream
+/// before it is turned away.
+const HELLO_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Receives audio sent by a `QuicTxStream`.
+///
+/// One sender is played at a time, and only once it has introduced
+/// its stream on the control stream. Its datagrams are left unread
+/// until then. Other senders are turned away until it disconnects or
+/// its connection times out, so a stranger can't take over the
+/// stream.
+pub struct QuicRxStream<T> {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+    jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+    stats: std::sync::Arc<StreamStats>,
+}
+
+/// Control stream of the sender being played.
+type ControlStream = (quinn::SendStream, quinn::RecvStream);
+
+/// What woke the receive task.
+enum Event {
+    Incoming(Option<quinn::Connecting>),
+    Connected(Result<quinn::NewConnection, quinn::ConnectionError>),
+    Control(Option<Result<ControlStream, quinn::ConnectionError>>),
+    /// The sender introduced itself, or why it can't be played.
+    Hello(Result<(), String>),
+    Datagram(Option<Result<bytes::Bytes, quinn::ConnectionError>>),
+}
+
+impl<T> QuicRxStream<T>
+where
+    T: Sample,
+{
+    /// Listens on `addr`, presenting the certificate in `tls`, or an
+    /// ephemeral self-signed one if it is `None`. Must be called
+    /// from within a tokio runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        format: StreamFormat,
+        config: JitterConfig,
+        tls: Option<&TLS>,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.listen(crate::stream::quic::server_config(tls)?);
+        let (endpoint, incoming) = endpoint
+            .bind(&addr)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let addr = endpoint.local_addr()?;
+        let jitter = std::sync::Arc::new(std::sync::Mutex::new(JitterBuffer::new(format, config)));
+        let stats = jitter.lock().unwrap().stats().clone();
+        let shutdown = {
+            let jitter = jitter.clone();
+            Shutdown::spawn(move |stop| Self::entry(jitter, endpoint, incoming, format, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            addr,
+            shutdown,
+            jitter,
+            stats,
+        }))
+    }
+
+    /// Address the stream is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Jitter buffer the audio is played from, for its statistics.
+    pub fn jitter(&self) -> &std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>> {
+        &self.jitter
+    }
+
+    /// Packet counters and the jitter buffer's statistics, readable
+    /// without locking it.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Stops receiving and closes the endpoint.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+        // Kept until the task exits, which releases the socket.
+        _endpoint: quinn::Endpoint,
+        mut incoming: quinn::Incoming,
+        format: StreamFormat,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut handshakes = FuturesUnordered::new();
+        // The sender being played. The control stream is only kept
+        // until the sender has introduced itself, which it must do by
+        // `hello_by`.
+        let mut connection: Option<quinn::Connection> = None;
+        let mut hello_by: Option<tokio::time::Instant> = None;
+        let mut datagrams: Option<quinn::Datagrams> = None;
+        let mut bi_streams: Option<quinn::IncomingBiStreams> = None;
+        let mut control: Option<ControlStream> = None;
+        let mut frames = FrameReader::new();
+        let mut fec = FecDecoder::new();
+        let stats = jitter.lock().unwrap().stats().clone();
+        stats.set_connection(ConnectionState::Listening);
+        let mut playout = Playout::new(jitter, format);
+        loop {
+            let event = tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => break,
+                connecting = incoming.next() => Event::Incoming(connecting),
+                Some(result) = handshakes.next(), if !handshakes.is_empty() => Event::Connected(result),
+                stream = next(&mut bi_streams) => Event::Control(stream),
+                result = next_hello(&mut control, &mut frames) => {
+                    Event::Hello(check_introduction(result, &format))
+                }
+                _ = until(hello_by) => Event::Hello(Err(format!(
+                    "no hello within {:?}",
+                    HELLO_TIMEOUT
+                ))),
+                // Nothing is played before the sender's introduction.
+                datagram = next(&mut datagrams), if hello_by.is_none() => Event::Datagram(datagram),
+            };
+            let src = connection.as_ref().map(|c| c.remote_address());
+            match event {
+                Event::Incoming(Some(connecting)) => handshakes.push(connecting),
+                // The endpoint was closed.
+                Event::Incoming(None) => break,
+                Event::Connected(Ok(conn)) => {
+                    let addr = conn.connection.remote_address();
+                    if let Some(src) = src {
+                        info!("quic rx: rejecting {}: already playing {}", addr, src);
+                        conn.connection.close(1u32.into(), b"busy");
+                        continue;
+                    }
+                    info!("quic rx: accepted {}", addr);
+                    frames.reset();
+                    stats.set_connection(ConnectionState::Connected);
+                    hello_by = Some(tokio::time::Instant::now() + HELLO_TIMEOUT);
+                    connection = Some(conn.connection);
+                    datagrams = Some(conn.datagrams);
+                    bi_streams = Some(conn.bi_streams);
+                    control = None;
+                }
+                Event::Connected(Err(e)) => warn!("quic rx: handshake failed: {}", e),
+                Event::Control(Some(Ok(stream))) => {
+                    // Only the first stream is the control stream.
+                    if control.is_none() {
+                        control = Some(stream);
+                    }
+                }
+                Event::Hello(result) => {
+                    let src = src.unwrap();
+                    hello_by = None;
+                    match result {
+                        Ok(()) => {
+                            info!("quic rx: {} introduced its stream", src);
+                            control = None;
+                        }
+                        Err(reason) => {
+                            warn!("quic rx: rejecting {}: {}", src, reason);
+                            if let Some((mut send, _)) = control.take() {
+                                reject(&mut send, &reason).await;
+                            }
+                            connection
+                                .take()
+                                .unwrap()
+                                .close(1u32.into(), reason.as_bytes());
+                            datagrams = None;
+                            bi_streams = None;
+                            control = None;
+                            stats.set_connection(ConnectionState::Listening);
+                        }
+                    }
+                }
+                Event::Datagram(Some(Ok(datagram))) => {
+                    stats.received(datagram.len());
+                    let src = src.unwrap();
+                    let (hdr, payload) = match Header::parse(&datagram) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("quic rx: dropping datagram from {}: {}", src, e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = hdr.verify(&format) {
+                        warn!("quic rx: dropping datagram from {}: {}", src, e);
+                        continue;
+                    }
+                    if hdr.is_parity() {
+                        let result = fec.parity(&hdr, payload, |packet| playout.recovered(packet));
+                        if let Err(e) = result {
+                            warn!("quic rx: dropping parity from {}: {}", src, e);
+                        }
+                        continue;
+                    }
+                    if let Err(e) = playout.insert(&hdr, payload) {
+                        warn!("quic rx: dropping datagram from {}: {}", src, e);
+                    }
+                    // Rebuild anything this packet completes before it
+                    // is due for playout.
+                    fec.data(&hdr, &datagram, |packet| playout.recovered(packet));
+                }
+                // Either stream ending means the connection is gone.
+                Event::Control(_) | Event::Datagram(_) => {
+                    if let Some(src) = src {
+                        info!("quic rx: {} disconnected", src);
+                    }
+                    connection = None;
+                    hello_by = None;
+                    datagrams = None;
+                    bi_streams = None;
+                    control = None;
+                    stats.set_connection(ConnectionState::Listening);
+                }
+            }
+        }
+        if let Some(connection) = connection {
+            connection.close(0u32.into(), b"shutdown");
+        }
+    }
+}
+
+/// Checks the introduction read from a sender's control stream.
+fn check_introduction(
+    result: std::io::Result<Option<&[u8]>>,
+    ours: &StreamFormat,
+) -> Result<(), String> {
+    match result {
+        Ok(Some(message)) => match Control::parse(message) {
+            Ok(Control::Hello {
+                version, format, ..
+            }) => check_hello(version, &format, ours),
+            Ok(message) => Err(format!("unexpected {:?}", message)),
+            Err(e) => Err(e.to_string()),
+        },
+        Ok(None) => Err("control stream closed before a hello".to_owned()),
+        Err(e) => Err(format!("control stream: {}", e)),
+    }
+}
+
+/// Checks that a sender's stream can be played.
+fn check_hello(version: u8, theirs: &StreamFormat, ours: &StreamFormat) -> Result<(), String> {
+    if version != crate::stream::header::VERSION {
+        return Err(format!(
+            "unsupported protocol version {}, expected {}",
+            version,
+            crate::stream::header::VERSION
+        ));
+    }
+    if theirs.channels != ours.channels {
+        return Err(format!(
+            "stream has {} channels, expected {}",
+            theirs.channels, ours.channels
+        ));
+    }
+    Ok(())
+}
+
+/// Tells the sender why it was rejected. Best effort, as the
+/// sender may already be gone.
+async fn reject(send: &mut quinn::SendStream, reason: &str) {
+    let mut message = Vec::new();
+    Control::Reject(reason.to_owned()).write(&mut message);
+    let mut frame = Vec::new();
+    framing::frame(&message, &mut frame);
+    if send.write_all(&frame).await.is_ok() {
+        let _ = send.finish().await;
+    }
+}
+
+/// Next item of a stream, or never if there is no stream.
+async fn next<S>(stream: &mut Option<S>) -> Option<S::Item>
+where
+    S: futures::Stream + Unpin,
+{
+    match stream {
+        Some(stream) => stream.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Completes at `deadline`, or never if there is none.
+async fn until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::delay_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads the sender's introduction from the control stream, once
+/// the sender has opened it.
+async fn next_hello<'a>(
+    control: &mut Option<ControlStream>,
+    frames: &'a mut FrameReader,
+) -> std::io::Result<Option<&'a [u8]>> {
+    match control {
+        Some((_, recv)) => frames.next(recv).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl<T> RxStream<T> for QuicRxStream<T>
+where
+    T: Sample,
+{
+    fn process(&self, output_buffer: &mut [T]) -> usize {
+        self.jitter.lock().unwrap().read(output_buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::ring::RingBuffer;
+    use crate::codec::CodecConfig;
+    use crate::stream::fingerprint::Fingerprint;
+    use crate::stream::header::SampleFormat;
+    use crate::stream::quic::DEFAULT_SERVER_NAME;
+    use crate::stream::tx::{quic::QuicTxStream, TxStream};
+
+    fn config() -> JitterConfig {
+        JitterConfig {
+            initial_delay: std::time::Duration::from_millis(5),
+            drift_compensation: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn loopback() {
+        let format = StreamFormat::new(SampleFormat::F32, 2, 48_000);
+        let rx = QuicRxStream::<f32>::new("127.0.0.1:0".parse().unwrap(), format, config(), None)
+            .unwrap();
+        let tx = QuicTxStream::<RingBuffer<f32>, f32>::new(
+            rx.local_addr(),
+            format,
+            CodecConfig::Pcm,
+            None,
+            false,
+            None,
+            true,
+        )
+        .unwrap();
+        // Give the sender a moment to connect.
+        tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+        let ramp = (0..9600).map(|i| (i / 2) as f32).collect::<Vec<_>>();
+        for chunk in ramp.chunks(96) {
+            tx.send(chunk);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        let mut out = vec![0.0f32; 480];
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while rx.process(&mut out) == 0 {
+            assert!(std::time::Instant::now() < deadline, "nothing received");
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        assert!(out.chunks(2).all(|frame| frame[0] == frame[1]));
+        assert!(out
+            .chunks(2)
+            .collect::<Vec<_>>()
+            .windows(2)
+            .all(|w| w[1][0] == w[0][0] + 1.0));
+        tx.shutdown().await;
+        rx.shutdown().await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn rejects_channel_mismatch() {
+        let rx = QuicRxStream::<f32>::new(
+            "127.0.0.1:0".parse().unwrap(),
+            StreamFormat::new(SampleFormat::F32, 2, 48_000),
+            config(),
+            None,
+        )
+        .unwrap();
+        let tx = QuicTxStream::<RingBuffer<f32>, f32>::new(
+            rx.local_addr(),
+            StreamFormat::new(SampleFormat::F32, 1, 48_000),
+            CodecConfig::Pcm,
+            None,
+            false,
+            None,
+            true,
+        )
+        .unwrap();
+        for _ in 0..200 {
+            tx.send(&[1.0f32; 48]);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        let mut out = vec![0.0f32; 96];
+        assert_eq!(rx.process(&mut out), 0);
+        tx.shutdown().await;
+        rx.shutdown().await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn turns_away_second_sender() {
+        let format = StreamFormat::new(SampleFormat::F32, 2, 48_000);
+        let rx = QuicRxStream::<f32>::new("127.0.0.1:0".parse().unwrap(), format, config(), None)
+            .unwrap();
+        let new_tx = || {
+            QuicTxStream::<RingBuffer<f32>, f32>::new(
+                rx.local_addr(),
+                format,
+                CodecConfig::Pcm,
+                None,
+                false,
+                None,
+                true,
+            )
+            .unwrap()
+        };
+        let first = new_tx();
+        assert!(receives(&first, &rx).await);
+        let second = new_tx();
+        let mut out = vec![0.0f32; 96];
+        for _ in 0..500 {
+            first.send(&[1.0f32; 96]);
+            second.send(&[2.0f32; 96]);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            let amt = rx.process(&mut out);
+            assert!(out[..amt].iter().all(|&sample| sample != 2.0));
+        }
+        first.shutdown().await;
+        second.shutdown().await;
+        rx.shutdown().await;
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn ignores_datagrams_before_hello() {
+        let format = StreamFormat::new(SampleFormat::F32, 2, 48_000);
+        let rx = QuicRxStream::<f32>::new("127.0.0.1:0".parse().unwrap(), format, config(), None)
+            .unwrap();
+        // A client that connects but never opens the control stream.
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.default_client_config(crate::stream::quic::client_config(None, true).unwrap());
+        let (endpoint, _) = endpoint.bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let conn = endpoint
+            .connect(&rx.local_addr(), DEFAULT_SERVER_NAME)
+            .unwrap()
+            .await
+            .unwrap();
+        let mut hdr = Header {
+            flags: 0,
+            stream_id: 1,
+            sequence: 0,
+            timestamp: 0,
+            format,
+            codec: crate::codec::Codec::Pcm,
+            frames: 48,
+        };
+        let mut packet = vec![0u8; crate::stream::header::HEADER_LEN + 48 * 2 * 4];
+        let mut out = vec![0.0f32; 96];
+        for _ in 0..200 {
+            let len = hdr.write(&mut packet[..]);
+            crate::sample::encode(&[1.0f32; 96], SampleFormat::F32, &mut packet[len..], None);
+            let _ = conn
+                .connection
+                .send_datagram(bytes::Bytes::copy_from_slice(&packet));
+            hdr.sequence += 1;
+            hdr.timestamp += 48;
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            assert_eq!(rx.process(&mut out), 0);
+        }
+        rx.shutdown().await;
+    }
+
+    /// Writes a self-signed certificate and its key to the temp
+    /// directory, returning their paths and the certificate's
+    /// fingerprint.
+    fn self_signed(name: &str) -> (String, String, Fingerprint) {
+        let cert = rcgen::generate_simple_self_signed(vec![DEFAULT_SERVER_NAME.into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let dir = std::env::temp_dir();
+        let prefix = format!("paradise-{}-{}", std::process::id(), name);
+        let cert_path = dir.join(format!("{}.crt", prefix));
+        let key_path = dir.join(format!("{}.key", prefix));
+        std::fs::write(&cert_path, &der).unwrap();
+        std::fs::write(&key_path, cert.serialize_private_key_der()).unwrap();
+        (
+            cert_path.to_str().unwrap().to_owned(),
+            key_path.to_str().unwrap().to_owned(),
+            Fingerprint::of(&der),
+        )
+    }
+
+    async fn receives(tx: &QuicTxStream<RingBuffer<f32>, f32>, rx: &QuicRxStream<f32>) -> bool {
+        let mut out = vec![0.0f32; 96];
+        for _ in 0..1000 {
+            tx.send(&[1.0f32; 96]);
+            tokio::time::delay_for(std::time::Duration::from_millis(2)).await;
+            if rx.process(&mut out) > 0 {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn pinned_mutual_tls() {
+        let format = StreamFormat::new(SampleFormat::F32, 2, 48_000);
+        let (server_cert, server_key, server_pin) = self_signed("server");
+        let (client_cert, client_key, client_pin) = self_signed("client");
+        let server = TLS {
+            cert: Some(server_cert),
+            key: Some(server_key),
+            pins: vec![client_pin.to_string()],
+            ..Default::default()
+        };
+        let client = TLS {
+            cert: Some(client_cert),
+            key: Some(client_key),
+            pins: vec![server_pin.to_string()],
+            ..Default::default()
+        };
+        let cases = vec![
+            (client.clone(), true),
+            // The client pins some other certificate.
+            (
+                TLS {
+                    pins: vec![client_pin.to_string()],
+                    ..client.clone()
+                },
+                false,
+            ),
+            // The client doesn't present a certificate.
+            (
+                TLS {
+                    cert: None,
+                    key: None,
+                    ..client
+                },
+                false,
+            ),
+        ];
+        for (client, accepted) in cases {
+            let rx = QuicRxStream::<f32>::new(
+                "127.0.0.1:0".parse().unwrap(),
+                format,
+                config(),
+                Some(&server),
+            )
+            .unwrap();
+            let tx = QuicTxStream::<RingBuffer<f32>, f32>::new(
+                rx.local_addr(),
+                format,
+                CodecConfig::Pcm,
+                None,
+                false,
+                Some(&client),
+                false,
+            )
+            .unwrap();
+            assert_eq!(receives(&tx, &rx).await, accepted);
+            tx.shutdown().await;
+            rx.shutdown().await;
+        }
+    }
+}
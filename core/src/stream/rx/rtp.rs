@@ -0,0 +1,291 @@
+use super::jitter::{JitterBuffer, JitterConfig};
+use super::*;
+use crate::codec::Codec;
+use crate::sample::Sample;
+use crate::stream::header::{Header, StreamFormat};
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::rtp::{self, RtpConfig, RtpError, RtpHeader};
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::StreamStats;
+use tokio::sync::oneshot;
+
+pub struct RtpRxStream<T> {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+    jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+    stats: std::sync::Arc<StreamStats>,
+}
+
+impl<T> RtpRxStream<T>
+where
+    T: Sample,
+{
+    /// Listens on `addr` for an RTP stream described by `rtp`, joining
+    /// the group as `multicast` configures if it's a multicast group,
+    /// and plays it out in `format`. Packets of other payload types
+    /// are dropped. Must be called from within a tokio runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        format: StreamFormat,
+        config: JitterConfig,
+        rtp: &RtpConfig,
+        multicast: &MulticastConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        rtp.validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        if rtp.channels != format.channels {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "stream has {} channels, expected {}",
+                    rtp.channels, format.channels
+                ),
+            ));
+        }
+        let sock = multicast::receiver(addr, multicast)?;
+        sock.set_nonblocking(true)?;
+        let addr = sock.local_addr()?;
+        let jitter = std::sync::Arc::new(std::sync::Mutex::new(JitterBuffer::new(format, config)));
+        let stats = jitter.lock().unwrap().stats().clone();
+        let shutdown = {
+            let (jitter, rtp) = (jitter.clone(), *rtp);
+            Shutdown::spawn(move |stop| Self::entry(jitter, sock, rtp, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            addr,
+            shutdown,
+            jitter,
+            stats,
+        }))
+    }
+
+    /// Address the stream is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Jitter buffer the audio is played from, for its statistics.
+    pub fn jitter(&self) -> &std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>> {
+        &self.jitter
+    }
+
+    /// Packet counters and the jitter buffer's statistics, readable
+    /// without locking it.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Stops receiving and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+        sock: std::net::UdpSocket,
+        rtp: RtpConfig,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("rtp rx: {}", e);
+                return;
+            }
+        };
+        const BUFFER_SIZE: usize = 65_536;
+        let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+        let mut samples: Vec<T> = Vec::new();
+        let mut counters = Counters::default();
+        let stats = jitter.lock().unwrap().stats().clone();
+        loop {
+            let result = tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                result = sock.recv_from(&mut buf[..]) => result,
+            };
+            let (amt, src) = match result {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("rtp rx recv_from: {:?}", e);
+                    continue;
+                }
+            };
+            stats.received(amt);
+            let (rtp_hdr, start, frames) = match parse(&buf[..amt], &rtp) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("rtp rx: dropping packet from {}: {}", src, e);
+                    continue;
+                }
+            };
+            let (sequence, timestamp) = counters.unwrap(&rtp_hdr);
+            // The jitter buffer orders packets by the native header,
+            // so the RTP one is translated. Timestamps are extended to
+            // 64 bits and sequence numbers to 32.
+            let hdr = Header {
+                flags: 0,
+                stream_id: rtp_hdr.ssrc,
+                sequence: sequence as u32,
+                timestamp,
+                format: rtp.format(),
+                codec: Codec::Pcm,
+                frames: frames as u16,
+            };
+            samples.resize(frames * rtp.channels as usize, T::default());
+            rtp::decode(
+                &mut buf[start..start + hdr.payload_len()],
+                rtp.encoding,
+                &mut samples[..],
+            );
+            jitter
+                .lock()
+                .unwrap()
+                .insert(&hdr, &samples[..], std::time::Instant::now());
+        }
+    }
+}
+
+/// Checks a packet against the stream's configuration, returning its
+/// header, where its payload starts and how many frames it holds.
+fn parse(packet: &[u8], rtp: &RtpConfig) -> Result<(RtpHeader, usize, usize), RtpError> {
+    let (hdr, payload) = RtpHeader::parse(packet)?;
+    if hdr.payload_type != rtp.payload_type {
+        return Err(RtpError::PayloadType {
+            expected: rtp.payload_type,
+            actual: hdr.payload_type,
+        });
+    }
+    let frame_size = rtp.format().bytes_per_frame();
+    let frames = payload.len() / frame_size;
+    if payload.len() % frame_size != 0 || frames == 0 || frames > u16::MAX as usize {
+        return Err(RtpError::PayloadLength(payload.len()));
+    }
+    let start = payload.as_ptr() as usize - packet.as_ptr() as usize;
+    Ok((hdr, start, frames))
+}
+
+/// Extended sequence number and timestamp of the current sender.
+#[derive(Default)]
+struct Counters {
+    ssrc: Option<u32>,
+    sequence: u64,
+    timestamp: u64,
+}
+
+impl Counters {
+    fn unwrap(&mut self, hdr: &RtpHeader) -> (u64, u64) {
+        if self.ssrc == Some(hdr.ssrc) {
+            self.sequence = rtp::unwrap(self.sequence, hdr.sequence as u64, 16);
+            self.timestamp = rtp::unwrap(self.timestamp, hdr.timestamp as u64, 32);
+        } else {
+            // A new sender, which the jitter buffer starts over for.
+            // Starting a wrap above zero leaves room for reordered
+            // packets from before the first one.
+            self.ssrc = Some(hdr.ssrc);
+            self.sequence = (1 << 16) | hdr.sequence as u64;
+            self.timestamp = (1 << 32) | hdr.timestamp as u64;
+        }
+        (self.sequence, self.timestamp)
+    }
+}
+
+impl<T> RxStream<T> for RtpRxStream<T>
+where
+    T: Sample,
+{
+    fn process(&self, output_buffer: &mut [T]) -> usize {
+        self.jitter.lock().unwrap().read(output_buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::ring::RingBuffer;
+    use crate::stream::header::SampleFormat;
+    use crate::stream::rtp::{Encoding, PacketTime};
+    use crate::stream::tx::{rtp::RtpTxStream, TxStream};
+
+    #[tokio::test(threaded_scheduler)]
+    async fn loopback() {
+        let rtp = RtpConfig {
+            encoding: Encoding::L16,
+            packet_time: PacketTime::Microseconds125,
+            channels: 1,
+            ..Default::default()
+        };
+        let config = JitterConfig {
+            initial_delay: std::time::Duration::from_millis(5),
+            drift_compensation: false,
+            ..Default::default()
+        };
+        let rx = RtpRxStream::<f32>::new(
+            "127.0.0.1:0".parse().unwrap(),
+            StreamFormat::new(SampleFormat::F32, 1, 48_000),
+            config,
+            &rtp,
+            &MulticastConfig::default(),
+        )
+        .unwrap();
+        let tx = RtpTxStream::<RingBuffer<f32>, f32>::new(
+            rx.local_addr(),
+            &rtp,
+            false,
+            &MulticastConfig::default(),
+        )
+        .unwrap();
+        let sdp = tx.describe("loopback");
+        assert_eq!(sdp.dest, rx.local_addr());
+        assert_eq!(sdp.origin, rx.local_addr().ip());
+        assert_eq!(sdp.rtp, rtp);
+        // Chunks that don't line up with the 6 frame packets. Audio
+        // is read while it streams, so the buffer never runs dry.
+        let ramp = (0..9600).map(|i| i as f32 / 32768.0).collect::<Vec<_>>();
+        let mut out = vec![0.0f32; 48];
+        let mut received = false;
+        for chunk in ramp.chunks(50) {
+            tx.send(chunk);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            if rx.process(&mut out) > 0 {
+                received = true;
+                break;
+            }
+        }
+        assert!(received, "nothing received");
+        assert!(out
+            .windows(2)
+            .all(|w| ((w[1] - w[0]) * 32768.0 - 1.0).abs() < 1e-3));
+        tx.shutdown().await;
+        rx.shutdown().await;
+    }
+
+    #[test]
+    fn rejects_mismatched_packets() {
+        let rtp = RtpConfig::default();
+        let mut packet = vec![0u8; 12 + rtp.format().bytes_per_frame() * rtp.frames()];
+        let mut hdr = RtpHeader {
+            marker: false,
+            payload_type: 96,
+            sequence: 0,
+            timestamp: 0,
+            ssrc: 1,
+        };
+        hdr.write(&mut packet[..]);
+        assert_eq!(parse(&packet[..], &rtp).unwrap().1, 12);
+        assert_eq!(
+            parse(&packet[..packet.len() - 1], &rtp),
+            Err(RtpError::PayloadLength(287))
+        );
+        hdr.payload_type = 97;
+        hdr.write(&mut packet[..]);
+        assert_eq!(
+            parse(&packet[..], &rtp),
+            Err(RtpError::PayloadType {
+                expected: 96,
+                actual: 97
+            })
+        );
+    }
+}
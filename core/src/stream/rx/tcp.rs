@@ -1,126 +1,290 @@
+use super::jitter::{JitterBuffer, JitterConfig};
+use super::playout::Playout;
 use super::*;
-use std::io::prelude::*;
+use crate::sample::Sample;
+use crate::stream::backoff::Backoff;
+use crate::stream::framing::FrameReader;
+use crate::stream::header::{Header, StreamFormat};
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::{ConnectionState, StreamStats};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
 
-pub struct TcpRxStream<B> where B: RxBuffer {
-    clock: std::sync::atomic::AtomicU64,
-    stop: crossbeam::crossbeam_channel::Sender<()>,
-    buf: std::sync::Arc<B>,
-    sync: crossbeam::crossbeam_channel::Sender<u64>,
+/// Receives audio sent by a `TcpTxStream`.
+///
+/// One sender is played at a time. A new connection replaces the
+/// current one, so a sender that reconnects after a network failure
+/// takes over even if the old connection hasn't timed out yet.
+pub struct TcpRxStream<T> {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+    jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+    stats: std::sync::Arc<StreamStats>,
 }
 
-impl<B> TcpRxStream<B> where B: 'static + RxBuffer {
-    pub fn new(port: u16) -> std::io::Result<std::sync::Arc<Self>> {
-        let buf  = std::sync::Arc::new(B::new());
-        let (stop_send, stop_recv) = crossbeam::crossbeam_channel::unbounded();
-        let (sync_send, sync_recv) = crossbeam::crossbeam_channel::unbounded();
-        let stream = std::sync::Arc::new(Self {
-            buf: buf.clone(),
-            stop: stop_send,
-            clock: std::default::Default::default(),
-            sync: sync_send,
-        });
-        crate::runtime::Runtime::get()
-            .rt
-            .lock()
-            .unwrap()
-            .block_on(async {
-                //tokio::task::spawn(Self::entry(stream.buf.clone(), port, sync_recv, stop_recv))
-            });
-        Ok(stream)
+/// What woke the receive task.
+enum Event<'a> {
+    Accepted(std::io::Result<(TcpStream, std::net::SocketAddr)>),
+    Frame(std::io::Result<Option<&'a [u8]>>),
+}
+
+impl<T> TcpRxStream<T>
+where
+    T: Sample,
+{
+    /// Listens on `addr`. Must be called from within a tokio runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        format: StreamFormat,
+        config: JitterConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let jitter = std::sync::Arc::new(std::sync::Mutex::new(JitterBuffer::new(format, config)));
+        let stats = jitter.lock().unwrap().stats().clone();
+        let shutdown = {
+            let jitter = jitter.clone();
+            Shutdown::spawn(move |stop| Self::entry(jitter, listener, format, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            addr,
+            shutdown,
+            jitter,
+            stats,
+        }))
     }
 
-    async fn entry(b: std::sync::Arc<B>, port: u16, sync: crossbeam::crossbeam_channel::Receiver<u64>, stop: crossbeam::crossbeam_channel::Receiver<()>) {
-        let addr = format!("0.0.0.0:{}", port);
-        let mut listener = std::net::TcpListener::bind(&addr).unwrap();
-        listener.set_nonblocking(true).unwrap();
-        const BUFFER_SIZE: usize = 256_000;
-        let mut recv_buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-        // TODO: set clock. Right now all samples are accepted.
-        let mut clock: u64 = 0;
-        let mut stream: Option<std::net::TcpStream> = None;
-        let mut msg: Vec<u8> = Vec::new();
-        loop {
-            std::thread::yield_now();
-            select! {
-                recv(stop) -> _ => return,
-                recv(sync) -> time => clock = time.unwrap(),
-                default => {},
+    /// Address the stream is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Packet counters and the jitter buffer's statistics, readable
+    /// without locking it.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Stops receiving and waits until the listener and any
+    /// connection are closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+        listener: std::net::TcpListener,
+        format: StreamFormat,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut listener = match TcpListener::from_std(listener) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("tcp rx: {}", e);
+                return;
             }
-            match listener.accept() {
-                Ok((incoming, _addr)) => {
-                    // Existing connection is closed when stream is dropped
-                    incoming.set_nonblocking(true).unwrap();
-                    stream = Some(incoming);
-                },
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::WouldBlock => {},
-                    _ => {
-                        println!("listener.accept(): {:?}", e);
-                    },
-                }
+        };
+        let mut conn: Option<(TcpStream, std::net::SocketAddr)> = None;
+        let mut frames = FrameReader::new();
+        let mut backoff = Backoff::default();
+        // Accepting is paused for a while after it fails, e.g. when
+        // out of file descriptors.
+        let mut accept_at = tokio::time::Instant::now();
+        let stats = jitter.lock().unwrap().stats().clone();
+        stats.set_connection(ConnectionState::Listening);
+        let mut playout = Playout::new(jitter, format);
+        loop {
+            let event = tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                result = accept(&mut listener, accept_at) => Event::Accepted(result),
+                result = next_frame(&mut conn, &mut frames) => Event::Frame(result),
             };
-            match &mut stream {
-                Some(stream) => {
-                    let amt = match stream.read(&mut recv_buf[..]) {
-                        Ok(amt) => amt,
-                        Err(e) => { 0 },
+            match event {
+                Event::Accepted(Ok((sock, src))) => {
+                    backoff.reset();
+                    if let Err(e) = sock.set_nodelay(true) {
+                        warn!("tcp rx: {}", e);
+                    }
+                    match &conn {
+                        Some((_, old)) => info!("tcp rx: {} replaces {}", src, old),
+                        None => info!("tcp rx: accepted {}", src),
+                    }
+                    frames.reset();
+                    stats.set_connection(ConnectionState::Connected);
+                    conn = Some((sock, src));
+                }
+                Event::Accepted(Err(e)) => {
+                    let delay = backoff.next_delay();
+                    warn!("tcp rx: accept: {}, retrying in {:?}", e, delay);
+                    accept_at = tokio::time::Instant::now() + delay;
+                }
+                Event::Frame(Ok(Some(packet))) => {
+                    stats.received(packet.len());
+                    let src = conn.as_ref().map(|(_, src)| *src).unwrap();
+                    let (hdr, payload) = match Header::parse(packet) {
+                        Ok(v) => v,
+                        Err(e) => {
+                            warn!("tcp rx: dropping packet from {}: {}", src, e);
+                            continue;
+                        }
                     };
-                    msg.extend_from_slice(&recv_buf[..amt]);
-                    if msg.len() >= 2 {
-                        let len = ((msg[0] as usize) << 8) |
-                            ((msg[1] as usize) << 0);
-                        if msg.len() >= len {
-                            // We have at least the given message
-                            let datagram = &msg[..len];
-                            let hdr = &datagram[..8];
-                            let timestamp = ((hdr[0] as u64) << 48) |
-                                ((hdr[1] as u64) << 40) |
-                                ((hdr[2] as u64) << 32) |
-                                ((hdr[3] as u64) << 24) |
-                                ((hdr[4] as u64) << 16) |
-                                ((hdr[5] as u64) << 8) |
-                                ((hdr[6] as u64) << 0);
-                            // Don't accumulate samples older than the oldest timestamp observed in the previous flushed
-                            let delta = timestamp - clock;
-                            if delta < 0 {
-                                // Current timestamp is higher than incoming.
-                                // Discard this sample.
-                                println!("discarding late sample buffer");
-                                msg.drain(..len);
-                                continue;
-                            }
-                            let status = hdr[7];
-                            let data = &datagram[8..];
-                            if data.len() % 4 != 0 {
-                                println!("data buffer is not divisible by four");
-                                msg.drain(..len);
-                                continue;
-                            }
-                            let num_samples = data.len() / 4;
-                            let samples: &[f32] = unsafe { std::slice::from_raw_parts(data.as_ptr() as _, num_samples) };
-                            b.accumulate(timestamp, samples);
-                            msg.drain(..len);
+                    if let Err(e) = hdr.verify(&format) {
+                        warn!("tcp rx: dropping packet from {}: {}", src, e);
+                        continue;
+                    }
+                    // Nothing is lost in transit, so parity is of no use.
+                    if hdr.is_parity() {
+                        continue;
+                    }
+                    if let Err(e) = playout.insert(&hdr, payload) {
+                        warn!("tcp rx: dropping packet from {}: {}", src, e);
+                    }
+                }
+                Event::Frame(result) => {
+                    if let Some((_, src)) = conn.take() {
+                        match result {
+                            Err(e) => warn!("tcp rx: connection from {} lost: {}", src, e),
+                            _ => info!("tcp rx: {} disconnected", src),
                         }
                     }
-                },
-                None => {
-                    println!("no incoming stream");
-                },
+                    stats.set_connection(ConnectionState::Listening);
+                }
             }
         }
     }
 }
 
-impl<B> std::ops::Drop for TcpRxStream<B>
-    where B: RxBuffer {
-    fn drop(&mut self) {
-        self.stop.send(());
+async fn accept(
+    listener: &mut TcpListener,
+    at: tokio::time::Instant,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    tokio::time::delay_until(at).await;
+    listener.accept().await
+}
+
+/// Reads the next frame from the current connection, if there is one.
+async fn next_frame<'a>(
+    conn: &mut Option<(TcpStream, std::net::SocketAddr)>,
+    frames: &'a mut FrameReader,
+) -> std::io::Result<Option<&'a [u8]>> {
+    match conn {
+        Some((sock, _)) => frames.next(sock).await,
+        None => std::future::pending().await,
+    }
+}
+
+impl<T> RxStream<T> for TcpRxStream<T>
+where
+    T: Sample,
+{
+    fn process(&self, output_buffer: &mut [T]) -> usize {
+        self.jitter.lock().unwrap().read(output_buffer)
     }
 }
 
-impl<B> RxStream for TcpRxStream<B> where B: 'static + RxBuffer {
-    fn process(&self, output_buffer: &mut [f32]) {
-        // Swap out the current receive buffer
-        self.buf.flush(output_buffer);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::ring::RingBuffer;
+    use crate::codec::CodecConfig;
+    use crate::stream::header::SampleFormat;
+    use crate::stream::tx::{tcp::TcpTxStream, TxStream};
+
+    fn format() -> StreamFormat {
+        StreamFormat::new(SampleFormat::F32, 1, 48_000)
+    }
+
+    fn config() -> JitterConfig {
+        JitterConfig {
+            initial_delay: std::time::Duration::from_millis(5),
+            drift_compensation: false,
+            ..Default::default()
+        }
+    }
+
+    /// Sends a ramp starting at `start` in 1 ms chunks.
+    async fn send_ramp<S: TxStream<f32>>(tx: &S, start: usize) {
+        let ramp = (start..start + 4800).map(|i| i as f32).collect::<Vec<_>>();
+        for chunk in ramp.chunks(48) {
+            tx.send(chunk);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Waits for audio and returns the first block received.
+    async fn receive(rx: &TcpRxStream<f32>) -> Vec<f32> {
+        let mut out = vec![0.0f32; 240];
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            if rx.process(&mut out) > 0 {
+                return out;
+            }
+            assert!(std::time::Instant::now() < deadline, "nothing received");
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn loopback() {
+        let rx =
+            TcpRxStream::<f32>::new("127.0.0.1:0".parse().unwrap(), format(), config()).unwrap();
+        let tx = TcpTxStream::<RingBuffer<f32>, f32>::new(
+            rx.local_addr(),
+            format(),
+            CodecConfig::Pcm,
+            false,
+        )
+        .unwrap();
+        // Give the sender a moment to connect.
+        tokio::time::delay_for(std::time::Duration::from_millis(50)).await;
+        send_ramp(&*tx, 0).await;
+        let out = receive(&rx).await;
+        assert!(out.windows(2).all(|w| w[1] == w[0] + 1.0));
+        tx.shutdown().await;
+        rx.shutdown().await;
+        // The listener is closed once shutdown returns.
+        std::net::TcpListener::bind(rx.local_addr()).unwrap();
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn reconnects() {
+        // Reserve a port, then start sending before anyone listens.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let tx = TcpTxStream::<RingBuffer<f32>, f32>::new(addr, format(), CodecConfig::Pcm, false)
+            .unwrap();
+        tokio::time::delay_for(std::time::Duration::from_millis(150)).await;
+        let rx = TcpRxStream::<f32>::new(addr, format(), config()).unwrap();
+        // Long enough for the sender's retries to find the listener.
+        tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+        send_ramp(&*tx, 0).await;
+        let out = receive(&rx).await;
+        assert!(out.windows(2).all(|w| w[1] == w[0] + 1.0));
+        // The receiver restarts and the sender finds it again.
+        rx.shutdown().await;
+        drop(rx);
+        let rx = TcpRxStream::<f32>::new(addr, format(), config()).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let mut out = vec![0.0f32; 240];
+        let mut start = 100_000;
+        loop {
+            send_ramp(&*tx, start).await;
+            start += 100_000;
+            let n = rx.process(&mut out);
+            if n > 0 && out.iter().any(|&s| s >= 100_000.0) {
+                break;
+            }
+            assert!(
+                std::time::Instant::now() < deadline,
+                "sender never reconnected"
+            );
+        }
+        tx.shutdown().await;
+        rx.shutdown().await;
     }
-}
\ No newline at end of file
+}
@@ -1,114 +1,339 @@
 use super::*;
-use crate::stream::buffer::Buffer;
-use std::marker::PhatomData;
+use crate::sample::Sample;
+use crate::stream::crypto::{self, Key, Opener};
+use crate::stream::fec::FecDecoder;
+use crate::stream::header::{Header, StreamFormat};
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::StreamStats;
+use super::jitter::{JitterBuffer, JitterConfig};
+use super::playout::Playout;
+use tokio::sync::oneshot;
 
-pub struct UdpRxStream<B, T>
-where
-    B: Buffer<T>,
-T: Clone,
-{
-    clock: std::sync::atomic::AtomicU64,
-    stop: crossbeam::crossbeam_channel::Sender<()>,
-    buf: std::sync::Arc<B>,
-    sync: std::sync::Arc<std::sync::atomic::AtomicU64>,
-    phantom: PhatomData<T>,
+pub struct UdpRxStream<T> {
+    addr: std::net::SocketAddr,
+    shutdown: Shutdown,
+    jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
+    stats: std::sync::Arc<StreamStats>,
 }
 
-impl<B, T> UdpRxStream<B, T>
+impl<T> UdpRxStream<T>
 where
-    B: 'static + Buffer<T>,
-    T: Clone,
+    T: Sample,
 {
-    pub fn new(addr: std::net::SocketAddr) -> std::io::Result<std::sync::Arc<Self>> {
-        let sock = std::net::UdpSocket::bind(&addr)?;
+    /// Listens on `addr`, joining the group as `multicast` configures
+    /// if it's a multicast group. Only datagrams encrypted with `key`
+    /// are accepted if it's given. Must be called from within a tokio
+    /// runtime.
+    pub fn new(
+        addr: std::net::SocketAddr,
+        format: StreamFormat,
+        config: JitterConfig,
+        key: Option<&Key>,
+        multicast: &MulticastConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        let sock = multicast::receiver(addr, multicast)?;
         sock.set_nonblocking(true)?;
-        let (s, stop_recv) = crossbeam::crossbeam_channel::unbounded();
-        let sync = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
-        let stream = std::sync::Arc::new(Self {
-            stop: s,
-            clock: std::default::Default::default(),
-            buf: std::sync::Arc::new(B::new()),
-            sync: sync.clone(),
-            phantom: PhantomData,
-        });
-        tokio::task::spawn(Self::entry(stream.buf.clone(), sock, sync, stop_recv));
-        Ok(stream)
+        let addr = sock.local_addr()?;
+        let jitter = std::sync::Arc::new(std::sync::Mutex::new(JitterBuffer::new(format, config)));
+        let stats = jitter.lock().unwrap().stats().clone();
+        let opener = key.map(Opener::new);
+        let shutdown = {
+            let jitter = jitter.clone();
+            Shutdown::spawn(move |stop| Self::entry(jitter, sock, format, opener, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            addr,
+            shutdown,
+            jitter,
+            stats,
+        }))
+    }
+
+    /// Address the stream is listening on, which tells the port
+    /// chosen when binding to port 0.
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Jitter buffer the audio is played from, for its statistics.
+    pub fn jitter(&self) -> &std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>> {
+        &self.jitter
+    }
+
+    /// Packet counters and the jitter buffer's statistics, readable
+    /// without locking it.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Stops receiving and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
     }
 
     async fn entry(
-        b: std::sync::Arc<B>,
+        jitter: std::sync::Arc<std::sync::Mutex<JitterBuffer<T>>>,
         sock: std::net::UdpSocket,
-        sync: std::sync::Arc<std::sync::atomic::AtomicU64>,
-        stop: crossbeam::crossbeam_channel::Receiver<()>,
+        format: StreamFormat,
+        mut opener: Option<Opener>,
+        mut stop: oneshot::Receiver<()>,
     ) {
-        /*
-        const BUFFER_SIZE: usize = 256_000;
-        let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-        // TODO: set clock. Right now all samples are accepted.
-        let mut clock: u64 = 0;
-        loop {
-            std::thread::yield_now();
-            select! {
-                recv(stop) -> _ => return,
-                default => {},
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("udp rx: {}", e);
+                return;
             }
-            let (amt, _src) = match sock.recv_from(&mut buf[..]) {
+        };
+        const BUFFER_SIZE: usize = 65_536;
+        let mut buf: Vec<u8> = vec![0; BUFFER_SIZE];
+        let mut fec = FecDecoder::new();
+        let stats = jitter.lock().unwrap().stats().clone();
+        let mut playout = Playout::new(jitter, format);
+        loop {
+            let result = tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                result = sock.recv_from(&mut buf[..]) => result,
+            };
+            let (amt, src) = match result {
                 Ok(value) => value,
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::WouldBlock => continue,
-                    _ => {
-                        println!("recv_from: {:?}", e);
+                Err(e) => {
+                    error!("udp rx recv_from: {:?}", e);
+                    continue;
+                }
+            };
+            stats.received(amt);
+            let amt = match &mut opener {
+                Some(opener) => match opener.open(&mut buf[..amt]) {
+                    Ok(amt) => amt,
+                    Err(e) => {
+                        warn!("udp rx: dropping datagram from {}: {}", src, e);
                         continue;
                     }
                 },
+                None if crypto::is_sealed(&buf[..amt]) => {
+                    warn!(
+                        "udp rx: dropping encrypted datagram from {} (no key configured)",
+                        src
+                    );
+                    continue;
+                }
+                None => amt,
+            };
+            let (hdr, payload) = match Header::parse(&buf[..amt]) {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("udp rx: dropping datagram from {}: {}", src, e);
+                    continue;
+                }
             };
-            let hdr = &buf[..8];
-            let timestamp = ((hdr[0] as u64) << 48)
-                | ((hdr[1] as u64) << 40)
-                | ((hdr[2] as u64) << 32)
-                | ((hdr[3] as u64) << 24)
-                | ((hdr[4] as u64) << 16)
-                | ((hdr[5] as u64) << 8)
-                | ((hdr[6] as u64) << 0);
-            // Don't accumulate samples older than the oldest timestamp observed in the previous flushed
-            let delta = timestamp - clock;
-            if delta < 0 {
-                // Current timestamp is higher than incoming.
-                // Discard this sample.
-                println!("discarding late sample buffer");
+            if let Err(e) = hdr.verify(&format) {
+                warn!("udp rx: dropping datagram from {}: {}", src, e);
                 continue;
             }
-            let status = hdr[7];
-            let data = &buf[8..amt - 8];
-            if data.len() % 4 != 0 {
-                println!("data buffer is not divisible by four");
+            if hdr.is_parity() {
+                let result = fec.parity(&hdr, payload, |packet| playout.recovered(packet));
+                if let Err(e) = result {
+                    warn!("udp rx: dropping parity from {}: {}", src, e);
+                }
                 continue;
             }
-            let num_samples = data.len() / 4;
-            let samples: &[T] =
-                unsafe { std::slice::from_raw_parts(data.as_ptr() as _, num_samples) };
-            b.accumulate(samples);
-        }*/
+            if let Err(e) = playout.insert(&hdr, payload) {
+                warn!("udp rx: dropping datagram from {}: {}", src, e);
+            }
+            // Rebuild anything this packet completes before it is
+            // due for playout.
+            fec.data(&hdr, &buf[..amt], |packet| playout.recovered(packet));
+        }
     }
 }
 
-impl<B, T> std::ops::Drop for UdpRxStream<B, T>
+impl<T> RxStream<T> for UdpRxStream<T>
 where
-    B: Buffer<T>,
-    T: Clone,
+    T: Sample,
 {
-    fn drop(&mut self) {
-        self.stop.send(());
+    fn process(&self, output_buffer: &mut [T]) -> usize {
+        self.jitter.lock().unwrap().read(output_buffer)
     }
 }
 
-impl<B, T> RxStream for UdpRxStream<B, T>
-where
-    B: 'static + Buffer<T>,
-    T: Clone,
-{
-    fn process(&self, output_buffer: &mut [T]) -> usize {
-        // Swap out the current receive buffer
-        self.buf.flush(output_buffer)
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::ring::RingBuffer;
+    use crate::codec::CodecConfig;
+    use crate::stream::fec::{FecConfig, FecScheme};
+    use crate::stream::header::SampleFormat;
+    use crate::stream::multicast::Interface;
+    use crate::stream::tx::{udp::UdpTxStream, TxStream};
+
+    #[tokio::test(threaded_scheduler)]
+    async fn loopback() {
+        let format = StreamFormat::new(SampleFormat::F32, 1, 48_000);
+        let config = JitterConfig {
+            initial_delay: std::time::Duration::from_millis(5),
+            drift_compensation: false,
+            ..Default::default()
+        };
+        let rx = UdpRxStream::<f32>::new(
+            "127.0.0.1:0".parse().unwrap(),
+            format,
+            config,
+            None,
+            &MulticastConfig::default(),
+        )
+        .unwrap();
+        let tx = UdpTxStream::<RingBuffer<f32>, f32>::new(
+            rx.local_addr(),
+            format,
+            CodecConfig::Pcm,
+            None,
+            false,
+            None,
+            &MulticastConfig::default(),
+        )
+        .unwrap();
+        let ramp = (0..4800).map(|i| i as f32).collect::<Vec<_>>();
+        for chunk in ramp.chunks(48) {
+            tx.send(chunk);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        let mut out = vec![0.0f32; 240];
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while rx.process(&mut out) == 0 {
+            assert!(std::time::Instant::now() < deadline, "nothing received");
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        assert!(out.windows(2).all(|w| w[1] == w[0] + 1.0));
+        // Both ends count the same datagrams, though some may still
+        // be in flight.
+        let (sent, received) = (tx.stats().snapshot(), rx.stats().snapshot());
+        assert!(received.packets_received > 0);
+        assert!(sent.packets_sent >= received.packets_received);
+        assert!(sent.bytes_sent >= received.bytes_received);
+        tx.shutdown().await;
+        rx.shutdown().await;
+        // The socket is closed once shutdown returns.
+        std::net::UdpSocket::bind(rx.local_addr()).unwrap();
+    }
+
+    /// Streams a ramp, with FEC so parity is encrypted too, and
+    /// returns whether it came through intact.
+    async fn receives(tx_key: Option<&Key>, rx_key: Option<&Key>) -> bool {
+        let format = StreamFormat::new(SampleFormat::I16, 2, 48_000);
+        let config = JitterConfig {
+            initial_delay: std::time::Duration::from_millis(5),
+            drift_compensation: false,
+            ..Default::default()
+        };
+        let rx = UdpRxStream::<f32>::new(
+            "127.0.0.1:0".parse().unwrap(),
+            format,
+            config,
+            rx_key,
+            &MulticastConfig::default(),
+        )
+        .unwrap();
+        let fec = FecConfig {
+            scheme: FecScheme::Xor,
+            group: 4,
+            parity: 1,
+        };
+        let tx = UdpTxStream::<RingBuffer<f32>, f32>::new(
+            rx.local_addr(),
+            format,
+            CodecConfig::Pcm,
+            Some(fec),
+            false,
+            tx_key,
+            &MulticastConfig::default(),
+        )
+        .unwrap();
+        let ramp = (0..9600)
+            .map(|i| (i / 2) as f32 / 32768.0)
+            .collect::<Vec<_>>();
+        let mut out = vec![0.0f32; 240];
+        let mut received = false;
+        for chunk in ramp.chunks(96) {
+            tx.send(chunk);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            if rx.process(&mut out) > 0 {
+                received = true;
+                break;
+            }
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_millis(500);
+        while !received && std::time::Instant::now() < deadline {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            received = rx.process(&mut out) > 0;
+        }
+        tx.shutdown().await;
+        rx.shutdown().await;
+        received && out.chunks(2).all(|frame| frame[0] == frame[1])
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn encrypted() {
+        let key = Key::generate();
+        assert!(receives(Some(&key), Some(&key)).await);
+        assert!(!receives(Some(&key), Some(&Key::generate())).await);
+        assert!(!receives(Some(&key), None).await, "no key to decrypt");
+        assert!(!receives(None, Some(&key)).await, "cleartext is rejected");
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn multicast() {
+        let format = StreamFormat::new(SampleFormat::F32, 1, 48_000);
+        let config = JitterConfig {
+            initial_delay: std::time::Duration::from_millis(5),
+            drift_compensation: false,
+            ..Default::default()
+        };
+        // Stay on the loopback interface so the test doesn't depend
+        // on the network.
+        let multicast = MulticastConfig {
+            ttl: 0,
+            interface: Some(Interface::Addr(std::net::Ipv4Addr::LOCALHOST)),
+        };
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group: std::net::SocketAddr = ([239, 255, 69, 1], port).into();
+        // Both receivers join the group on the same port.
+        let receivers = (0..2)
+            .map(|_| {
+                UdpRxStream::<f32>::new(group, format, config.clone(), None, &multicast).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let tx = UdpTxStream::<RingBuffer<f32>, f32>::new(
+            group,
+            format,
+            CodecConfig::Pcm,
+            None,
+            false,
+            None,
+            &multicast,
+        )
+        .unwrap();
+        let ramp = (0..4800).map(|i| i as f32).collect::<Vec<_>>();
+        for chunk in ramp.chunks(48) {
+            tx.send(chunk);
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        for rx in &receivers {
+            let mut out = vec![0.0f32; 240];
+            while rx.process(&mut out) == 0 {
+                assert!(std::time::Instant::now() < deadline, "nothing received");
+                tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            }
+            assert!(out.windows(2).all(|w| w[1] == w[0] + 1.0));
+            rx.shutdown().await;
+        }
+        tx.shutdown().await;
     }
 }
@@ -0,0 +1,359 @@
+//! Session Announcement Protocol (RFC 2974), which multicasts the SDP
+//! of every stream a host sends so receivers can find them (see
+//! [`super::catalog`]). AES67 devices announce on the same group.
+//!
+//! ```text
+//!  0       1       2               4
+//!  +-------+-------+---------------+
+//!  |V A R T E C|auth|  msg id hash  |
+//!  +-------+-------+---------------+
+//!  4
+//!  +-------------------------------+-------------------+-------
+//!  | originating source (4 or 16)  | application/sdp\0 | SDP ...
+//!  +-------------------------------+-------------------+-------
+//! ```
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::sdp::SessionDescription;
+use crate::stream::shutdown::Shutdown;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+
+lazy_static! {
+    /// Group and port announcements are sent to, the one AES67
+    /// devices use.
+    pub static ref SAP_ADDR: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 255)), 9875);
+}
+
+/// How often each stream is announced.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+const SAP_VERSION: u8 = 1;
+const FLAG_IPV6: u8 = 1 << 4;
+const FLAG_DELETION: u8 = 1 << 2;
+const FLAG_ENCRYPTED: u8 = 1 << 1;
+const FLAG_COMPRESSED: u8 = 1;
+const PAYLOAD_TYPE: &str = "application/sdp";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SapError {
+    Truncated(usize),
+    UnsupportedVersion(u8),
+    /// Encrypted and compressed announcements aren't supported.
+    Unsupported,
+    /// The payload isn't SDP.
+    PayloadType(String),
+    /// The payload isn't UTF-8.
+    InvalidPayload,
+}
+
+impl std::fmt::Display for SapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SapError::Truncated(len) => write!(f, "packet truncated ({} bytes)", len),
+            SapError::UnsupportedVersion(v) => write!(f, "unsupported SAP version {}", v),
+            SapError::Unsupported => write!(f, "encrypted or compressed announcement"),
+            SapError::PayloadType(t) => write!(f, "unsupported payload type \"{}\"", t),
+            SapError::InvalidPayload => write!(f, "payload is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for SapError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SapPacket {
+    /// Set when the session is withdrawn rather than announced.
+    pub deletion: bool,
+    /// Identifies the announcement together with `origin`, and
+    /// changes whenever the SDP does.
+    pub hash: u16,
+    /// Address of the host announcing the session.
+    pub origin: IpAddr,
+    pub sdp: String,
+}
+
+impl SapPacket {
+    /// Announces (or withdraws) `sdp`.
+    pub fn new(sdp: &SessionDescription, deletion: bool) -> Self {
+        let origin = sdp.origin;
+        let sdp = sdp.to_string();
+        Self {
+            deletion,
+            hash: hash(&sdp),
+            origin,
+            sdp,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(24 + PAYLOAD_TYPE.len() + 1 + self.sdp.len());
+        let mut flags = SAP_VERSION << 5;
+        if self.origin.is_ipv6() {
+            flags |= FLAG_IPV6;
+        }
+        if self.deletion {
+            flags |= FLAG_DELETION;
+        }
+        buf.push(flags);
+        // No authentication data.
+        buf.push(0);
+        buf.extend_from_slice(&self.hash.to_be_bytes());
+        match self.origin {
+            IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+        }
+        buf.extend_from_slice(PAYLOAD_TYPE.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(self.sdp.as_bytes());
+        buf
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, SapError> {
+        if buf.len() < 4 {
+            return Err(SapError::Truncated(buf.len()));
+        }
+        let version = buf[0] >> 5;
+        if version != SAP_VERSION {
+            return Err(SapError::UnsupportedVersion(version));
+        }
+        if buf[0] & (FLAG_ENCRYPTED | FLAG_COMPRESSED) != 0 {
+            return Err(SapError::Unsupported);
+        }
+        let auth_len = buf[1] as usize * 4;
+        let hash = u16::from_be_bytes([buf[2], buf[3]]);
+        let (origin, start) = if buf[0] & FLAG_IPV6 != 0 {
+            if buf.len() < 20 {
+                return Err(SapError::Truncated(buf.len()));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf[4..20]);
+            (IpAddr::from(octets), 20)
+        } else {
+            if buf.len() < 8 {
+                return Err(SapError::Truncated(buf.len()));
+            }
+            (IpAddr::from([buf[4], buf[5], buf[6], buf[7]]), 8)
+        };
+        let start = start + auth_len;
+        if buf.len() < start {
+            return Err(SapError::Truncated(buf.len()));
+        }
+        let mut payload = &buf[start..];
+        // The payload type is optional, in which case the SDP follows
+        // directly.
+        if !payload.starts_with(b"v=0") {
+            let end = payload
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or(SapError::InvalidPayload)?;
+            let payload_type =
+                std::str::from_utf8(&payload[..end]).map_err(|_| SapError::InvalidPayload)?;
+            if payload_type != PAYLOAD_TYPE {
+                return Err(SapError::PayloadType(payload_type.to_string()));
+            }
+            payload = &payload[end + 1..];
+        }
+        let sdp = std::str::from_utf8(payload).map_err(|_| SapError::InvalidPayload)?;
+        Ok(SapPacket {
+            deletion: buf[0] & FLAG_DELETION != 0,
+            hash,
+            origin,
+            sdp: sdp.to_string(),
+        })
+    }
+}
+
+/// FNV-1a, folded to 16 bits.
+fn hash(sdp: &str) -> u16 {
+    let hash = sdp.bytes().fold(0x811c_9dc5u32, |hash, b| {
+        (hash ^ b as u32).wrapping_mul(0x0100_0193)
+    });
+    (hash >> 16) as u16 ^ hash as u16
+}
+
+#[derive(Default)]
+struct Sessions {
+    active: HashMap<u64, SessionDescription>,
+    /// Withdrawn since the last send.
+    withdrawn: Vec<SessionDescription>,
+}
+
+/// Periodically announces a set of streams. A stream is announced as
+/// soon as it's added and withdrawn as soon as it's removed, so
+/// listeners don't have to wait out the interval.
+pub struct Announcer {
+    shutdown: Shutdown,
+    sessions: std::sync::Arc<std::sync::Mutex<Sessions>>,
+    /// Wakes the send task when the set of streams changes.
+    wake: std::sync::Arc<Notify>,
+}
+
+impl Announcer {
+    /// Announces to `group`, usually [`SAP_ADDR`], every `interval`.
+    /// Must be called from within a tokio runtime.
+    pub fn new(
+        group: SocketAddr,
+        interval: Duration,
+        multicast: &MulticastConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        let sock = multicast::sender(group, multicast)?;
+        sock.set_nonblocking(true)?;
+        let sessions = std::sync::Arc::new(std::sync::Mutex::new(Sessions::default()));
+        let wake = std::sync::Arc::new(Notify::new());
+        let shutdown = {
+            let (sessions, wake) = (sessions.clone(), wake.clone());
+            Shutdown::spawn(move |stop| Self::entry(sessions, wake, sock, group, interval, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            shutdown,
+            sessions,
+            wake,
+        }))
+    }
+
+    /// Starts announcing `sdp`, replacing any session with the same
+    /// id.
+    pub fn announce(&self, sdp: SessionDescription) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .active
+            .insert(sdp.session_id, sdp);
+        self.wake.notify();
+    }
+
+    /// Stops announcing the session and tells listeners it's gone.
+    pub fn withdraw(&self, session_id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(sdp) = sessions.active.remove(&session_id) {
+            sessions.withdrawn.push(sdp);
+            self.wake.notify();
+        }
+    }
+
+    /// Sessions currently announced.
+    pub fn sessions(&self) -> Vec<SessionDescription> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .active
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Withdraws every session and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        sessions: std::sync::Arc<std::sync::Mutex<Sessions>>,
+        wake: std::sync::Arc<Notify>,
+        sock: std::net::UdpSocket,
+        group: SocketAddr,
+        interval: Duration,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("sap: {}", e);
+                return;
+            }
+        };
+        loop {
+            let stopped = tokio::select! {
+                // Stopped, or the announcer was dropped.
+                _ = &mut stop => true,
+                _ = wake.notified() => false,
+                _ = tokio::time::delay_for(interval) => false,
+            };
+            let packets = {
+                let mut sessions = sessions.lock().unwrap();
+                if stopped {
+                    let active = sessions
+                        .active
+                        .drain()
+                        .map(|(_, sdp)| sdp)
+                        .collect::<Vec<_>>();
+                    sessions.withdrawn.extend(active);
+                }
+                let withdrawn = sessions.withdrawn.drain(..).collect::<Vec<_>>();
+                withdrawn
+                    .iter()
+                    .map(|sdp| SapPacket::new(sdp, true))
+                    .chain(
+                        sessions
+                            .active
+                            .values()
+                            .map(|sdp| SapPacket::new(sdp, false)),
+                    )
+                    .map(|packet| packet.to_bytes())
+                    .collect::<Vec<_>>()
+            };
+            for packet in &packets {
+                if let Err(e) = sock.send_to(packet, &group).await {
+                    warn!("sap: send to {}: {}", group, e);
+                }
+            }
+            if stopped {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::rtp::RtpConfig;
+
+    fn sdp() -> SessionDescription {
+        SessionDescription {
+            session_id: 42,
+            session_version: 1,
+            origin: "192.168.1.20".parse().unwrap(),
+            name: String::from("Studio A monitors"),
+            dest: "239.69.1.1:5004".parse().unwrap(),
+            ttl: Some(1),
+            rtp: RtpConfig::default(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let packet = SapPacket::new(&sdp(), false);
+        assert_eq!(packet.origin, sdp().origin);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes[0], 0x20);
+        assert_eq!(&bytes[4..8], &[192, 168, 1, 20]);
+        assert_eq!(SapPacket::parse(&bytes).unwrap(), packet);
+        let deletion = SapPacket::new(&sdp(), true);
+        assert_eq!(deletion.hash, packet.hash);
+        assert!(SapPacket::parse(&deletion.to_bytes()).unwrap().deletion);
+    }
+
+    #[test]
+    fn parses_without_payload_type() {
+        // Some devices send the SDP straight after the origin, and
+        // may include authentication data.
+        let mut bytes = vec![0x20, 1, 0x12, 0x34, 10, 0, 0, 1, 0, 0, 0, 0];
+        bytes.extend_from_slice(sdp().to_string().as_bytes());
+        let packet = SapPacket::parse(&bytes).unwrap();
+        assert_eq!(packet.hash, 0x1234);
+        assert_eq!(packet.origin, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(packet.sdp.parse::<SessionDescription>().unwrap(), sdp());
+        bytes[0] |= FLAG_COMPRESSED;
+        assert_eq!(SapPacket::parse(&bytes), Err(SapError::Unsupported));
+        let mut bytes = SapPacket::new(&sdp(), false).to_bytes();
+        bytes[8] = b'x';
+        assert_eq!(
+            SapPacket::parse(&bytes),
+            Err(SapError::PayloadType(String::from("xpplication/sdp")))
+        );
+    }
+}
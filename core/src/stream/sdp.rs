@@ -0,0 +1,344 @@
+//! Session descriptions (RFC 4566) of RTP streams, in the subset used
+//! by AES67 (see [`super::rtp`]), e.g.
+//!
+//! ```text
+//! v=0
+//! o=- 1311738121 1311738121 IN IP4 192.168.1.20
+//! s=Studio A monitors
+//! c=IN IP4 239.69.1.1/32
+//! t=0 0
+//! m=audio 5004 RTP/AVP 96
+//! a=rtpmap:96 L24/48000/2
+//! a=ptime:1
+//! a=recvonly
+//! a=ts-refclk:local
+//! ```
+//!
+//! Paradise senders are not synchronized to PTP, so they describe
+//! their clock as local. Only the first audio stream of a description
+//! is parsed; anything else is ignored.
+use crate::stream::rtp::{Encoding, PacketTime, RtpConfig};
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionDescription {
+    /// Identifies the session to whoever announces it.
+    pub session_id: u64,
+    /// Changes whenever the description does.
+    pub session_version: u64,
+    /// Address the stream is sent from.
+    pub origin: IpAddr,
+    pub name: String,
+    /// Address the stream is sent to, usually a multicast group.
+    pub dest: SocketAddr,
+    /// TTL of an IPv4 multicast stream.
+    pub ttl: Option<u32>,
+    pub rtp: RtpConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SdpError {
+    /// A required line is missing.
+    Missing(&'static str),
+    /// A line is malformed.
+    Invalid(String),
+    /// The stream isn't L16 or L24 audio over RTP.
+    Unsupported(String),
+}
+
+impl std::fmt::Display for SdpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SdpError::Missing(what) => write!(f, "missing {}", what),
+            SdpError::Invalid(line) => write!(f, "invalid line \"{}\"", line),
+            SdpError::Unsupported(what) => write!(f, "unsupported {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+impl std::fmt::Display for SessionDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Lines end in CRLF as the RFC asks, which every parser
+        // accepts.
+        write!(f, "v=0\r\n")?;
+        write!(
+            f,
+            "o=- {} {} {} {}\r\n",
+            self.session_id,
+            self.session_version,
+            addr_type(self.origin),
+            self.origin
+        )?;
+        write!(f, "s={}\r\n", self.name)?;
+        write!(f, "c={} {}", addr_type(self.dest.ip()), self.dest.ip())?;
+        match (self.dest.ip(), self.ttl) {
+            (IpAddr::V4(ip), Some(ttl)) if ip.is_multicast() => write!(f, "/{}\r\n", ttl)?,
+            _ => write!(f, "\r\n")?,
+        }
+        write!(f, "t=0 0\r\n")?;
+        write!(
+            f,
+            "m=audio {} RTP/AVP {}\r\n",
+            self.dest.port(),
+            self.rtp.payload_type
+        )?;
+        write!(
+            f,
+            "a=rtpmap:{} {}/{}/{}\r\n",
+            self.rtp.payload_type,
+            self.rtp.encoding.as_str(),
+            self.rtp.sample_rate,
+            self.rtp.channels
+        )?;
+        write!(f, "a=ptime:{}\r\n", self.rtp.packet_time.as_millis())?;
+        write!(f, "a=recvonly\r\n")?;
+        write!(f, "a=ts-refclk:local\r\n")
+    }
+}
+
+impl std::str::FromStr for SessionDescription {
+    type Err = SdpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut origin = None;
+        let mut name = String::new();
+        let mut conn = None;
+        let mut media: Option<(u16, u8)> = None;
+        let mut rtpmaps = Vec::new();
+        let mut ptime = None;
+        // Once past the first audio stream, the rest is ignored.
+        let mut done = false;
+        for line in s.lines().map(|line| line.trim_end()) {
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || SdpError::Invalid(line.to_string());
+            if line.len() < 2 || &line[1..2] != "=" {
+                return Err(invalid());
+            }
+            let value = &line[2..];
+            match &line[..1] {
+                "m" if media.is_some() => done = true,
+                _ if done => {}
+                "o" => {
+                    let fields = value.split_whitespace().collect::<Vec<_>>();
+                    if fields.len() != 6 {
+                        return Err(invalid());
+                    }
+                    let session_id = fields[1].parse().map_err(|_| invalid())?;
+                    let session_version = fields[2].parse().map_err(|_| invalid())?;
+                    let addr = fields[5].parse().map_err(|_| invalid())?;
+                    origin = Some((session_id, session_version, addr));
+                }
+                "s" => name = value.to_string(),
+                "c" => {
+                    let fields = value.split_whitespace().collect::<Vec<_>>();
+                    if fields.len() != 3 || fields[0] != "IN" {
+                        return Err(invalid());
+                    }
+                    let mut parts = fields[2].split('/');
+                    let addr: IpAddr = parts
+                        .next()
+                        .and_then(|addr| addr.parse().ok())
+                        .ok_or_else(invalid)?;
+                    let ttl = match (addr, parts.next()) {
+                        (IpAddr::V4(_), Some(ttl)) => Some(ttl.parse().map_err(|_| invalid())?),
+                        _ => None,
+                    };
+                    conn = Some((addr, ttl));
+                }
+                "m" => {
+                    let fields = value.split_whitespace().collect::<Vec<_>>();
+                    if fields.len() < 4 {
+                        return Err(invalid());
+                    }
+                    if fields[0] != "audio" {
+                        continue;
+                    }
+                    if fields[2] != "RTP/AVP" {
+                        return Err(SdpError::Unsupported(format!("transport {}", fields[2])));
+                    }
+                    let port = fields[1]
+                        .split('/')
+                        .next()
+                        .and_then(|port| port.parse().ok())
+                        .ok_or_else(invalid)?;
+                    let payload_type = fields[3].parse().map_err(|_| invalid())?;
+                    media = Some((port, payload_type));
+                }
+                "a" => {
+                    if let Some(rtpmap) = value.strip_prefix("rtpmap:") {
+                        rtpmaps.push(rtpmap.to_string());
+                    } else if let Some(value) = value.strip_prefix("ptime:") {
+                        ptime = Some(value.trim().to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        let (session_id, session_version, origin) = origin.ok_or(SdpError::Missing("origin"))?;
+        let (addr, ttl) = conn.ok_or(SdpError::Missing("connection address"))?;
+        let (port, payload_type) = media.ok_or(SdpError::Missing("audio stream"))?;
+        let rtpmap = rtpmaps
+            .iter()
+            .find_map(|rtpmap| {
+                let mut fields = rtpmap.splitn(2, ' ');
+                match fields.next()?.parse::<u8>() {
+                    Ok(pt) if pt == payload_type => fields.next(),
+                    _ => None,
+                }
+            })
+            .ok_or(SdpError::Missing("rtpmap"))?;
+        let mut parts = rtpmap.trim().split('/');
+        let encoding = match parts.next() {
+            Some("L16") => Encoding::L16,
+            Some("L24") => Encoding::L24,
+            other => {
+                return Err(SdpError::Unsupported(format!(
+                    "encoding {}",
+                    other.unwrap_or_default()
+                )))
+            }
+        };
+        let invalid = || SdpError::Invalid(format!("a=rtpmap:{}", rtpmap));
+        let sample_rate = parts
+            .next()
+            .and_then(|rate| rate.parse().ok())
+            .ok_or_else(invalid)?;
+        let channels = match parts.next() {
+            Some(channels) => channels.parse().map_err(|_| invalid())?,
+            None => 1,
+        };
+        let packet_time = match ptime {
+            None => PacketTime::default(),
+            Some(ptime) => match ptime.parse::<f64>() {
+                Ok(ms) if (ms - 1.0).abs() < 0.01 => PacketTime::Millisecond,
+                Ok(ms) if (ms - 0.125).abs() < 0.01 => PacketTime::Microseconds125,
+                _ => return Err(SdpError::Unsupported(format!("packet time {} ms", ptime))),
+            },
+        };
+        let rtp = RtpConfig {
+            payload_type,
+            encoding,
+            packet_time,
+            sample_rate,
+            channels,
+        };
+        rtp.validate()
+            .map_err(|e| SdpError::Unsupported(e.to_string()))?;
+        Ok(SessionDescription {
+            session_id,
+            session_version,
+            origin,
+            name,
+            dest: SocketAddr::new(addr, port),
+            ttl,
+            rtp,
+        })
+    }
+}
+
+fn addr_type(addr: IpAddr) -> &'static str {
+    match addr {
+        IpAddr::V4(_) => "IN IP4",
+        IpAddr::V6(_) => "IN IP6",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let sdp = SessionDescription {
+            session_id: 1311738121,
+            session_version: 1311738121,
+            origin: "192.168.1.20".parse().unwrap(),
+            name: String::from("Studio A monitors"),
+            dest: "239.69.1.1:5004".parse().unwrap(),
+            ttl: Some(32),
+            rtp: RtpConfig::default(),
+        };
+        let text = sdp.to_string();
+        assert!(text.contains("c=IN IP4 239.69.1.1/32\r\n"));
+        assert!(text.contains("a=rtpmap:96 L24/48000/2\r\n"));
+        assert!(text.contains("a=ptime:1\r\n"));
+        assert_eq!(text.parse::<SessionDescription>().unwrap(), sdp);
+        let sdp = SessionDescription {
+            dest: "[ff0e::1:5]:5004".parse().unwrap(),
+            ttl: None,
+            rtp: RtpConfig {
+                payload_type: 97,
+                encoding: Encoding::L16,
+                packet_time: PacketTime::Microseconds125,
+                channels: 8,
+                ..Default::default()
+            },
+            ..sdp
+        };
+        let text = sdp.to_string();
+        assert!(text.contains("c=IN IP6 ff0e::1:5\r\n"));
+        assert!(text.contains("a=ptime:0.125\r\n"));
+        assert_eq!(text.parse::<SessionDescription>().unwrap(), sdp);
+    }
+
+    #[test]
+    fn parses_dante() {
+        // As exported by a Dante device in AES67 mode.
+        let text = "v=0\n\
+            o=- 1423986 1423994 IN IP4 169.254.98.63\n\
+            s=AOIP44-serial-1614 : 2\n\
+            c=IN IP4 239.65.125.63/32\n\
+            t=0 0\n\
+            a=keywds:Dante\n\
+            m=audio 5004 RTP/AVP 97\n\
+            i=2 channels: TxChan 0, TxChan 1\n\
+            a=recvonly\n\
+            a=rtpmap:97 L24/48000/2\n\
+            a=ptime:1\n\
+            a=ts-refclk:ptp=IEEE1588-2008:00-1D-C1-FF-FE-0E-10-C4:0\n\
+            a=mediaclk:direct=750129560\n";
+        let sdp = text.parse::<SessionDescription>().unwrap();
+        assert_eq!(sdp.session_id, 1423986);
+        assert_eq!(sdp.name, "AOIP44-serial-1614 : 2");
+        assert_eq!(sdp.dest, "239.65.125.63:5004".parse().unwrap());
+        assert_eq!(sdp.ttl, Some(32));
+        assert_eq!(sdp.rtp.payload_type, 97);
+        assert_eq!(sdp.rtp.encoding, Encoding::L24);
+        assert_eq!(sdp.rtp.channels, 2);
+    }
+
+    #[test]
+    fn errors() {
+        let sdp = SessionDescription {
+            session_id: 1,
+            session_version: 1,
+            origin: "10.0.0.1".parse().unwrap(),
+            name: String::new(),
+            dest: "10.0.0.2:5004".parse().unwrap(),
+            ttl: None,
+            rtp: RtpConfig::default(),
+        }
+        .to_string();
+        assert_eq!(
+            sdp.replace("L24", "opus").parse::<SessionDescription>(),
+            Err(SdpError::Unsupported(String::from("encoding opus")))
+        );
+        assert_eq!(
+            sdp.replace("a=ptime:1", "a=ptime:4")
+                .parse::<SessionDescription>(),
+            Err(SdpError::Unsupported(String::from("packet time 4 ms")))
+        );
+        assert_eq!(
+            sdp.replace("c=", "x=").parse::<SessionDescription>(),
+            Err(SdpError::Missing("connection address"))
+        );
+        assert_eq!(
+            "v=0\r\nbogus\r\n".parse::<SessionDescription>(),
+            Err(SdpError::Invalid(String::from("bogus")))
+        );
+    }
+}
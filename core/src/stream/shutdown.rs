@@ -0,0 +1,90 @@
+//! Lifetime management for the background task behind a stream.
+use std::future::Future;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Handle to a spawned stream task. The task is given a receiver that
+/// resolves when it should stop, and is expected to select on it
+/// alongside its socket.
+///
+/// `shutdown` stops the task and waits until it has exited and
+/// released its socket. Dropping the handle also stops the task, but
+/// can't wait for it, since `Drop` may run on a runtime thread.
+pub struct Shutdown {
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Shutdown {
+    /// Spawns `task` on the current tokio runtime.
+    pub fn spawn<F, Fut>(task: F) -> Self
+    where
+        F: FnOnce(oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (stop, stopped) = oneshot::channel();
+        Self {
+            stop: Mutex::new(Some(stop)),
+            task: Mutex::new(Some(tokio::task::spawn(task(stopped)))),
+        }
+    }
+
+    /// Tells the task to stop without waiting for it.
+    pub fn signal(&self) {
+        if let Some(stop) = self.stop.lock().unwrap().take() {
+            // The task may already have exited on its own.
+            let _ = stop.send(());
+        }
+    }
+
+    /// Stops the task and waits for it to exit. Calling this more than
+    /// once, or concurrently, is harmless.
+    pub async fn shutdown(&self) {
+        self.signal();
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            if let Err(e) = task.await {
+                error!("stream task failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        self.signal();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn waits_for_task() {
+        let exited = Arc::new(AtomicBool::new(false));
+        let flag = exited.clone();
+        let handle = Shutdown::spawn(|stop| async move {
+            let _ = stop.await;
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+            flag.store(true, Ordering::SeqCst);
+        });
+        handle.shutdown().await;
+        assert!(exited.load(Ordering::SeqCst));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn drop_stops_task() {
+        let (done, finished) = oneshot::channel();
+        let handle = Shutdown::spawn(|stop| async move {
+            let _ = stop.await;
+            let _ = done.send(());
+        });
+        drop(handle);
+        finished.await.unwrap();
+    }
+}
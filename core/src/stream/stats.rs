@@ -0,0 +1,238 @@
+//! Counters describing the health of a stream. Every transport keeps
+//! a [`StreamStats`] that its task updates as packets come and go, and
+//! which can be read from any thread, e.g. to display it, without
+//! taking a lock the audio path depends on.
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct StreamStats {
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    lost: AtomicU64,
+    reordered: AtomicU64,
+    duplicates: AtomicU64,
+    late: AtomicU64,
+    concealed_frames: AtomicU64,
+    underruns: AtomicU64,
+    overruns: AtomicU64,
+    /// In nanoseconds.
+    jitter: AtomicU64,
+    /// In nanoseconds.
+    latency: AtomicU64,
+    /// In nanoseconds.
+    target_delay: AtomicU64,
+    connection: AtomicU8,
+}
+
+/// Where a connection-oriented stream is in its life. Transports
+/// without connections stay at `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    None,
+    /// Waiting for a sender to connect.
+    Listening,
+    /// Connecting to the receiver.
+    Connecting,
+    Connected,
+    /// Waiting to reconnect after the connection failed or was lost.
+    Backoff,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState::None
+    }
+}
+
+impl ConnectionState {
+    /// The states a connection can be in, for listing every one of
+    /// them, e.g. as metrics.
+    pub const ALL: [ConnectionState; 4] = [
+        ConnectionState::Listening,
+        ConnectionState::Connecting,
+        ConnectionState::Connected,
+        ConnectionState::Backoff,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::None => "none",
+            ConnectionState::Listening => "listening",
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Backoff => "backoff",
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => ConnectionState::Listening,
+            2 => ConnectionState::Connecting,
+            3 => ConnectionState::Connected,
+            4 => ConnectionState::Backoff,
+            _ => ConnectionState::None,
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            ConnectionState::None => 0,
+            ConnectionState::Listening => 1,
+            ConnectionState::Connecting => 2,
+            ConnectionState::Connected => 3,
+            ConnectionState::Backoff => 4,
+        }
+    }
+}
+
+/// The counters of a stream at one point in time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Packets handed to the network, including FEC parity.
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// Packets that arrived, whether or not they could be played.
+    pub packets_received: u64,
+    pub bytes_received: u64,
+    /// Packets that never arrived, from gaps in the sequence numbers
+    /// of the packets played.
+    pub lost: u64,
+    /// Packets that arrived after one sent later than them.
+    pub reordered: u64,
+    pub duplicates: u64,
+    /// Packets discarded for arriving after their playout time.
+    pub late: u64,
+    /// Frames synthesized to cover lost packets and underruns.
+    pub concealed_frames: u64,
+    /// Times the jitter buffer ran dry and had to rebuffer.
+    pub underruns: u64,
+    /// Times the jitter buffer held so much more than its target
+    /// delay that audio was skipped.
+    pub overruns: u64,
+    /// Smoothed interarrival jitter.
+    pub jitter: Duration,
+    /// Audio buffered ahead of playout, i.e. the delay added on
+    /// the receiving side.
+    pub latency: Duration,
+    /// How much the jitter buffer aims to hold.
+    pub target_delay: Duration,
+    pub connection: ConnectionState,
+}
+
+impl StreamStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Snapshot {
+            packets_sent: load(&self.packets_sent),
+            bytes_sent: load(&self.bytes_sent),
+            packets_received: load(&self.packets_received),
+            bytes_received: load(&self.bytes_received),
+            lost: load(&self.lost),
+            reordered: load(&self.reordered),
+            duplicates: load(&self.duplicates),
+            late: load(&self.late),
+            concealed_frames: load(&self.concealed_frames),
+            underruns: load(&self.underruns),
+            overruns: load(&self.overruns),
+            jitter: Duration::from_nanos(load(&self.jitter)),
+            latency: Duration::from_nanos(load(&self.latency)),
+            target_delay: Duration::from_nanos(load(&self.target_delay)),
+            connection: ConnectionState::from_u8(self.connection.load(Ordering::Relaxed)),
+        }
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn lost(&self, packets: u64) {
+        self.lost.fetch_add(packets, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reordered(&self) {
+        self.reordered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn late(&self) {
+        self.late.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Totals kept elsewhere, e.g. by the concealer, are published
+    /// rather than added to.
+    pub(crate) fn set_concealed_frames(&self, frames: u64) {
+        self.concealed_frames.store(frames, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_jitter(&self, jitter: Duration) {
+        self.jitter
+            .store(jitter.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_latency(&self, latency: Duration) {
+        self.latency
+            .store(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_target_delay(&self, delay: Duration) {
+        self.target_delay
+            .store(delay.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_connection(&self, state: ConnectionState) {
+        self.connection.store(state.as_u8(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot() {
+        let stats = StreamStats::new();
+        stats.sent(100);
+        stats.sent(50);
+        stats.received(60);
+        stats.lost(3);
+        stats.set_concealed_frames(480);
+        stats.set_concealed_frames(960);
+        stats.set_jitter(Duration::from_micros(250));
+        stats.set_connection(ConnectionState::Backoff);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.packets_sent, 2);
+        assert_eq!(snapshot.bytes_sent, 150);
+        assert_eq!(snapshot.packets_received, 1);
+        assert_eq!(snapshot.bytes_received, 60);
+        assert_eq!(snapshot.lost, 3);
+        assert_eq!(snapshot.concealed_frames, 960);
+        assert_eq!(snapshot.jitter, Duration::from_micros(250));
+        assert_eq!(snapshot.latency, Duration::from_secs(0));
+        assert_eq!(snapshot.connection, ConnectionState::Backoff);
+    }
+}
@@ -0,0 +1,187 @@
+//! Batched datagram sends.
+//!
+//! A stream typically has several datagrams ready at once (a packet
+//! per codec frame plus FEC parity), so they are collected and sent
+//! together. On Linux the whole batch goes out in a single `sendmmsg`
+//! call; elsewhere, or when the socket buffer is full, datagrams are
+//! sent one at a time as the socket becomes writable.
+use crate::stream::stats::StreamStats;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// Most datagrams handed to a single `sendmmsg` call.
+#[cfg(target_os = "linux")]
+const MAX_BATCH: usize = 64;
+
+#[derive(Default)]
+pub struct Batch {
+    /// Datagrams back to back.
+    data: Vec<u8>,
+    /// End offset of each datagram in `data`.
+    ends: Vec<usize>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, datagram: &[u8]) {
+        self.data.extend_from_slice(datagram);
+        self.ends.push(self.data.len());
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = std::iter::once(0).chain(self.ends.iter().cloned());
+        starts
+            .zip(self.ends.iter())
+            .map(move |(start, &end)| &self.data[start..end])
+    }
+
+    /// Sends every datagram to `dest`, counting them in `stats`, and
+    /// empties the batch. Failed datagrams are logged and skipped, as
+    /// UDP makes no promises about delivery anyway.
+    pub async fn send(&mut self, sock: &mut UdpSocket, dest: SocketAddr, stats: &StreamStats) {
+        let sent = self.send_now(sock, dest);
+        for datagram in self.iter().take(sent) {
+            stats.sent(datagram.len());
+        }
+        for datagram in self.iter().skip(sent) {
+            match sock.send_to(datagram, &dest).await {
+                Ok(_) => stats.sent(datagram.len()),
+                Err(e) => warn!("udp tx: send to {}: {}", dest, e),
+            }
+        }
+        self.data.clear();
+        self.ends.clear();
+    }
+
+    /// Sends as much of the batch as the socket takes without waiting
+    /// and returns how many datagrams went out.
+    #[cfg(target_os = "linux")]
+    fn send_now(&self, sock: &UdpSocket, dest: SocketAddr) -> usize {
+        use std::os::unix::io::AsRawFd;
+        let datagrams = self.iter().collect::<Vec<_>>();
+        let mut sent = 0;
+        for chunk in datagrams.chunks(MAX_BATCH) {
+            match sys::sendmmsg(sock.as_raw_fd(), chunk, dest) {
+                Ok(n) => {
+                    sent += n;
+                    if n < chunk.len() {
+                        break;
+                    }
+                }
+                // Full socket buffers and per-datagram errors are
+                // left to the one at a time path.
+                Err(_) => break,
+            }
+        }
+        sent
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn send_now(&self, _sock: &UdpSocket, _dest: SocketAddr) -> usize {
+        0
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::net::SocketAddr;
+    use std::os::unix::io::RawFd;
+
+    pub fn sendmmsg(fd: RawFd, datagrams: &[&[u8]], dest: SocketAddr) -> std::io::Result<usize> {
+        let (mut addr, addr_len) = sockaddr(dest);
+        let mut iovecs = datagrams
+            .iter()
+            .map(|datagram| libc::iovec {
+                iov_base: datagram.as_ptr() as *mut libc::c_void,
+                iov_len: datagram.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut messages = iovecs
+            .iter_mut()
+            .map(|iov| {
+                // Zeroed first, as the field layout varies between libcs.
+                let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                msg.msg_hdr.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+                msg.msg_hdr.msg_namelen = addr_len;
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect::<Vec<_>>();
+        let n = unsafe {
+            libc::sendmmsg(
+                fd,
+                messages.as_mut_ptr(),
+                messages.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                std::mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_scope_id = addr.scope_id();
+                std::mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_in_order() {
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut rx = UdpSocket::bind(local).await.unwrap();
+        let dest = rx.local_addr().unwrap();
+        let mut tx = UdpSocket::bind(local).await.unwrap();
+        let mut batch = Batch::new();
+        for i in 0..100u8 {
+            batch.push(&vec![i; 1 + i as usize]);
+        }
+        assert_eq!(batch.len(), 100);
+        assert_eq!(batch.iter().nth(3), Some(&[3u8; 4][..]));
+        let stats = StreamStats::new();
+        batch.send(&mut tx, dest, &stats).await;
+        assert!(batch.is_empty());
+        assert_eq!(stats.snapshot().packets_sent, 100);
+        assert_eq!(stats.snapshot().bytes_sent, (1..=100).sum::<u64>());
+        let mut buf = [0u8; 256];
+        for i in 0..100u8 {
+            let (amt, _) = rx.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..amt], &vec![i; 1 + i as usize][..]);
+        }
+    }
+}
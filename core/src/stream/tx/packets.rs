@@ -0,0 +1,136 @@
+use crate::codec::{Codec, CodecConfig, CodecError, Packetizer};
+use crate::sample::{self, Dither, Sample};
+use crate::stream::buffer::Buffer;
+use crate::stream::header::{Header, StreamFormat, HEADER_LEN};
+
+/// Turns samples accumulated in a send buffer into the packets of a
+/// single stream, either uncompressed or through the codec.
+pub(crate) struct Packets<T> {
+    format: StreamFormat,
+    samples: Vec<T>,
+    buf: Vec<u8>,
+    /// Header of the next uncompressed packet.
+    hdr: Header,
+    packetizer: Option<Packetizer>,
+    dither: Option<Dither>,
+}
+
+impl<T> Packets<T>
+where
+    T: Sample,
+{
+    /// Starts a new stream whose uncompressed packets, header
+    /// included, are no longer than `max_packet` bytes. Samples are
+    /// dithered as `UdpTxStream::new` describes.
+    pub(crate) fn new(
+        format: StreamFormat,
+        codec: CodecConfig,
+        max_packet: usize,
+        dither: bool,
+    ) -> Result<Self, CodecError> {
+        let encoder = codec.encoder(format)?;
+        let frame_size = format.bytes_per_frame();
+        let max_frames = std::cmp::min(
+            max_packet.saturating_sub(HEADER_LEN) / frame_size,
+            u16::MAX as usize,
+        );
+        let max_frames = std::cmp::max(max_frames, 1);
+        let stream_id = crate::stream::header::new_stream_id();
+        Ok(Self {
+            format,
+            samples: vec![T::default(); max_frames * format.channels as usize],
+            buf: vec![0; HEADER_LEN + max_frames * frame_size],
+            hdr: Header {
+                flags: crate::stream::header::FLAG_DISCONTINUITY,
+                stream_id,
+                sequence: 0,
+                timestamp: 0,
+                format,
+                codec: Codec::Pcm,
+                frames: 0,
+            },
+            packetizer: encoder.map(|encoder| Packetizer::new(encoder, format, stream_id)),
+            dither: if dither {
+                Some(Dither::default())
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Packetizes everything accumulated in `b`, passing each packet
+    /// to `emit` along with its header. A frame the codec fails on is
+    /// skipped, and the last such error is returned once `b` is empty.
+    pub(crate) fn drain<B, F>(&mut self, b: &B, mut emit: F) -> Result<(), CodecError>
+    where
+        B: Buffer<T>,
+        F: FnMut(&Header, &[u8]),
+    {
+        let mut result = Ok(());
+        loop {
+            let amt = b.flush(&mut self.samples[..]);
+            if amt == 0 {
+                return result;
+            }
+            if let Some(packetizer) = &mut self.packetizer {
+                if let Err(e) = packetizer.push(&self.samples[..amt], &mut emit) {
+                    result = Err(e);
+                }
+                continue;
+            }
+            self.hdr.frames = (amt / self.format.channels as usize) as u16;
+            let hdr_len = self.hdr.write(&mut self.buf[..]);
+            let i = hdr_len
+                + sample::encode(
+                    &self.samples[..amt],
+                    self.format.sample_format,
+                    &mut self.buf[hdr_len..],
+                    self.dither.as_mut(),
+                );
+            emit(&self.hdr, &self.buf[..i]);
+            self.hdr.flags = 0;
+            self.hdr.sequence = self.hdr.sequence.wrapping_add(1);
+            self.hdr.timestamp += self.hdr.frames as u64;
+        }
+    }
+
+    /// Throws away everything accumulated in `b`, e.g. audio that
+    /// queued up while a connection was down.
+    pub(crate) fn discard<B>(&mut self, b: &B)
+    where
+        B: Buffer<T>,
+    {
+        while b.flush(&mut self.samples[..]) > 0 {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::buffer::locking::LockingBuffer;
+    use crate::stream::header::SampleFormat;
+
+    #[test]
+    fn splits_into_packets() {
+        let format = StreamFormat::new(SampleFormat::I16, 2, 48_000);
+        // Room for 10 frames per packet.
+        let mut packets =
+            Packets::<f32>::new(format, CodecConfig::Pcm, HEADER_LEN + 40, false).unwrap();
+        let b = LockingBuffer::<f32>::new();
+        b.accumulate(&[0.5f32; 2 * 25]);
+        let mut emitted = Vec::new();
+        packets
+            .drain(&b, |hdr, packet| emitted.push((*hdr, packet.len())))
+            .unwrap();
+        let frames = emitted
+            .iter()
+            .map(|(hdr, _)| hdr.frames)
+            .collect::<Vec<_>>();
+        assert_eq!(frames, vec![10, 10, 5]);
+        assert!(emitted[0].0.is_discontinuity());
+        assert!(!emitted[1].0.is_discontinuity());
+        assert_eq!(emitted[1].0.timestamp, 10);
+        assert_eq!(emitted[2].0.sequence, 2);
+        assert_eq!(emitted[0].1, HEADER_LEN + 40);
+    }
+}
@@ -0,0 +1,266 @@
+use super::packets::Packets;
+use super::*;
+use crate::codec::CodecConfig;
+use crate::sample::Sample;
+use crate::stream::backoff::Backoff;
+use crate::stream::buffer::Buffer;
+use crate::stream::control::Control;
+use crate::stream::fec::{FecConfig, FecEncoder, PARITY_OVERHEAD};
+use crate::stream::framing::{self, FrameReader};
+use crate::stream::header::{StreamFormat, HEADER_LEN};
+use crate::stream::quic::TLS;
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::{ConnectionState, StreamStats};
+use anyhow::{anyhow, Result};
+use std::marker::PhantomData;
+use tokio::sync::{oneshot, Notify};
+
+/// Streams audio over a QUIC connection. Packets are sent as
+/// unreliable datagrams, optionally with forward error correction,
+/// after introducing the stream on a reliable control stream.
+///
+/// Like `TcpTxStream`, the connection is retried with exponential
+/// backoff, and audio accumulated while disconnected is discarded.
+pub struct QuicTxStream<B, T>
+where
+    B: Buffer<T>,
+    T: Clone,
+{
+    shutdown: Shutdown,
+    buf: std::sync::Arc<B>,
+    /// Wakes the send task when samples are accumulated.
+    wake: std::sync::Arc<Notify>,
+    stats: std::sync::Arc<StreamStats>,
+    phantom: PhantomData<T>,
+}
+
+/// What a connection is set up with.
+struct Settings {
+    dest: std::net::SocketAddr,
+    server_name: String,
+    format: StreamFormat,
+    codec: CodecConfig,
+    fec: Option<FecConfig>,
+    dither: bool,
+}
+
+impl<B, T> QuicTxStream<B, T>
+where
+    B: 'static + Buffer<T>,
+    T: Sample,
+{
+    /// Streams audio to the listener at `dest`, verifying it as
+    /// `TLS` describes unless `insecure` is set. Samples are
+    /// converted and dithered as `UdpTxStream` does. Must be called
+    /// from within a tokio runtime.
+    pub fn new(
+        dest: std::net::SocketAddr,
+        format: StreamFormat,
+        codec: CodecConfig,
+        fec: Option<FecConfig>,
+        dither: bool,
+        tls: Option<&TLS>,
+        insecure: bool,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        if format.channels == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "stream format has no channels",
+            ));
+        }
+        // Settings are checked up front, though each connection
+        // starts over with its own encoders.
+        Packets::<T>::new(format, codec, HEADER_LEN, dither)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        if let Some(fec) = fec {
+            FecEncoder::new(fec)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        }
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.default_client_config(crate::stream::quic::client_config(tls, insecure)?);
+        let local: std::net::SocketAddr = if dest.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let (endpoint, _) = endpoint
+            .bind(&local)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        let settings = Settings {
+            dest,
+            server_name: TLS::server_name(tls).to_owned(),
+            format,
+            codec,
+            fec,
+            dither,
+        };
+        let buf = std::sync::Arc::new(B::with_channels(format.channels as usize));
+        let wake = std::sync::Arc::new(Notify::new());
+        let stats = std::sync::Arc::new(StreamStats::new());
+        let shutdown = {
+            let (buf, wake, stats) = (buf.clone(), wake.clone(), stats.clone());
+            Shutdown::spawn(move |stop| Self::entry(buf, wake, stats, endpoint, settings, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            shutdown,
+            buf,
+            wake,
+            stats,
+            phantom: PhantomData,
+        }))
+    }
+
+    /// Counters of the packets sent, readable from any thread.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Stops sending and waits until the connection is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    async fn entry(
+        b: std::sync::Arc<B>,
+        wake: std::sync::Arc<Notify>,
+        stats: std::sync::Arc<StreamStats>,
+        endpoint: quinn::Endpoint,
+        settings: Settings,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let dest = settings.dest;
+        let mut backoff = Backoff::default();
+        loop {
+            stats.set_connection(ConnectionState::Connecting);
+            let result = tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                result = Self::connect(&endpoint, &settings) => result,
+            };
+            match result {
+                Ok(conn) => {
+                    info!("quic tx: connected to {}", dest);
+                    stats.set_connection(ConnectionState::Connected);
+                    let result =
+                        Self::send_all(&b, &wake, &stats, conn, &settings, &mut backoff, &mut stop)
+                            .await;
+                    match result {
+                        Ok(()) => return,
+                        Err(e) => warn!("quic tx: connection to {} lost: {}", dest, e),
+                    }
+                }
+                Err(e) => warn!("quic tx: connect to {}: {}", dest, e),
+            }
+            stats.set_connection(ConnectionState::Backoff);
+            let delay = backoff.next_delay();
+            info!("quic tx: reconnecting to {} in {:?}", dest, delay);
+            tokio::select! {
+                _ = &mut stop => return,
+                _ = tokio::time::delay_for(delay) => {}
+            }
+        }
+    }
+
+    async fn connect(
+        endpoint: &quinn::Endpoint,
+        settings: &Settings,
+    ) -> Result<quinn::NewConnection> {
+        Ok(endpoint
+            .connect(&settings.dest, &settings.server_name)?
+            .await?)
+    }
+
+    /// Streams over a single connection. Returns `Ok` once stopped,
+    /// or the error that ended the connection.
+    async fn send_all(
+        b: &B,
+        wake: &Notify,
+        stats: &StreamStats,
+        conn: quinn::NewConnection,
+        settings: &Settings,
+        backoff: &mut Backoff,
+        stop: &mut oneshot::Receiver<()>,
+    ) -> Result<()> {
+        let connection = conn.connection;
+        let max_datagram = connection
+            .max_datagram_size()
+            .ok_or_else(|| anyhow!("peer does not support datagrams"))?;
+        // Parity packets wrap a whole datagram, so leave room for them.
+        let overhead = match settings.fec {
+            Some(_) => HEADER_LEN + PARITY_OVERHEAD,
+            None => 0,
+        };
+        let mut packets = Packets::new(
+            settings.format,
+            settings.codec,
+            max_datagram.saturating_sub(overhead),
+            settings.dither,
+        )?;
+        let mut fec = settings.fec.map(FecEncoder::new).transpose()?;
+        let (mut send, mut recv) = connection.open_bi().await?;
+        let mut hello = Vec::new();
+        Control::hello(settings.format, settings.codec.codec()).write(&mut hello);
+        let mut frame = Vec::new();
+        framing::frame(&hello, &mut frame);
+        send.write_all(&frame).await?;
+        // Whatever queued up while disconnected is stale by now.
+        packets.discard(b);
+        let mut frames = FrameReader::new();
+        let mut control_open = true;
+        let mut datagrams = Vec::new();
+        loop {
+            tokio::select! {
+                _ = &mut *stop => {
+                    connection.close(0u32.into(), b"stopped");
+                    return Ok(());
+                }
+                _ = wake.notified() => {}
+                result = frames.next(&mut recv), if control_open => match result? {
+                    Some(message) => match Control::parse(message)? {
+                        Control::Reject(reason) => return Err(anyhow!("rejected: {}", reason)),
+                        message => warn!("quic tx: unexpected {:?}", message),
+                    },
+                    None => control_open = false,
+                },
+            }
+            let result = packets.drain(b, |hdr, packet| {
+                datagrams.push(bytes::Bytes::copy_from_slice(packet));
+                if let Some(fec) = &mut fec {
+                    fec.push(hdr, packet, |parity| {
+                        datagrams.push(bytes::Bytes::copy_from_slice(parity))
+                    });
+                }
+            });
+            if let Err(e) = result {
+                error!("quic tx: {}", e);
+            }
+            for datagram in datagrams.drain(..) {
+                let len = datagram.len();
+                match connection.send_datagram(datagram) {
+                    Ok(()) => {
+                        stats.sent(len);
+                        backoff.reset();
+                    }
+                    Err(quinn::SendDatagramError::TooLarge) => {
+                        warn!(
+                            "quic tx: dropping packet larger than {} bytes",
+                            max_datagram
+                        )
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+}
+
+impl<B, T> TxStream<T> for QuicTxStream<B, T>
+where
+    B: 'static + Buffer<T>,
+    T: Clone,
+{
+    fn send(&self, payload: &[T]) {
+        self.buf.accumulate(payload);
+        self.wake.notify();
+    }
+}
@@ -0,0 +1,248 @@
+use super::batch::Batch;
+use super::*;
+use crate::sample::{Dither, Sample};
+use crate::stream::buffer::Buffer;
+use crate::stream::crypto;
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::rtp::{self, RtpConfig, RtpHeader, RTP_HEADER_LEN};
+use crate::stream::sap::Announcer;
+use crate::stream::sdp::SessionDescription;
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::StreamStats;
+use std::marker::PhantomData;
+use tokio::sync::{oneshot, Notify};
+
+pub struct RtpTxStream<B, T>
+where
+    B: Buffer<T>,
+    T: Clone,
+{
+    shutdown: Shutdown,
+    buf: std::sync::Arc<B>,
+    /// Wakes the send task when samples are accumulated.
+    wake: std::sync::Arc<Notify>,
+    /// Describes the stream with a session name.
+    sdp: SessionDescription,
+    /// Where the stream is announced, if it is.
+    announcer: std::sync::Mutex<Option<std::sync::Arc<Announcer>>>,
+    stats: std::sync::Arc<StreamStats>,
+    phantom: PhantomData<T>,
+}
+
+impl<B, T> RtpTxStream<B, T>
+where
+    B: 'static + Buffer<T>,
+    T: Sample,
+{
+    /// Streams audio to `dest` as RTP packets of `rtp.packet_time`,
+    /// converted from `T` with TPDF dither if `dither` is set, as
+    /// [`super::udp::UdpTxStream::new`] describes. Samples are held
+    /// back until a whole packet's worth has accumulated. Must be
+    /// called from within a tokio runtime.
+    pub fn new(
+        dest: std::net::SocketAddr,
+        rtp: &RtpConfig,
+        dither: bool,
+        multicast: &MulticastConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        rtp.validate()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let sock = multicast::sender(dest, multicast)?;
+        sock.set_nonblocking(true)?;
+        let packets = RtpPackets::new(*rtp, dither);
+        let sdp = SessionDescription {
+            session_id: packets.hdr.ssrc as u64,
+            session_version: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            origin: multicast::source_addr(dest, multicast),
+            name: String::new(),
+            dest,
+            ttl: Some(multicast.ttl),
+            rtp: *rtp,
+        };
+        let buf = std::sync::Arc::new(B::with_channels(rtp.channels as usize));
+        let wake = std::sync::Arc::new(Notify::new());
+        let stats = std::sync::Arc::new(StreamStats::new());
+        let shutdown = {
+            let (buf, wake, stats) = (buf.clone(), wake.clone(), stats.clone());
+            Shutdown::spawn(move |stop| Self::entry(buf, wake, stats, sock, dest, packets, stop))
+        };
+        Ok(std::sync::Arc::new(Self {
+            shutdown,
+            buf,
+            wake,
+            sdp,
+            announcer: std::sync::Mutex::new(None),
+            stats,
+            phantom: PhantomData,
+        }))
+    }
+
+    /// Describes the stream as `name`, for receivers to subscribe to.
+    pub fn describe(&self, name: &str) -> SessionDescription {
+        SessionDescription {
+            name: name.to_string(),
+            ..self.sdp.clone()
+        }
+    }
+
+    /// Announces the stream as `name` until it's shut down or
+    /// dropped.
+    pub fn announce(&self, announcer: &std::sync::Arc<Announcer>, name: &str) {
+        announcer.announce(self.describe(name));
+        if let Some(previous) = self.announcer.lock().unwrap().replace(announcer.clone()) {
+            if !std::sync::Arc::ptr_eq(&previous, announcer) {
+                previous.withdraw(self.sdp.session_id);
+            }
+        }
+    }
+
+    /// Counters of the packets sent, readable from any thread.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
+    }
+
+    /// Stops sending and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.withdraw();
+        self.shutdown.shutdown().await
+    }
+
+    fn withdraw(&self) {
+        if let Some(announcer) = self.announcer.lock().unwrap().take() {
+            announcer.withdraw(self.sdp.session_id);
+        }
+    }
+
+    async fn entry(
+        b: std::sync::Arc<B>,
+        wake: std::sync::Arc<Notify>,
+        stats: std::sync::Arc<StreamStats>,
+        sock: std::net::UdpSocket,
+        dest: std::net::SocketAddr,
+        mut packets: RtpPackets<T>,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("rtp tx: {}", e);
+                return;
+            }
+        };
+        let mut batch = Batch::new();
+        loop {
+            tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                _ = wake.notified() => {}
+            }
+            packets.drain(&*b, |packet| batch.push(packet));
+            batch.send(&mut sock, dest, &stats).await;
+        }
+    }
+}
+
+impl<B, T> Drop for RtpTxStream<B, T>
+where
+    B: Buffer<T>,
+    T: Clone,
+{
+    fn drop(&mut self) {
+        if let Some(announcer) = self.announcer.lock().unwrap().take() {
+            announcer.withdraw(self.sdp.session_id);
+        }
+    }
+}
+
+impl<B, T> TxStream<T> for RtpTxStream<B, T>
+where
+    B: 'static + Buffer<T>,
+    T: Clone,
+{
+    fn send(&self, payload: &[T]) {
+        // Accumulate the samples in the send buffer
+        self.buf.accumulate(payload);
+        self.wake.notify();
+    }
+}
+
+/// Cuts accumulated samples into packets of exactly one packet time.
+struct RtpPackets<T> {
+    rtp: RtpConfig,
+    /// Samples that don't yet fill a packet.
+    pending: Vec<T>,
+    scratch: Vec<T>,
+    buf: Vec<u8>,
+    /// Header of the next packet.
+    hdr: RtpHeader,
+    dither: Option<Dither>,
+}
+
+impl<T> RtpPackets<T>
+where
+    T: Sample,
+{
+    fn new(rtp: RtpConfig, dither: bool) -> Self {
+        let samples = rtp.frames() * rtp.channels as usize;
+        let bytes = samples * rtp.encoding.sample_format().bytes_per_sample();
+        // The sequence number and timestamp start at random values,
+        // as RFC 3550 asks.
+        let random: [u8; 8] = crypto::random();
+        let ssrc = u32::from_be_bytes([random[0], random[1], random[2], random[3]]);
+        let start = u32::from_be_bytes([random[4], random[5], random[6], random[7]]);
+        Self {
+            rtp,
+            pending: Vec::with_capacity(2 * samples),
+            scratch: vec![T::default(); samples],
+            buf: vec![0; RTP_HEADER_LEN + bytes],
+            hdr: RtpHeader {
+                marker: true,
+                payload_type: rtp.payload_type,
+                sequence: start as u16,
+                timestamp: start,
+                ssrc,
+            },
+            dither: if dither {
+                Some(Dither::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Packetizes everything accumulated in `b`, passing each packet
+    /// to `emit`. A partial packet is kept until more samples arrive.
+    fn drain<B, F>(&mut self, b: &B, mut emit: F)
+    where
+        B: Buffer<T>,
+        F: FnMut(&[u8]),
+    {
+        loop {
+            let amt = b.flush(&mut self.scratch[..]);
+            if amt == 0 {
+                return;
+            }
+            self.pending.extend_from_slice(&self.scratch[..amt]);
+            let samples = self.scratch.len();
+            let mut start = 0;
+            while self.pending.len() - start >= samples {
+                let len = self.hdr.write(&mut self.buf[..]);
+                rtp::encode(
+                    &self.pending[start..start + samples],
+                    self.rtp.encoding,
+                    &mut self.buf[len..],
+                    self.dither.as_mut(),
+                );
+                emit(&self.buf[..]);
+                start += samples;
+                self.hdr.marker = false;
+                self.hdr.sequence = self.hdr.sequence.wrapping_add(1);
+                self.hdr.timestamp = self.hdr.timestamp.wrapping_add(self.rtp.frames() as u32);
+            }
+            self.pending.drain(..start);
+        }
+    }
+}
@@ -1,75 +1,199 @@
+use super::packets::Packets;
 use super::*;
-use std::io::prelude::*;
+use crate::codec::CodecConfig;
+use crate::sample::Sample;
+use crate::stream::backoff::Backoff;
+use crate::stream::buffer::Buffer;
+use crate::stream::framing::{self, MAX_FRAME};
+use crate::stream::header::StreamFormat;
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::{ConnectionState, StreamStats};
+use std::marker::PhantomData;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Notify};
 
-pub struct TcpTxStream<B> where B: TxBuffer {
-    stop: crossbeam::crossbeam_channel::Sender<()>,
+/// Streams audio over a TCP connection, for networks where UDP is
+/// blocked. Packets are the same as over UDP, each prefixed with its
+/// length.
+///
+/// The connection is retried with exponential backoff until it
+/// succeeds, and again whenever it drops. Audio accumulated while
+/// disconnected is discarded, and each connection starts a new
+/// stream. When the receiver can't keep up, writes wait and samples
+/// queue in the send buffer, which drops them once it is full.
+pub struct TcpTxStream<B, T>
+where
+    B: Buffer<T>,
+    T: Clone,
+{
+    shutdown: Shutdown,
     buf: std::sync::Arc<B>,
+    /// Wakes the send task when samples are accumulated.
+    wake: std::sync::Arc<Notify>,
+    stats: std::sync::Arc<StreamStats>,
+    phantom: PhantomData<T>,
 }
 
-impl<B> std::ops::Drop for TcpTxStream<B>
-    where B: TxBuffer {
-    fn drop(&mut self) {
-        self.stop.send(());
-    }
-}
-
-impl<B> TcpTxStream<B> where B: 'static + TxBuffer {
+impl<B, T> TcpTxStream<B, T>
+where
+    B: 'static + Buffer<T>,
+    T: Sample,
+{
+    /// Streams audio to `dest`, converting and dithering samples as
+    /// `UdpTxStream` does. Must be called from within a tokio runtime.
     pub fn new(
-        addr: std::net::SocketAddr,
+        dest: std::net::SocketAddr,
+        format: StreamFormat,
+        codec: CodecConfig,
+        dither: bool,
     ) -> std::io::Result<std::sync::Arc<Self>> {
-        let (stop, stop_recv) = crossbeam::crossbeam_channel::unbounded();
-        let s = std::sync::Arc::new(Self {
-            stop,
-            buf: std::sync::Arc::new(B::new()),
-        });
-        tokio::task::spawn(Self::entry(s.buf.clone(), addr, stop_recv));
-        Ok(s)
+        if format.channels == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "stream format has no channels",
+            ));
+        }
+        // Each connection starts a new stream, but fail early if the
+        // codec can't handle the format at all.
+        Packets::<T>::new(format, codec, MAX_FRAME, dither)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let buf = std::sync::Arc::new(B::with_channels(format.channels as usize));
+        let wake = std::sync::Arc::new(Notify::new());
+        let stats = std::sync::Arc::new(StreamStats::new());
+        let shutdown = {
+            let (buf, wake, stats) = (buf.clone(), wake.clone(), stats.clone());
+            Shutdown::spawn(move |stop| {
+                Self::entry(buf, wake, stats, dest, format, codec, dither, stop)
+            })
+        };
+        Ok(std::sync::Arc::new(Self {
+            shutdown,
+            buf,
+            wake,
+            stats,
+            phantom: PhantomData,
+        }))
+    }
+
+    /// Counters of the packets sent, readable from any thread.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
     }
 
-    async fn entry(b: std::sync::Arc<B>, addr: std::net::SocketAddr, stop: crossbeam::crossbeam_channel::Receiver<()>) {
-        const BUFFER_SIZE: usize = 256_000;
-        let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
-        let mut stream = std::net::TcpStream::connect(addr).unwrap();
-        let clock = std::time::Instant::now();
+    /// Stops sending and waits until the connection is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn entry(
+        b: std::sync::Arc<B>,
+        wake: std::sync::Arc<Notify>,
+        stats: std::sync::Arc<StreamStats>,
+        dest: std::net::SocketAddr,
+        format: StreamFormat,
+        codec: CodecConfig,
+        dither: bool,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        let mut backoff = Backoff::default();
         loop {
-            std::thread::yield_now();
-            match stop.try_recv() {
-                Ok(_) => {
-                    return;
-                }
-                Err(e) => match e {
-                    crossbeam::channel::TryRecvError::Empty => {},
-                    crossbeam::channel::TryRecvError::Disconnected => {
-                        // Stop stream send channel was dropped.
-                        return;
-                    },
-                }
+            stats.set_connection(ConnectionState::Connecting);
+            let result = tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                result = TcpStream::connect(dest) => result,
             };
-            let hdr_len = write_message_header(&mut buf[..], Some(0), Some(clock.elapsed()));
-            let data: &mut [f32] = unsafe { std::slice::from_raw_parts_mut(buf[hdr_len..].as_mut_ptr() as _, buf[hdr_len..].len() / 4) };
-            let amt = b.flush(data);
-            if amt == 0 {
+            match result {
+                Ok(mut sock) => {
+                    if let Err(e) = sock.set_nodelay(true) {
+                        warn!("tcp tx: {}", e);
+                    }
+                    info!("tcp tx: connected to {}", dest);
+                    stats.set_connection(ConnectionState::Connected);
+                    let result = Self::send_all(
+                        &b,
+                        &wake,
+                        &stats,
+                        &mut sock,
+                        format,
+                        codec,
+                        dither,
+                        &mut backoff,
+                        &mut stop,
+                    )
+                    .await;
+                    match result {
+                        Ok(()) => return,
+                        Err(e) => warn!("tcp tx: connection to {} lost: {}", dest, e),
+                    }
+                }
+                Err(e) => warn!("tcp tx: connect to {}: {}", dest, e),
+            }
+            stats.set_connection(ConnectionState::Backoff);
+            let delay = backoff.next_delay();
+            info!("tcp tx: reconnecting to {} in {:?}", dest, delay);
+            tokio::select! {
+                _ = &mut stop => return,
+                _ = tokio::time::delay_for(delay) => {}
+            }
+        }
+    }
+
+    /// Streams over a single connection. Returns `Ok` once stopped,
+    /// or the error that ended the connection.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_all(
+        b: &B,
+        wake: &Notify,
+        stats: &StreamStats,
+        sock: &mut TcpStream,
+        format: StreamFormat,
+        codec: CodecConfig,
+        dither: bool,
+        backoff: &mut Backoff,
+        stop: &mut oneshot::Receiver<()>,
+    ) -> std::io::Result<()> {
+        let mut packets = Packets::new(format, codec, MAX_FRAME, dither)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        // Whatever queued up while disconnected is stale by now.
+        packets.discard(b);
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            tokio::select! {
+                _ = &mut *stop => return Ok(()),
+                _ = wake.notified() => {}
+            }
+            let result = packets.drain(b, |_, packet| {
+                stats.sent(packet.len());
+                framing::frame(packet, &mut out)
+            });
+            if let Err(e) = result {
+                error!("tcp tx: {}", e);
+            }
+            if out.is_empty() {
                 continue;
             }
-            // Include datagram length in message
-            let i = hdr_len + amt * 4;
-            buf[0] = (i >> 24) as u8;
-            buf[1] = (i >> 16) as u8;
-            match stream.write(&buf[..i]) {
-                Ok(_) => {},
-                Err(e) => {
-                    println!("tcp stream write: {:?}", e);
-                    continue;
-                },
-            };
+            // Blocks while the receiver is behind, which is what lets
+            // the send buffer absorb the backlog.
+            tokio::select! {
+                _ = &mut *stop => return Ok(()),
+                result = sock.write_all(&out) => result?,
+            }
+            out.clear();
+            backoff.reset();
         }
     }
 }
 
-impl<B> TxStream for TcpTxStream<B> where B: 'static + TxBuffer {
-    fn process(&self, input_buffer: &[f32]) {
-        // Accumulate the samples in the send buffer
-        self.buf.accumulate(input_buffer)
+impl<B, T> TxStream<T> for TcpTxStream<B, T>
+where
+    B: 'static + Buffer<T>,
+    T: Clone,
+{
+    fn send(&self, payload: &[T]) {
+        self.buf.accumulate(payload);
+        self.wake.notify();
     }
 }
-
@@ -1,109 +1,152 @@
+use super::batch::Batch;
+use super::packets::Packets;
 use super::*;
+use crate::codec::CodecConfig;
+use crate::sample::Sample;
 use crate::stream::buffer::Buffer;
-use std::marker::PhatomData;
+use crate::stream::crypto::{Key, Sealer, SEAL_OVERHEAD};
+use crate::stream::fec::{FecConfig, FecEncoder, PARITY_OVERHEAD};
+use crate::stream::header::{StreamFormat, HEADER_LEN};
+use crate::stream::multicast::{self, MulticastConfig};
+use crate::stream::shutdown::Shutdown;
+use crate::stream::stats::StreamStats;
+use std::marker::PhantomData;
+use tokio::sync::{oneshot, Notify};
+
+/// Largest payload that fits in a single UDP datagram.
+pub(crate) const MAX_DATAGRAM: usize = 65_507;
 
 pub struct UdpTxStream<B, T>
 where
     B: Buffer<T>,
     T: Clone,
 {
-    stop: crossbeam::crossbeam_channel::Sender<()>,
+    shutdown: Shutdown,
     buf: std::sync::Arc<B>,
-    clock: std::sync::Arc<std::sync::atomic::AtomicU64>,
-    status: std::sync::Arc<std::sync::atomic::AtomicU64>,
-    phantom: PhatomData<T>,
-}
-
-impl<B, T> std::ops::Drop for UdpTxStream<B, T>
-where
-    B: Buffer<T>,
-    T: Clone,
-{
-    fn drop(&mut self) {
-        self.stop.send(());
-    }
+    /// Wakes the send task when samples are accumulated.
+    wake: std::sync::Arc<Notify>,
+    stats: std::sync::Arc<StreamStats>,
+    phantom: PhantomData<T>,
 }
 
 impl<B, T> UdpTxStream<B, T>
 where
     B: 'static + Buffer<T>,
-    T: Clone,
+    T: Sample,
 {
-    pub fn new(dest: std::net::SocketAddr) -> std::io::Result<std::sync::Arc<Self>> {
-        let addr = format!("0.0.0.0:0"); // double check me
-        let sock = std::net::UdpSocket::bind(addr)?;
-        let (s, r) = crossbeam::crossbeam_channel::unbounded();
-        let clock = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
-        let status = std::sync::Arc::new(std::sync::atomic::AtomicU64::new(0));
-        let stream = std::sync::Arc::new(Self {
-            stop: s,
-            buf: std::sync::Arc::new(B::new()),
-            clock: status.clone(),
-            status: status.clone(),
+    /// Streams audio to `dest`. Samples are sent in the format's
+    /// sample format, converted from `T` if that differs, with TPDF
+    /// dither if `dither` is set and the conversion loses precision.
+    /// Datagrams are encrypted if a pre-shared `key` is given. `dest`
+    /// may be a multicast group, sent to as `multicast` configures.
+    /// Must be called from within a tokio runtime.
+    pub fn new(
+        dest: std::net::SocketAddr,
+        format: StreamFormat,
+        codec: CodecConfig,
+        fec: Option<FecConfig>,
+        dither: bool,
+        key: Option<&Key>,
+        multicast: &MulticastConfig,
+    ) -> std::io::Result<std::sync::Arc<Self>> {
+        if format.channels == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "stream format has no channels",
+            ));
+        }
+        // Parity packets wrap a whole datagram, so leave room for them.
+        let mut overhead = match fec {
+            Some(_) => HEADER_LEN + PARITY_OVERHEAD,
+            None => 0,
+        };
+        if key.is_some() {
+            overhead += SEAL_OVERHEAD;
+        }
+        let packets = Packets::new(format, codec, MAX_DATAGRAM - overhead, dither)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let fec = fec
+            .map(FecEncoder::new)
+            .transpose()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let sealer = key.map(Sealer::new);
+        let sock = multicast::sender(dest, multicast)?;
+        sock.set_nonblocking(true)?;
+        let buf = std::sync::Arc::new(B::with_channels(format.channels as usize));
+        let wake = std::sync::Arc::new(Notify::new());
+        let stats = std::sync::Arc::new(StreamStats::new());
+        let shutdown = {
+            let (buf, wake, stats) = (buf.clone(), wake.clone(), stats.clone());
+            Shutdown::spawn(move |stop| {
+                Self::entry(buf, wake, stats, sock, dest, packets, fec, sealer, stop)
+            })
+        };
+        Ok(std::sync::Arc::new(Self {
+            shutdown,
+            buf,
+            wake,
+            stats,
             phantom: PhantomData,
-        });
-        tokio::task::spawn(Self::entry(
-            stream.buf.clone(),
-            sock,
-            dest,
-            clock,
-            status,
-            r,
-        ));
-        Ok(stream)
+        }))
+    }
+
+    /// Counters of the packets sent, readable from any thread.
+    pub fn stats(&self) -> &std::sync::Arc<StreamStats> {
+        &self.stats
     }
 
+    /// Stops sending and waits until the socket is closed.
+    pub async fn shutdown(&self) {
+        self.shutdown.shutdown().await
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn entry(
         b: std::sync::Arc<B>,
+        wake: std::sync::Arc<Notify>,
+        stats: std::sync::Arc<StreamStats>,
         sock: std::net::UdpSocket,
         dest: std::net::SocketAddr,
-        clock: std::sync::Arc<std::sync::atomic::AtomicU64>,
-        status: std::sync::Arc<std::sync::atomic::AtomicU64>,
-        stop: crossbeam::crossbeam_channel::Receiver<()>,
+        mut packets: Packets<T>,
+        mut fec: Option<FecEncoder>,
+        mut sealer: Option<Sealer>,
+        mut stop: oneshot::Receiver<()>,
     ) {
-        const BUFFER_SIZE: usize = 256_000;
-        let mut buf: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+        let mut sock = match tokio::net::UdpSocket::from_std(sock) {
+            Ok(sock) => sock,
+            Err(e) => {
+                error!("udp tx: {}", e);
+                return;
+            }
+        };
+        let mut batch = Batch::new();
         loop {
-            std::thread::yield_now();
-            match stop.try_recv() {
-                Ok(_) => {
-                    return;
-                }
-                Err(e) => match e {
-                    crossbeam::channel::TryRecvError::Empty => {}
-                    crossbeam::channel::TryRecvError::Disconnected => {
-                        // Stop stream send channel was dropped.
-                        return;
-                    }
-                },
-            };
-            let clock = clock.load(std::sync::atomic::Ordering::SeqCst);
-            let status = status.load(std::sync::atomic::Ordering::SeqCst) as u8;
-            let hdr_len = write_message_header(&mut buf[..], None, clock, status);
-            let data: &mut [T] = unsafe {
-                std::slice::from_raw_parts_mut(
-                    buf[hdr_len..].as_mut_ptr() as _,
-                    buf[hdr_len..].len() / 4,
-                )
-            };
-            let amt = b.flush(data);
-            if amt == 0 {
-                continue;
+            tokio::select! {
+                // Stopped, or the stream was dropped.
+                _ = &mut stop => return,
+                _ = wake.notified() => {}
             }
-            let i = hdr_len + amt * 4;
-            match sock.send_to(&buf[..i], dest) {
-                Ok(amt) => {
-                    // TODO
-                }
-                Err(e) => {
-                    // TODO
+            // Packetize everything accumulated since the last wakeup,
+            // then send it in one go.
+            let mut push = |datagram: &[u8]| match &mut sealer {
+                Some(sealer) => batch.push(sealer.seal(datagram)),
+                None => batch.push(datagram),
+            };
+            let result = packets.drain(&*b, |hdr, packet| {
+                push(packet);
+                if let Some(fec) = &mut fec {
+                    fec.push(hdr, packet, &mut push);
                 }
+            });
+            if let Err(e) = result {
+                error!("udp tx: {}", e);
             }
+            batch.send(&mut sock, dest, &stats).await;
         }
     }
 }
 
-impl<B, T> TxStream for UdpTxStream<B, T>
+impl<B, T> TxStream<T> for UdpTxStream<B, T>
 where
     B: 'static + Buffer<T>,
     T: Clone,
@@ -111,5 +154,6 @@ where
     fn send(&self, payload: &[T]) {
         // Accumulate the samples in the send buffer
         self.buf.accumulate(payload);
+        self.wake.notify();
     }
 }
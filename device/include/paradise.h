@@ -13,10 +13,13 @@ struct DriverHandle {
 
 extern "C" {
 
+/// Called from the IO thread with `num_frames` interleaved 32-bit
+/// float frames, one sample per output channel.
 void rust_io_proc(const void *driver,
                   const uint8_t *buffer,
-                  uint32_t buffer_size,
-                  double sample_time);
+                  uint32_t num_frames,
+                  double sample_time,
+                  double sample_rate);
 
 DriverHandle rust_new_driver(const char *driver_name, const char *driver_path);
 
@@ -5349,7 +5349,8 @@ OSStatus ProxyAudioDevice::DoIOOperation(AudioServerPlugInDriverRef inDriver,
             rust_io_proc(rust_driver.weak,
                          (const Byte *) ioMainBuffer,
                          inIOBufferFrameSize,
-                         inIOCycleInfo->mOutputTime.mSampleTime);
+                         inIOCycleInfo->mOutputTime.mSampleTime,
+                         gDevice_SampleRate);
 
             inputBuffer->Store((const Byte *)ioMainBuffer, inIOBufferFrameSize, inIOCycleInfo->mOutputTime.mSampleTime);
             
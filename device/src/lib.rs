@@ -15,53 +15,21 @@ use std::{ptr, ffi::{c_void, CStr}};
 use std::path::PathBuf;
 use std::os::raw::c_char;
 use anyhow::{Result, Error};
-use paradise_core::{Frame, device::{DeviceSpec, Endpoint}};
-use futures::StreamExt;
-use std::{net::SocketAddr, sync::{Arc, Weak, Mutex}};
-use quinn::{ClientConfig, ClientConfigBuilder};
-/// Dummy certificate verifier that treats any certificate as valid.
-/// NOTE, such verification is vulnerable to MITM attacks, but convenient for testing.
-struct SkipServerVerification;
-
-impl SkipServerVerification {
-    fn new() -> Arc<Self> {
-        Arc::new(Self)
-    }
-}
-
-impl rustls::ServerCertVerifier for SkipServerVerification {
-    fn verify_server_cert(
-        &self,
-        _roots: &rustls::RootCertStore,
-        _presented_certs: &[rustls::Certificate],
-        _dns_name: webpki::DNSNameRef,
-        _ocsp_response: &[u8],
-    ) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
-        Ok(rustls::ServerCertVerified::assertion())
-    }
-}
-
-async fn connect(server_addr: SocketAddr) -> Result<(quinn::Endpoint, quinn::Connection)> {
-    warn!("configuring client");
-    let client_cfg = configure_client();
-
-    warn!("building endpoint...");
-    let mut endpoint_builder = quinn::Endpoint::builder();
-    endpoint_builder.default_client_config(client_cfg);
-
-    let addr = "127.0.0.1:0".parse()?;
-    warn!("binding endpoint {}", &addr);
-    let (endpoint, _) = endpoint_builder.bind(&addr)?;
-
-    warn!("connecting to server...");
-    let quinn::NewConnection { connection, .. } = endpoint
-        .connect(&server_addr, "localhost")?
-        .await?;
-
-    warn!("[client] connected: addr={}", connection.remote_address());
-
-    Ok((endpoint, connection))
-}
+use paradise_core::{
+    buffer::ring::RingBuffer,
+    channels::ChannelMap,
+    device::{DeviceSpec, Endpoint},
+    stream::{
+        addr::Protocol,
+        backoff::Backoff,
+        header::StreamFormat,
+        metrics::{Direction, Metrics, MetricsServer},
+        shutdown::Shutdown,
+        tx::{quic::QuicTxStream, TxStream},
+    },
+};
+use std::{net::SocketAddr, sync::{Arc, Weak, Mutex, atomic::{AtomicU32, Ordering}}};
+use tokio::sync::{oneshot, Notify};
 
 fn init_logger() -> Result<()> {
     std::panic::set_hook(Box::new(|panic_info| {
@@ -97,16 +65,6 @@ fn init_logger() -> Result<()> {
     Ok(())
 }
 
-fn configure_client() -> ClientConfig {
-    let mut cfg = ClientConfigBuilder::default().build();
-    let tls_cfg: &mut rustls::ClientConfig = Arc::get_mut(&mut cfg.crypto).unwrap();
-    // this is only available when compiled with "dangerous_configuration" feature
-    tls_cfg
-        .dangerous()
-        .set_certificate_verifier(SkipServerVerification::new());
-    cfg
-}
-
 lazy_static! {
     static ref RUNTIME: Arc<Mutex<tokio::runtime::Runtime>> = Arc::new(Mutex::new(tokio::runtime::Builder::new()
         .threaded_scheduler()
@@ -120,65 +78,189 @@ async fn driver_entry(driver: Arc<Driver>, stop: Receiver<()>) -> Result<()> {
         return Err(Error::msg("no endpoints"));
     }
     for endpoint in &driver.spec.endpoints {
-        let driver = driver.clone();
-        let endpoint = endpoint.clone();
-        tokio::spawn(async move {
-            driver.connect_with_retry(endpoint).await;
-        });
+        let addr: SocketAddr = match endpoint.addr.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("error parsing addr '{}' for endpoint '{}': {}", &endpoint.addr, &endpoint.name, e);
+                continue;
+            }
+        };
+        let map = match &endpoint.channels {
+            Some(channels) => match ChannelMap::new(channels, driver.spec.outputs) {
+                Ok(map) => map,
+                Err(e) => {
+                    error!("invalid channels for endpoint '{}': {}", &endpoint.name, e);
+                    continue;
+                }
+            },
+            None => ChannelMap::identity(driver.spec.outputs),
+        };
+        let output = Output::new(endpoint.clone(), addr, map, driver.metrics.clone());
+        if let Err(e) = driver.add_output(output) {
+            error!("failed to add output: {}", e);
+        }
+    }
+    if let Some(addr) = driver.spec.metrics {
+        match MetricsServer::new(addr, driver.metrics.clone()) {
+            Ok(server) => {
+                warn!("serving metrics on http://{}{}", server.local_addr(), paradise_core::stream::metrics::PATH);
+                *driver.metrics_server.lock().unwrap() = Some(server);
+            }
+            Err(e) => error!("cannot serve metrics on {}: {}", addr, e),
+        }
     }
     Ok(())
 }
 
+/// Largest IO buffer the host is expected to hand the driver, in
+/// frames. Scratch space is allocated for it up front.
+const MAX_IO_FRAMES: usize = 4096;
+
+type Stream = Arc<QuicTxStream<RingBuffer<f32>, f32>>;
+
+/// Stream to an endpoint, along with the format it was created for.
+type Slot = Arc<Mutex<Option<(StreamFormat, Stream)>>>;
+
 pub struct Output {
     pub spec: Endpoint,
-    pub conn: quinn::Connection,
-    pub endpoint: quinn::Endpoint,
+    /// Device channels sent to the endpoint.
+    map: ChannelMap,
+    /// Holds the selected channels while they're sent.
+    scratch: Vec<f32>,
+    /// Sample rate the host is running at, or 0 before the first
+    /// buffer.
+    rate: Arc<AtomicU32>,
+    /// Wakes the task that builds the stream when `rate` changes.
+    wake: Arc<Notify>,
+    /// Stream ready to send to. Streams do file and socket IO and
+    /// spawn tasks when they're created, none of which may happen on
+    /// the IO thread, so a task on the runtime builds them and swaps
+    /// them in here. Each connects, and reconnects, on its own.
+    stream: Slot,
+    /// Stops the task building the stream.
+    builder: Shutdown,
 }
 
-pub struct Driver {
-    // TODO: spec for inputs and outputs
-    outputs: Mutex<Vec<Output>>,
-    spec: DeviceSpec,
-    stop: Mutex<Sender<()>>,
+impl Output {
+    /// Must be called from within the runtime.
+    fn new(spec: Endpoint, addr: SocketAddr, map: ChannelMap, metrics: Arc<Metrics>) -> Self {
+        let rate = Arc::new(AtomicU32::new(0));
+        let wake = Arc::new(Notify::new());
+        let stream: Slot = Arc::new(Mutex::new(None));
+        let scratch = Vec::with_capacity(MAX_IO_FRAMES * map.channels() as usize);
+        let builder = {
+            let (spec, channels) = (spec.clone(), map.channels());
+            let (rate, wake, stream) = (rate.clone(), wake.clone(), stream.clone());
+            Shutdown::spawn(move |stop| build(spec, addr, channels, rate, wake, stream, metrics, stop))
+        };
+        Output {
+            spec,
+            map,
+            scratch,
+            rate,
+            wake,
+            stream,
+            builder,
+        }
+    }
+
+    /// Sends the endpoint's channels of `samples`, which are
+    /// interleaved across all of the device's outputs. Called on the
+    /// IO thread, so it never waits: audio is dropped until a stream
+    /// for `sample_rate` is ready.
+    fn send(&mut self, sample_rate: u32, samples: &[f32]) {
+        if self.rate.swap(sample_rate, Ordering::SeqCst) != sample_rate {
+            // The host changed the sample rate, or this is the first
+            // buffer.
+            self.wake.notify();
+        }
+        let stream = match self.stream.try_lock() {
+            Ok(stream) => stream,
+            // A new stream is being swapped in.
+            Err(_) => return,
+        };
+        if let Some((format, stream)) = &*stream {
+            if format.sample_rate == sample_rate {
+                stream.send(self.map.gather(samples, &mut self.scratch));
+            }
+        }
+    }
 }
 
-impl Driver {
-    /// "Fire and forget" connect method
-    async fn connect_with_retry(&self, endpoint: Endpoint) {
-        warn!("connecting to '{}' ({}, insecure={})",
-              &endpoint.name,
-              &endpoint.addr,
-              &endpoint.insecure);
-        let server_addr: SocketAddr = match endpoint.addr.parse() {
-            Ok(v) => v,
+/// Builds a stream to the endpoint whenever the host's sample rate
+/// changes, retrying with backoff until one can be created.
+#[allow(clippy::too_many_arguments)]
+async fn build(
+    spec: Endpoint,
+    addr: SocketAddr,
+    channels: u16,
+    rate: Arc<AtomicU32>,
+    wake: Arc<Notify>,
+    slot: Slot,
+    metrics: Arc<Metrics>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let mut backoff = Backoff::default();
+    let mut retry = false;
+    loop {
+        if retry {
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = tokio::time::delay_for(backoff.next_delay()) => {}
+                _ = wake.notified() => {}
+            }
+        } else {
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = wake.notified() => {}
+            }
+        }
+        let sample_rate = rate.load(Ordering::SeqCst);
+        if sample_rate == 0 {
+            continue;
+        }
+        let format = StreamFormat::new(spec.sample_format, channels, sample_rate);
+        if let Some((current, _)) = &*slot.lock().unwrap() {
+            if *current == format {
+                retry = false;
+                continue;
+            }
+        }
+        let stream = match QuicTxStream::new(addr, format, spec.codec, None, spec.dither, spec.tls.as_ref(), spec.insecure) {
+            Ok(stream) => stream,
             Err(e) => {
-                error!("error parsing addr '{}' for endpoint '{}': {}", &endpoint.addr, &endpoint.name, e);
-                return;
+                error!("cannot stream to output '{}': {}", &spec.name, e);
+                metrics.unregister(&spec.name, Direction::Tx);
+                retry = true;
+                continue;
             }
         };
-        loop {
-            match connect(server_addr.clone()).await {
-                Ok((e, conn)) => {
-                    match self.add_output(Output {
-                        spec: endpoint,
-                        endpoint: e,
-                        conn,
-                    }) {
-                        Err(e) => {
-                            error!("failed to add output: {}", e);
-                        },
-                        _ => {},
-                    }
-                    return;
-                },
-                Err(e) => {
-                    error!("error connecting to {}: {}", &server_addr, e);
-                    std::thread::sleep(std::time::Duration::from_secs(5));
-                }
-            }
+        backoff.reset();
+        retry = false;
+        metrics.register(&spec.name, Direction::Tx, Protocol::Quic, stream.stats().clone());
+        let old = slot.lock().unwrap().replace((format, stream));
+        if let Some((_, old)) = old {
+            old.shutdown().await;
         }
     }
+    let stream = slot.lock().unwrap().take();
+    if let Some((_, stream)) = stream {
+        stream.shutdown().await;
+    }
+}
+
+pub struct Driver {
+    // TODO: spec for inputs and outputs
+    outputs: Mutex<Vec<Output>>,
+    spec: DeviceSpec,
+    stop: Mutex<Sender<()>>,
+    /// Statistics and connection states of the output streams.
+    metrics: Arc<Metrics>,
+    /// Serves `metrics` if the spec gives an address to.
+    metrics_server: Mutex<Option<Arc<MetricsServer>>>,
+}
 
+impl Driver {
     fn add_output(&self, output: Output) -> Result<()> {
         let mut outputs = self.outputs.lock().unwrap();
         if let Some(_) = outputs.iter().find(|o| o.spec.name == output.spec.name) {
@@ -193,27 +275,33 @@ impl Driver {
         Ok(())
     }
 
-    fn io_proc(&self, buffer: &[u8], sample_time: f64) -> Result<()> {
-        let payload = bytes::Bytes::from(bincode::serialize(&Frame{
-            buffer: Vec::from(buffer),
-            sample_time,
-        })?);
-        let outputs = match self.outputs.try_lock() {
+    /// Packets are timestamped by each stream's own sample clock, so
+    /// the host's `_sample_time` isn't needed.
+    fn io_proc(&self, buffer: &[u8], _sample_time: f64, sample_rate: f64) -> Result<()> {
+        let mut outputs = match self.outputs.try_lock() {
             Ok(l) => l,
             Err(e) => return Err(anyhow!("{:?}", e)),
         };
-        for output in &*outputs {
-            match output.conn.send_datagram(payload.clone()) {
-                Ok(()) => {}
-                Err(e) => {
-                    error!("failed to send datagram to output '{}': {}", &output.spec.name, e);
-                }
-            }
+        if buffer.as_ptr() as usize % std::mem::align_of::<f32>() != 0 {
+            return Err(anyhow!("IO buffer is not aligned for f32 samples"));
+        }
+        // Aligned, and any bit pattern is an f32.
+        let samples = unsafe {
+            std::slice::from_raw_parts(buffer.as_ptr() as *const f32, buffer.len() / std::mem::size_of::<f32>())
+        };
+        for output in &mut *outputs {
+            output.send(sample_rate as u32, samples);
         }
         Ok(())
     }
 
     fn stop(&self) {
+        for output in &*self.outputs.lock().unwrap() {
+            output.builder.signal();
+        }
+        if let Some(server) = self.metrics_server.lock().unwrap().take() {
+            RUNTIME.lock().unwrap().block_on(server.shutdown());
+        }
         // TODO: wait for stoppage
         self.stop.lock()
             .unwrap()
@@ -222,8 +310,10 @@ impl Driver {
     }
 }
 
+/// Called from the IO thread with `num_frames` interleaved 32-bit
+/// float frames, one sample per output channel.
 #[no_mangle]
-pub extern "C" fn rust_io_proc(driver: *const c_void, buffer: *const u8, buffer_size: u32, sample_time: f64) {
+pub extern "C" fn rust_io_proc(driver: *const c_void, buffer: *const u8, num_frames: u32, sample_time: f64, sample_rate: f64) {
     let driver: Arc<Driver> = match unsafe {
         Weak::from_raw(driver as _)
     }.upgrade() {
@@ -233,10 +323,11 @@ pub extern "C" fn rust_io_proc(driver: *const c_void, buffer: *const u8, buffer_
             return;
         }
     };
+    let buffer_size = num_frames as usize * driver.spec.outputs as usize * std::mem::size_of::<f32>();
     let buffer = unsafe {
-        std::slice::from_raw_parts(buffer, buffer_size as _)
+        std::slice::from_raw_parts(buffer, buffer_size)
     };
-    match driver.io_proc(buffer, sample_time) {
+    match driver.io_proc(buffer, sample_time, sample_rate) {
         Err(e) => {
             error!("ioproc: {:?}", e)
         }
@@ -290,6 +381,8 @@ pub extern "C" fn rust_new_driver(driver_name: *const c_char, driver_path: *cons
         spec,
         stop: Mutex::new(stop_send),
         outputs: Mutex::new(vec![]),
+        metrics: Arc::new(Metrics::new()),
+        metrics_server: Mutex::new(None),
     });
     let strong = Arc::into_raw(driver.clone()) as _;
     let weak = Weak::into_raw(Arc::downgrade(&driver)) as _;